@@ -0,0 +1,110 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::fetcher::Source;
+use crate::jsonl;
+use crate::types::BeatmapInfo;
+
+/// one page of a fetch, a line of the checkpoint file
+#[derive(Debug, Serialize, Deserialize)]
+struct Batch {
+    user: String,
+    source: String,
+    /// offset of the page after this one
+    next_offset: usize,
+    maps: Vec<BeatmapInfo>,
+}
+
+/// the pages of a fetch saved as they arrive, so a fetch that crashed or failed can pick up
+/// after the last saved page with `fetch --resume`. removed once the fetch is saved
+pub struct Checkpoint {
+    path: PathBuf,
+    user: String,
+    // what the checkpoint file had when resuming, by source name
+    resumed: Mutex<HashMap<String, (usize, Vec<BeatmapInfo>)>>,
+}
+
+impl Checkpoint {
+    /// the checkpoint of a fetch saving to `output`
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut name = output.file_name().unwrap_or_default().to_os_string();
+        name.push(".checkpoint.jsonl");
+        jsonl::state_path(output.with_file_name(name))
+    }
+
+    /// start a checkpoint for fetching `user`'s maps. with `resume` the pages an earlier fetch
+    /// saved are picked up, otherwise they're thrown away
+    pub fn open(path: PathBuf, user: &str, resume: bool) -> Result<Self> {
+        let mut resumed: HashMap<String, (usize, Vec<BeatmapInfo>)> = HashMap::new();
+        if resume && path.exists() {
+            let contents =
+                jsonl::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+            // a crash can cut the last line short, it's fetched again
+            for batch in contents
+                .lines()
+                .filter_map(|l| serde_json::from_str::<Batch>(l).ok())
+            {
+                if batch.user != user {
+                    bail!(
+                        "{} is from fetching {}'s maps, not {}'s",
+                        path.display(),
+                        batch.user,
+                        user
+                    );
+                }
+                let (next_offset, maps) = resumed.entry(batch.source).or_default();
+                *next_offset = (*next_offset).max(batch.next_offset);
+                maps.extend(batch.maps);
+            }
+            for (source, (_, maps)) in &resumed {
+                println!(
+                    "Resuming {} after {} maps saved by an earlier fetch",
+                    source,
+                    maps.len()
+                );
+            }
+        } else if resume {
+            println!("No checkpoint at {}, fetching everything", path.display());
+        } else if path.exists() {
+            println!("Discarding the checkpoint of an earlier fetch, use --resume to continue it instead");
+            fs::remove_file(&path)?;
+        }
+        Ok(Self {
+            path,
+            user: user.to_string(),
+            resumed: Mutex::new(resumed),
+        })
+    }
+
+    /// the offset to continue `source` at and the maps fetched before it, (0, []) for a new fetch
+    pub fn resume_point(&self, source: Source) -> (usize, Vec<BeatmapInfo>) {
+        self.resumed
+            .lock()
+            .unwrap()
+            .remove(source.name())
+            .unwrap_or_default()
+    }
+
+    /// append the maps of a page, `next_offset` being where the following page starts
+    pub fn record(&self, source: Source, next_offset: usize, maps: &[BeatmapInfo]) -> Result<()> {
+        let batch = Batch {
+            user: self.user.clone(),
+            source: source.name().to_string(),
+            next_offset,
+            maps: maps.to_vec(),
+        };
+        jsonl::append(&self.path, &serde_json::to_string(&batch)?)
+    }
+
+    /// remove the checkpoint once the fetched maps are saved
+    pub fn finish(self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
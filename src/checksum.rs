@@ -0,0 +1,67 @@
+use anyhow::Result;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::persist;
+
+/// sidecar file in the output directory, in the same format `sha256sum` uses
+pub const CHECKSUMS_FILE: &str = "checksums.txt";
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// recorded checksums by filename, later lines win over earlier ones
+pub fn load(dir: &Path) -> HashMap<String, String> {
+    std::fs::read_to_string(dir.join(CHECKSUMS_FILE))
+        .map(|s| {
+            s.lines()
+                .filter_map(|l| l.split_once("  "))
+                .map(|(hash, name)| (name.to_string(), hash.to_string()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// append `hash`, the checksum of `filename` in `dir`
+pub fn record(dir: &Path, filename: &str, hash: &str) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(CHECKSUMS_FILE))?;
+    // a single write so concurrent downloads don't interleave lines
+    file.write_all(format!("{}  {}\n", hash, filename).as_bytes())?;
+    Ok(())
+}
+
+/// point the recorded checksums of renamed files at their new names
+pub fn rename(dir: &Path, renames: &[(String, String)]) -> Result<()> {
+    let path = dir.join(CHECKSUMS_FILE);
+    if renames.is_empty() || !path.exists() {
+        return Ok(());
+    }
+
+    let contents: String = std::fs::read_to_string(&path)?
+        .lines()
+        .map(|line| match line.split_once("  ") {
+            Some((hash, name)) => {
+                let name = renames
+                    .iter()
+                    .find(|(old, _)| old == name)
+                    .map_or(name, |(_, new)| new);
+                format!("{}  {}\n", hash, name)
+            }
+            None => format!("{}\n", line),
+        })
+        .collect();
+    persist::write_atomic(&path, contents.as_bytes())
+}
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// the most downloads that can be allowed at once while running
+pub const MAX_CONCURRENCY: usize = 32;
+
+/// how many downloads of a run go at once, adjustable while they run
+pub struct Concurrency {
+    permits: Semaphore,
+    limit: AtomicUsize,
+    // permits to drop instead of returning, lowering the limit while every permit was taken
+    owed: AtomicUsize,
+}
+
+/// a running download's share of the limit, returned when dropped
+pub struct Permit<'a> {
+    permit: Option<SemaphorePermit<'a>>,
+    owner: &'a Concurrency,
+}
+
+impl Concurrency {
+    pub fn new(limit: usize) -> Self {
+        let limit = limit.clamp(1, MAX_CONCURRENCY);
+        Self {
+            permits: Semaphore::new(limit),
+            limit: AtomicUsize::new(limit),
+            owed: AtomicUsize::new(0),
+        }
+    }
+
+    pub async fn acquire(&self) -> Permit<'_> {
+        // the semaphore is never closed
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("concurrency semaphore closed");
+        Permit {
+            permit: Some(permit),
+            owner: self,
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit.load(Ordering::SeqCst)
+    }
+
+    /// allow one more download at once, returning the new limit
+    pub fn raise(&self) -> usize {
+        if self
+            .limit
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < MAX_CONCURRENCY).then_some(n + 1)
+            })
+            .is_ok()
+        {
+            // a permit that was still going to be dropped is kept instead
+            let cancelled = self
+                .owed
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if !cancelled {
+                self.permits.add_permits(1);
+            }
+        }
+        self.limit()
+    }
+
+    /// allow one download fewer at once, returning the new limit. downloads already running
+    /// finish, the limit applies once enough of them did
+    pub fn lower(&self) -> usize {
+        if self
+            .limit
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n > 1).then_some(n - 1)
+            })
+            .is_ok()
+        {
+            match self.permits.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(_) => {
+                    self.owed.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        self.limit()
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let owed = &self.owner.owed;
+        if owed
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            if let Some(permit) = self.permit.take() {
+                permit.forget();
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::settings;
+use crate::units;
+use crate::verify;
+
+/// archives of one beatmapset, the one to keep and the others
+struct Duplicates {
+    id: u32,
+    keep: PathBuf,
+    remove: Vec<(PathBuf, u64)>,
+}
+
+/// sets with more than one archive in `dir`. the archive kept is the largest valid one, or the
+/// largest when none is valid
+fn find(dir: &Path) -> Result<Vec<Duplicates>> {
+    let mut by_id: HashMap<u32, Vec<PathBuf>> = HashMap::new();
+    for path in verify::list_archives(dir)? {
+        if let Some(id) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(verify::parse_mapset_id)
+        {
+            by_id.entry(id).or_default().push(path);
+        }
+    }
+
+    let mut duplicates: Vec<Duplicates> = by_id
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(id, paths)| {
+            let mut archives: Vec<(PathBuf, u64, bool)> = paths
+                .into_iter()
+                .map(|path| {
+                    let size = fs::metadata(&path).map_or(0, |m| m.len());
+                    let valid = verify::verify_archive(&path).is_ok();
+                    (path, size, valid)
+                })
+                .collect();
+            archives.sort_by_key(|(path, size, valid)| {
+                (std::cmp::Reverse((*valid, *size)), path.clone())
+            });
+            let keep = archives.remove(0).0;
+            let remove = archives
+                .into_iter()
+                .map(|(path, size, _)| (path, size))
+                .collect();
+            Duplicates { id, keep, remove }
+        })
+        .collect();
+    duplicates.sort_by_key(|d| d.id);
+    Ok(duplicates)
+}
+
+/// remove the extra archives of sets that have several in `dir` after asking
+pub fn dedupe_output(dir: &Path) -> Result<()> {
+    println!("\nLooking for duplicate archives in {}...", dir.display());
+    let duplicates = find(dir)?;
+    if duplicates.is_empty() {
+        println!("No duplicates found");
+        return Ok(());
+    }
+
+    for set in &duplicates {
+        println!("{}: keeping {}", set.id, set.keep.display());
+        for (path, _) in &set.remove {
+            println!("  remove {}", path.display());
+        }
+    }
+    let count: usize = duplicates.iter().map(|d| d.remove.len()).sum();
+    let bytes: u64 = duplicates
+        .iter()
+        .flat_map(|d| &d.remove)
+        .map(|(_, size)| size)
+        .sum();
+    let units = settings::get().units;
+    if !crate::prompt_confirm(&format!(
+        "Remove {} duplicate archives of {} sets ({})?",
+        units::count(count),
+        units::count(duplicates.len()),
+        units::bytes(bytes, units)
+    ))? {
+        println!("Left the duplicates in place");
+        return Ok(());
+    }
+
+    let mut reclaimed = 0;
+    for (path, size) in duplicates.iter().flat_map(|d| &d.remove) {
+        match fs::remove_file(path) {
+            Ok(()) => reclaimed += size,
+            Err(e) => println!("Failed to remove {}: {}", path.display(), e),
+        }
+    }
+    println!("Reclaimed {}", units::bytes(reclaimed, units));
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use anyhow::{bail, Result};
+use std::path::Path;
+
+use crate::downloader;
+use crate::exit::Failure;
+use crate::fetcher;
+use crate::persist;
+use crate::settings;
+use crate::units;
+
+// warn when less than this is free in the output directory
+const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+
+struct Checklist {
+    failed: usize,
+}
+
+impl Checklist {
+    fn pass(&self, msg: &str) {
+        println!("[ok]   {}", msg);
+    }
+
+    fn fail(&mut self, msg: &str, hint: &str) {
+        self.failed += 1;
+        println!("[fail] {}", msg);
+        println!("       hint: {}", hint);
+    }
+
+    fn check(&mut self, ok: bool, msg: &str, hint: &str) -> bool {
+        if ok {
+            self.pass(msg);
+        } else {
+            self.fail(msg, hint);
+        }
+        ok
+    }
+}
+
+/// check the configuration and environment, printing a pass/fail checklist
+pub async fn run(output_dir: &Path, user_agent: Option<&str>) -> Result<()> {
+    let mut list = Checklist { failed: 0 };
+    println!("osu! beatmap downloader doctor");
+    println!("==========================================\n");
+
+    let settings = settings::get();
+    if settings.env_files.is_empty() {
+        list.pass("no .env file found, using the environment only");
+    } else {
+        let files: Vec<String> = settings
+            .env_files
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect();
+        list.pass(&format!("settings read from {}", files.join(" over ")));
+    }
+    for problem in &settings.problems {
+        list.fail(
+            &problem.to_string(),
+            "fix the value in your .env or remove it",
+        );
+    }
+
+    // credentials, an invalid client ID already failed with the settings above
+    let id_ok = settings.problems.iter().all(|p| p.key != "OSU_CLIENT_ID")
+        && list.check(
+            settings.client_id.is_some(),
+            "OSU_CLIENT_ID is set to a numeric client ID",
+            "copy the client ID from https://osu.ppy.sh/home/account/edit#oauth into your .env",
+        );
+    let secret_ok = list.check(
+        settings.client_secret.is_some(),
+        "OSU_CLIENT_SECRET is set",
+        "copy the client secret of your OAuth application into your .env",
+    );
+    list.check(
+        settings.username.is_some(),
+        "OSU_USERNAME is set",
+        "put your osu! username (or user ID) into your .env",
+    );
+
+    if id_ok && secret_ok {
+        match fetcher::connect().await {
+            Ok(_) => list.pass("authentication with the osu! API works"),
+            Err(e) if Failure::of(&e) == Some(Failure::FetchFailed) => list.fail(
+                &format!("{:#}", e),
+                "check your connection, the credentials couldn't be checked",
+            ),
+            Err(e) => list.fail(
+                &format!("{:#}", e),
+                "double-check the client ID and secret, or create a new OAuth application",
+            ),
+        }
+    } else {
+        list.fail("authentication skipped", "fix the credentials above first");
+    }
+
+    // mirror
+    // an invalid USER_AGENT is listed with the other settings and the default is used
+    let user_agent = downloader::user_agent(user_agent);
+    list.pass(&format!("user agent: {}", user_agent));
+    match downloader::check_mirror(&user_agent).await {
+        Ok((name, status)) => list.pass(&format!("{} mirror is reachable (HTTP {})", name, status)),
+        Err(e) => list.fail(
+            &format!("mirror is not reachable: {:#}", e),
+            "check your connection, or switch mirrors with USE_ALTERNATIVE_MIRROR",
+        ),
+    }
+
+    // output directory
+    match persist::ensure_output_dir(output_dir) {
+        Ok(_) => list.pass(&format!(
+            "output directory {} is writable",
+            output_dir.display()
+        )),
+        Err(e) => list.fail(
+            &format!("{:#}", e),
+            "pick another directory with --output or BEATMAP_OUTPUT_DIR",
+        ),
+    }
+
+    if let Ok(free) = fs2::available_space(output_dir) {
+        list.check(
+            free >= MIN_FREE_SPACE,
+            &format!(
+                "{} free in the output directory",
+                units::bytes(free, settings::get().units)
+            ),
+            "a large collection can take tens of GiB, free up some space first",
+        );
+    }
+
+    println!();
+    if list.failed > 0 {
+        bail!("{} checks failed", list.failed);
+    }
+    println!("Everything looks good!");
+    Ok(())
+}
@@ -1,329 +1,2561 @@
-use anyhow::Result;
-use futures_util::{StreamExt, stream};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::{header::HeaderMap, Client};
-use serde::Deserialize;
-use std::collections::HashSet;
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use futures_util::{stream, Stream, StreamExt};
+use indicatif::ProgressBar;
+use regex::Regex;
+use reqwest::Client;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::{self, File};
-use std::io::Write;
-use std::path::Path;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{watch, Mutex, OwnedMutexGuard};
 
-use crate::types::BeatmapInfo;
+use crate::checksum;
+use crate::concurrency::{Concurrency, MAX_CONCURRENCY};
+use crate::dedupe;
+use crate::eta::Eta;
+use crate::exit::{self, Failure};
+use crate::failures::{self, DownloadError, FailedDownload, Reason};
+use crate::fetcher;
+use crate::heal::{self, HealOptions};
+use crate::index::Index;
+use crate::keyboard::{self, Command};
+use crate::mirror::{self, Mirror, MirrorPool, MirrorSlot};
+use crate::naming::NamingOptions;
+use crate::notify;
+use crate::persist::{self, DirLock, TempFile};
+use crate::pins::{self, PinOptions};
+use crate::progress;
+use crate::realm;
+use crate::reliability::Reliability;
+use crate::rename;
+use crate::retention::RetentionOptions;
+use crate::session::Sessions;
+use crate::settings;
+use crate::stats::{self, DownloadStat, Downloaded};
+use crate::tagging;
+use crate::types::{ArchiveExtension, BeatmapInfo, Unavailable};
+use crate::units;
+use crate::validators::{self, Validator};
+use crate::verify;
+use crate::webhook;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum Mirror {
-    Nerinyan,
-    Catboy,
-}
-
-impl Mirror {
-    fn from_env() -> Self {
-        let use_alt = std::env::var("USE_ALTERNATIVE_MIRROR")
-            .unwrap_or_default()
-            .to_lowercase();
-        
-        if use_alt == "true" || use_alt == "yes" || use_alt == "1" {
-            Mirror::Catboy
-        } else {
-            Mirror::Nerinyan
+/// options shared by the `download` and `all` commands
+#[derive(Args, Debug, Clone)]
+pub struct DownloadOptions {
+    /// stop after this many successful downloads in a single run
+    #[arg(long, value_name = "N")]
+    pub max_downloads: Option<usize>,
+    /// how hard the mirrors are pushed: the concurrency, pauses and retries of the profile
+    /// apply where --concurrency, --delay, --jitter and --retries aren't given
+    #[arg(long, value_enum, default_value_t = Profile::Balanced)]
+    pub profile: Profile,
+    /// how many downloads run at once (default: what the mirrors allow)
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..=MAX_CONCURRENCY as i64))]
+    pub concurrency: Option<u32>,
+    /// pause before every download, e.g. 2s
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub delay: Option<Duration>,
+    /// longest random pause added before every download, e.g. 500ms
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub jitter: Option<Duration>,
+    /// how often a download is retried on one mirror before moving on to the next
+    #[arg(long, value_name = "N")]
+    pub retries: Option<u32>,
+    /// order in which missing maps are downloaded
+    #[arg(long, value_enum, default_value_t = DownloadOrder::List)]
+    pub order: DownloadOrder,
+    /// where to write the maps left over when --max-downloads is reached
+    #[arg(long, default_value = "osu_remaining_maps.json")]
+    pub resume_file: PathBuf,
+    /// validate the downloaded archives once the run finishes
+    #[arg(long)]
+    pub verify_after: bool,
+    /// with --verify-after, check every archive in the output directory, not just this run's
+    #[arg(long, requires = "verify_after")]
+    pub verify_all: bool,
+    /// with --verify-after, delete corrupt archives and download them again
+    #[arg(long, requires = "verify_after")]
+    pub requeue_corrupt: bool,
+    #[command(flatten)]
+    pub naming: NamingOptions,
+    /// what to do with maps whose --dir-template directory can't be created or written to
+    #[arg(long, value_enum, default_value_t = DirFallback::Root)]
+    pub dir_fallback: DirFallback,
+    /// store each map's metadata as JSON in the zip comment of its archive
+    #[arg(long)]
+    pub tag_archives: bool,
+    /// archives smaller than this are treated as incomplete and downloaded again
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_MIN_VALID_SIZE)]
+    pub min_valid_size: u64,
+    /// record the SHA-256 of every downloaded archive in checksums.txt
+    #[arg(long)]
+    pub checksums: bool,
+    /// record every downloaded archive with its metadata in index.sqlite in the output
+    /// directory, for the query command
+    #[arg(long)]
+    pub index: bool,
+    /// once the run is done, find sets with more than one archive in the output directory and
+    /// remove all but the largest valid one after asking
+    #[arg(long)]
+    pub dedupe_output: bool,
+    /// user agent sent to the mirrors, some operators ask heavy users to put contact info in
+    /// it. USER_AGENT in the .env works too
+    #[arg(long, value_name = "UA", value_parser = parse_user_agent)]
+    pub user_agent: Option<String>,
+    /// download even if another run holds the lock on the output directory. both runs may
+    /// write the same archives, so only use it when they can't overlap
+    #[arg(long)]
+    pub force_lock: bool,
+    /// the process that started this one holds the lock on the output directory for it (the
+    /// web UI does, so nothing else can start in between)
+    #[arg(long, hide = true, conflicts_with = "force_lock")]
+    pub parent_lock: bool,
+    /// re-download existing archives whose SHA-256 doesn't match checksums.txt (reads every file)
+    #[arg(long)]
+    pub skip_existing_by_hash: bool,
+    /// don't scan the output directory for archives already there and download every map of
+    /// the list, for a fresh or slow (e.g. network mounted) output directory. archives already
+    /// there are downloaded again
+    #[arg(long, conflicts_with_all = ["skip_existing_by_hash", "max_age", "heal"])]
+    pub no_scan: bool,
+    /// skip the sets already imported into osu!lazer, read from client.realm in its data
+    /// directory. a realm file that can't be read (e.g. from a newer lazer) only prints a
+    /// warning
+    #[arg(long, value_name = "LAZER_DIR")]
+    pub skip_lazer: Option<PathBuf>,
+    /// directory with archives to take sets from instead of downloading them, e.g. an older
+    /// backup on another drive (hard linked when possible, copied otherwise)
+    #[arg(long, value_name = "DIR")]
+    pub cache_dir: Option<PathBuf>,
+    /// also try maps an earlier run found were deleted from osu!
+    #[arg(long)]
+    pub include_deleted: bool,
+    /// where to write the maps no mirror had, marked as deleted from osu! or only missing
+    /// from the mirrors
+    #[arg(long, default_value = "osu_unavailable_maps.json")]
+    pub unavailable_file: PathBuf,
+    /// where to write every failed download with the reason and the error it ended with
+    #[arg(long, default_value = "failed_downloads.json")]
+    pub failed_file: PathBuf,
+    /// write the time, size, speed, retries and mirror of every finished download to this
+    /// JSON file
+    #[arg(long, value_name = "PATH")]
+    pub stats_file: Option<PathBuf>,
+    /// run this shell command once downloading finished, with OSU_DOWNLOADED, OSU_FAILED,
+    /// OSU_BYTES and OSU_OUTPUT_DIR set to the results
+    #[arg(long, value_name = "COMMAND")]
+    pub notify: Option<String>,
+    /// post a summary of the run to this Discord (or compatible) webhook once downloading
+    /// finished
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<String>,
+    /// show a desktop notification with the results once downloading finished
+    #[arg(long)]
+    pub desktop_notify: bool,
+    /// re-download existing archives last written longer ago than this, e.g. 30d, to pick up
+    /// updates mappers made since
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_age: Option<Duration>,
+    /// mirrors to use in order of preference, later ones are tried when a download fails
+    /// (defaults to USE_ALTERNATIVE_MIRROR)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub mirrors: Vec<Mirror>,
+    /// with --mirrors, spread downloads over all mirrors at once, each with its own rate limit
+    #[arg(long, requires = "mirrors")]
+    pub balance: bool,
+    /// put the mirrors that worked best in earlier runs first, and favour them when balancing
+    #[arg(long)]
+    pub prefer_reliable: bool,
+    /// show the reliability score of every mirror recorded in the output directory and exit
+    #[arg(long)]
+    pub list_mirrors: bool,
+    /// testing: pretend every mirror allows only N requests per minute, to exercise the
+    /// rate limiter's waits and resets
+    #[arg(long, value_name = "N", hide = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub pretend_rate_limit: Option<u32>,
+    /// when writes to the output directory take longer than this many milliseconds on average
+    /// (e.g. a slow network share), only start a download once no other is writing
+    #[arg(long, value_name = "MS")]
+    pub slow_write_ms: Option<u64>,
+    /// size of the buffer each download writes its archive through. chunks from the network
+    /// at least this large skip it, 0 writes every chunk as it arrives
+    #[arg(long, value_name = "BYTES", default_value_t = DEFAULT_WRITE_BUFFER)]
+    pub write_buffer_size: usize,
+    /// pause after every N successful downloads (see --batch-delay)
+    #[arg(long, value_name = "N", requires = "batch_delay")]
+    pub batch_size: Option<usize>,
+    /// how long to pause between batches, e.g. 30s, 5m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "batch_size")]
+    pub batch_delay: Option<Duration>,
+    /// split the run into sessions of N downloads, each starting once the one before finished
+    /// and --session-cooldown passed
+    #[arg(long, value_name = "N", requires = "session_cooldown", value_parser = clap::value_parser!(u64).range(1..))]
+    pub session_size: Option<u64>,
+    /// how long to wait between sessions, e.g. 30m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "session_size")]
+    pub session_cooldown: Option<Duration>,
+    /// rewrite mirror download URLs with a regex, as 'pattern=>replacement' ($1 refers to a
+    /// capture group), can be given more than once and the rewrites apply in order
+    #[arg(long, value_name = "RULE", value_parser = UrlRewrite::parse)]
+    pub url_rewrite: Vec<UrlRewrite>,
+    #[command(flatten)]
+    pub retention: RetentionOptions,
+    #[command(flatten)]
+    pub heal: HealOptions,
+    #[command(flatten)]
+    pub pins: PinOptions,
+}
+
+/// sets downloaded again although they had an archive, counted apart in the summary
+#[derive(Debug, Default)]
+pub struct Redownloads {
+    /// the archive was older than --max-age
+    pub stale: HashSet<u32>,
+    /// the archive failed the --heal check and was quarantined
+    pub healed: HashSet<u32>,
+}
+
+/// a regex replacement applied to the download URL of every map
+#[derive(Debug, Clone)]
+pub struct UrlRewrite {
+    rule: String,
+    pattern: Regex,
+    replacement: String,
+}
+
+impl UrlRewrite {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let (pattern, replacement) = s
+            .split_once("=>")
+            .ok_or_else(|| format!("invalid rewrite '{}', expected 'pattern=>replacement'", s))?;
+        let pattern =
+            Regex::new(pattern).map_err(|e| format!("invalid pattern '{}': {}", pattern, e))?;
+        Ok(Self {
+            rule: s.to_string(),
+            pattern,
+            replacement: replacement.to_string(),
+        })
+    }
+
+    /// the rule as it was given, 'pattern=>replacement'
+    pub fn rule(&self) -> &str {
+        &self.rule
+    }
+
+    pub fn apply(rules: &[Self], url: String) -> String {
+        rules.iter().fold(url, |url, rule| {
+            rule.pattern
+                .replace_all(&url, rule.replacement.as_str())
+                .into_owned()
+        })
+    }
+}
+
+// error pages and truncated downloads are a few hundred bytes, while even the smallest real
+// beatmapsets carry an audio file and stay well above a few KB
+const DEFAULT_MIN_VALID_SIZE: u64 = 4 * 1024;
+
+/// parse a duration like `500ms`, `30s`, `5m`, `1h` or `30d`, plain numbers are seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration '{}'", s))?;
+
+    match unit {
+        "ms" => Ok(Duration::from_millis(value)),
+        "" | "s" => Ok(Duration::from_secs(value)),
+        "m" => Ok(Duration::from_secs(value * 60)),
+        "h" => Ok(Duration::from_secs(value * 60 * 60)),
+        "d" => Ok(Duration::from_secs(value * 24 * 60 * 60)),
+        _ => Err(format!(
+            "unknown unit '{}' in '{}', use ms, s, m, h or d",
+            unit, s
+        )),
+    }
+}
+
+/// bundles of --concurrency, --delay, --jitter and --retries for --profile
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Profile {
+    /// one download at a time, 2s apart plus up to 1s of jitter, 3 retries
+    Gentle,
+    /// as many downloads at once as the mirrors allow, up to 500ms of jitter, 5 retries
+    Balanced,
+    /// twice as many downloads at once as the mirrors allow, no pauses, 8 retries
+    Aggressive,
+}
+
+/// the values --profile and the flags overriding it come to
+pub struct Politeness {
+    pub concurrency: usize,
+    pub delay: Duration,
+    pub jitter: Duration,
+    pub retries: u32,
+}
+
+impl DownloadOptions {
+    /// lock the output directory for the run, unless the parent process holds the lock
+    fn lock(&self, output_dir: &Path) -> Result<Option<DirLock>> {
+        if self.parent_lock {
+            return Ok(None);
+        }
+        DirLock::acquire(output_dir, self.force_lock)
+    }
+
+    /// the politeness settings for mirrors allowing `mirror_concurrency` downloads at once
+    pub fn politeness(&self, mirror_concurrency: usize) -> Politeness {
+        let profile = match self.profile {
+            Profile::Gentle => Politeness {
+                concurrency: 1,
+                delay: Duration::from_secs(2),
+                jitter: Duration::from_secs(1),
+                retries: 3,
+            },
+            Profile::Balanced => Politeness {
+                concurrency: mirror_concurrency,
+                delay: Duration::ZERO,
+                jitter: Duration::from_millis(500),
+                retries: 5,
+            },
+            Profile::Aggressive => Politeness {
+                concurrency: mirror_concurrency * 2,
+                delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+                retries: 8,
+            },
+        };
+        Politeness {
+            concurrency: self
+                .concurrency
+                .map_or(profile.concurrency, |n| n as usize)
+                .clamp(1, MAX_CONCURRENCY),
+            delay: self.delay.unwrap_or(profile.delay),
+            jitter: self.jitter.unwrap_or(profile.jitter),
+            retries: self.retries.unwrap_or(profile.retries),
         }
     }
+}
+
+/// what happens to maps whose --dir-template directory can't be created or written to
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DirFallback {
+    /// save them in the output directory itself
+    Root,
+    /// leave them out of this run
+    Skip,
+    /// stop before downloading anything
+    Fail,
+}
+
+/// create the directories the queued maps go to and check they can be written to,
+/// returning the ones (relative to `output_dir`) that can't be used
+fn prepare_dirs(
+    output_dir: &Path,
+    maps: &[&BeatmapInfo],
+    opts: &DownloadOptions,
+) -> HashSet<PathBuf> {
+    let dirs: HashSet<PathBuf> = maps
+        .iter()
+        .filter_map(|m| {
+            Some(
+                opts.naming
+                    .relative_path(m, opts.naming.extension)
+                    .parent()?
+                    .to_path_buf(),
+            )
+        })
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .collect();
 
-    fn download_url(&self, beatmapset_id: u32) -> String {
-        match self {
-            Mirror::Nerinyan => format!("https://api.nerinyan.moe/d/{}", beatmapset_id),
-            Mirror::Catboy => format!("https://catboy.best/d/{}", beatmapset_id),
+    let mut unusable = HashSet::new();
+    for dir in dirs {
+        let path = output_dir.join(&dir);
+        let probe = path.join(".write_test");
+        let result = fs::create_dir_all(&path)
+            .and_then(|_| File::create(&probe))
+            .and_then(|_| fs::remove_file(&probe));
+        if let Err(e) = result {
+            println!("Warning: can't use directory {} ({})", path.display(), e);
+            unusable.insert(dir);
         }
     }
+    unusable
+}
 
-    fn name(&self) -> &'static str {
-        match self {
-            Mirror::Nerinyan => "Nerinyan",
-            Mirror::Catboy => "Catboy",
+/// where an archive goes relative to the output directory, the root when its directory is unusable
+fn target_path(
+    beatmap: &BeatmapInfo,
+    opts: &DownloadOptions,
+    unusable_dirs: &HashSet<PathBuf>,
+) -> PathBuf {
+    let relative = opts.naming.relative_path(beatmap, opts.naming.extension);
+    match relative.parent() {
+        Some(dir) if unusable_dirs.contains(dir) => {
+            relative.file_name().map(PathBuf::from).unwrap_or(relative)
         }
+        _ => relative,
     }
 }
 
-// catboy.best API structs
-#[derive(Debug, Deserialize)]
-struct CatboyRateLimitResponse {
-    remaining: CatboyRemaining,
-    types: CatboyTypes,
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum DownloadOrder {
+    /// keep the order of the input list
+    List,
+    /// most played maps first
+    PlayCount,
 }
 
-#[derive(Debug, Deserialize)]
-struct CatboyRemaining { download: u32 }
-#[derive(Debug, Deserialize)]
-struct CatboyTypes { download: u32 }
+/// what a download run achieved
+#[derive(Debug, Default)]
+pub struct DownloadReport {
+    pub downloaded: usize,
+    pub failed: usize,
+    /// size of the archives downloaded
+    pub bytes: u64,
+    pub duration: Duration,
+    pub failed_ids: Vec<u32>,
+    /// stopped early by Ctrl-C
+    pub cancelled: bool,
+}
 
-/// rate limiter
-struct RateLimiter {
-    mirror: Mirror,
-    // group mutable state into one mutex to reduce lock contention and complexity
-    state: Mutex<RateLimitState>,
-    client: Client,
+impl DownloadReport {
+    /// the exit code category of the run, if anything failed
+    pub fn failure(&self) -> Option<Failure> {
+        if self.cancelled {
+            return Some(Failure::Cancelled);
+        }
+        match (self.downloaded, self.failed) {
+            (_, 0) => None,
+            (0, _) => Some(Failure::DownloadFailed),
+            _ => Some(Failure::PartialDownload),
+        }
+    }
 }
 
-struct RateLimitState {
-    remaining: u32,
-    reset_at: Instant,
-    limit_cap: u32,
-    download_count: u32,
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Downloaded,
+    Cached,
+    /// refreshed, but the mirror said the archive didn't change
+    Unchanged,
+    Failed,
+    NotAttempted,
 }
 
-impl RateLimiter {
-    fn new(mirror: Mirror, client: Client) -> Self {
-        Self {
-            mirror,
-            state: Mutex::new(RateLimitState {
-                remaining: 60,
-                reset_at: Instant::now() + Duration::from_secs(60),
-                limit_cap: 60,
-                download_count: 0,
-            }),
-            client,
+/// archives in the --cache-dir by beatmapset ID
+fn index_cache(dir: &Path) -> Result<HashMap<u32, PathBuf>> {
+    let mut cache = HashMap::new();
+    for path in verify::list_archives(dir)? {
+        if let Some(id) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(verify::parse_mapset_id)
+        {
+            cache.entry(id).or_insert(path);
         }
     }
+    Ok(cache)
+}
 
-    /// wait until we're allowed to make a request
-    async fn wait(&self) {
-        loop {
-            let mut state = self.state.lock().await;
-            
-            // if tokens remaining, consume one and proceed
-            if state.remaining > 0 {
-                state.remaining -= 1;
-                return;
-            }
+/// put a cached archive at the map's path after checking it, as a hard link unless the
+/// archive gets tagged, which rewrites it
+async fn copy_from_cache(
+    cached: &Path,
+    beatmap: &BeatmapInfo,
+    output_dir: &Path,
+    relative: &Path,
+    opts: &DownloadOptions,
+    index: Option<&Index>,
+) -> Result<()> {
+    verify::verify_archive(cached)?;
+    // an .osz2 package keeps its extension
+    let relative = match ArchiveExtension::of(cached) {
+        Some(ext) if !ext.is_zip() => relative.with_extension(ext.as_str()),
+        _ => relative.to_path_buf(),
+    };
+    let filepath = output_dir.join(&relative);
+    if let Some(parent) = filepath.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // an outdated or incomplete archive may be in the way
+    if filepath.exists() {
+        fs::remove_file(&filepath)?;
+    }
+    let tag = opts.tag_archives && ArchiveExtension::of(&relative).is_none_or(|ext| ext.is_zip());
+    if tag || fs::hard_link(cached, &filepath).is_err() {
+        fs::copy(cached, &filepath)?;
+    }
 
-            // if time window passed, reset
-            if Instant::now() >= state.reset_at {
-                state.remaining = state.limit_cap;
-                state.reset_at = Instant::now() + Duration::from_secs(60);
-                continue;
-            }
+    let filename = relative.to_string_lossy();
+    if tag {
+        tagging::tag_archive(&filepath, beatmap)?;
+    }
+    record_archive(output_dir, beatmap, &filename, opts, index).await
+}
 
-            // otherwise wait until reset
-            let sleep_time = state.reset_at.duration_since(Instant::now()) + Duration::from_millis(100);
-            drop(state); // drop lock before sleeping
-            tokio::time::sleep(sleep_time).await;
-        }
+/// add a saved archive to checksums.txt and the index, whichever the run keeps, hashing it
+/// once on the blocking pool for both
+async fn record_archive(
+    output_dir: &Path,
+    beatmap: &BeatmapInfo,
+    filename: &str,
+    opts: &DownloadOptions,
+    index: Option<&Index>,
+) -> Result<()> {
+    if !opts.checksums && index.is_none() {
+        return Ok(());
+    }
+    let path = output_dir.join(filename);
+    let (sha256, size) = tokio::task::spawn_blocking(move || -> Result<_> {
+        Ok((checksum::sha256_file(&path)?, fs::metadata(&path)?.len()))
+    })
+    .await??;
+    if opts.checksums {
+        checksum::record(output_dir, filename, &sha256).context("Failed to record the checksum")?;
+    }
+    if let Some(index) = index {
+        index
+            .record(beatmap, filename, size, sha256)
+            .await
+            .context("Failed to add it to the index")?;
     }
+    Ok(())
+}
 
-    /// update limits based on response headers (nerinyan.moe)
-    async fn update_from_headers(&self, headers: &HeaderMap) {
-        if self.mirror == Mirror::Nerinyan {
-            let mut state = self.state.lock().await;
-            
-            if let Some(rem) = get_header_u32(headers, "x-ratelimit-remaining-minute") {
-                state.remaining = rem;
-            }
-            if let Some(cap) = get_header_u32(headers, "x-ratelimit-limit-minute") {
-                state.limit_cap = cap;
-            }
-            if let Some(secs) = get_header_u64(headers, "x-ratelimit-reset")
-                .or_else(|| get_header_u64(headers, "retry-after")) 
-            {
-                state.reset_at = Instant::now() + Duration::from_secs(secs + 1);
-            }
-        }
+// default size of the buffer each download writes through, a chunk is only pulled from the
+// network once the previous one is written, so at most this much per download waits for the disk
+const DEFAULT_WRITE_BUFFER: usize = 64 * 1024;
+
+/// how much downloaded data of a run waits for the disk, shared by its downloads
+#[derive(Default)]
+struct WriteStats {
+    // bytes received from the network that aren't written yet
+    buffered: AtomicU64,
+    // moving average of how long a write that reaches the file takes, in microseconds
+    latency_us: AtomicU64,
+    // downloads currently writing their archive
+    writers: AtomicUsize,
+}
+
+impl WriteStats {
+    fn record_latency(&self, elapsed: Duration) {
+        let sample = elapsed.as_micros() as u64;
+        let average = self.latency_us.load(Ordering::Relaxed);
+        // new samples weigh 1/8, so a single slow write doesn't count for much
+        let average = if average == 0 {
+            sample
+        } else {
+            average - average / 8 + sample / 8
+        };
+        self.latency_us.store(average, Ordering::Relaxed);
     }
 
-    /// explicitly fetch limits (catboy.best)
-    async fn refresh_catboy_limits(&self) -> Result<()> {
-        if self.mirror == Mirror::Catboy {
-            let response = self.client.get("https://catboy.best/api/ratelimits").send().await?;
-            if response.status().is_success() {
-                let data: CatboyRateLimitResponse = response.json().await?;
-                let mut state = self.state.lock().await;
-                state.remaining = data.remaining.download;
-                state.limit_cap = data.types.download;
-                state.reset_at = Instant::now() + Duration::from_secs(60);
-            }
-        }
-        Ok(())
+    fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us.load(Ordering::Relaxed))
     }
+}
 
-    async fn on_download_complete(&self) {
-        if self.mirror == Mirror::Catboy {
-            let mut needs_refresh = false;
-            {
-                let mut state = self.state.lock().await;
-                state.download_count += 1;
-                if state.download_count % 50 == 0 {
-                    needs_refresh = true;
-                }
-            }
-            if needs_refresh {
-                let _ = self.refresh_catboy_limits().await;
+/// counts a download in `WriteStats::writers` until dropped
+struct Writing<'a>(&'a WriteStats);
+
+impl<'a> Writing<'a> {
+    fn start(writes: &'a WriteStats) -> Self {
+        writes.writers.fetch_add(1, Ordering::Relaxed);
+        Writing(writes)
+    }
+}
+
+impl Drop for Writing<'_> {
+    fn drop(&mut self) {
+        self.0.writers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// write `body` to `out` a chunk at a time, awaiting each write before the next chunk is pulled
+/// from the network, so at most the buffer and a chunk per download wait for the disk. returns
+/// the bytes written and the error that cut the body short, if one did
+async fn write_body<C: AsRef<[u8]>, E>(
+    mut body: impl Stream<Item = Result<C, E>> + Unpin,
+    out: &mut BufWriter<impl AsyncWrite + Unpin>,
+    buffer_size: usize,
+    writes: &WriteStats,
+) -> std::io::Result<(u64, Option<E>)> {
+    let mut written = 0;
+    let mut lost = None;
+    while let Some(chunk) = body.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                lost = Some(e);
+                break;
             }
+        };
+        let chunk = chunk.as_ref();
+        let len = chunk.len() as u64;
+        // only writes that reach the file say how fast the disk is, the others copy into the
+        // buffer
+        let reaches_file = out.buffer().len() + chunk.len() >= buffer_size;
+        writes.buffered.fetch_add(len, Ordering::Relaxed);
+        let write_started = Instant::now();
+        let result = out.write_all(chunk).await;
+        writes.buffered.fetch_sub(len, Ordering::Relaxed);
+        result?;
+        if reaches_file {
+            writes.record_latency(write_started.elapsed());
         }
+        written += len;
     }
+    let flush_started = Instant::now();
+    out.flush().await?;
+    writes.record_latency(flush_started.elapsed());
+    Ok((written, lost))
 }
 
-// helper to parse headers
-fn get_header_u32(h: &HeaderMap, key: &str) -> Option<u32> {
-    h.get(key)?.to_str().ok()?.parse().ok()
+/// where the body of a 206 response starts, from its Content-Range. `None` when the server
+/// sent the whole file instead
+fn resumed_at(response: &reqwest::Response) -> Option<u64> {
+    if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    // "bytes <start>-<end>/<total>", a missing or unparseable one never matches
+    let range = response
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok());
+    let start = range
+        .and_then(|r| r.strip_prefix("bytes "))
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(start, _)| start.trim().parse().ok());
+    Some(start.unwrap_or(u64::MAX))
 }
-fn get_header_u64(h: &HeaderMap, key: &str) -> Option<u64> {
-    h.get(key)?.to_str().ok()?.parse().ok()
+
+/// where set `beatmapset_id` is downloaded to before it's moved to `filepath`. named by set ID,
+/// two sets whose names sanitize the same never share it, and a random suffix keeps a leftover
+/// of an attempt being torn down apart
+fn part_path(filepath: &Path, beatmapset_id: u32) -> PathBuf {
+    filepath.with_file_name(format!(
+        "{}.{:08x}.part",
+        beatmapset_id,
+        rand::random::<u32>()
+    ))
 }
 
+/// the package type of a downloaded file, `None` for a zip archive. an error page sent
+/// in place of the archive fails the download
+fn package_type(path: &Path, server_filename: Option<&str>) -> Result<Option<ArchiveExtension>> {
+    let mut header = Vec::with_capacity(4);
+    File::open(path)?.take(4).read_to_end(&mut header)?;
+    let package = ArchiveExtension::detect(&header, server_filename);
+    if package.is_none() && !ArchiveExtension::is_zip_header(&header) {
+        return Err(DownloadError::NotAnArchive.into());
+    }
+    Ok(package)
+}
+
+/// where an archive was saved, its configured path unless the mirror sent an .osz2 package
+fn saved_path(output_dir: &Path, relative: &Path) -> PathBuf {
+    let path = output_dir.join(relative);
+    let osz2 = path.with_extension(ArchiveExtension::Osz2.as_str());
+    if !path.exists() && osz2.exists() {
+        osz2
+    } else {
+        path
+    }
+}
+
+/// a set to download and where it goes
+struct Target<'a> {
+    beatmap: &'a BeatmapInfo,
+    /// path of the archive relative to the output directory
+    relative: &'a Path,
+    /// caching headers of the archive that's there, by mirror, only when refreshing it
+    validators: Vec<&'a Validator>,
+}
+
+/// where the archives of a run go and what's kept about writing them
+struct Output<'a> {
+    dir: &'a Path,
+    index: Option<&'a Index>,
+    writes: &'a WriteStats,
+}
+
+/// download a single map from one mirror
 async fn download_beatmap(
     client: &Client,
-    beatmap: &BeatmapInfo,
-    output_dir: &Path,
-    mirror: Mirror,
-    rate_limiter: &RateLimiter,
+    target: &Target<'_>,
+    out: &Output<'_>,
+    slot: &MirrorSlot,
     pb: &ProgressBar,
-) -> Result<()> {
-    let filename = beatmap.filename();
-    let filepath = output_dir.join(&filename);
+    opts: &DownloadOptions,
+) -> Result<Downloaded> {
+    let output_dir = out.dir;
+    let &Target {
+        beatmap, relative, ..
+    } = target;
+    let validator = target.validators.iter().find(|v| v.mirror == slot.mirror);
+    let mut filename = relative.to_string_lossy().into_owned();
+    let filepath = output_dir.join(relative);
+    if let Some(parent) = filepath.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let rate_limiter = &slot.limiter;
 
-    let url = mirror.download_url(beatmap.beatmapset_id);
+    let url = UrlRewrite::apply(
+        &opts.url_rewrite,
+        slot.mirror.download_url(beatmap.beatmapset_id),
+    );
     let mut retry_count = 0;
-    const MAX_RETRIES: u32 = 5;
+    let max_retries = opts.politeness(slot.mirror.max_concurrent()).retries;
+    // removed again by the guard if the download fails or the task is cancelled. a connection
+    // lost partway through continues the file with a range request
+    let mut part: Option<TempFile> = None;
+    let mut received: u64 = 0;
+    let started = Instant::now();
 
     loop {
         rate_limiter.wait().await;
 
         let msg = if retry_count > 0 {
-            format!("Retry {}/{} for {}", retry_count, MAX_RETRIES, beatmap.title) 
+            format!(
+                "Retry {}/{} for {}",
+                retry_count, max_retries, beatmap.title
+            )
         } else {
             format!("Downloading {}", beatmap.title)
         };
         pb.set_message(msg);
 
-        let response = client.get(&url).send().await?;
-        rate_limiter.update_from_headers(response.headers()).await;
+        let mut request = slot
+            .mirror
+            .download_request(client, &url, beatmap.beatmapset_id);
+        if received > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", received));
+        } else if let Some(validator) = validator {
+            if let Some(etag) = &validator.etag {
+                request = request.header(reqwest::header::IF_NONE_MATCH, etag);
+            }
+            if let Some(modified) = &validator.last_modified {
+                request = request.header(reqwest::header::IF_MODIFIED_SINCE, modified);
+            }
+        }
+        let response = match request.send().await {
+            Ok(response) => response,
+            // the connection that dropped partway often takes a moment to come back, the part
+            // file is kept for the next attempt
+            Err(e) if received > 0 && retry_count < max_retries => {
+                retry_count += 1;
+                progress::println(
+                    pb,
+                    format!(
+                        "Reconnecting for {} failed ({}), trying again",
+                        beatmap.beatmapset_id, e
+                    ),
+                );
+                tokio::time::sleep(Duration::from_secs(retry_count as u64)).await;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        rate_limiter
+            .update_from_headers(response.headers(), pb)
+            .await;
 
         if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
-            if retry_count >= MAX_RETRIES {
-                return Err(anyhow::anyhow!("Hit rate limit too many times"));
+            if retry_count >= max_retries {
+                return Err(DownloadError::RateLimited.into());
             }
-            let wait_secs = get_header_u64(response.headers(), "retry-after").unwrap_or(10);
-            pb.set_message(format!("Rate limited. Waiting {}s...", wait_secs));
-            tokio::time::sleep(Duration::from_secs(wait_secs)).await;
+            let wait_secs = mirror::get_header_u64(response.headers(), "retry-after").unwrap_or(10);
+            // the other downloads from this mirror wait out the same cooldown in `wait`
+            let cooldown = rate_limiter.cool_down(Duration::from_secs(wait_secs)).await;
+            progress::status(
+                pb,
+                format!("Rate limited. Waiting {}s...", cooldown.as_secs()),
+            );
             retry_count += 1;
             continue;
         }
 
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Err(DownloadError::NotOnMirror.into());
+        }
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED && validator.is_some() {
+            // counts as fresh for --max-age again
+            let existing = saved_path(output_dir, relative);
+            if let Err(e) = File::options()
+                .write(true)
+                .open(&existing)
+                .and_then(|f| f.set_modified(SystemTime::now()))
+            {
+                progress::println(
+                    pb,
+                    format!("Failed to update the time of {}: {}", existing.display(), e),
+                );
+            }
+            pb.set_message(format!("Unchanged {}", beatmap.title));
+            return Ok(Downloaded {
+                bytes: 0,
+                elapsed: started.elapsed(),
+                retries: retry_count,
+                mirror: slot.mirror,
+                unchanged: true,
+            });
+        }
         if !response.status().is_success() {
-            return Err(anyhow::anyhow!("Failed: HTTP {}", response.status()));
+            return Err(DownloadError::Status(response.status()).into());
         }
 
-        let mut file = File::create(&filepath)?;
-        let mut stream = response.bytes_stream();
+        let server_filename = mirror::server_filename(response.headers());
+        let validator =
+            Validator::from_headers(beatmap.beatmapset_id, slot.mirror, response.headers());
 
-        while let Some(chunk) = stream.next().await {
-            file.write_all(&chunk?)?;
+        if received > 0 {
+            match resumed_at(&response) {
+                Some(start) if start == received => {}
+                // the range doesn't line up with what's on disk, start over
+                Some(_) => {
+                    progress::println(pb, format!(
+                        "{} sent a range that doesn't continue at byte {} of {}, downloading it again",
+                        slot.mirror.name(), received, beatmap.beatmapset_id
+                    ));
+                    received = 0;
+                    retry_count += 1;
+                    if retry_count > max_retries {
+                        return Err(anyhow::anyhow!("the mirror keeps sending the wrong range"));
+                    }
+                    continue;
+                }
+                // the mirror ignored the range and sent the whole archive
+                None => received = 0,
+            }
         }
 
-        rate_limiter.on_download_complete().await;
+        let file = match &part {
+            Some(part) if received > 0 => fs::OpenOptions::new().append(true).open(part.path())?,
+            Some(part) => File::create(part.path())?,
+            None => {
+                let (created, file) =
+                    TempFile::create_at(part_path(&filepath, beatmap.beatmapset_id), &filepath)?;
+                part = Some(created);
+                file
+            }
+        };
+        // small chunks from the network are gathered in the buffer and written a buffer at a
+        // time, larger ones are written as they are, so they need no collecting into blocks
+        // first. a 50 MB set arrived in 60-400 KiB chunks from a local server, even one sending
+        // 4 KiB at a time, and took the same time with buffers from 0 to 1 MiB
+        let mut file =
+            BufWriter::with_capacity(opts.write_buffer_size, tokio::fs::File::from_std(file));
+        let writing = Writing::start(out.writes);
+        let (written, lost) = write_body(
+            response.bytes_stream(),
+            &mut file,
+            opts.write_buffer_size,
+            out.writes,
+        )
+        .await?;
+        drop((file, writing));
+        received += written;
+
+        if let Some(e) = lost {
+            if retry_count >= max_retries {
+                return Err(e.into());
+            }
+            progress::println(
+                pb,
+                format!(
+                    "Connection lost after {} of {}, continuing where it stopped",
+                    units::bytes(received, settings::get().units),
+                    beatmap.beatmapset_id
+                ),
+            );
+            retry_count += 1;
+            continue;
+        }
+        let mut part = part
+            .take()
+            .expect("the part file exists once the body was written");
+
+        rate_limiter.on_download_complete(pb).await;
+
+        // a few sets come as .osz2 packages, which aren't zip archives and can't be tagged
+        let package = package_type(part.path(), server_filename.as_deref())?;
+        if let Some(ext) = package {
+            let renamed = relative.with_extension(ext.as_str());
+            part.set_target(output_dir.join(&renamed));
+            filename = renamed.to_string_lossy().into_owned();
+            progress::println(
+                pb,
+                format!(
+                    "{} is an .{} package, saving it as {}",
+                    beatmap.beatmapset_id,
+                    ext.as_str(),
+                    filename
+                ),
+            );
+        }
+        if opts.tag_archives && package.is_none() {
+            if let Err(e) = tagging::tag_archive(part.path(), beatmap) {
+                progress::println(pb, format!("Failed to tag {}: {:#}", filename, e));
+            }
+        }
+        part.commit()?;
+        if let Some(validator) = &validator {
+            if let Err(e) = validators::record(output_dir, validator) {
+                progress::println(
+                    pb,
+                    format!(
+                        "Failed to record the caching headers of {}: {:#}",
+                        filename, e
+                    ),
+                );
+            }
+        }
+        if let Err(e) = record_archive(output_dir, beatmap, &filename, opts, out.index).await {
+            progress::println(pb, format!("{}: {:#}", filename, e));
+        }
         pb.set_message(format!("Downloaded {}", beatmap.title));
-        return Ok(());
+        return Ok(Downloaded {
+            bytes: received,
+            elapsed: started.elapsed(),
+            retries: retry_count,
+            mirror: slot.mirror,
+            unchanged: false,
+        });
+    }
+}
+
+// a lock per set, so a set queued twice (a duplicate entry, a re-download of a corrupt
+// archive) never has two attempts writing it at once. an entry goes away with the last
+// attempt holding or waiting for it
+static SET_LOCKS: std::sync::Mutex<BTreeMap<u32, Arc<Mutex<()>>>> =
+    std::sync::Mutex::new(BTreeMap::new());
+
+/// an attempt's hold on its set, see `lock_set`
+struct SetLock {
+    beatmapset_id: u32,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for SetLock {
+    fn drop(&mut self) {
+        // under the map's lock no other attempt can pick up the entry in between
+        let mut locks = SET_LOCKS.lock().unwrap();
+        self.guard.take();
+        if locks
+            .get(&self.beatmapset_id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.beatmapset_id);
+        }
+    }
+}
+
+/// wait until no other attempt for the set runs, held until the guard is dropped
+async fn lock_set(beatmapset_id: u32) -> SetLock {
+    let lock = SET_LOCKS
+        .lock()
+        .unwrap()
+        .entry(beatmapset_id)
+        .or_default()
+        .clone();
+    // a cancelled wait drops its clone of the lock before the guard below, so the entry is
+    // still removed
+    let mut held = SetLock {
+        beatmapset_id,
+        guard: None,
+    };
+    held.guard = Some(lock.lock_owned().await);
+    held
+}
+
+/// download a map, moving on to the next mirror of the pool whenever one fails
+async fn download_with_fallback(
+    client: &Client,
+    pool: &MirrorPool,
+    target: &Target<'_>,
+    out: &Output<'_>,
+    pb: &ProgressBar,
+    opts: &DownloadOptions,
+) -> Result<Downloaded> {
+    let beatmap = target.beatmap;
+    let _set = lock_set(beatmap.beatmapset_id).await;
+    let mut tried = Vec::new();
+    // the first failure that wasn't a 404, only a 404 from every mirror counts as the set
+    // being unavailable
+    let mut other_failure: Option<anyhow::Error> = None;
+    let started = Instant::now();
+    loop {
+        // the last failure returns below, so only an empty pool runs out here
+        let slot = pool.pick(&tried).await.context("no mirror left to try")?;
+        let _permit = slot.acquire().await?;
+
+        match download_beatmap(client, target, out, slot, pb, opts).await {
+            Ok(mut download) => {
+                if !download.unchanged {
+                    slot.record_download(download.bytes);
+                }
+                // the mirrors that failed before count as retries, and their time as well
+                download.elapsed = started.elapsed();
+                download.retries += tried.len() as u32;
+                return Ok(download);
+            }
+            Err(e) => {
+                slot.record_failure();
+                tried.push(slot.mirror);
+                let not_found = is_not_found(&e);
+                if tried.len() >= pool.len() {
+                    return Err(match other_failure {
+                        Some(other) if not_found => other,
+                        _ => e,
+                    });
+                }
+                progress::println(
+                    pb,
+                    format!(
+                        "{} failed for {} ({}), trying another mirror",
+                        slot.mirror.name(),
+                        beatmap.beatmapset_id,
+                        e
+                    ),
+                );
+                if !not_found && other_failure.is_none() {
+                    other_failure = Some(e);
+                }
+            }
+        }
+    }
+}
+
+fn is_not_found(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<DownloadError>(),
+        Some(DownloadError::NotOnMirror)
+    )
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!(
+    "osu-beatmap-downloader/",
+    env!("CARGO_PKG_VERSION"),
+    " (https://github.com/zfi2/osu-beatmap-downloader)"
+);
+
+pub fn parse_user_agent(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() {
+        return Err("the user agent can't be empty".to_string());
+    }
+    reqwest::header::HeaderValue::from_str(s)
+        .map_err(|_| format!("'{}' can't be sent as a header", s))?;
+    Ok(s.to_string())
+}
+
+/// the user agent of requests to the mirrors: --user-agent, USER_AGENT from the .env or the default
+pub fn user_agent(flag: Option<&str>) -> String {
+    flag.map(str::to_string)
+        .or_else(|| settings::get().user_agent.clone())
+        .unwrap_or_else(|| DEFAULT_USER_AGENT.to_string())
+}
+
+/// a client for the mirrors, using `tls` instead of the system's TLS when given (--pin-certs)
+fn build_client(user_agent: &str, tls: Option<rustls::ClientConfig>) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent(user_agent)
+        .timeout(Duration::from_secs(120));
+    if let Some(tls) = tls {
+        builder = builder.use_preconfigured_tls(tls);
     }
+    Ok(builder.build()?)
 }
 
-pub async fn download_beatmaps(maps: &[BeatmapInfo], output_dir: &Path) -> Result<()> {
+/// check that the configured mirror answers at all, returning its name and the HTTP status
+pub async fn check_mirror(user_agent: &str) -> Result<(&'static str, reqwest::StatusCode)> {
     let mirror = Mirror::from_env();
-    println!("osu! beatmap downloader ({} mirror)", mirror.name());
+    let client = build_client(user_agent, None)?;
+    let response = client
+        .get(mirror.status_url())
+        .timeout(Duration::from_secs(15))
+        .send()
+        .await?;
+    Ok((mirror.name(), response.status()))
+}
+
+/// mirrors in the order they're tried, the most reliable first with --prefer-reliable, and
+/// the reliability recorded in the output directory
+pub fn mirror_order(output_dir: &Path, opts: &DownloadOptions) -> (Vec<Mirror>, Reliability) {
+    let mut mirrors = if opts.mirrors.is_empty() {
+        vec![Mirror::from_env()]
+    } else {
+        opts.mirrors.clone()
+    };
+    let reliability = Reliability::load(output_dir);
+    if opts.prefer_reliable {
+        // most reliable first, keeping the given order between equal scores
+        mirrors.sort_by(|a, b| reliability.score(*b).total_cmp(&reliability.score(*a)));
+    }
+    (mirrors, reliability)
+}
+
+fn print_banner(mirrors: &[Mirror], opts: &DownloadOptions) {
+    let mirror_names = mirrors
+        .iter()
+        .map(|m| m.name())
+        .collect::<Vec<_>>()
+        .join(", ");
+    println!("osu! beatmap downloader ({} mirror)", mirror_names);
     println!("==========================================\n");
+    if let Some(cap) = opts.pretend_rate_limit {
+        println!(
+            "Pretending every mirror allows {} requests per minute\n",
+            cap
+        );
+    }
+}
+
+pub async fn download_beatmaps(
+    maps: &[BeatmapInfo],
+    output_dir: &Path,
+    opts: &DownloadOptions,
+) -> Result<DownloadReport> {
+    opts.retention.check(output_dir)?;
+    let (mirrors, reliability) = mirror_order(output_dir, opts);
+    print_banner(&mirrors, opts);
+    persist::ensure_output_dir(output_dir)?;
+    let _lock = opts.lock(output_dir)?;
+    // broken archives are moved out of the way first, so the scan finds their sets missing
+    let healed = if opts.heal.heal {
+        heal::heal(maps, output_dir, &opts.heal)?
+    } else {
+        HashSet::new()
+    };
+    let (missing_maps, stale) = scan_missing(maps, output_dir, opts)?;
+    let redownloads = Redownloads { stale, healed };
+    let report = download_missing(
+        maps,
+        missing_maps,
+        &redownloads,
+        output_dir,
+        opts,
+        mirrors,
+        reliability,
+    )
+    .await?;
+    announce(&report, output_dir, opts).await?;
+    Ok(report)
+}
+
+/// download exactly `maps` without scanning the output directory, for `download --plan`.
+/// the archives of the sets in `refresh` are replaced
+pub async fn download_planned(
+    maps: &[BeatmapInfo],
+    refresh: &HashSet<u32>,
+    output_dir: &Path,
+    opts: &DownloadOptions,
+) -> Result<DownloadReport> {
+    download_exactly(maps, refresh, output_dir, opts, "Planned maps").await
+}
+
+/// download `maps` again even where their archives are already there, replacing them, for
+/// `download --only-status-changed`
+pub async fn download_replacing(
+    maps: &[BeatmapInfo],
+    output_dir: &Path,
+    opts: &DownloadOptions,
+) -> Result<DownloadReport> {
+    download_exactly(maps, &HashSet::new(), output_dir, opts, "Maps to replace").await
+}
 
-    fs::create_dir_all(output_dir)?;
+async fn download_exactly(
+    maps: &[BeatmapInfo],
+    refresh: &HashSet<u32>,
+    output_dir: &Path,
+    opts: &DownloadOptions,
+    heading: &str,
+) -> Result<DownloadReport> {
+    opts.retention.check(output_dir)?;
+    let (mirrors, reliability) = mirror_order(output_dir, opts);
+    print_banner(&mirrors, opts);
+    persist::ensure_output_dir(output_dir)?;
+    let _lock = opts.lock(output_dir)?;
+    println!("{}: {}\n", heading, maps.len());
+    let redownloads = Redownloads {
+        stale: refresh.clone(),
+        ..Default::default()
+    };
+    let report = download_missing(
+        maps,
+        maps.iter().collect(),
+        &redownloads,
+        output_dir,
+        opts,
+        mirrors,
+        reliability,
+    )
+    .await?;
+    announce(&report, output_dir, opts).await?;
+    Ok(report)
+}
+
+/// write the maps of `queue` not downloaded yet to the --resume-file and the failures so far to
+/// the --failed-file, between sessions
+fn save_progress(
+    queue: &[&BeatmapInfo],
+    succeeded: &HashSet<u32>,
+    failures: &[FailedDownload],
+    opts: &DownloadOptions,
+) -> Result<()> {
+    let remaining: Vec<BeatmapInfo> = queue
+        .iter()
+        .filter(|m| !succeeded.contains(&m.beatmapset_id))
+        .map(|m| (*m).clone())
+        .collect();
+    fetcher::save_beatmaps(&remaining, &opts.resume_file)?;
+    failures::save(failures, &opts.failed_file)
+}
 
-    // scan for existing mapsets
-    println!("Scanning directory: {}", output_dir.display());
-    let existing_mapsets: HashSet<u32> = fs::read_dir(output_dir)?
-        .filter_map(|e| e.ok())
-        .filter(|e| e.path().extension().map_or(false, |ext| ext == "osz"))
-        .filter_map(|e| {
-            // check if the file size is correct
-            if e.metadata().map(|m| m.len() == 0).unwrap_or(true) {
+/// run the --notify command, post to the --webhook and show the --desktop-notify notification
+async fn announce(
+    report: &DownloadReport,
+    output_dir: &Path,
+    opts: &DownloadOptions,
+) -> Result<()> {
+    if let Some(command) = &opts.notify {
+        notify::run(command, report, output_dir);
+    }
+    if opts.desktop_notify {
+        notify::desktop(report);
+    }
+    if let Some(url) = &opts.webhook {
+        let client = build_client(&user_agent(opts.user_agent.as_deref()), None)?;
+        webhook::send(&client, url, report, output_dir).await;
+    }
+    Ok(())
+}
+
+/// the maps of `maps` that have no usable archive in `output_dir`, in download order, and the
+/// IDs of the sets among them that only need one because theirs is older than --max-age
+pub fn scan_missing<'a>(
+    maps: &'a [BeatmapInfo],
+    output_dir: &Path,
+    opts: &DownloadOptions,
+) -> Result<(Vec<&'a BeatmapInfo>, HashSet<u32>)> {
+    if opts.no_scan {
+        println!(
+            "Not scanning {} (--no-scan), every map is downloaded",
+            output_dir.display()
+        );
+    } else {
+        println!("Scanning directory: {}", output_dir.display());
+    }
+    let checksums = if opts.skip_existing_by_hash {
+        checksum::load(output_dir)
+    } else {
+        Default::default()
+    };
+    // archives older than --max-age, downloaded again like missing ones
+    let mut stale: HashSet<u32> = HashSet::new();
+    // subdirectories are included, so sets are found whichever folder a template put them in
+    let archives = if opts.no_scan {
+        Vec::new()
+    } else {
+        verify::list_archives(output_dir)?
+    };
+    let existing_mapsets: HashSet<u32> = archives
+        .into_iter()
+        .filter_map(|path| {
+            let metadata = fs::metadata(&path).ok()?;
+            // check if the file size is plausible
+            if metadata.len() < opts.min_valid_size.max(1) {
                 return None;
             }
+            if let Some(max_age) = opts.max_age {
+                let age = metadata.modified().ok().and_then(|t| t.elapsed().ok());
+                if age.is_some_and(|age| age > max_age) {
+                    stale.extend(path.file_name()?.to_str().and_then(verify::parse_mapset_id));
+                    return None;
+                }
+            }
+            // compare against the recorded checksum, files without one count as present
+            let relative = path
+                .strip_prefix(output_dir)
+                .ok()?
+                .to_string_lossy()
+                .into_owned();
+            if let Some(expected) = checksums.get(&relative) {
+                if checksum::sha256_file(&path).ok().as_ref() != Some(expected) {
+                    println!("Checksum mismatch, will re-download: {}", path.display());
+                    return None;
+                }
+            }
             // parse ID from start of filename
-            e.file_name().to_str().and_then(|s| {
-                s.split_whitespace().next().and_then(|id| id.parse::<u32>().ok())
-            })
+            path.file_name()?.to_str().and_then(verify::parse_mapset_id)
         })
         .collect();
 
-    let missing_maps: Vec<&BeatmapInfo> = maps
+    let mut missing_maps: Vec<&BeatmapInfo> = maps
         .iter()
         .filter(|m| !existing_mapsets.contains(&m.beatmapset_id))
         .collect();
 
+    let mut in_lazer = None;
+    if let Some(data_dir) = &opts.skip_lazer {
+        match realm::imported_sets(data_dir) {
+            Ok(imported) => {
+                let before = missing_maps.len();
+                missing_maps.retain(|m| !imported.contains(&m.beatmapset_id));
+                in_lazer = Some(before - missing_maps.len());
+            }
+            Err(e) => println!(
+                "Warning: couldn't read the sets in osu!lazer, downloading them as usual ({:#})",
+                e
+            ),
+        }
+    }
+
+    if !opts.include_deleted {
+        let before = missing_maps.len();
+        missing_maps.retain(|m| m.unavailable != Some(Unavailable::Deleted));
+        if missing_maps.len() < before {
+            println!(
+                "Skipping {} maps deleted from osu! (see --include-deleted)",
+                before - missing_maps.len()
+            );
+        }
+    }
+
+    if opts.order == DownloadOrder::PlayCount {
+        missing_maps.sort_by_key(|m| std::cmp::Reverse(m.play_count));
+    }
+
     println!("Total maps:        {}", maps.len());
-    println!("Already downloaded: {}", existing_mapsets.len());
+    println!(
+        "Already downloaded: {}",
+        units::count(existing_mapsets.len())
+    );
+    if let Some(in_lazer) = in_lazer {
+        println!("Already in lazer:  {}", units::count(in_lazer));
+    }
     println!("To download:       {}\n", missing_maps.len());
+    // a set with a newer copy elsewhere in the directory isn't refreshed
+    stale.retain(|id| !existing_mapsets.contains(id));
+    let refreshing = missing_maps
+        .iter()
+        .filter(|m| stale.contains(&m.beatmapset_id))
+        .count();
+    if refreshing > 0 {
+        println!(
+            "{} of them are older than --max-age and will be refreshed\n",
+            refreshing
+        );
+    }
+
+    // archives are matched by set ID only, a changed title just leaves an outdated name
+    let drifted = if opts.no_scan {
+        0
+    } else {
+        rename::drifted(output_dir, maps, &opts.naming)?.len()
+    };
+    if drifted > 0 {
+        println!(
+            "{} archives have outdated names, run rename-existing to update them\n",
+            drifted
+        );
+    }
+    Ok((missing_maps, stale))
+}
+
+async fn download_missing(
+    maps: &[BeatmapInfo],
+    mut missing_maps: Vec<&BeatmapInfo>,
+    redownloads: &Redownloads,
+    output_dir: &Path,
+    opts: &DownloadOptions,
+    mirrors: Vec<Mirror>,
+    mut reliability: Reliability,
+) -> Result<DownloadReport> {
+    let started = Instant::now();
+    let stale = &redownloads.stale;
+    let units = settings::get().units;
+    // Ctrl-C stops new downloads from starting, the ones in flight finish and the run saves
+    // what's left as usual
+    let _graceful = exit::Graceful::enter();
+
+    let unusable_dirs = prepare_dirs(output_dir, &missing_maps, opts);
+    if !unusable_dirs.is_empty() {
+        let affected = |m: &BeatmapInfo| {
+            let relative = opts.naming.relative_path(m, opts.naming.extension);
+            relative
+                .parent()
+                .is_some_and(|dir| unusable_dirs.contains(dir))
+        };
+        let count = missing_maps.iter().filter(|m| affected(m)).count();
+        match opts.dir_fallback {
+            DirFallback::Fail => {
+                anyhow::bail!(
+                    "{} output directories can't be used ({} maps)",
+                    unusable_dirs.len(),
+                    count
+                )
+            }
+            DirFallback::Skip => {
+                missing_maps.retain(|m| !affected(m));
+                println!("Skipping {} maps whose directory can't be used\n", count);
+            }
+            DirFallback::Root => {
+                println!(
+                    "Saving {} maps whose directory can't be used to {}\n",
+                    count,
+                    output_dir.display()
+                )
+            }
+        }
+    }
 
     if missing_maps.is_empty() {
         println!("All maps up to date!");
-        return Ok(());
+        if opts.dedupe_output {
+            dedupe::dedupe_output(output_dir)?;
+        }
+        opts.retention.apply(0)?;
+        return Ok(DownloadReport {
+            duration: started.elapsed(),
+            ..Default::default()
+        });
     }
 
-    let client = Client::builder()
-        .user_agent("osu-beatmap-downloader/1.0.0 (https://github.com/zfi2/osu-beatmap-downloader)")
-        .timeout(Duration::from_secs(120))
-        .build()?;
+    let (tls, pins) = pins::tls_config(output_dir, &opts.pins).unzip();
+    let client = build_client(&user_agent(opts.user_agent.as_deref()), tls)?;
+    let score = |mirror| {
+        if opts.prefer_reliable {
+            reliability.score(mirror)
+        } else {
+            1.0
+        }
+    };
+    let pool = MirrorPool::new(
+        &mirrors,
+        opts.balance,
+        opts.pretend_rate_limit,
+        score,
+        &client,
+    )
+    .await;
+    // the hosts pinned while connecting for the rate limits, printed before any bar is drawn
+    if let Some(pins) = &pins {
+        pins.flush(&ProgressBar::hidden());
+    }
+    for (mirror, remaining, cap) in pool.quotas().await {
+        println!("{} quota: {}/{} this minute", mirror.name(), remaining, cap);
+        if (remaining as usize) < mirror.max_concurrent() {
+            println!(
+                "Warning: fewer requests left than concurrent downloads on {}, they will wait for the next minute",
+                mirror.name()
+            );
+        }
+    }
+
+    // caching headers of earlier downloads, for refreshing with conditional requests
+    let known = validators::load(output_dir);
 
-    let rate_limiter = Arc::new(RateLimiter::new(mirror, client.clone()));
-    if mirror == Mirror::Catboy {
-        rate_limiter.refresh_catboy_limits().await?;
+    let cache = match &opts.cache_dir {
+        Some(dir) => index_cache(dir)?,
+        None => HashMap::new(),
+    };
+    // sets taken from the cache don't count towards --max-downloads
+    let cached = missing_maps
+        .iter()
+        .filter(|m| cache.contains_key(&m.beatmapset_id))
+        .count();
+    if let Some(dir) = &opts.cache_dir {
+        println!(
+            "{} of the maps are in the cache at {}",
+            cached,
+            dir.display()
+        );
     }
 
-    let multi_progress = MultiProgress::new();
-    let overall_pb = multi_progress.add(ProgressBar::new(missing_maps.len() as u64));
-    overall_pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta})")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
+    let multi_progress = progress::multi();
+    let total = opts.max_downloads.map_or(missing_maps.len(), |max| {
+        (max + cached).min(missing_maps.len())
+    });
+    let overall_pb = multi_progress.add(ProgressBar::new(total as u64));
+    let eta = Arc::new(Eta::default());
+    overall_pb.set_style(eta.style(progress::bar_style(
+        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} ({eta}) {msg}",
+    )));
 
     let status_pb = multi_progress.add(ProgressBar::new(0));
-    status_pb.set_style(ProgressStyle::default_bar().template("{msg}").unwrap());
+    status_pb.set_style(progress::bar_style("{msg}"));
+
+    let max_concurrent = pool.max_concurrent();
+    let politeness = opts.politeness(max_concurrent);
+    if politeness.concurrency > max_concurrent {
+        println!(
+            "Running {} downloads at once, the mirrors allow {}. expect more rate limiting\n",
+            politeness.concurrency, max_concurrent
+        );
+    }
+    // starts at what the mirrors allow unless --profile or --concurrency say otherwise,
+    // SIGUSR1 and SIGUSR2 change it while running
+    let concurrency = Concurrency::new(politeness.concurrency);
+    let politeness = &politeness;
+
+    // successful downloads from a mirror plus the ones currently in flight, so the cap is never
+    // overshot
+    let reserved = AtomicUsize::new(0);
+    let max_downloads = opts.max_downloads.unwrap_or(usize::MAX);
 
-    let max_concurrent = match mirror {
-        Mirror::Catboy => 1,
-        Mirror::Nerinyan => 3,
+    // held while pausing between batches so no new download starts
+    let batch_gate = Mutex::new(());
+    let completed = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let durations: std::sync::Mutex<HashMap<u32, Downloaded>> = Default::default();
+    let failures: std::sync::Mutex<Vec<FailedDownload>> = Default::default();
+    let sessions = opts
+        .session_size
+        .zip(opts.session_cooldown)
+        .map(|(size, cooldown)| Sessions::new(size as usize, cooldown));
+    // sets downloaded so far and the queue they're from, for the maps left when a session ends
+    let succeeded: std::sync::Mutex<HashSet<u32>> = Default::default();
+    let queue = missing_maps.clone();
+
+    let index = if opts.index {
+        Some(Index::open(output_dir).await?)
+    } else {
+        None
+    };
+    let writes = WriteStats::default();
+    let out = Output {
+        dir: output_dir,
+        index: index.as_ref(),
+        writes: &writes,
     };
 
+    // set from the keyboard, new downloads wait while it's true and in-flight ones finish
+    let (pause_tx, pause_rx) = watch::channel(false);
+    let mut listener = keyboard::listen();
+    if listener.interactive {
+        progress::println(
+            &status_pb,
+            "Type p to pause, r to resume or s for a status summary (then enter)",
+        );
+    }
+
     let downloads = stream::iter(missing_maps)
         .map(|beatmap| {
             let client = &client;
             let output_dir = &output_dir;
-            let rate_limiter = &rate_limiter;
+            let unusable_dirs = &unusable_dirs;
+            let pool = &pool;
             let status_pb = &status_pb;
             let overall_pb = &overall_pb;
+            let reserved = &reserved;
+            let batch_gate = &batch_gate;
+            let completed = &completed;
+            let failed = &failed;
+            let durations = &durations;
+            let known = &known;
+            let failures = &failures;
+            let eta = &eta;
+            let cache = &cache;
+            let out = &out;
+            let pins = &pins;
+            let concurrency = &concurrency;
+            let sessions = &sessions;
+            let succeeded = &succeeded;
+            let queue = &queue;
+            let mut paused = pause_rx.clone();
 
             async move {
-                // add some jitter for good measure
-                let jitter = rand::random::<u64>() % 500;
-                tokio::time::sleep(Duration::from_millis(jitter)).await;
+                let permit = exit::unless_cancelled(async {
+                    let permit = concurrency.acquire().await;
+                    // only errors once the sender is gone, which can't happen while the stream runs
+                    let _ = paused.wait_for(|p| !p).await;
+                    drop(batch_gate.lock().await);
+                    permit
+                });
+                let Some(_permit) = permit.await else {
+                    return (beatmap, Outcome::NotAttempted);
+                };
+
+                if let Some(slow) = opts.slow_write_ms.map(Duration::from_millis) {
+                    while out.writes.latency() > slow
+                        && out.writes.writers.load(Ordering::Relaxed) > 0
+                    {
+                        tokio::time::sleep(Duration::from_millis(250)).await;
+                    }
+                }
+
+                let relative = target_path(beatmap, opts, unusable_dirs);
+                if let Some(cached) = cache.get(&beatmap.beatmapset_id) {
+                    match copy_from_cache(cached, beatmap, output_dir, &relative, opts, out.index)
+                        .await
+                    {
+                        Ok(()) => {
+                            overall_pb.inc(1);
+                            let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                            if overall_pb.is_hidden() {
+                                println!(
+                                    "[{}/{}] Copied {} from the cache",
+                                    done, total, beatmap.title
+                                );
+                            }
+                            return (beatmap, Outcome::Cached);
+                        }
+                        Err(e) => progress::println(
+                            status_pb,
+                            format!(
+                                "Cached archive of {} can't be used, downloading it: {:#}",
+                                beatmap.beatmapset_id, e
+                            ),
+                        ),
+                    }
+                }
+
+                // copies from the cache are no downloads, only what's fetched from a mirror counts
+                // towards --max-downloads and the sessions
+                let has_slot = reserved
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                        (n < max_downloads).then_some(n + 1)
+                    })
+                    .is_ok();
+                if !has_slot {
+                    return (beatmap, Outcome::NotAttempted);
+                }
+                if let Some(sessions) = sessions {
+                    let entered = sessions.enter(status_pb, || {
+                        let succeeded = succeeded.lock().unwrap();
+                        if let Err(e) =
+                            save_progress(queue, &succeeded, &failures.lock().unwrap(), opts)
+                        {
+                            progress::println(
+                                status_pb,
+                                format!("Failed to save the progress: {:#}", e),
+                            );
+                        }
+                    });
+                    if exit::unless_cancelled(entered).await.is_none() {
+                        return (beatmap, Outcome::NotAttempted);
+                    }
+                }
+
+                // --delay plus some jitter for good measure
+                let jitter = rand::random::<u64>() % (politeness.jitter.as_millis() as u64 + 1);
+                let delay = tokio::time::sleep(politeness.delay + Duration::from_millis(jitter));
+                if exit::unless_cancelled(delay).await.is_none() {
+                    return (beatmap, Outcome::NotAttempted);
+                }
 
-                match download_beatmap(client, beatmap, output_dir, mirror, rate_limiter, status_pb).await {
-                    Ok(_) => {
+                // a set refreshed for --max-age is only sent again if it changed, where the
+                // mirror supports conditional requests
+                let refreshing = stale.contains(&beatmap.beatmapset_id);
+                let validators = known
+                    .iter()
+                    .filter(|((id, _), _)| refreshing && *id == beatmap.beatmapset_id);
+                let target = Target {
+                    beatmap,
+                    relative: &relative,
+                    validators: validators.map(|(_, v)| v).collect(),
+                };
+                let result =
+                    download_with_fallback(client, pool, &target, out, status_pb, opts).await;
+                if let Some(pins) = pins {
+                    pins.flush(status_pb);
+                }
+                match result {
+                    Ok(download) if download.unchanged => {
+                        // nothing was sent, the slot goes to another map
+                        reserved.fetch_sub(1, Ordering::SeqCst);
                         overall_pb.inc(1);
+                        let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                        if overall_pb.is_hidden() {
+                            println!("[{}/{}] {} is unchanged", done, total, beatmap.title);
+                        }
+                        (beatmap, Outcome::Unchanged)
+                    }
+                    Ok(download) => {
+                        overall_pb.inc(1);
+                        eta.record(download.elapsed);
+                        durations
+                            .lock()
+                            .unwrap()
+                            .insert(beatmap.beatmapset_id, download);
+
+                        let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+                        eta.update(
+                            total.saturating_sub(done),
+                            concurrency.limit(),
+                            pool.rate_budget().await,
+                        );
+                        if overall_pb.is_hidden() {
+                            println!("[{}/{}] Downloaded {}", done, total, beatmap.title);
+                        }
+                        if let (Some(size), Some(delay)) = (opts.batch_size, opts.batch_delay) {
+                            if done.is_multiple_of(size) && done < total {
+                                let _gate = batch_gate.lock().await;
+                                progress::status(
+                                    status_pb,
+                                    format!("Batch of {} done, pausing for {:?}...", size, delay),
+                                );
+                                tokio::time::sleep(delay).await;
+                            }
+                        }
+                        (beatmap, Outcome::Downloaded)
                     }
                     Err(e) => {
-                        status_pb.println(format!("Failed to download {}: {}", beatmap.beatmapset_id, e));
+                        reserved.fetch_sub(1, Ordering::SeqCst);
+                        failed.fetch_add(1, Ordering::SeqCst);
+                        failures
+                            .lock()
+                            .unwrap()
+                            .push(FailedDownload::new(beatmap, &e));
+                        progress::println(
+                            status_pb,
+                            format!("Failed to download {}: {}", beatmap.beatmapset_id, e),
+                        );
+                        (beatmap, Outcome::Failed)
                     }
                 }
             }
         })
-        .buffer_unordered(max_concurrent);
+        .buffer_unordered(MAX_CONCURRENCY)
+        .inspect(|(beatmap, outcome)| {
+            // cached and unchanged sets are done too, a later run doesn't need them again
+            if matches!(
+                outcome,
+                Outcome::Downloaded | Outcome::Cached | Outcome::Unchanged
+            ) {
+                succeeded.lock().unwrap().insert(beatmap.beatmapset_id);
+            }
+            if let Some(sessions) = &sessions {
+                match outcome {
+                    Outcome::Downloaded | Outcome::Failed => {
+                        let bytes = durations
+                            .lock()
+                            .unwrap()
+                            .get(&beatmap.beatmapset_id)
+                            .map_or(0, |d| d.bytes);
+                        sessions.record(*outcome == Outcome::Downloaded, bytes);
+                    }
+                    Outcome::Unchanged => sessions.record_unchanged(),
+                    Outcome::Cached | Outcome::NotAttempted => {}
+                }
+            }
+        });
+
+    let controls = async {
+        while let Some(command) = listener.commands.recv().await {
+            match command {
+                Command::Pause => {
+                    pause_tx.send_replace(true);
+                    progress::status(&overall_pb, "paused, type r to resume");
+                }
+                Command::Resume => {
+                    pause_tx.send_replace(false);
+                    progress::status(&overall_pb, "");
+                }
+                Command::Status => {
+                    let (done, failed) = (
+                        completed.load(Ordering::SeqCst),
+                        failed.load(Ordering::SeqCst),
+                    );
+                    // every slot not given back is a download that finished or is in flight
+                    let downloaded = durations.lock().unwrap().len();
+                    let in_flight = reserved.load(Ordering::SeqCst).saturating_sub(downloaded);
+                    progress::println(&status_pb, format!(
+                        "Status: {} downloaded, {} failed, {} in flight (at most {}), {} queued, {} waiting \
+                         for the disk ({:.1} ms per write){}",
+                        downloaded,
+                        failed,
+                        in_flight,
+                        concurrency.limit(),
+                        total.saturating_sub(done + failed + in_flight),
+                        units::bytes(writes.buffered.load(Ordering::Relaxed), units),
+                        writes.latency().as_secs_f64() * 1000.0,
+                        if *pause_tx.borrow() { " (paused)" } else { "" }
+                    ));
+                }
+                Command::MoreConcurrency | Command::LessConcurrency => {
+                    let limit = if command == Command::MoreConcurrency {
+                        concurrency.raise()
+                    } else {
+                        concurrency.lower()
+                    };
+                    // the per-mirror limits still apply on top
+                    let note = if limit > max_concurrent {
+                        format!(", the mirrors allow {} at once", max_concurrent)
+                    } else {
+                        String::new()
+                    };
+                    progress::println(
+                        &status_pb,
+                        format!("Concurrency: {} downloads at once{}", limit, note),
+                    );
+                }
+            }
+        }
+        std::future::pending::<()>().await
+    };
 
     //execute the stream
-    downloads.collect::<Vec<()>>().await;
+    let results: Vec<(&BeatmapInfo, Outcome)> = tokio::select! {
+        results = downloads.collect() => results,
+        _ = controls => unreachable!("the command handler never finishes"),
+    };
+    // what's typed from now on is for the prompts, e.g. --dedupe-output's
+    listener.stop();
+    let cancelled = exit::cancelled();
 
-    overall_pb.finish_with_message("All downloads complete!");
+    overall_pb.finish_with_message(if cancelled {
+        "Stopped"
+    } else {
+        "All downloads complete!"
+    });
     status_pb.finish_and_clear();
 
+    for (mirror, successes, failures) in pool.outcomes() {
+        reliability.add(mirror, successes, failures);
+    }
+    if let Err(e) = reliability.save() {
+        println!("Failed to save mirror stats: {:#}", e);
+    }
+
+    let count = |outcome: Outcome| results.iter().filter(|(_, o)| *o == outcome).count();
+    println!("\nDownloaded: {}", units::count(count(Outcome::Downloaded)));
+    println!("Failed:     {}", units::count(count(Outcome::Failed)));
+    if let Some(sessions) = &sessions {
+        sessions.print_summary();
+        // the maps left when the last session ended are the ones that failed
+        save_progress(
+            &queue,
+            &succeeded.lock().unwrap(),
+            &failures.lock().unwrap(),
+            opts,
+        )?;
+    }
+    let failures = failures.into_inner().unwrap();
+    if !failures.is_empty() {
+        failures::print_summary(&failures);
+        failures::save(&failures, &opts.failed_file)?;
+        println!("Details saved to {}", opts.failed_file.display());
+    }
+    // sets every mirror answered 404 for
+    let not_found: Vec<u32> = failures
+        .iter()
+        .filter(|f| f.reason == Reason::NotFound)
+        .map(|f| f.beatmapset_id)
+        .collect();
+    let mut unavailable = HashMap::new();
+    if !not_found.is_empty() {
+        // without credentials a 404 from every mirror can't be told apart from a deleted set
+        if settings::get().client_id.is_some() {
+            match fetcher::classify_unavailable(&not_found).await {
+                Ok(classified) => unavailable = classified,
+                Err(e) => println!("Failed to check the unavailable sets: {:#}", e),
+            }
+        }
+        let deleted = unavailable
+            .values()
+            .filter(|u| **u == Unavailable::Deleted)
+            .count();
+        println!("  not on any mirror:  {}", not_found.len() - deleted);
+        println!("  deleted from osu!:  {}", deleted);
+
+        let unavailable_maps: Vec<BeatmapInfo> = results
+            .iter()
+            .filter(|(m, _)| not_found.contains(&m.beatmapset_id))
+            .map(|(m, _)| BeatmapInfo {
+                unavailable: unavailable.get(&m.beatmapset_id).copied(),
+                ..(*m).clone()
+            })
+            .collect();
+        fetcher::save_beatmaps(&unavailable_maps, &opts.unavailable_file)?;
+        println!(
+            "Unavailable maps saved to {}",
+            opts.unavailable_file.display()
+        );
+    }
+
+    if !cache.is_empty() {
+        println!("From cache: {}", count(Outcome::Cached));
+    }
+    if !redownloads.healed.is_empty() {
+        let healed = results
+            .iter()
+            .filter(|(m, o)| {
+                *o == Outcome::Downloaded && redownloads.healed.contains(&m.beatmapset_id)
+            })
+            .count();
+        println!(
+            "Healed:     {} of {} sets with a broken archive",
+            healed,
+            redownloads.healed.len()
+        );
+    }
+    if !stale.is_empty() {
+        let refreshed = results
+            .iter()
+            .filter(|(m, o)| *o == Outcome::Downloaded && stale.contains(&m.beatmapset_id))
+            .count();
+        println!("Refreshed:  {} (older than --max-age)", refreshed);
+        if count(Outcome::Unchanged) > 0 {
+            println!(
+                "Unchanged:  {} (the mirror answered 304 Not Modified)",
+                count(Outcome::Unchanged)
+            );
+        }
+    }
+    if pool.len() > 1 {
+        for (mirror, downloaded, bytes) in pool.stats() {
+            println!(
+                "  {}: {} maps, {}",
+                mirror.name(),
+                units::count(downloaded),
+                units::bytes(bytes, units)
+            );
+        }
+    }
+
+    let durations = durations.into_inner().unwrap();
+    let download_stats: Vec<DownloadStat> = results
+        .iter()
+        .filter_map(|(m, _)| Some(DownloadStat::new(m, durations.get(&m.beatmapset_id)?)))
+        .collect();
+    print!("{}", stats::summary(&download_stats, units));
+    if let Some(path) = &opts.stats_file {
+        stats::save(&download_stats, path)?;
+        println!("Download stats saved to {}", path.display());
+    }
+
+    if count(Outcome::NotAttempted) > 0 {
+        let remaining: Vec<BeatmapInfo> = results
+            .iter()
+            .filter(|(_, o)| *o == Outcome::Failed || *o == Outcome::NotAttempted)
+            .map(|(m, _)| BeatmapInfo {
+                unavailable: unavailable.get(&m.beatmapset_id).copied(),
+                ..(*m).clone()
+            })
+            .collect();
+        fetcher::save_beatmaps(&remaining, &opts.resume_file)?;
+        if cancelled {
+            println!(
+                "Stopped by Ctrl-C, {} maps left for a later run",
+                remaining.len()
+            );
+        } else {
+            println!(
+                "Reached --max-downloads ({}), {} maps left for a later run",
+                max_downloads,
+                remaining.len()
+            );
+        }
+        println!("Remaining maps saved to {}", opts.resume_file.display());
+    }
+
+    // a stopped run skips the checks and prompts that would keep it going
+    if opts.verify_after && !cancelled {
+        let paths = if opts.verify_all {
+            verify::list_archives(output_dir)?
+        } else {
+            results
+                .iter()
+                .filter(|(_, o)| *o == Outcome::Downloaded)
+                .map(|(m, _)| saved_path(output_dir, &target_path(m, opts, &unusable_dirs)))
+                .collect()
+        };
+
+        println!("\nVerifying {} archives...", paths.len());
+        let corrupt = verify::verify_archives(&paths);
+        for (path, e) in &corrupt {
+            println!("Corrupt: {} ({:#})", path.display(), e);
+        }
+        println!(
+            "Verified {} archives, {} corrupt",
+            paths.len(),
+            corrupt.len()
+        );
+
+        if opts.requeue_corrupt && !corrupt.is_empty() {
+            let requeue: Vec<(&PathBuf, &BeatmapInfo)> = corrupt
+                .iter()
+                .filter_map(|(path, _)| {
+                    let id = path
+                        .file_name()?
+                        .to_str()
+                        .and_then(verify::parse_mapset_id)?;
+                    let beatmap = maps.iter().find(|m| m.beatmapset_id == id)?;
+                    Some((path, beatmap))
+                })
+                .collect();
+
+            println!("Re-downloading {} corrupt archives...", requeue.len());
+            let status_pb = ProgressBar::new(0);
+            status_pb.set_style(progress::bar_style("{msg}"));
+
+            let mut fixed = 0;
+            for &(path, beatmap) in &requeue {
+                fs::remove_file(path)?;
+
+                let relative = target_path(beatmap, opts, &unusable_dirs);
+                let target = Target {
+                    beatmap,
+                    relative: &relative,
+                    validators: Vec::new(),
+                };
+                let result =
+                    download_with_fallback(&client, &pool, &target, &out, &status_pb, opts).await;
+                if let Some(pins) = &pins {
+                    pins.flush(&status_pb);
+                }
+                match result {
+                    Ok(_) => match verify::verify_archive(&saved_path(output_dir, &relative)) {
+                        Ok(_) => fixed += 1,
+                        Err(e) => progress::println(
+                            &status_pb,
+                            format!(
+                                "Still corrupt: {} ({:#})",
+                                saved_path(output_dir, &relative).display(),
+                                e
+                            ),
+                        ),
+                    },
+                    Err(e) => progress::println(
+                        &status_pb,
+                        format!("Failed to download {}: {}", beatmap.beatmapset_id, e),
+                    ),
+                }
+            }
+            status_pb.finish_and_clear();
+            println!("Repaired {}/{} corrupt archives", fixed, requeue.len());
+        }
+    }
+
+    if opts.dedupe_output && !cancelled {
+        dedupe::dedupe_output(output_dir)?;
+    }
+    opts.retention.apply(count(Outcome::Failed))?;
+
     println!("\nDone! Check {}", output_dir.display());
-    Ok(())
-}
\ No newline at end of file
+    Ok(DownloadReport {
+        downloaded: count(Outcome::Downloaded),
+        failed: count(Outcome::Failed),
+        bytes: download_stats.iter().map(|s| s.bytes).sum(),
+        duration: started.elapsed(),
+        failed_ids: results
+            .iter()
+            .filter(|(_, o)| *o == Outcome::Failed)
+            .map(|(m, _)| m.beatmapset_id)
+            .collect(),
+        cancelled,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mirror::MirrorConfig;
+    use crate::testutil::{archive, names, ok, serve, temp_dir};
+    use clap::Parser;
+    use std::net::SocketAddr;
+    use tokio::io::AsyncReadExt;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        opts: DownloadOptions,
+    }
+
+    /// a mirror at `addr`, every download of beatconnect goes there
+    fn options_with(addr: SocketAddr, args: &[&str]) -> DownloadOptions {
+        let rewrite = format!("^https://beatconnect.io=>http://{}", addr);
+        let mut all = vec![
+            "test",
+            "--mirrors",
+            "beatconnect",
+            "--url-rewrite",
+            &rewrite,
+            "--min-valid-size",
+            "1",
+        ];
+        all.extend_from_slice(args);
+        Cli::parse_from(all).opts
+    }
+
+    /// a mirror answering with `respond`, options pointing there and a directory to save into,
+    /// removed again when dropped
+    struct Fixture {
+        dir: PathBuf,
+        opts: DownloadOptions,
+        client: Client,
+        pool: MirrorPool,
+        writes: WriteStats,
+        /// the most requests the mirror answered at once
+        most: Arc<AtomicUsize>,
+    }
+
+    impl Fixture {
+        async fn new(name: &str, respond: fn(usize, &str) -> Vec<u8>, args: &[&str]) -> Self {
+            let (addr, most) = serve(respond).await;
+            let opts = options_with(addr, args);
+            let client = build_client("test", None).unwrap();
+            let pool = MirrorPool::new(&opts.mirrors, false, None, |_| 1.0, &client).await;
+            Fixture {
+                dir: temp_dir(name),
+                opts,
+                client,
+                pool,
+                writes: WriteStats::default(),
+                most,
+            }
+        }
+
+        /// where `beatmap` is saved, relative to the directory
+        fn relative(beatmap: &BeatmapInfo) -> PathBuf {
+            PathBuf::from(format!("{} {}.osz", beatmap.beatmapset_id, beatmap.title))
+        }
+
+        async fn download(
+            &self,
+            beatmap: &BeatmapInfo,
+            validators: Vec<&Validator>,
+        ) -> Result<Downloaded> {
+            let relative = Self::relative(beatmap);
+            let target = Target {
+                beatmap,
+                relative: &relative,
+                validators,
+            };
+            let out = Output {
+                dir: &self.dir,
+                index: None,
+                writes: &self.writes,
+            };
+            download_with_fallback(
+                &self.client,
+                &self.pool,
+                &target,
+                &out,
+                &ProgressBar::hidden(),
+                &self.opts,
+            )
+            .await
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn titled(id: u32, title: &str) -> BeatmapInfo {
+        BeatmapInfo {
+            beatmapset_id: id,
+            title: title.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn by_artist(id: u32, artist: &str) -> BeatmapInfo {
+        BeatmapInfo {
+            beatmapset_id: id,
+            artist: artist.to_string(),
+            title: "song".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unusable_template_dirs_fall_back_to_the_root() {
+        let dir = temp_dir("dir-fallback");
+        // a file where the directory of one artist would go
+        fs::write(dir.join("Blocked"), "").unwrap();
+        let opts = Cli::parse_from(["test", "--dir-template", "{artist}"]).opts;
+        let (blocked, usable) = (by_artist(1, "Blocked"), by_artist(2, "Usable"));
+
+        let unusable = prepare_dirs(&dir, &[&blocked, &usable], &opts);
+
+        assert_eq!(unusable, HashSet::from([PathBuf::from("Blocked")]));
+        assert!(dir.join("Usable").is_dir());
+        assert_eq!(names(&dir.join("Usable")), Vec::<String>::new());
+        assert_eq!(
+            target_path(&blocked, &opts, &unusable),
+            PathBuf::from("1 Blocked - song.osz")
+        );
+        assert_eq!(
+            target_path(&usable, &opts, &unusable),
+            Path::new("Usable").join("2 Usable - song.osz")
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_template_dirs_fall_back_to_the_root() {
+        let dir = temp_dir("dir-fallback-invalid");
+        let opts = Cli::parse_from(["test", "--dir-template", "{artist}"]).opts;
+        // longer than a name may be on NTFS, so invalid on Windows, and on ext4 and APFS as well
+        let long = by_artist(1, &"a".repeat(300));
+
+        let unusable = prepare_dirs(&dir, &[&long], &opts);
+
+        assert!(unusable.contains(Path::new(&long.artist)));
+        assert_eq!(
+            target_path(&long, &opts, &unusable),
+            PathBuf::from(format!("1 {} - song.osz", long.artist))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn one_attempt_per_set_at_a_time() {
+        let fixture = Fixture::new("set-lock", |_, _| ok(&archive()), &[]).await;
+        let beatmap = titled(454, "queued");
+
+        let attempts = (0..6).map(|_| fixture.download(&beatmap, Vec::new()));
+        for result in futures_util::future::join_all(attempts).await {
+            result.unwrap();
+        }
+
+        assert_eq!(fixture.most.load(Ordering::SeqCst), 1);
+        assert_eq!(names(&fixture.dir), ["454 queued.osz"]);
+        assert_eq!(
+            fs::read(fixture.dir.join("454 queued.osz")).unwrap(),
+            archive()
+        );
+        assert!(!SET_LOCKS.lock().unwrap().contains_key(&454));
+    }
+
+    #[tokio::test]
+    async fn continues_after_the_connection_drops_mid_body() {
+        let respond = |n, head: &str| {
+            let body = archive();
+            if n == 0 {
+                // promises the whole archive, sends half and hangs up
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .into_bytes();
+                response.extend_from_slice(&body[..body.len() / 2]);
+                return response;
+            }
+            let start: usize = head
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix("range: bytes=")
+                        .or_else(|| line.strip_prefix("Range: bytes="))
+                })
+                .and_then(|range| range.trim_end_matches('-').parse().ok())
+                .expect("the retry asks for the rest");
+            let mut response = format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                start,
+                body.len() - 1,
+                body.len(),
+                body.len() - start
+            )
+            .into_bytes();
+            response.extend_from_slice(&body[start..]);
+            response
+        };
+        let fixture = Fixture::new("mid-body", respond, &[]).await;
+
+        let download = fixture
+            .download(&titled(465, "dropped"), Vec::new())
+            .await
+            .unwrap();
+
+        assert_eq!(download.retries, 1);
+        assert_eq!(download.bytes, archive().len() as u64);
+        assert_eq!(
+            fs::read(fixture.dir.join("465 dropped.osz")).unwrap(),
+            archive()
+        );
+        assert_eq!(names(&fixture.dir), ["465 dropped.osz"]);
+    }
+
+    /// the first half of the archive, then the connection goes
+    fn half() -> Vec<u8> {
+        let body = archive();
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend_from_slice(&body[..body.len() / 2]);
+        response
+    }
+
+    /// download set `id` from a mirror sending `respond`, returning the download and the archive
+    async fn resume(id: u32, respond: fn(usize, &str) -> Vec<u8>) -> (Downloaded, Vec<u8>) {
+        let fixture = Fixture::new(&format!("resume-{}", id), respond, &[]).await;
+        let beatmap = titled(id, "resumed");
+
+        let download = fixture.download(&beatmap, Vec::new()).await.unwrap();
+
+        let relative = Fixture::relative(&beatmap);
+        assert_eq!(names(&fixture.dir), [relative.to_string_lossy()]);
+        (download, fs::read(fixture.dir.join(&relative)).unwrap())
+    }
+
+    #[tokio::test]
+    async fn whole_archives_sent_for_a_range_replace_the_part() {
+        // the mirror ignores the range and sends all of it again
+        let (download, saved) =
+            resume(4452, |n, _| if n == 0 { half() } else { ok(&archive()) }).await;
+        assert_eq!(saved, archive());
+        assert_eq!(download.bytes, archive().len() as u64);
+        assert_eq!(download.retries, 1);
+    }
+
+    #[tokio::test]
+    async fn wrong_ranges_start_over() {
+        let respond = |n, head: &str| match n {
+            0 => half(),
+            // asked for the rest, sends a range starting elsewhere
+            1 => {
+                assert!(
+                    head.to_ascii_lowercase().contains("range: bytes=1024-"),
+                    "{}",
+                    head
+                );
+                let body = archive();
+                let mut response = format!(
+                    "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 512-{}/{}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len() - 1,
+                    body.len(),
+                    body.len() - 512
+                )
+                .into_bytes();
+                response.extend_from_slice(&body[512..]);
+                response
+            }
+            _ => {
+                assert!(!head.to_ascii_lowercase().contains("range:"), "{}", head);
+                ok(&archive())
+            }
+        };
+        let (download, saved) = resume(4453, respond).await;
+        assert_eq!(saved, archive());
+        assert_eq!(download.retries, 2);
+    }
+
+    #[test]
+    fn part_files_are_named_by_set() {
+        // two sets whose names sanitize to the same archive
+        let filepath = Path::new("out").join("Artist - Title_.osz");
+        let (first, second) = (part_path(&filepath, 4471), part_path(&filepath, 4472));
+        assert_ne!(first, second);
+        for (path, id) in [(&first, "4471."), (&second, "4472.")] {
+            assert_eq!(path.parent(), Some(Path::new("out")));
+            let name = path.file_name().unwrap().to_string_lossy();
+            assert!(name.starts_with(id) && name.ends_with(".part"), "{}", name);
+        }
+        assert_ne!(part_path(&filepath, 4471), part_path(&filepath, 4471));
+    }
+
+    #[tokio::test]
+    async fn unchanged_archives_are_kept_on_304() {
+        let respond = |_, head: &str| {
+            if head.to_ascii_lowercase().contains("if-none-match: \"v1\"") {
+                return b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n"
+                    .to_vec();
+            }
+            let body = archive();
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sat, 17 Oct 2026 12:00:00 GMT\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body);
+            response
+        };
+        let fixture = Fixture::new("not-modified", respond, &[]).await;
+        let beatmap = titled(449, "cached");
+
+        let first = fixture.download(&beatmap, Vec::new()).await.unwrap();
+        assert!(!first.unchanged);
+        let known = validators::load(&fixture.dir);
+        let validator = &known[&(449, Mirror::Beatconnect)];
+        assert_eq!(validator.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(
+            validator.last_modified.as_deref(),
+            Some("Sat, 17 Oct 2026 12:00:00 GMT")
+        );
+
+        // the refresh sends them back and keeps the archive, only marking it as fresh
+        let archive_path = fixture.dir.join("449 cached.osz");
+        let old = SystemTime::now() - Duration::from_secs(30 * 24 * 60 * 60);
+        File::options()
+            .write(true)
+            .open(&archive_path)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        let refresh = fixture.download(&beatmap, vec![validator]).await.unwrap();
+
+        assert!(refresh.unchanged);
+        assert_eq!(refresh.bytes, 0);
+        assert_eq!(fs::read(&archive_path).unwrap(), archive());
+        assert!(
+            fs::metadata(&archive_path).unwrap().modified().unwrap()
+                > old + Duration::from_secs(60)
+        );
+        assert_eq!(
+            names(&fixture.dir),
+            ["449 cached.osz", validators::VALIDATORS_FILE]
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_downloads_leave_no_part_file() {
+        // every attempt hangs up halfway, or isn't an archive at all
+        let respond = |_, head: &str| {
+            if head.contains("/b/4361") {
+                return ok(b"<html>not here</html>");
+            }
+            let body = archive();
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend_from_slice(&body[..body.len() / 2]);
+            response
+        };
+        let fixture = Fixture::new("part-cleanup", respond, &["--retries", "1"]).await;
+
+        for id in [436, 4361] {
+            let result = fixture.download(&titled(id, "broken"), Vec::new()).await;
+            assert!(result.is_err(), "{}", id);
+            assert_eq!(names(&fixture.dir), Vec::<String>::new(), "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn no_mirror_to_try_is_an_error() {
+        let mut fixture = Fixture::new("no-mirror", |_, _| unreachable!(), &[]).await;
+        fixture.pool = MirrorPool::new(&[], false, None, |_| 1.0, &fixture.client).await;
+        let result = fixture.download(&titled(423, "nowhere"), Vec::new()).await;
+        assert_eq!(result.unwrap_err().to_string(), "no mirror left to try");
+    }
+
+    #[tokio::test]
+    async fn mirrors_can_post_a_body() {
+        // the mirror sends back the request it got
+        let fixture = Fixture::new("post", |_, request| ok(request.as_bytes()), &[]).await;
+        let config = MirrorConfig {
+            method: reqwest::Method::POST,
+            body_template: Some(r#"{"set":{id}}"#),
+        };
+        let url = UrlRewrite::apply(
+            &fixture.opts.url_rewrite,
+            Mirror::Beatconnect.download_url(437),
+        );
+
+        let response = config
+            .request(&fixture.client, &url, 437)
+            .send()
+            .await
+            .unwrap();
+
+        let received = response.text().await.unwrap();
+        assert!(
+            received.starts_with("POST /b/437 HTTP/1.1\r\n"),
+            "{}",
+            received
+        );
+        assert!(
+            received
+                .to_ascii_lowercase()
+                .contains("\r\ncontent-type: application/json\r\n"),
+            "{}",
+            received
+        );
+        assert!(received.ends_with("\r\n\r\n{\"set\":437}"), "{}", received);
+    }
+
+    #[tokio::test]
+    async fn slow_disks_hold_back_the_body() {
+        const CHUNK: usize = 16 * 1024;
+        const CHUNKS: usize = 64;
+        const BUFFER: usize = 64 * 1024;
+        const PIPE: usize = 32 * 1024;
+        // the disk, taking a while for every piece it takes in
+        let (file, mut disk) = tokio::io::duplex(PIPE);
+        let drained = Arc::new(AtomicU64::new(0));
+        let writing = tokio::spawn({
+            let drained = drained.clone();
+            async move {
+                let mut piece = vec![0; 8 * 1024];
+                loop {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    match disk.read(&mut piece).await.unwrap() {
+                        0 => break,
+                        n => drained.fetch_add(n as u64, Ordering::SeqCst),
+                    };
+                }
+            }
+        });
+        // how far the network got ahead of the disk at most
+        let pulled = AtomicU64::new(0);
+        let ahead = AtomicU64::new(0);
+        let body = stream::iter(0..CHUNKS).map(|_| {
+            let pulled = pulled.fetch_add(CHUNK as u64, Ordering::SeqCst) + CHUNK as u64;
+            ahead.fetch_max(pulled - drained.load(Ordering::SeqCst), Ordering::SeqCst);
+            Ok::<_, std::io::Error>(vec![7; CHUNK])
+        });
+
+        let writes = WriteStats::default();
+        let mut out = BufWriter::with_capacity(BUFFER, file);
+        let (written, lost) = write_body(body, &mut out, BUFFER, &writes).await.unwrap();
+        drop(out);
+        writing.await.unwrap();
+
+        assert!(lost.is_none());
+        assert_eq!(written, (CHUNK * CHUNKS) as u64);
+        assert_eq!(drained.load(Ordering::SeqCst), written);
+        assert!(
+            ahead.load(Ordering::SeqCst) <= (BUFFER + PIPE + CHUNK) as u64,
+            "{:?}",
+            ahead
+        );
+        assert_eq!(writes.buffered.load(Ordering::SeqCst), 0);
+        assert!(writes.latency() > Duration::ZERO);
+    }
+
+    #[test]
+    fn report_categories() {
+        let report = |downloaded, failed, cancelled| DownloadReport {
+            downloaded,
+            failed,
+            cancelled,
+            ..Default::default()
+        };
+        assert_eq!(report(3, 0, false).failure(), None);
+        assert_eq!(
+            report(3, 1, false).failure(),
+            Some(Failure::PartialDownload)
+        );
+        assert_eq!(report(0, 1, false).failure(), Some(Failure::DownloadFailed));
+        // a stopped run exits as cancelled, whatever it got done
+        assert_eq!(report(3, 0, true).failure(), Some(Failure::Cancelled));
+        assert_eq!(report(0, 2, true).failure(), Some(Failure::Cancelled));
+    }
+
+    #[tokio::test]
+    async fn cancelled_waits_leave_no_lock_behind() {
+        let held = lock_set(4540).await;
+        let waiting = tokio::time::timeout(Duration::from_millis(20), lock_set(4540)).await;
+        assert!(waiting.is_err());
+        assert!(SET_LOCKS.lock().unwrap().contains_key(&4540));
+        drop(held);
+        assert!(!SET_LOCKS.lock().unwrap().contains_key(&4540));
+    }
+}
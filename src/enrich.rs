@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::exit::Failure;
+use crate::fetcher;
+use crate::persist;
+use crate::types::BeatmapInfo;
+
+/// metadata `enrich` can fill in
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Field {
+    Stars,
+    Status,
+    Checksum,
+    Length,
+    Bpm,
+    Mode,
+    Creator,
+    Genre,
+    Language,
+    /// difficulty count and star range of the set
+    Summary,
+    /// name and star rating of every difficulty of the set
+    Difficulties,
+}
+
+impl Field {
+    fn is_missing(self, map: &BeatmapInfo) -> bool {
+        match self {
+            Field::Stars => map.stars.is_none(),
+            Field::Status => map.status.is_none(),
+            Field::Checksum => map.checksum.is_none(),
+            Field::Length => map.length_seconds.is_none(),
+            Field::Bpm => map.bpm.is_none(),
+            Field::Mode => map.mode.is_none(),
+            Field::Creator => map.creator.is_none(),
+            Field::Genre => map.genre.is_none(),
+            Field::Language => map.language.is_none(),
+            Field::Summary => map.set_summary.is_none(),
+            Field::Difficulties => map.difficulties.is_empty(),
+        }
+    }
+
+    /// whether a difficulty lookup has the field, the others (the BPM too) need the whole set
+    fn per_difficulty(self) -> bool {
+        matches!(
+            self,
+            Field::Stars | Field::Status | Field::Checksum | Field::Length | Field::Mode
+        )
+    }
+}
+
+/// look up the requested `fields` (all of them when empty) for the entries of `input` that
+/// lack any, at most `rate` requests per minute, and save the file. fields of the entries this
+/// version doesn't know are kept
+pub async fn enrich(input: &Path, fields: &[Field], rate: Option<u32>) -> Result<()> {
+    let fields = if fields.is_empty() {
+        Field::value_variants()
+    } else {
+        fields
+    };
+    let pace = rate.map_or(fetcher::API_PACE, |rate| {
+        Duration::from_secs_f64(60.0 / rate as f64)
+    });
+
+    let contents = std::fs::read_to_string(input)
+        .context("Failed to read JSON file")
+        .context(Failure::InvalidInput)?;
+    let mut entries: Vec<Value> = serde_json::from_str(&contents).context(Failure::InvalidInput)?;
+
+    // entries that don't parse are left as they are
+    let (indices, mut maps): (Vec<usize>, Vec<BeatmapInfo>) = entries
+        .iter()
+        .enumerate()
+        .filter_map(|(i, entry)| {
+            serde_json::from_value(entry.clone())
+                .ok()
+                .map(|map| (i, map))
+        })
+        .unzip();
+    let skipped = entries.len() - maps.len();
+
+    let missing = |map: &BeatmapInfo, per_difficulty: bool| {
+        fields
+            .iter()
+            .any(|f| f.per_difficulty() == per_difficulty && f.is_missing(map))
+    };
+    let incomplete = maps
+        .iter()
+        .filter(|m| missing(m, true) || missing(m, false))
+        .count();
+    let before: Vec<Value> = maps
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<Result<_, _>>()?;
+
+    fetcher::enrich_difficulties(&mut maps, |m| missing(m, true), pace).await?;
+    fetcher::enrich_beatmaps(&mut maps, |m| missing(m, false), pace).await?;
+
+    let mut updated = 0;
+    for ((index, map), before) in indices.into_iter().zip(&maps).zip(&before) {
+        let after = serde_json::to_value(map)?;
+        if after == *before {
+            continue;
+        }
+        if let (Some(entry), Value::Object(fields)) = (entries[index].as_object_mut(), after) {
+            entry.extend(fields);
+        }
+        updated += 1;
+    }
+    let unresolved = maps
+        .iter()
+        .filter(|m| missing(m, true) || missing(m, false))
+        .count();
+
+    if updated > 0 {
+        let json = serde_json::to_string_pretty(&entries)?;
+        persist::write_atomic(input, json.as_bytes())?;
+    }
+
+    println!("\nUpdated:          {}", updated);
+    println!("Already complete: {}", maps.len() - incomplete);
+    println!(
+        "Still missing:    {} (deleted maps or fields the API doesn't have)",
+        unresolved
+    );
+    if skipped > 0 {
+        println!(
+            "Invalid entries:  {} (left unchanged, see `list --invalid`)",
+            skipped
+        );
+    }
+    if updated > 0 {
+        println!("Saved to {}", input.display());
+    }
+    Ok(())
+}
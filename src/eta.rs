@@ -0,0 +1,66 @@
+use indicatif::{ProgressState, ProgressStyle};
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::mirror::RateBudget;
+use crate::units;
+
+// how many recent downloads the average download time is taken over
+const WINDOW: usize = 20;
+
+/// time left in a run, from how long recent downloads took spread over the concurrent
+/// downloads, but never less than the rate limits of the mirrors in use allow
+#[derive(Default)]
+pub struct Eta {
+    state: Mutex<EtaState>,
+}
+
+#[derive(Default)]
+struct EtaState {
+    recent: VecDeque<Duration>,
+    // the last estimate and when it was made, it counts down until the next one
+    estimate: Option<(Duration, Instant)>,
+}
+
+impl Eta {
+    pub fn record(&self, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.recent.push_back(elapsed);
+        if state.recent.len() > WINDOW {
+            state.recent.pop_front();
+        }
+    }
+
+    /// estimate again for `remaining` maps at `concurrency` downloads at a time
+    pub fn update(&self, remaining: usize, concurrency: usize, budget: RateBudget) {
+        let mut state = self.state.lock().unwrap();
+        if state.recent.is_empty() {
+            return;
+        }
+        let average = state.recent.iter().sum::<Duration>() / state.recent.len() as u32;
+        let by_downloads = average.mul_f64(remaining as f64 / concurrency.max(1) as f64);
+        // when the limits aren't binding this is 0 and the estimate is the plain throughput one
+        let by_limits = budget.time_for(remaining);
+        state.estimate = Some((by_downloads.max(by_limits), Instant::now()));
+    }
+
+    fn remaining(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        state
+            .estimate
+            .map(|(eta, at)| eta.saturating_sub(at.elapsed()))
+    }
+
+    /// `style` with `{eta}` rendered from this estimate, indicatif's own is shown until the
+    /// first download finishes
+    pub fn style(self: &Arc<Self>, style: ProgressStyle) -> ProgressStyle {
+        let eta = Arc::clone(self);
+        style.with_key("eta", move |state: &ProgressState, w: &mut dyn Write| {
+            let _ = w.write_str(&units::duration(
+                eta.remaining().unwrap_or_else(|| state.eta()),
+            ));
+        })
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::LazyLock;
+use tokio_util::sync::CancellationToken;
+
+/// failure categories, each with a stable process exit code (documented in the README).
+/// attach one to an error with `.context(Failure::...)`, `main` looks it up to pick the exit code
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Failure {
+    AuthFailed,
+    UserNotFound,
+    FetchFailed,
+    InvalidInput,
+    PartialDownload,
+    DownloadFailed,
+    PartialFetch,
+    Cancelled,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::AuthFailed => 3,
+            Failure::UserNotFound => 4,
+            Failure::FetchFailed => 5,
+            Failure::InvalidInput => 6,
+            Failure::PartialDownload => 7,
+            Failure::DownloadFailed => 8,
+            Failure::PartialFetch => 9,
+            // the usual code for being interrupted by SIGINT
+            Failure::Cancelled => 130,
+        }
+    }
+
+    /// the category attached anywhere in the error's context chain
+    pub fn of(err: &anyhow::Error) -> Option<Self> {
+        err.downcast_ref::<Failure>().copied()
+    }
+}
+
+/// the process exit code for how a run went
+pub fn code(result: &anyhow::Result<()>) -> u8 {
+    match result {
+        Ok(()) => 0,
+        Err(e) => Failure::of(e).map_or(1, Failure::code),
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Failure::AuthFailed => "authentication with the osu! API failed",
+            Failure::UserNotFound => "osu! user not found",
+            Failure::FetchFailed => "fetching from the osu! API failed",
+            Failure::InvalidInput => "invalid beatmap list",
+            Failure::PartialDownload => "some downloads failed",
+            Failure::DownloadFailed => "every download failed",
+            Failure::PartialFetch => {
+                "the fetch stopped partway, the maps fetched so far were saved"
+            }
+            Failure::Cancelled => "cancelled by user",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Failure {}
+
+// cancelled by the first Ctrl-C while a run that stops by itself is going on
+static CANCEL: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+// how many of those are going on
+static GRACEFUL: AtomicUsize = AtomicUsize::new(0);
+
+/// held by a run that stops by itself once `cancelled`, saving what it has, instead of being
+/// dropped on Ctrl-C
+pub struct Graceful(());
+
+impl Graceful {
+    pub fn enter() -> Self {
+        GRACEFUL.fetch_add(1, Ordering::SeqCst);
+        Self(())
+    }
+}
+
+impl Drop for Graceful {
+    fn drop(&mut self) {
+        GRACEFUL.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// whether Ctrl-C asked the run to stop
+pub fn cancelled() -> bool {
+    CANCEL.is_cancelled()
+}
+
+/// `future`'s output, or None when Ctrl-C asks the run to stop first
+pub async fn unless_cancelled<T>(future: impl Future<Output = T>) -> Option<T> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = CANCEL.cancelled() => None,
+    }
+}
+
+/// resolves on the Ctrl-C that should stop the program right away: the first one, or the
+/// second while a `Graceful` run is stopping after the first
+pub async fn interrupted() {
+    let ctrl_c = || async {
+        // without a handler Ctrl-C keeps its default of ending the process
+        if tokio::signal::ctrl_c().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    };
+    ctrl_c().await;
+    if GRACEFUL.load(Ordering::SeqCst) > 0 {
+        CANCEL.cancel();
+        eprintln!("\nStopping once the downloads in progress finish, press Ctrl-C again to quit right away");
+        ctrl_c().await;
+    }
+}
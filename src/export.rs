@@ -0,0 +1,254 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+
+use crate::persist;
+use crate::plan::Plan;
+use crate::types::BeatmapInfo;
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ExportFormat {
+    /// the plain array with only the fields the first versions wrote, for older scripts
+    FlatJson,
+    /// the osu! page of every beatmapset, one per line
+    Urls,
+    /// a Markdown table with the linked title, artist, stars and status of every map, for
+    /// pasting into Discord or a forum post
+    Markdown,
+}
+
+/// an entry as the first versions wrote it
+#[derive(Serialize)]
+struct FlatEntry<'a> {
+    beatmap_id: u32,
+    beatmapset_id: u32,
+    title: &'a str,
+    artist: &'a str,
+    version: &'a str,
+    play_count: u32,
+    download_link: &'a str,
+}
+
+impl<'a> From<&'a BeatmapInfo> for FlatEntry<'a> {
+    fn from(map: &'a BeatmapInfo) -> Self {
+        FlatEntry {
+            beatmap_id: map.beatmap_id,
+            beatmapset_id: map.beatmapset_id,
+            title: &map.title,
+            artist: &map.artist,
+            version: &map.version,
+            play_count: map.play_count,
+            download_link: &map.download_link,
+        }
+    }
+}
+
+/// write `maps` to `output` in `format`, streamed entry by entry
+pub fn export(maps: &[BeatmapInfo], format: ExportFormat, output: &Path) -> Result<()> {
+    persist::write_atomic_with(output, |out| {
+        match format {
+            ExportFormat::FlatJson => {
+                let entries: Vec<FlatEntry> = maps.iter().map(FlatEntry::from).collect();
+                serde_json::to_writer_pretty(out, &entries)?;
+            }
+            ExportFormat::Urls => {
+                let mut seen = HashSet::new();
+                for map in maps.iter().filter(|m| seen.insert(m.beatmapset_id)) {
+                    writeln!(out, "{}", set_url(map.beatmapset_id))?;
+                }
+            }
+            ExportFormat::Markdown => {
+                writeln!(out, "| Title | Artist | Stars | Status |")?;
+                writeln!(out, "|---|---|---:|---|")?;
+                for map in maps {
+                    let title = if map.version.is_empty() {
+                        map.title.clone()
+                    } else {
+                        format!("{} [{}]", map.title, map.version)
+                    };
+                    writeln!(
+                        out,
+                        "| [{}]({}) | {} | {} | {} |",
+                        escape_markdown(&title),
+                        set_url(map.beatmapset_id),
+                        escape_markdown(&map.artist),
+                        map.stars.map(|s| format!("{:.2}★", s)).unwrap_or_default(),
+                        map.status.as_deref().unwrap_or("")
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+fn set_url(beatmapset_id: u32) -> String {
+    format!("https://osu.ppy.sh/beatmapsets/{}", beatmapset_id)
+}
+
+/// `text` with the characters that would end a table cell or link, or start emphasis or code,
+/// escaped with a backslash
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\\' | '|' | '[' | ']' | '(' | ')' | '*' | '_' | '`' | '~' | '<' | '>' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            // a line break would end the row
+            '\n' | '\r' => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum UrlFormat {
+    /// one URL per line, from the first mirror
+    Plain,
+    /// aria2c input file: every mirror's URL on one line with the directory and file name
+    Aria2,
+}
+
+/// write the download URLs of the maps in `plan`, for downloading them with another tool
+pub fn export_urls(plan: &Plan, dir: &Path, format: UrlFormat, output: &Path) -> Result<()> {
+    let mut contents = String::new();
+    for entry in &plan.entries {
+        match format {
+            UrlFormat::Plain => {
+                if let Some(url) = entry.urls.first() {
+                    contents.push_str(url);
+                    contents.push('\n');
+                }
+            }
+            UrlFormat::Aria2 => {
+                // aria2 treats tab separated URLs as mirrors of the same file
+                contents.push_str(&entry.urls.join("\t"));
+                contents.push('\n');
+                let target = dir.join(&entry.path);
+                if let Some(parent) = target.parent() {
+                    contents.push_str(&format!("  dir={}\n", parent.display()));
+                }
+                if let Some(name) = target.file_name() {
+                    contents.push_str(&format!("  out={}\n", name.to_string_lossy()));
+                }
+            }
+        }
+    }
+    persist::write_atomic(output, contents.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetcher;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn escapes_markdown() {
+        assert_eq!(escape_markdown("plain title"), "plain title");
+        assert_eq!(escape_markdown("a|b"), "a\\|b");
+        assert_eq!(
+            escape_markdown("[Hard] (TV Size)"),
+            "\\[Hard\\] \\(TV Size\\)"
+        );
+        assert_eq!(escape_markdown("*star* _under_"), "\\*star\\* \\_under\\_");
+        assert_eq!(escape_markdown("two\nlines\r\n"), "two lines  ");
+        assert_eq!(escape_markdown("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn exports_a_markdown_table() {
+        let dir = temp_dir("export-markdown");
+        let path = dir.join("maps.md");
+        let map = BeatmapInfo {
+            beatmapset_id: 466,
+            title: "Pipe | [Bracket]".to_string(),
+            version: "Insane".to_string(),
+            artist: "*Artist*".to_string(),
+            stars: Some(5.123),
+            status: Some("ranked".to_string()),
+            ..Default::default()
+        };
+        export(&[map], ExportFormat::Markdown, &path).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "| Title | Artist | Stars | Status |\n\
+             |---|---|---:|---|\n\
+             | [Pipe \\| \\[Bracket\\] \\[Insane\\]](https://osu.ppy.sh/beatmapsets/466) | \\*Artist\\* | 5.12★ | ranked |\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// an entry of the list the first versions saved
+    #[derive(Serialize)]
+    struct V1 {
+        beatmap_id: u32,
+        beatmapset_id: u32,
+        title: String,
+        artist: String,
+        version: String,
+        play_count: u32,
+        download_link: String,
+    }
+
+    #[test]
+    fn flat_json_round_trips_the_first_format() {
+        let dir = temp_dir("export-flat");
+        let v1 = |beatmap_id,
+                  beatmapset_id: u32,
+                  title: &str,
+                  artist: &str,
+                  version: &str,
+                  play_count| V1 {
+            beatmap_id,
+            beatmapset_id,
+            title: title.to_string(),
+            artist: artist.to_string(),
+            version: version.to_string(),
+            play_count,
+            download_link: format!("https://osu.ppy.sh/beatmapsets/{}", beatmapset_id),
+        };
+        let original = serde_json::to_string_pretty(&[
+            v1(129891, 39804, "FREEDOM DiVE", "xi", "FOUR DIMENSIONS", 412),
+            v1(
+                2449135,
+                1172819,
+                "Exit This Earth's \"Atomosphere\"",
+                "Camellia",
+                "Extra",
+                0,
+            ),
+            v1(0, 2147483647, "Café \\ ☆", "Ｍüller", "", u32::MAX),
+        ])
+        .unwrap();
+
+        // v1 read as the current list, given the fields the first versions didn't have and saved
+        let v1_path = dir.join("v1.json");
+        std::fs::write(&v1_path, &original).unwrap();
+        let (mut maps, invalid) = fetcher::load_beatmaps_checked(&v1_path).unwrap();
+        assert!(invalid.is_empty());
+        for map in &mut maps {
+            map.stars = Some(6.5);
+            map.mode = Some("osu".to_string());
+            map.status = Some("ranked".to_string());
+            map.sources = vec!["most-played".to_string()];
+        }
+        let v2_path = dir.join("v2.json");
+        fetcher::save_beatmaps(&maps, &v2_path).unwrap();
+        assert!(std::fs::read_to_string(&v2_path)
+            .unwrap()
+            .contains("\"stars\""));
+
+        let flat_path = dir.join("flat.json");
+        let (v2, _) = fetcher::load_beatmaps_checked(&v2_path).unwrap();
+        export(&v2, ExportFormat::FlatJson, &flat_path).unwrap();
+        assert_eq!(std::fs::read_to_string(&flat_path).unwrap(), original);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,151 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::persist;
+use crate::types::BeatmapInfo;
+
+// how many set IDs a reason lists before collapsing the rest
+const IDS_SHOWN: usize = 10;
+
+/// an error a download ends with that says why it failed
+#[derive(Debug)]
+pub enum DownloadError {
+    /// the mirror answered 404 for the set
+    NotOnMirror,
+    /// still answered 429 after every retry
+    RateLimited,
+    Status(reqwest::StatusCode),
+    /// the mirror answered with something that's neither a zip archive nor an .osz2 package
+    NotAnArchive,
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::NotOnMirror => write!(f, "Failed: HTTP 404 Not Found"),
+            DownloadError::RateLimited => write!(f, "Hit rate limit too many times"),
+            DownloadError::Status(status) => write!(f, "Failed: HTTP {}", status),
+            DownloadError::NotAnArchive => write!(f, "Failed: the response isn't an archive"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// what a failed download is grouped under in the summary, in the order they're listed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    RateLimited,
+    NotFound,
+    ServerError,
+    InvalidArchive,
+    Network,
+    Disk,
+    Other,
+}
+
+impl Reason {
+    /// the reason of the first cause in the error's chain that has one
+    pub fn of(err: &anyhow::Error) -> Self {
+        for cause in err.chain() {
+            if let Some(e) = cause.downcast_ref::<DownloadError>() {
+                return match e {
+                    DownloadError::NotOnMirror => Reason::NotFound,
+                    DownloadError::RateLimited => Reason::RateLimited,
+                    DownloadError::Status(_) => Reason::ServerError,
+                    DownloadError::NotAnArchive => Reason::InvalidArchive,
+                };
+            }
+            // checked before io errors, reqwest wraps the ones of the connection
+            if cause.is::<reqwest::Error>() {
+                return Reason::Network;
+            }
+            if cause.is::<std::io::Error>() {
+                return Reason::Disk;
+            }
+            if cause.is::<zip::result::ZipError>() {
+                return Reason::InvalidArchive;
+            }
+        }
+        Reason::Other
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Reason::RateLimited => "rate limited",
+            Reason::NotFound => "not found",
+            Reason::ServerError => "server error",
+            Reason::InvalidArchive => "invalid archive",
+            Reason::Network => "network",
+            Reason::Disk => "disk",
+            Reason::Other => "other",
+        }
+    }
+}
+
+/// a download that failed, as written to the failures file
+#[derive(Debug, Clone, Serialize)]
+pub struct FailedDownload {
+    pub beatmapset_id: u32,
+    pub title: String,
+    pub reason: Reason,
+    pub error: String,
+}
+
+impl FailedDownload {
+    pub fn new(beatmap: &BeatmapInfo, err: &anyhow::Error) -> Self {
+        Self {
+            beatmapset_id: beatmap.beatmapset_id,
+            title: beatmap.title.clone(),
+            reason: Reason::of(err),
+            error: format!("{:#}", err),
+        }
+    }
+}
+
+/// the failures by reason, each with its set IDs in order
+fn group(failures: &[FailedDownload]) -> BTreeMap<Reason, Vec<u32>> {
+    let mut groups: BTreeMap<Reason, Vec<u32>> = BTreeMap::new();
+    for failure in failures {
+        groups
+            .entry(failure.reason)
+            .or_default()
+            .push(failure.beatmapset_id);
+    }
+    for ids in groups.values_mut() {
+        ids.sort_unstable();
+    }
+    groups
+}
+
+/// print one line per reason with the affected sets
+pub fn print_summary(failures: &[FailedDownload]) {
+    for (reason, ids) in group(failures) {
+        let shown: Vec<String> = ids
+            .iter()
+            .take(IDS_SHOWN)
+            .map(|id| id.to_string())
+            .collect();
+        let more = if ids.len() > IDS_SHOWN {
+            format!(" and {} more", ids.len() - IDS_SHOWN)
+        } else {
+            String::new()
+        };
+        println!(
+            "  {:<16} {:>4}: {}{}",
+            reason.describe(),
+            ids.len(),
+            shown.join(", "),
+            more
+        );
+    }
+}
+
+pub fn save(failures: &[FailedDownload], path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(failures)?;
+    persist::write_atomic(path, json.as_bytes())
+}
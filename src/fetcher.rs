@@ -1,94 +1,2095 @@
 use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::{Args, ValueEnum};
+use indicatif::ProgressBar;
 use rosu_v2::prelude::*;
-use std::fs::File;
-use std::io::Write;
-use std::path::Path;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::types::BeatmapInfo;
+use crate::checkpoint::Checkpoint;
+use crate::downloader;
+use crate::exit::Failure;
+use crate::formats::{self, Format};
+use crate::osudb;
+use crate::persist;
+use crate::progress;
+use crate::replay;
+use crate::settings;
+use crate::setup;
+use crate::types::{BeatmapInfo, DifficultyInfo, Genre, Language, SetSummary, Unavailable};
 
-pub async fn fetch_most_played() -> Result<Vec<BeatmapInfo>> {
-    let get_env = |key: &str, msg: &str| -> Result<String> {
-        std::env::var(key).context(format!("{} - {}", key, msg))
-    };
+/// wait between API requests when looking up metadata, same as the pagination
+pub const API_PACE: Duration = Duration::from_millis(100);
 
-    let client_id = get_env("OSU_CLIENT_ID", "get it from https://osu.ppy.sh/home/account/edit#oauth")?;
-    let client_secret = get_env("OSU_CLIENT_SECRET", "not set")?;
-    let user_id = get_env("OSU_USERNAME", "put your osu username here")?;
+/// the most difficulties the API returns for one lookup
+const BEATMAPS_PER_REQUEST: usize = 50;
 
-    println!("Authenticating with osu! API...");
-    
-    let osu = Osu::builder()
-        .client_id(client_id.parse()?)
-        .client_secret(client_secret)
+fn credentials() -> Result<(u64, String)> {
+    let settings = settings::get();
+    let client_id = settings
+        .client_id
+        .context("OSU_CLIENT_ID - get it from https://osu.ppy.sh/home/account/edit#oauth")?;
+    let client_secret = settings
+        .client_secret
+        .clone()
+        .context("OSU_CLIENT_SECRET - not set")?;
+    Ok((client_id, client_secret))
+}
+
+async fn build(client_id: u64, client_secret: &str) -> OsuResult<Osu> {
+    Osu::builder()
+        .client_id(client_id)
+        .client_secret(client_secret.to_string())
         .build()
-        .await?;
+        .await
+}
+
+pub async fn connect() -> Result<Osu> {
+    let (client_id, client_secret) = credentials()?;
+
+    println!("Authenticating with osu! API...");
+
+    let osu = build(client_id, &client_secret).await.map_err(auth_error)?;
+
+    println!("Authenticated successfully!");
+    Ok(osu)
+}
+
+/// a failed authentication explained by what went wrong: credentials the API rejected need
+/// fixing in .env, while a network error has nothing to do with them
+fn auth_error(e: OsuError) -> anyhow::Error {
+    if is_rejected(&e) {
+        anyhow::Error::new(e)
+            .context(
+                "the osu! API rejected OSU_CLIENT_ID and OSU_CLIENT_SECRET (invalid client). one of them is \
+                 mistyped, or the secret was reset or the OAuth application deleted: copy both again from \
+                 https://osu.ppy.sh/home/account/edit#oauth into .env, or run `init`",
+            )
+            .context(Failure::AuthFailed)
+    } else if is_unreachable(&e) {
+        anyhow::Error::new(e)
+            .context("couldn't reach the osu! API to authenticate, check your connection (the credentials weren't checked)")
+            .context(Failure::FetchFailed)
+    } else {
+        anyhow::Error::new(e).context(Failure::AuthFailed)
+    }
+}
+
+/// whether the token request failed because the client ID or secret is wrong
+fn is_rejected(e: &OsuError) -> bool {
+    match e {
+        OsuError::UpdateToken { source } => is_rejected(source),
+        // the token endpoint answers 401 with "invalid_client" for a wrong ID or secret
+        OsuError::Response { status, body, .. } => {
+            status.as_u16() == 401 || body.contains("invalid_client")
+        }
+        _ => false,
+    }
+}
+
+/// whether the token request never got an answer
+fn is_unreachable(e: &OsuError) -> bool {
+    match e {
+        OsuError::UpdateToken { source } => is_unreachable(source),
+        OsuError::Request { .. } => true,
+        _ => false,
+    }
+}
+
+/// the client for paginated fetches, which authenticates again when the API starts rejecting
+/// its token partway through a long fetch
+struct Api {
+    client_id: u64,
+    client_secret: String,
+    osu: RwLock<Arc<Osu>>,
+    /// for the endpoints rosu-v2 has no request for
+    rest: Rest,
+}
+
+/// the osu! API, for the endpoints rosu-v2 has no request for
+const API_URL: &str = "https://osu.ppy.sh/api/v2";
+const TOKEN_URL: &str = "https://osu.ppy.sh/oauth/token";
+/// the response format rosu-v2 parses, sent so `get` deserializes the same models
+const API_VERSION: &str = "20220705";
+
+impl Api {
+    async fn connect() -> Result<Self> {
+        let osu = connect().await?;
+        let (client_id, client_secret) = credentials()?;
+        let rest = Rest::new(client_id, client_secret.clone(), API_URL, TOKEN_URL)?;
+        Ok(Self {
+            client_id,
+            client_secret,
+            osu: RwLock::new(Arc::new(osu)),
+            rest,
+        })
+    }
+
+    /// run `request`, and if the token is rejected authenticate again and run it once more.
+    /// the error is only returned when the new token is rejected too
+    async fn call<T, F, Fut>(&self, request: F) -> OsuResult<T>
+    where
+        F: Fn(Arc<Osu>) -> Fut,
+        Fut: Future<Output = OsuResult<T>>,
+    {
+        retry_rejected(&self.osu, request, || {
+            build(self.client_id, &self.client_secret)
+        })
+        .await
+    }
+}
+
+/// run `request` with the client in `current`. when the API rejects its token the client is
+/// replaced by one from `reconnect` and `request` runs once more
+async fn retry_rejected<C, T, F, Fut, R, RFut>(
+    current: &RwLock<Arc<C>>,
+    request: F,
+    reconnect: R,
+) -> OsuResult<T>
+where
+    F: Fn(Arc<C>) -> Fut,
+    Fut: Future<Output = OsuResult<T>>,
+    R: FnOnce() -> RFut,
+    RFut: Future<Output = OsuResult<C>>,
+{
+    let client = Arc::clone(&*current.read().await);
+    match request(Arc::clone(&client)).await {
+        Err(OsuError::Response { status, .. }) if status.as_u16() == 401 => {
+            let fresh = {
+                let mut current = current.write().await;
+                // a concurrent fetch may have authenticated again already
+                if Arc::ptr_eq(&current, &client) {
+                    println!("The osu! API rejected the token, authenticating again...");
+                    *current = Arc::new(reconnect().await?);
+                }
+                Arc::clone(&current)
+            };
+            request(fresh).await
+        }
+        result => result,
+    }
+}
+
+/// plain requests to the osu! API with a client credentials token of their own
+struct Rest {
+    client_id: u64,
+    client_secret: String,
+    api_url: String,
+    token_url: String,
+    http: reqwest::Client,
+    token: tokio::sync::Mutex<Option<String>>,
+}
+
+impl Rest {
+    fn new(client_id: u64, client_secret: String, api_url: &str, token_url: &str) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .user_agent(downloader::user_agent(None))
+            .timeout(Duration::from_secs(30))
+            .build()?;
+        Ok(Self {
+            client_id,
+            client_secret,
+            api_url: api_url.to_string(),
+            token_url: token_url.to_string(),
+            http,
+            token: Default::default(),
+        })
+    }
+
+    /// a client credentials token for `get`, requested on first use and again once `stale`
+    /// was rejected
+    async fn token(&self, stale: Option<&str>) -> Result<String> {
+        #[derive(serde::Deserialize)]
+        struct Token {
+            access_token: String,
+        }
+
+        let mut token = self.token.lock().await;
+        if let Some(current) = token.as_deref().filter(|&current| Some(current) != stale) {
+            return Ok(current.to_string());
+        }
+        let form = [
+            ("client_id", self.client_id.to_string()),
+            ("client_secret", self.client_secret.clone()),
+            ("grant_type", "client_credentials".to_string()),
+            ("scope", "public".to_string()),
+        ];
+        let response = self
+            .http
+            .post(&self.token_url)
+            .form(&form)
+            .send()
+            .await
+            .context("couldn't reach the osu! API to authenticate")?;
+        let fresh = response
+            .error_for_status()
+            .context("the osu! API refused a token")?
+            .json::<Token>()
+            .await
+            .context("the osu! API sent an unreadable token")?
+            .access_token;
+        *token = Some(fresh.clone());
+        Ok(fresh)
+    }
+
+    /// GET `path` from the osu! API without rosu-v2, authenticating again once when the token
+    /// is rejected like `Api::call`. a missing resource is `OsuError::NotFound`, as from rosu-v2
+    async fn get<T: serde::de::DeserializeOwned>(
+        &self,
+        path: &str,
+        query: &[(&str, String)],
+    ) -> Result<T> {
+        let url = &format!("{}/{}", self.api_url, path);
+        let send = |token: String| async move {
+            self.http
+                .get(url)
+                .query(query)
+                .bearer_auth(token)
+                .header("x-api-version", API_VERSION)
+                .send()
+                .await
+                .with_context(|| format!("couldn't reach the osu! API ({})", path))
+        };
+
+        let token = self.token(None).await?;
+        let mut response = send(token.clone()).await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            println!("The osu! API rejected the token, authenticating again...");
+            response = send(self.token(Some(&token)).await?).await?;
+        }
+
+        let status = response.status();
+        if status == reqwest::StatusCode::NOT_FOUND {
+            return Err(OsuError::NotFound.into());
+        }
+        let body = response
+            .text()
+            .await
+            .with_context(|| format!("reading the osu! API's answer ({})", path))?;
+        if !status.is_success() {
+            anyhow::bail!("the osu! API answered {} ({}): {}", status, path, body);
+        }
+        serde_json::from_str(&body).map_err(|source| OsuError::Parsing { body, source }.into())
+    }
+}
+
+/// where the beatmap list is fetched from
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Source {
+    /// the user's most played beatmaps
+    MostPlayed,
+    /// the user's favourite beatmapsets
+    Favourites,
+    /// beatmaps the user holds a #1 score on
+    #[value(alias = "firsts")]
+    FirstPlaces,
+    /// beatmaps of the scores pinned on the user's profile
+    Pinned,
+    /// beatmaps the user set a score on within --window, at most the last 24 hours the osu!
+    /// API keeps recent scores for. not part of `all`
+    Recent,
+    /// all of the above, merged into one list. several sources can also be picked at once,
+    /// e.g. `most-played,favourites`
+    All,
+    /// the user's best and recent scores, only fetched with --deep
+    #[value(skip)]
+    Deep,
+}
 
-    println!("Authenticated successfully! Fetching maps...");
+impl Source {
+    /// the name stored in `BeatmapInfo::sources`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Source::MostPlayed => "most-played",
+            Source::Favourites => "favourites",
+            Source::FirstPlaces => "first-places",
+            Source::Pinned => "pinned",
+            Source::Recent => "recent",
+            Source::All => "all",
+            Source::Deep => "deep",
+        }
+    }
+}
 
+fn spinner() -> ProgressBar {
     let pb = ProgressBar::new_spinner();
-    pb.set_style(
-        ProgressStyle::default_spinner()
-            .template("{spinner:.green} {msg}")
-            .unwrap()
-    );
+    pb.set_style(progress::spinner_style("{spinner:.green} {msg}"));
+    pb
+}
+
+/// the user whose maps are fetched
+pub fn username() -> Result<String> {
+    settings::get()
+        .username
+        .clone()
+        .context("OSU_USERNAME - put your osu username here")
+}
+
+/// the sources `all` stands for
+const ALL_SOURCES: [Source; 4] = [
+    Source::MostPlayed,
+    Source::Favourites,
+    Source::FirstPlaces,
+    Source::Pinned,
+];
+
+/// the names a map can be tagged with in `BeatmapInfo::sources`
+pub const SOURCE_NAMES: [&str; 6] = [
+    "most-played",
+    "favourites",
+    "first-places",
+    "pinned",
+    "recent",
+    "deep",
+];
+
+/// `sources` without duplicates, with `all` standing for every source
+pub fn expand_sources(sources: &[Source]) -> Vec<Source> {
+    let mut expanded: Vec<Source> = Vec::new();
+    for &source in sources {
+        let sources = if source == Source::All {
+            &ALL_SOURCES[..]
+        } else {
+            std::slice::from_ref(&source)
+        };
+        for &source in sources {
+            if !expanded.contains(&source) {
+                expanded.push(source);
+            }
+        }
+    }
+    expanded
+}
+
+/// how the `recent` source is fetched
+#[derive(Args, Debug, Clone)]
+pub struct RecentOptions {
+    /// with --source recent, also take maps of failed scores
+    #[arg(long)]
+    pub include_fails: bool,
+    /// with --source recent, how far back scores count, e.g. 6h. the osu! API only keeps the
+    /// recent scores of the last 24 hours
+    #[arg(long, value_name = "DURATION", default_value = "24h", value_parser = downloader::parse_duration)]
+    pub window: Duration,
+}
+
+/// how long the osu! API keeps recent scores
+const RECENT_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// fetch the maps of `sources`, saving each page to `checkpoint` if given. every map is tagged
+/// with the sources it came from
+pub async fn fetch(
+    sources: &[Source],
+    recent: &RecentOptions,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let user_id = username()?;
+    let api = Api::connect().await?;
+
+    println!("Fetching maps...");
+
+    let maps = match expand_sources(sources).as_slice() {
+        [source] => fetch_source(&api, &user_id, *source, recent, &spinner(), checkpoint)
+            .await
+            .map(|mut maps| {
+                for map in &mut maps {
+                    map.sources = vec![source.name().to_string()];
+                }
+                maps
+            }),
+        sources => fetch_all(&api, &user_id, sources, recent, checkpoint).await,
+    };
+    maps.map_err(fetch_failure)
+}
+
+/// a paginated fetch that failed partway, with the maps fetched until then
+#[derive(Debug)]
+pub struct PartialFetch {
+    pub list: Source,
+    /// offset of the page that failed
+    pub offset: usize,
+    pub maps: Vec<BeatmapInfo>,
+    pub error: anyhow::Error,
+}
+
+impl PartialFetch {
+    fn stopped(
+        list: Source,
+        offset: usize,
+        maps: Vec<BeatmapInfo>,
+        error: impl Into<anyhow::Error>,
+    ) -> anyhow::Error {
+        PartialFetch {
+            list,
+            offset,
+            maps,
+            error: error.into(),
+        }
+        .into()
+    }
+}
+
+impl std::fmt::Display for PartialFetch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fetching {} stopped at offset {}",
+            self.list.name(),
+            self.offset
+        )
+    }
+}
+
+impl std::error::Error for PartialFetch {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// where a fetch of `source` starts, after the pages an earlier fetch saved when resuming
+fn resume_point(checkpoint: Option<&Checkpoint>, source: Source) -> (usize, Vec<BeatmapInfo>) {
+    checkpoint
+        .map(|c| c.resume_point(source))
+        .unwrap_or_default()
+}
+
+/// add a fetched page to the checkpoint, a fetch that can't save its progress goes on anyway
+fn save_page(
+    checkpoint: Option<&Checkpoint>,
+    source: Source,
+    next_offset: usize,
+    maps: &[BeatmapInfo],
+    pb: &ProgressBar,
+) {
+    if let Some(Err(e)) = checkpoint.map(|c| c.record(source, next_offset, maps)) {
+        progress::println(pb, format!("Failed to save the fetch checkpoint: {:#}", e));
+    }
+}
 
-    let mut all_maps = Vec::new();
-    let mut offset = 0;
+/// tag an API error with its exit code category
+fn fetch_failure(e: anyhow::Error) -> anyhow::Error {
+    let failure = match e.chain().find_map(|e| e.downcast_ref::<OsuError>()) {
+        Some(OsuError::NotFound) => Failure::UserNotFound,
+        _ => Failure::FetchFailed,
+    };
+    e.context(failure)
+}
+
+async fn fetch_source(
+    api: &Api,
+    user_id: &str,
+    source: Source,
+    recent: &RecentOptions,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let maps = match source {
+        Source::MostPlayed => fetch_most_played(api, user_id, pb, checkpoint).await?,
+        Source::Favourites => fetch_favourites(api, user_id, pb, checkpoint).await?,
+        Source::FirstPlaces => {
+            fetch_user_scores(api, user_id, ScoreKind::Firsts, None, pb, checkpoint).await?
+        }
+        Source::Pinned => fetch_pinned(api, user_id, pb, checkpoint).await?,
+        Source::Recent => fetch_recent(api, user_id, recent, pb, checkpoint).await?,
+        Source::All => unreachable!("expanded by expand_sources"),
+        Source::Deep => unreachable!("handled by fetch_deep"),
+    };
+    pb.finish_with_message(format!(
+        "Fetched {} {} maps total!",
+        maps.len(),
+        source.name()
+    ));
+    Ok(maps)
+}
+
+/// fetch several sources at once and merge them into one list with a single entry per
+/// beatmapset, each tagged with the sources it came from
+async fn fetch_all(
+    api: &Api,
+    user_id: &str,
+    sources: &[Source],
+    recent: &RecentOptions,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let bars = progress::multi();
+
+    // one request per source at a time, rosu-v2 keeps the combined rate within the API limit
+    let fetched = futures_util::future::join_all(sources.iter().map(|&source| {
+        let pb = bars.add(spinner());
+        async move {
+            fetch_source(api, user_id, source, recent, &pb, checkpoint)
+                .await
+                .map(|maps| (source, maps))
+        }
+    }))
+    .await;
+
+    // a source that stopped partway still contributes what it got, for --continue-on-partial-fetch
+    let mut results = Vec::new();
+    let mut stopped: Option<PartialFetch> = None;
+    for result in fetched {
+        match result {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                let mut partial = e.downcast::<PartialFetch>()?;
+                results.push((partial.list, std::mem::take(&mut partial.maps)));
+                stopped.get_or_insert(partial);
+            }
+        }
+    }
+
+    for (source, maps) in &results {
+        println!("{}: {} maps", source.name(), maps.len());
+    }
+    let merged = merge_sources(results);
+    println!("Merged into {} beatmapsets", merged.len());
+    if let Some(mut partial) = stopped {
+        partial.maps = merged;
+        return Err(partial.into());
+    }
+    Ok(merged)
+}
+
+/// the maps of several sources with a single entry per beatmapset, the most played difficulty's,
+/// tagged with every source the set came from
+fn merge_sources(results: Vec<(Source, Vec<BeatmapInfo>)>) -> Vec<BeatmapInfo> {
+    let mut merged: Vec<BeatmapInfo> = Vec::new();
+    // position of each set in `merged`
+    let mut positions: HashMap<u32, usize> = HashMap::new();
+    for (source, maps) in results {
+        for mut map in maps {
+            match positions.get(&map.beatmapset_id) {
+                Some(&i) => {
+                    let existing = &mut merged[i];
+                    if !existing.sources.iter().any(|s| s == source.name()) {
+                        existing.sources.push(source.name().to_string());
+                    }
+                    // prefer the most played difficulty's entry
+                    if map.play_count > existing.play_count {
+                        map.sources = std::mem::take(&mut existing.sources);
+                        *existing = map;
+                    }
+                }
+                None => {
+                    map.sources = vec![source.name().to_string()];
+                    positions.insert(map.beatmapset_id, merged.len());
+                    merged.push(map);
+                }
+            }
+        }
+    }
+    merged
+}
+
+async fn fetch_most_played(
+    api: &Api,
+    user_id: &str,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let (mut offset, mut all_maps) = resume_point(checkpoint, Source::MostPlayed);
     const LIMIT: usize = 50; // 50 is the limit for the 'most_played' field
 
     loop {
         pb.set_message(format!("Fetched {} maps...", all_maps.len()));
-        
-        let maps: Vec<MostPlayedMap> = osu
-            .user_most_played(&user_id)
-            .limit(LIMIT)
-            .offset(offset)
-            .await?;
+
+        let request = |osu: Arc<Osu>| async move {
+            osu.user_most_played(user_id)
+                .limit(LIMIT)
+                .offset(offset)
+                .await
+        };
+        let maps: Vec<MostPlayedMap> = match api.call(request).await {
+            Ok(maps) => maps,
+            Err(e) => {
+                return Err(PartialFetch::stopped(
+                    Source::MostPlayed,
+                    offset,
+                    all_maps,
+                    e,
+                ))
+            }
+        };
 
         let batch_size = maps.len();
         if batch_size == 0 {
             break;
         }
 
+        let before = all_maps.len();
         for map in maps {
             let beatmap_info = BeatmapInfo {
                 beatmap_id: map.map_id,
                 beatmapset_id: map.mapset.mapset_id,
-                title: map.mapset.title.to_string(),
-                artist: map.mapset.artist.to_string(),
+                title: nfc(&map.mapset.title),
+                artist: nfc(&map.mapset.artist),
                 version: map.map.version.to_string(),
                 play_count: map.count as u32,
                 download_link: format!("https://osu.ppy.sh/beatmapsets/{}", map.mapset.mapset_id),
+                creator: Some(nfc(&map.mapset.creator_name)),
+                length_seconds: Some(map.map.seconds_total),
+                mode: Some(mode_name(map.map.mode).to_string()),
+                stars: Some(map.map.stars),
+                status: Some(status_name(map.map.status).to_string()),
+                checksum: map.map.checksum.clone(),
+                genre: map.mapset.genre.and_then(genre),
+                language: map.mapset.language.and_then(language),
+                ..Default::default()
             };
             all_maps.push(beatmap_info);
         }
 
+        save_page(
+            checkpoint,
+            Source::MostPlayed,
+            offset + batch_size,
+            &all_maps[before..],
+            pb,
+        );
+
         if batch_size < LIMIT {
             break;
         }
-        
+
         offset += batch_size;
         pb.tick();
         // be polite to the API :3
         tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     }
 
-    pb.finish_with_message(format!("Fetched {} maps total!", all_maps.len()));
+    Ok(all_maps)
+}
+
+async fn fetch_favourites(
+    api: &Api,
+    user_id: &str,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let (mut offset, mut all_maps) = resume_point(checkpoint, Source::Favourites);
+    const LIMIT: usize = 100;
+
+    // rosu-v2 has no request for favourites, which the API only lists by numeric user ID
+    let user = api
+        .call(|osu: Arc<Osu>| async move { osu.user(user_id).await })
+        .await;
+    let user_id = match user {
+        Ok(user) => user.user_id,
+        Err(e) => {
+            return Err(PartialFetch::stopped(
+                Source::Favourites,
+                offset,
+                all_maps,
+                e,
+            ))
+        }
+    };
+    let path = format!("users/{}/beatmapsets/favourite", user_id);
+
+    loop {
+        pb.set_message(format!("Fetched {} favourites...", all_maps.len()));
+
+        let query = [("limit", LIMIT.to_string()), ("offset", offset.to_string())];
+        let mapsets: Vec<BeatmapsetExtended> = match api.rest.get(&path, &query).await {
+            Ok(mapsets) => mapsets,
+            Err(e) => {
+                return Err(PartialFetch::stopped(
+                    Source::Favourites,
+                    offset,
+                    all_maps,
+                    e,
+                ))
+            }
+        };
+
+        let batch_size = mapsets.len();
+        if batch_size == 0 {
+            break;
+        }
+
+        let before = all_maps.len();
+        for mapset in &mapsets {
+            // favourites are whole sets, use the first difficulty for the per-map fields
+            let first = mapset.maps.as_ref().and_then(|maps| maps.first());
+            let mut info = BeatmapInfo {
+                beatmap_id: first.map_or(0, |m| m.map_id),
+                beatmapset_id: mapset.mapset_id,
+                title: nfc(&mapset.title),
+                artist: nfc(&mapset.artist),
+                version: first.map_or_else(String::new, |m| m.version.to_string()),
+                mode: first.map(|m| mode_name(m.mode).to_string()),
+                play_count: 0,
+                download_link: format!("https://osu.ppy.sh/beatmapsets/{}", mapset.mapset_id),
+                ..Default::default()
+            };
+            fill_from_mapset(&mut info, mapset);
+            all_maps.push(info);
+        }
+
+        save_page(
+            checkpoint,
+            Source::Favourites,
+            offset + batch_size,
+            &all_maps[before..],
+            pb,
+        );
+
+        if batch_size < LIMIT {
+            break;
+        }
+
+        offset += batch_size;
+        pb.tick();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
 
     Ok(all_maps)
 }
 
+#[derive(Debug, Clone, Copy)]
+enum ScoreKind {
+    Firsts,
+    Pinned,
+    Best,
+    Recent { include_fails: bool },
+}
+
+impl ScoreKind {
+    fn source(self) -> Source {
+        match self {
+            ScoreKind::Firsts => Source::FirstPlaces,
+            ScoreKind::Pinned => Source::Pinned,
+            ScoreKind::Recent { .. } => Source::Recent,
+            ScoreKind::Best => Source::Deep,
+        }
+    }
+}
+
+/// page through one of the user's score lists, one entry per beatmap. with `since` (a unix
+/// timestamp), only scores set from then on are kept
+async fn fetch_user_scores(
+    api: &Api,
+    user_id: &str,
+    kind: ScoreKind,
+    since: Option<i64>,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let (mut offset, mut all_maps) = resume_point(checkpoint, kind.source());
+    const LIMIT: usize = 100; // 100 is the limit for user scores
+
+    loop {
+        pb.set_message(format!("Fetched {} maps...", all_maps.len()));
+
+        let request = |osu: Arc<Osu>| async move {
+            let request = osu.user_scores(user_id).limit(LIMIT).offset(offset);
+            match kind {
+                ScoreKind::Firsts => request.firsts().await,
+                ScoreKind::Pinned => request.pinned().await,
+                ScoreKind::Best => request.best().await,
+                ScoreKind::Recent { include_fails } => {
+                    request.recent().include_fails(include_fails).await
+                }
+            }
+        };
+        let scores: Vec<Score> = match api.call(request).await {
+            Ok(scores) => scores,
+            Err(e) => return Err(PartialFetch::stopped(kind.source(), offset, all_maps, e)),
+        };
+
+        let batch_size = scores.len();
+        if batch_size == 0 {
+            break;
+        }
+
+        let before = all_maps.len();
+        for score in scores
+            .iter()
+            .filter(|s| since.is_none_or(|since| s.ended_at.unix_timestamp() >= since))
+        {
+            if let Some(mut info) = beatmap_from_score(score) {
+                if matches!(kind, ScoreKind::Recent { .. }) {
+                    info.last_played = Some(score.ended_at.unix_timestamp());
+                }
+                if !all_maps.iter().any(|m| m.beatmap_id == info.beatmap_id) {
+                    all_maps.push(info);
+                }
+            }
+        }
+
+        save_page(
+            checkpoint,
+            kind.source(),
+            offset + batch_size,
+            &all_maps[before..],
+            pb,
+        );
+
+        if batch_size < LIMIT {
+            break;
+        }
+
+        offset += batch_size;
+        pb.tick();
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+
+    Ok(all_maps)
+}
+
+/// the beatmaps of the user's pinned scores, one entry per beatmapset. several pinned scores
+/// can be on difficulties of the same set, the first one pinned is kept
+async fn fetch_pinned(
+    api: &Api,
+    user_id: &str,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    let mut maps = fetch_user_scores(api, user_id, ScoreKind::Pinned, None, pb, checkpoint).await?;
+    let mut seen = HashSet::new();
+    maps.retain(|m| seen.insert(m.beatmapset_id));
+    Ok(maps)
+}
+
+/// the beatmaps the user set a score on within --window, newest first and one entry per
+/// beatmapset. the osu! API only keeps the last 24 hours of them, a longer window gets no more
+async fn fetch_recent(
+    api: &Api,
+    user_id: &str,
+    opts: &RecentOptions,
+    pb: &ProgressBar,
+    checkpoint: Option<&Checkpoint>,
+) -> Result<Vec<BeatmapInfo>> {
+    if opts.window > RECENT_RETENTION {
+        progress::println(
+            pb,
+            "Warning: --window is longer than the 24 hours the osu! API keeps recent scores for, \
+             only those are fetched. run it at least once a day to miss nothing",
+        );
+    }
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let since = now.saturating_sub(opts.window).as_secs() as i64;
+    let kind = ScoreKind::Recent {
+        include_fails: opts.include_fails,
+    };
+    let mut maps = fetch_user_scores(api, user_id, kind, Some(since), pb, checkpoint).await?;
+    let mut seen = HashSet::new();
+    maps.retain(|m| seen.insert(m.beatmapset_id));
+    if maps.is_empty() {
+        progress::println(
+            pb,
+            "No scores set within the window, nothing was played or the scores expired",
+        );
+    }
+    Ok(maps)
+}
+
+/// add the sets of the user's best and recent scores set within `window` that aren't in `maps`
+/// yet, tagged with the `deep` source. a score list that fails partway still adds what it got
+pub async fn fetch_deep(maps: &mut Vec<BeatmapInfo>, window: Duration) -> Result<usize> {
+    let user_id = username()?;
+    let api = Api::connect().await?;
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+    let since = now.saturating_sub(window).as_secs() as i64;
+
+    let pb = spinner();
+    let mut found = Vec::new();
+    for kind in [
+        ScoreKind::Best,
+        ScoreKind::Recent {
+            include_fails: true,
+        },
+    ] {
+        match fetch_user_scores(&api, &user_id, kind, Some(since), &pb, None).await {
+            Ok(scores) => found.extend(scores),
+            Err(e) => {
+                let partial = e.downcast::<PartialFetch>()?;
+                progress::println(
+                    &pb,
+                    format!(
+                        "Warning: the deep fetch stopped partway ({:#})",
+                        partial.error
+                    ),
+                );
+                found.extend(partial.maps);
+            }
+        }
+    }
+
+    let mut known: HashSet<u32> = maps.iter().map(|m| m.beatmapset_id).collect();
+    let before = maps.len();
+    for mut map in found {
+        if known.insert(map.beatmapset_id) {
+            map.sources = vec![Source::Deep.name().to_string()];
+            maps.push(map);
+        }
+    }
+    pb.finish_with_message(format!(
+        "Deep fetch found {} more beatmapsets",
+        maps.len() - before
+    ));
+    Ok(maps.len() - before)
+}
+
+fn beatmap_from_score(score: &Score) -> Option<BeatmapInfo> {
+    let map = score.map.as_ref()?;
+    let mapset = score.mapset.as_ref()?;
+    // the map of a score on a convert comes in the mode it was played in, only osu!standard
+    // maps are converted
+    let native = if map.convert { GameMode::Osu } else { map.mode };
+
+    Some(BeatmapInfo {
+        beatmap_id: map.map_id,
+        beatmapset_id: mapset.mapset_id,
+        title: nfc(&mapset.title),
+        artist: nfc(&mapset.artist),
+        version: map.version.to_string(),
+        // scores don't carry a play count
+        play_count: 0,
+        download_link: format!("https://osu.ppy.sh/beatmapsets/{}", mapset.mapset_id),
+        creator: Some(nfc(&mapset.creator_name)),
+        length_seconds: Some(map.seconds_total),
+        bpm: Some(map.bpm),
+        mode: Some(mode_name(native).to_string()),
+        played_mode: (score.mode != native).then(|| mode_name(score.mode).to_string()),
+        stars: Some(map.stars),
+        status: Some(status_name(map.status).to_string()),
+        checksum: map.checksum.clone(),
+        genre: mapset.genre.and_then(genre),
+        language: mapset.language.and_then(language),
+        ..Default::default()
+    })
+}
+
+/// resolve the beatmaps of the replays in `dir` by their MD5, one entry per map with the
+/// number of replays on it
+pub async fn fetch_from_replays(dir: &Path) -> Result<Vec<BeatmapInfo>> {
+    let counts = replay::scan(dir)?;
+    println!("Found replays on {} beatmaps", counts.len());
+
+    let resolved = resolve_hashes(counts).await?;
+    Ok(resolved
+        .into_iter()
+        .map(|(mut map, count)| {
+            map.replay_count = Some(count);
+            map
+        })
+        .collect())
+}
+
+/// resolve the maps of a collection in osu!stable's `collection.db`, one entry per beatmapset.
+/// without a name the available collections are listed
+pub async fn fetch_from_collection(path: &Path, name: Option<&str>) -> Result<Vec<BeatmapInfo>> {
+    let collections = osudb::read_collections(path).context(Failure::InvalidInput)?;
+    let Some(collection) = name.and_then(|name| collections.iter().find(|c| c.name == name)) else {
+        println!("Collections in {}:", path.display());
+        for c in &collections {
+            println!("  {} ({} maps)", c.name, c.hashes.len());
+        }
+        let err = match name {
+            Some(name) => anyhow::anyhow!("No collection named '{}'", name),
+            None => anyhow::anyhow!("Pick a collection with --collection"),
+        };
+        return Err(err.context(Failure::InvalidInput));
+    };
+    println!(
+        "Collection '{}' has {} maps",
+        collection.name,
+        collection.hashes.len()
+    );
+
+    let resolved = resolve_hashes(collection.hashes.iter().map(|h| (h.clone(), 1))).await?;
+    let mut maps: Vec<BeatmapInfo> = Vec::new();
+    for (map, _) in resolved {
+        if !maps.iter().any(|m| m.beatmapset_id == map.beatmapset_id) {
+            maps.push(map);
+        }
+    }
+    Ok(maps)
+}
+
+/// look up beatmaps by MD5, adding up the counts of hashes that resolve to the same map.
+/// the API has no batch lookup by checksum, so it's one request per hash. hashes that don't
+/// resolve (deleted maps, locally edited difficulties) are listed at the end
+async fn resolve_hashes(
+    hashes: impl IntoIterator<Item = (String, u32)>,
+) -> Result<Vec<(BeatmapInfo, u32)>> {
+    let hashes: Vec<(String, u32)> = hashes.into_iter().collect();
+    let osu = connect().await?;
+
+    let pb = ProgressBar::new(hashes.len() as u64);
+    pb.set_style(progress::bar_style(
+        "{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+    ));
+
+    let mut maps: Vec<(BeatmapInfo, u32)> = Vec::new();
+    let mut unresolved = Vec::new();
+    for (hash, count) in hashes {
+        match osu.beatmap().checksum(&hash).await {
+            Ok(map) => match maps.iter_mut().find(|(m, _)| m.beatmap_id == map.map_id) {
+                Some((_, existing)) => *existing += count,
+                None => {
+                    let mut info = BeatmapInfo {
+                        beatmap_id: map.map_id,
+                        beatmapset_id: map.mapset_id,
+                        version: map.version.to_string(),
+                        download_link: format!("https://osu.ppy.sh/beatmapsets/{}", map.mapset_id),
+                        length_seconds: Some(map.seconds_total),
+                        bpm: Some(map.bpm),
+                        mode: Some(mode_name(map.mode).to_string()),
+                        ..Default::default()
+                    };
+                    fill_from_difficulty(&mut info, &map);
+                    if let Some(mapset) = &map.mapset {
+                        info.title = nfc(&mapset.title);
+                        info.artist = nfc(&mapset.artist);
+                        fill_from_mapset(&mut info, mapset);
+                    }
+                    maps.push((info, count));
+                }
+            },
+            Err(OsuError::NotFound) => unresolved.push(hash),
+            Err(e) => return Err(fetch_failure(e.into())),
+        }
+        pb.inc(1);
+        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+    }
+    pb.finish_with_message(format!("Resolved {} beatmaps", maps.len()));
+
+    if !unresolved.is_empty() {
+        println!(
+            "\n{} beatmap hashes couldn't be resolved:",
+            unresolved.len()
+        );
+        for hash in &unresolved {
+            println!("  {}", hash);
+        }
+    }
+    Ok(maps)
+}
+
+/// look up the beatmapsets of the maps matching `needs` and fill in their missing metadata,
+/// returns how many sets were resolved
+pub async fn enrich_beatmaps(
+    maps: &mut [BeatmapInfo],
+    needs: impl Fn(&BeatmapInfo) -> bool,
+    pace: Duration,
+) -> Result<usize> {
+    let mapset_ids: BTreeSet<u32> = maps
+        .iter()
+        .filter(|m| needs(m))
+        .map(|m| m.beatmapset_id)
+        .collect();
+
+    if mapset_ids.is_empty() {
+        return Ok(0);
+    }
+
+    println!(
+        "Looking up metadata for {} beatmapsets...",
+        mapset_ids.len()
+    );
+    let osu = connect().await?;
+
+    let pb = ProgressBar::new(mapset_ids.len() as u64);
+    pb.set_style(progress::bar_style(
+        "{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+    ));
+
+    let mut resolved = 0;
+    for mapset_id in mapset_ids {
+        match osu.beatmapset(mapset_id).await {
+            Ok(mapset) => {
+                for map in maps.iter_mut().filter(|m| m.beatmapset_id == mapset_id) {
+                    fill_from_mapset(map, &mapset);
+                }
+                resolved += 1;
+            }
+            Err(e) => progress::println(
+                &pb,
+                format!("Failed to look up beatmapset {}: {}", mapset_id, e),
+            ),
+        }
+        pb.inc(1);
+        tokio::time::sleep(pace).await;
+    }
+
+    pb.finish_with_message(format!("Resolved {} beatmapsets", resolved));
+    Ok(resolved)
+}
+
+/// look up the difficulties matching `needs`, as many per request as the API allows, and fill
+/// in their missing fields. returns how many were found, deleted maps aren't
+pub async fn enrich_difficulties(
+    maps: &mut [BeatmapInfo],
+    needs: impl Fn(&BeatmapInfo) -> bool,
+    pace: Duration,
+) -> Result<usize> {
+    // favourites without difficulties have no map ID to look up
+    let map_ids: Vec<u32> = maps
+        .iter()
+        .filter(|m| needs(m) && m.beatmap_id != 0)
+        .map(|m| m.beatmap_id)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    if map_ids.is_empty() {
+        return Ok(0);
+    }
+
+    println!("Looking up {} difficulties...", map_ids.len());
+    let osu = connect().await?;
+
+    let pb = ProgressBar::new(map_ids.len() as u64);
+    pb.set_style(progress::bar_style(
+        "{spinner:.green} [{bar:40.cyan/blue}] {pos}/{len} {msg}",
+    ));
+
+    let mut found = 0;
+    for batch in map_ids.chunks(BEATMAPS_PER_REQUEST) {
+        match osu.beatmaps(batch.iter().copied()).await {
+            Ok(beatmaps) => {
+                for beatmap in &beatmaps {
+                    for map in maps.iter_mut().filter(|m| m.beatmap_id == beatmap.map_id) {
+                        fill_from_beatmap(map, beatmap);
+                    }
+                }
+                found += beatmaps.len();
+            }
+            Err(e) => progress::println(
+                &pb,
+                format!("Failed to look up {} difficulties: {}", batch.len(), e),
+            ),
+        }
+        pb.inc(batch.len() as u64);
+        tokio::time::sleep(pace).await;
+    }
+
+    pb.finish_with_message(format!("Found {} difficulties", found));
+    Ok(found)
+}
+
+/// ask the osu! API about sets no mirror had, to tell deleted ones from ones the mirrors lack.
+/// sets the lookup fails for are left out
+pub async fn classify_unavailable(mapset_ids: &[u32]) -> Result<HashMap<u32, Unavailable>> {
+    println!(
+        "\nChecking {} sets no mirror had with the osu! API...",
+        mapset_ids.len()
+    );
+    let osu = connect().await?;
+
+    let mut classified = HashMap::new();
+    for &mapset_id in mapset_ids {
+        match osu.beatmapset(mapset_id).await {
+            Ok(_) => {
+                classified.insert(mapset_id, Unavailable::MirrorMissing);
+            }
+            Err(OsuError::NotFound) => {
+                classified.insert(mapset_id, Unavailable::Deleted);
+            }
+            Err(e) => println!("Failed to look up beatmapset {}: {}", mapset_id, e),
+        }
+        tokio::time::sleep(API_PACE).await;
+    }
+    Ok(classified)
+}
+
+/// the fields of a difficulty lookup, which has no BPM (that comes with the set)
+fn fill_from_beatmap(map: &mut BeatmapInfo, beatmap: &Beatmap) {
+    if map.stars.is_none() {
+        map.stars = Some(beatmap.stars);
+    }
+    if map.status.is_none() {
+        map.status = Some(status_name(beatmap.status).to_string());
+    }
+    if map.checksum.is_none() {
+        map.checksum = beatmap.checksum.clone();
+    }
+    if map.length_seconds.is_none() {
+        map.length_seconds = Some(beatmap.seconds_total);
+    }
+    if map.mode.is_none() {
+        map.mode = Some(mode_name(beatmap.mode).to_string());
+    }
+}
+
+/// the fields of a difficulty that came with its set or a score, including the BPM
+fn fill_from_difficulty(map: &mut BeatmapInfo, difficulty: &BeatmapExtended) {
+    if map.stars.is_none() {
+        map.stars = Some(difficulty.stars);
+    }
+    if map.status.is_none() {
+        map.status = Some(status_name(difficulty.status).to_string());
+    }
+    if map.checksum.is_none() {
+        map.checksum = difficulty.checksum.clone();
+    }
+    if map.length_seconds.is_none() {
+        map.length_seconds = Some(difficulty.seconds_total);
+    }
+    if map.bpm.is_none() {
+        map.bpm = Some(difficulty.bpm);
+    }
+    if map.mode.is_none() {
+        map.mode = Some(mode_name(difficulty.mode).to_string());
+    }
+}
+
+fn fill_from_mapset(map: &mut BeatmapInfo, mapset: &BeatmapsetExtended) {
+    let difficulty = mapset
+        .maps
+        .as_ref()
+        .and_then(|maps| maps.iter().find(|d| d.map_id == map.beatmap_id));
+
+    if map.creator.is_none() {
+        map.creator = Some(nfc(&mapset.creator_name));
+    }
+    if let Some(difficulty) = difficulty {
+        fill_from_difficulty(map, difficulty);
+    }
+    if map.bpm.is_none() {
+        map.bpm = Some(mapset.bpm);
+    }
+    if map.genre.is_none() {
+        map.genre = mapset.genre.and_then(genre);
+    }
+    if map.language.is_none() {
+        map.language = mapset.language.and_then(language);
+    }
+    if map.set_summary.is_none() {
+        map.set_summary = mapset
+            .maps
+            .as_ref()
+            .filter(|maps| !maps.is_empty())
+            .map(|maps| SetSummary {
+                difficulties: maps.len() as u32,
+                min_stars: maps.iter().map(|d| d.stars).fold(f32::INFINITY, f32::min),
+                max_stars: maps.iter().map(|d| d.stars).fold(0.0, f32::max),
+                mode_counts: maps.iter().fold(BTreeMap::new(), |mut counts, d| {
+                    *counts.entry(mode_name(d.mode).to_string()).or_insert(0) += 1;
+                    counts
+                }),
+            });
+    }
+    if map.difficulties.is_empty() {
+        map.difficulties = mapset
+            .maps
+            .iter()
+            .flatten()
+            .map(|d| DifficultyInfo {
+                beatmap_id: d.map_id,
+                version: d.version.to_string(),
+                stars: d.stars,
+                mode: mode_name(d.mode).to_string(),
+            })
+            .collect();
+        map.difficulties.sort_by(|a, b| a.stars.total_cmp(&b.stars));
+    }
+}
+
+/// the API returns some titles in NFD and others in NFC, keep them all in NFC so the same
+/// title always gives the same filename
+fn nfc(s: &str) -> String {
+    s.nfc().collect()
+}
+
+/// the name the osu! API uses for a ranked status
+fn status_name(status: RankStatus) -> &'static str {
+    match status {
+        RankStatus::Graveyard => "graveyard",
+        RankStatus::WIP => "wip",
+        RankStatus::Pending => "pending",
+        RankStatus::Ranked => "ranked",
+        RankStatus::Approved => "approved",
+        RankStatus::Qualified => "qualified",
+        RankStatus::Loved => "loved",
+    }
+}
+
+/// the name the osu! API uses for a game mode
+fn mode_name(mode: GameMode) -> &'static str {
+    match mode {
+        GameMode::Osu => "osu",
+        GameMode::Taiko => "taiko",
+        GameMode::Catch => "fruits",
+        GameMode::Mania => "mania",
+    }
+}
+
+/// "any" is only a search option, a set never has it
+fn genre(genre: rosu_v2::prelude::Genre) -> Option<Genre> {
+    use rosu_v2::prelude::Genre as ApiGenre;
+    Some(match genre {
+        ApiGenre::Any => return None,
+        ApiGenre::Unspecified => Genre::Unspecified,
+        ApiGenre::VideoGame => Genre::VideoGame,
+        ApiGenre::Anime => Genre::Anime,
+        ApiGenre::Rock => Genre::Rock,
+        ApiGenre::Pop => Genre::Pop,
+        ApiGenre::Other => Genre::Other,
+        ApiGenre::Novelty => Genre::Novelty,
+        ApiGenre::HipHop => Genre::HipHop,
+        ApiGenre::Electronic => Genre::Electronic,
+        ApiGenre::Metal => Genre::Metal,
+        ApiGenre::Classical => Genre::Classical,
+        ApiGenre::Folk => Genre::Folk,
+        ApiGenre::Jazz => Genre::Jazz,
+    })
+}
+
+fn language(language: rosu_v2::prelude::Language) -> Option<Language> {
+    use rosu_v2::prelude::Language as ApiLanguage;
+    Some(match language {
+        ApiLanguage::Any => return None,
+        ApiLanguage::Unspecified => Language::Unspecified,
+        ApiLanguage::English => Language::English,
+        ApiLanguage::Japanese => Language::Japanese,
+        ApiLanguage::Chinese => Language::Chinese,
+        ApiLanguage::Instrumental => Language::Instrumental,
+        ApiLanguage::Korean => Language::Korean,
+        ApiLanguage::French => Language::French,
+        ApiLanguage::German => Language::German,
+        ApiLanguage::Swedish => Language::Swedish,
+        ApiLanguage::Spanish => Language::Spanish,
+        ApiLanguage::Italian => Language::Italian,
+        ApiLanguage::Russian => Language::Russian,
+        ApiLanguage::Polish => Language::Polish,
+        ApiLanguage::Other => Language::Other,
+    })
+}
+
+/// make sure a list can be saved to `path`, so a fetch doesn't find out only once it's done
+pub fn check_list_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        anyhow::bail!(
+            "{} is a directory, pass the path of the JSON file to save the list to",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
 pub fn save_beatmaps(maps: &[BeatmapInfo], path: &Path) -> Result<()> {
+    check_list_path(path)?;
     let json = serde_json::to_string_pretty(maps)?;
-    let mut file = File::create(path)?;
-    file.write_all(json.as_bytes())?;
-    Ok(())
+    persist::write_atomic(path, json.as_bytes())
 }
 
-pub fn load_beatmaps(path: &Path) -> Result<Vec<BeatmapInfo>> {
+/// what to do when a fetched list is much shorter than the saved one it would replace
+#[derive(Args, Debug, Clone)]
+pub struct ShrinkOptions {
+    /// how many percent fewer maps the fetched list may have than the saved one before it's
+    /// taken for a partial fetch and merged into the saved list instead of replacing it
+    #[arg(long, value_name = "PERCENT", default_value_t = 50, value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub max_shrink: u8,
+    /// replace the saved list even when the fetched one is much shorter
+    #[arg(long)]
+    pub accept_shrink: bool,
+}
+
+/// the maps to save to `path` in place of the list there. when `maps` shrank by more than
+/// --max-shrink, the saved entries it lacks are kept unless --accept-shrink is passed
+pub fn guard_shrink(maps: Vec<BeatmapInfo>, path: &Path, opts: &ShrinkOptions) -> Vec<BeatmapInfo> {
+    let Ok((saved, _)) = load_beatmaps_checked(path) else {
+        return maps;
+    };
+    let min_len = saved.len() * (100 - opts.max_shrink as usize) / 100;
+    if maps.len() >= min_len {
+        return maps;
+    }
+
+    println!(
+        "Warning: fetched {} beatmaps, but {} has {} (more than {}% fewer)",
+        maps.len(),
+        path.display(),
+        saved.len(),
+        opts.max_shrink
+    );
+    println!("The API probably returned a partial list");
+    if opts.accept_shrink {
+        println!("Replacing the saved list anyway (--accept-shrink)");
+        return maps;
+    }
+
+    let fetched: HashSet<(u32, u32)> = maps
+        .iter()
+        .map(|m| (m.beatmapset_id, m.beatmap_id))
+        .collect();
+    let mut merged = maps;
+    let saved_sets = set_index(&saved);
+    for map in &mut merged {
+        for entry in saved_sets.get(&map.beatmapset_id).into_iter().flatten() {
+            union_sources(&mut map.sources, entry.sources.iter().cloned());
+        }
+    }
+    let before = merged.len();
+    merged.extend(
+        saved
+            .into_iter()
+            .filter(|m| !fetched.contains(&(m.beatmapset_id, m.beatmap_id))),
+    );
+    println!(
+        "Kept {} saved beatmaps the fetch didn't return, pass --accept-shrink to replace the list instead",
+        merged.len() - before
+    );
+    merged
+}
+
+/// `maps` merged into the list saved at `path`: a difficulty in both keeps the fetched entry
+/// with the higher play count, the saved difficulties the fetch didn't return stay in the list,
+/// and every entry of a set is tagged with the sources of all of them. without a saved list
+/// that's `maps`, one that can't be read is an error rather than replaced
+pub fn merge_saved(maps: Vec<BeatmapInfo>, path: &Path) -> Result<Vec<BeatmapInfo>> {
+    let saved = match load_beatmaps_checked(path) {
+        Ok((saved, _)) => saved,
+        Err(e) if is_not_found(&e) => return Ok(maps),
+        Err(e) => {
+            return Err(e.context(format!(
+                "can't merge into {}, move it away or fix it first so its maps aren't lost",
+                path.display()
+            )))
+        }
+    };
+    let mut merged = maps;
+    let saved_sets = set_index(&saved);
+    let saved_maps: HashMap<(u32, u32), &BeatmapInfo> = saved
+        .iter()
+        .map(|m| ((m.beatmapset_id, m.beatmap_id), m))
+        .collect();
+    for map in &mut merged {
+        for entry in saved_sets.get(&map.beatmapset_id).into_iter().flatten() {
+            union_sources(&mut map.sources, entry.sources.iter().cloned());
+        }
+        if let Some(entry) = saved_maps.get(&(map.beatmapset_id, map.beatmap_id)) {
+            // a source without play counts (scores) doesn't reset the saved one
+            map.play_count = map.play_count.max(entry.play_count);
+            map.last_played = map.last_played.max(entry.last_played);
+        }
+    }
+
+    let fetched: HashSet<(u32, u32)> = merged
+        .iter()
+        .map(|m| (m.beatmapset_id, m.beatmap_id))
+        .collect();
+    let fetched_sets = set_index(&merged);
+    let kept: Vec<BeatmapInfo> = saved
+        .iter()
+        .filter(|m| !fetched.contains(&(m.beatmapset_id, m.beatmap_id)))
+        .map(|m| {
+            let mut map = m.clone();
+            for entry in fetched_sets.get(&m.beatmapset_id).into_iter().flatten() {
+                union_sources(&mut map.sources, entry.sources.iter().cloned());
+            }
+            map
+        })
+        .collect();
+    println!("Kept {} saved beatmaps the fetch didn't return", kept.len());
+    merged.extend(kept);
+    Ok(merged)
+}
+
+/// the entries of `maps` by set ID
+fn set_index(maps: &[BeatmapInfo]) -> HashMap<u32, Vec<&BeatmapInfo>> {
+    let mut sets: HashMap<u32, Vec<&BeatmapInfo>> = HashMap::new();
+    for map in maps {
+        sets.entry(map.beatmapset_id).or_default().push(map);
+    }
+    sets
+}
+
+/// whether `e` comes from a file that doesn't exist
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|io| io.kind() == std::io::ErrorKind::NotFound)
+}
+
+fn union_sources(sources: &mut Vec<String>, more: impl IntoIterator<Item = String>) {
+    for source in more {
+        if !sources.contains(&source) {
+            sources.push(source);
+        }
+    }
+}
+
+/// where the sources a list was fetched from are recorded, `<list>.sources.json`
+pub fn sources_path(list: &Path) -> Result<PathBuf> {
+    persist::sibling_path(list, ".sources.json")
+}
+
+/// record which sources the list at `path` includes: the ones just fetched, which may have
+/// returned nothing, and those the entries it kept from earlier fetches are tagged with. with
+/// `merged`, the sources recorded before stay as well
+pub fn record_sources(
+    path: &Path,
+    fetched: &[Source],
+    maps: &[BeatmapInfo],
+    merged: bool,
+) -> Result<()> {
+    let mut sources: BTreeSet<String> = fetched.iter().map(|s| s.name().to_string()).collect();
+    if merged {
+        sources.extend(recorded_sources(path).unwrap_or_default());
+    }
+    sources.extend(maps.iter().flat_map(|m| m.sources.iter().cloned()));
+    persist::write_atomic(
+        &sources_path(path)?,
+        serde_json::to_string_pretty(&sources)?.as_bytes(),
+    )
+}
+
+/// the sources recorded for the list at `path`, `None` for a list saved without them
+pub fn recorded_sources(path: &Path) -> Option<BTreeSet<String>> {
+    let content = std::fs::read_to_string(sources_path(path).ok()?).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// an entry of the JSON list that can't be downloaded
+pub struct InvalidEntry {
+    /// position in the JSON array
+    pub index: usize,
+    pub reason: String,
+}
+
+// the osu! API uses signed 32 bit IDs, anything above can't be a real beatmapset
+const MAX_MAPSET_ID: u32 = i32::MAX as u32;
+
+/// load the list, separating out the entries that are malformed or have an impossible beatmapset ID.
+/// besides the full list, beatmapset IDs and the community formats of `formats` are read
+pub fn load_beatmaps_checked(path: &Path) -> Result<(Vec<BeatmapInfo>, Vec<InvalidEntry>)> {
+    let (_, maps, invalid) = load_detected(path)?;
+    Ok((maps, invalid))
+}
+
+/// `load_beatmaps_checked`, also telling which format the list was in
+fn load_detected(path: &Path) -> Result<(Format, Vec<BeatmapInfo>, Vec<InvalidEntry>)> {
     let file_content = std::fs::read_to_string(path)
-        .context("Failed to read JSON file")?;
-    let maps: Vec<BeatmapInfo> = serde_json::from_str(&file_content)?;
+        .context("Failed to read JSON file")
+        .context(Failure::InvalidInput)?;
+    let detected = formats::read(&file_content)
+        .with_context(|| format!("Can't read {}", path.display()))
+        .context(Failure::InvalidInput)?;
+
+    let mut maps = Vec::new();
+    let mut invalid = Vec::new();
+    for (index, parsed) in detected.entries.into_iter().enumerate() {
+        match parsed {
+            Ok(map) if map.beatmapset_id == 0 || map.beatmapset_id > MAX_MAPSET_ID => {
+                invalid.push(InvalidEntry {
+                    index,
+                    reason: format!("invalid beatmapset_id {}", map.beatmapset_id),
+                });
+            }
+            Ok(map) => maps.push(map),
+            Err(reason) => invalid.push(InvalidEntry { index, reason }),
+        }
+    }
+    Ok((detected.format, maps, invalid))
+}
+
+/// write the beatmapset IDs of `maps` without duplicates, as a JSON array when `path` ends in
+/// .json and one per line otherwise
+pub fn save_ids(maps: &[BeatmapInfo], path: &Path) -> Result<usize> {
+    let mut seen = HashSet::new();
+    let ids: Vec<u32> = maps
+        .iter()
+        .map(|m| m.beatmapset_id)
+        .filter(|id| seen.insert(*id))
+        .collect();
+    let content = if path
+        .extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+    {
+        serde_json::to_string(&ids)?
+    } else {
+        ids.iter().map(|id| format!("{}\n", id)).collect()
+    };
+    persist::write_atomic(path, content.as_bytes())?;
+    Ok(ids.len())
+}
+
+/// the modes `fetch --split-by-mode` writes a list for, maps without a known mode go to "unknown"
+pub const SPLIT_MODES: [&str; 5] = ["osu", "taiko", "fruits", "mania", "unknown"];
+
+/// the list of `mode` next to `output`, e.g. `osu_most_played_maps_mania.json`
+pub fn mode_path(output: &Path, mode: &str) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{}_{}.{}", stem, mode, ext.to_string_lossy()),
+        None => format!("{}_{}", stem, mode),
+    };
+    output.with_file_name(name)
+}
+
+/// `maps` grouped by the mode they were made for
+pub fn split_by_mode(maps: Vec<BeatmapInfo>) -> BTreeMap<&'static str, Vec<BeatmapInfo>> {
+    let mut split: BTreeMap<&str, Vec<BeatmapInfo>> = BTreeMap::new();
+    for map in maps {
+        let mode = SPLIT_MODES
+            .iter()
+            .find(|m| map.native_mode() == Some(**m))
+            .copied()
+            .unwrap_or("unknown");
+        split.entry(mode).or_default().push(map);
+    }
+    split
+}
+
+/// the list files `paths` stand for, a directory standing for the .json files in it
+pub fn input_files(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if path.is_dir() {
+            let mut lists: Vec<PathBuf> = std::fs::read_dir(path)?
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|p| {
+                    p.is_file()
+                        && p.extension()
+                            .is_some_and(|e| e.eq_ignore_ascii_case("json"))
+                })
+                .collect();
+            if lists.is_empty() {
+                return Err(anyhow::anyhow!("no .json lists in {}", path.display()))
+                    .context(Failure::InvalidInput);
+            }
+            lists.sort();
+            files.extend(lists);
+        } else if path.exists() {
+            files.push(path.clone());
+        } else {
+            return Err(setup::missing_list(path));
+        }
+    }
+    Ok(files)
+}
+
+/// load every list of `paths` into one, see `input_files`
+pub fn load_inputs(paths: &[PathBuf]) -> Result<Vec<BeatmapInfo>> {
+    let files = input_files(paths)?;
+    if let [file] = files.as_slice() {
+        return load_beatmaps(file);
+    }
+    let mut maps = Vec::new();
+    for file in &files {
+        let loaded = load_beatmaps(file)?;
+        println!("{} beatmaps from {}", loaded.len(), file.display());
+        maps.extend(loaded);
+    }
+    Ok(maps)
+}
+
+pub fn load_beatmaps(path: &Path) -> Result<Vec<BeatmapInfo>> {
+    let (format, maps, invalid) = load_detected(path)?;
+    if format != Format::Native {
+        println!("Reading {} as a {}", path.display(), format.name());
+    }
+
+    if !invalid.is_empty() {
+        println!("Skipping {} invalid entries:", invalid.len());
+        for entry in invalid.iter().take(10) {
+            println!("  #{}: {}", entry.index, entry.reason);
+        }
+        if invalid.len() > 10 {
+            println!(
+                "  ...and {} more, run `list --invalid` to see all of them",
+                invalid.len() - 10
+            );
+        }
+        if maps.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Every entry in {} is invalid",
+                path.display()
+            ))
+            .context(Failure::InvalidInput);
+        }
+    }
     Ok(maps)
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::naming::{FilenameFormat, ModeTokenPolicy, NamingOptions, DEFAULT_FILENAME_FORMAT};
+    use crate::testutil::temp_dir;
+    use crate::types::ArchiveExtension;
+    use rosu_v2::error::ApiError;
+
+    fn response(status: u16, body: &str) -> OsuError {
+        OsuError::Response {
+            body: body.to_string(),
+            source: ApiError { error: None },
+            status: reqwest::StatusCode::from_u16(status).unwrap(),
+        }
+    }
+
+    fn map(set: u32, sources: &[&str], play_count: u32) -> BeatmapInfo {
+        BeatmapInfo {
+            beatmap_id: set * 10,
+            beatmapset_id: set,
+            sources: sources.iter().map(|s| s.to_string()).collect(),
+            play_count,
+            ..Default::default()
+        }
+    }
+
+    /// a path in a fresh directory under the system's temp directory
+    fn temp_list(name: &str) -> PathBuf {
+        temp_dir(&format!("fetcher-{}", name)).join("list.json")
+    }
+
+    #[test]
+    fn merge_without_saved_list() {
+        let path = temp_list("merge-missing");
+        let merged = merge_saved(vec![map(1, &["recent"], 0)], &path).unwrap();
+        assert_eq!(merged.len(), 1);
+    }
+
+    #[test]
+    fn merge_refuses_unreadable_list() {
+        let path = temp_list("merge-corrupt");
+        std::fs::write(&path, "{ not a list").unwrap();
+        assert!(merge_saved(vec![map(1, &["recent"], 0)], &path).is_err());
+    }
+
+    #[test]
+    fn merge_keeps_saved_sets() {
+        let path = temp_list("merge");
+        let saved = vec![map(1, &["most-played"], 7), map(2, &["favourites"], 0)];
+        std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        let merged =
+            merge_saved(vec![map(1, &["recent"], 0), map(3, &["recent"], 0)], &path).unwrap();
+        let sets: Vec<u32> = merged.iter().map(|m| m.beatmapset_id).collect();
+        assert_eq!(sets, [1, 3, 2]);
+        assert_eq!(merged[0].sources, ["recent", "most-played"]);
+        assert_eq!(merged[0].play_count, 7);
+    }
+
+    #[test]
+    fn merge_keeps_the_other_difficulties_of_a_set() {
+        let path = temp_list("merge-difficulties");
+        let difficulty = |beatmap_id, play_count| BeatmapInfo {
+            beatmap_id,
+            ..map(4, &["most-played"], play_count)
+        };
+        let saved = vec![difficulty(40, 12), difficulty(41, 5), difficulty(42, 3)];
+        std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        let merged = merge_saved(
+            vec![BeatmapInfo {
+                beatmap_id: 41,
+                ..map(4, &["favourites"], 0)
+            }],
+            &path,
+        )
+        .unwrap();
+
+        let entries: Vec<(u32, u32)> = merged
+            .iter()
+            .map(|m| (m.beatmap_id, m.play_count))
+            .collect();
+        assert_eq!(entries, [(41, 5), (40, 12), (42, 3)]);
+        for map in &merged {
+            assert_eq!(map.sources.len(), 2, "{:?}", map.sources);
+            assert!(
+                map.sources.contains(&"favourites".to_string())
+                    && map.sources.contains(&"most-played".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn recent_fetches_keep_the_saved_difficulties() {
+        let path = temp_list("merge-recent");
+        let difficulty = |beatmap_id, play_count, last_played| BeatmapInfo {
+            beatmap_id,
+            last_played,
+            ..map(5, &["most-played"], play_count)
+        };
+        let saved = vec![
+            difficulty(50, 20, Some(1_000)),
+            difficulty(51, 8, Some(2_000)),
+        ];
+        std::fs::write(&path, serde_json::to_string(&saved).unwrap()).unwrap();
+
+        // a score on the second difficulty, as fetched from recent scores
+        let played = BeatmapInfo {
+            beatmap_id: 51,
+            last_played: Some(3_000),
+            ..map(5, &["recent"], 0)
+        };
+        let merged = merge_saved(vec![played], &path).unwrap();
+
+        let entries: Vec<(u32, u32, Option<i64>)> = merged
+            .iter()
+            .map(|m| (m.beatmap_id, m.play_count, m.last_played))
+            .collect();
+        assert_eq!(entries, [(51, 8, Some(3_000)), (50, 20, Some(1_000))]);
+        assert!(merged
+            .iter()
+            .all(|m| m.sources == ["recent", "most-played"]
+                || m.sources == ["most-played", "recent"]));
+    }
+
+    #[test]
+    fn merges_sources_per_set() {
+        let merged = merge_sources(vec![
+            (Source::Favourites, vec![map(1, &[], 0), map(2, &[], 0)]),
+            (
+                Source::MostPlayed,
+                vec![
+                    BeatmapInfo {
+                        beatmap_id: 11,
+                        ..map(1, &[], 9)
+                    },
+                    map(3, &[], 4),
+                    map(1, &[], 2),
+                ],
+            ),
+        ]);
+        let entries: Vec<(u32, u32, u32)> = merged
+            .iter()
+            .map(|m| (m.beatmapset_id, m.beatmap_id, m.play_count))
+            .collect();
+        assert_eq!(entries, [(1, 11, 9), (2, 20, 0), (3, 30, 4)]);
+        assert_eq!(merged[0].sources, ["favourites", "most-played"]);
+        assert_eq!(merged[2].sources, ["most-played"]);
+    }
+
+    #[test]
+    fn normalization_forms_collapse_to_one_entry() {
+        // the same set as two sources returned it, its title once composed and once not
+        let fetched = |title: &str, play_count| BeatmapInfo {
+            title: nfc(title),
+            artist: nfc("Mu\u{308}ller"),
+            ..map(434, &[], play_count)
+        };
+        let merged = merge_sources(vec![
+            (Source::Favourites, vec![fetched("Caf\u{e9}", 0)]),
+            // the entry kept, it has the play count
+            (Source::MostPlayed, vec![fetched("Cafe\u{301}", 3)]),
+        ]);
+
+        assert_eq!(merged.len(), 1);
+        assert_eq!(merged[0].title, "Caf\u{e9}");
+        assert_eq!(merged[0].artist, "M\u{fc}ller");
+        assert_eq!(merged[0].sources, ["favourites", "most-played"]);
+        let naming = NamingOptions {
+            extension: ArchiveExtension::Osz,
+            filename_format: FilenameFormat::Template(DEFAULT_FILENAME_FORMAT.to_string()),
+            dir_template: None,
+            mode_token_policy: ModeTokenPolicy::Dominant,
+        };
+        assert_eq!(
+            naming.filename(&merged[0], ArchiveExtension::Osz),
+            "434 M\u{fc}ller - Caf\u{e9}.osz"
+        );
+    }
+
+    #[test]
+    fn rejected_credentials() {
+        assert!(is_rejected(&response(401, "")));
+        assert!(is_rejected(&response(400, r#"{"error":"invalid_client"}"#)));
+        assert!(is_rejected(&OsuError::UpdateToken {
+            source: Box::new(response(401, ""))
+        }));
+    }
+
+    #[test]
+    fn other_errors_arent_rejected_credentials() {
+        assert!(!is_rejected(&response(500, "")));
+        assert!(!is_rejected(&OsuError::NotFound));
+        assert!(!is_unreachable(&response(401, "")));
+        assert!(!is_unreachable(&OsuError::NotFound));
+    }
+
+    #[tokio::test]
+    async fn rejected_clients_are_replaced_once() {
+        let current = RwLock::new(Arc::new(0));
+        // client 0 has a token the API no longer takes
+        let request = |client: Arc<u32>| async move {
+            if *client == 0 {
+                Err(response(401, ""))
+            } else {
+                Ok(*client)
+            }
+        };
+        let reconnects = std::sync::atomic::AtomicUsize::new(0);
+        let reconnect = || async {
+            reconnects.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(1)
+        };
+        assert_eq!(
+            retry_rejected(&current, request, reconnect).await.unwrap(),
+            1
+        );
+        assert_eq!(
+            retry_rejected(&current, request, reconnect).await.unwrap(),
+            1
+        );
+        assert_eq!(reconnects.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        let rejected = |_: Arc<u32>| async { Err::<u32, _>(response(401, "")) };
+        let result = retry_rejected(&current, rejected, reconnect).await;
+        assert!(matches!(result, Err(OsuError::Response { status, .. }) if status.as_u16() == 401));
+        assert_eq!(reconnects.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    /// an osu! API handing out `token-1`, `token-2`... and answering every page with its offset.
+    /// `token-1` expires at offset 4, every token is rejected for `users/0`. the requests are
+    /// logged as "<method> <path> <token>"
+    async fn mock_api(log: Arc<std::sync::Mutex<Vec<String>>>) -> std::net::SocketAddr {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let tokens = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let (log, tokens) = (log.clone(), tokens.clone());
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    // the whole request, a token request has a form after the head
+                    let head_end = loop {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                            break end + 4;
+                        }
+                    };
+                    let head = String::from_utf8_lossy(&request[..head_end]).into_owned();
+                    let length: usize = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("content-length:")
+                                .map(|v| v.trim().to_string())
+                        })
+                        .map_or(0, |v| v.parse().unwrap());
+                    while request.len() < head_end + length {
+                        let n = socket.read(&mut buf).await.unwrap();
+                        request.extend_from_slice(&buf[..n]);
+                    }
+
+                    let mut words = head.split_whitespace();
+                    let (method, target) = (
+                        words.next().unwrap().to_string(),
+                        words.next().unwrap().to_string(),
+                    );
+                    let token = head
+                        .lines()
+                        .find_map(|l| {
+                            l.to_ascii_lowercase()
+                                .strip_prefix("authorization: bearer ")
+                                .map(str::to_string)
+                        })
+                        .unwrap_or_default();
+                    log.lock()
+                        .unwrap()
+                        .push(format!("{} {} {}", method, target, token));
+
+                    let (status, body) = if target == "/oauth/token" {
+                        let n = tokens.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+                        (
+                            "200 OK",
+                            format!(r#"{{"access_token":"token-{}","expires_in":86400}}"#, n),
+                        )
+                    } else if target.contains("/users/0/")
+                        || (target.ends_with("offset=4") && token == "token-1")
+                    {
+                        (
+                            "401 Unauthorized",
+                            r#"{"authentication":"basic"}"#.to_string(),
+                        )
+                    } else {
+                        let offset = target.rsplit('=').next().unwrap();
+                        ("200 OK", format!(r#"[{{"offset":{}}}]"#, offset))
+                    };
+                    let response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+        addr
+    }
+
+    fn mock_rest(addr: std::net::SocketAddr) -> Rest {
+        Rest::new(
+            1,
+            "secret".to_string(),
+            &format!("http://{}/api/v2", addr),
+            &format!("http://{}/oauth/token", addr),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pages_continue_with_a_fresh_token() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let rest = mock_rest(mock_api(log.clone()).await);
+
+        for offset in 0..8 {
+            let query = [("limit", "1".to_string()), ("offset", offset.to_string())];
+            let page: Vec<serde_json::Value> = rest
+                .get("users/1/beatmapsets/favourite", &query)
+                .await
+                .unwrap();
+            assert_eq!(page[0]["offset"], offset);
+        }
+
+        let log = log.lock().unwrap();
+        let page = |offset, token| {
+            format!(
+                "GET /api/v2/users/1/beatmapsets/favourite?limit=1&offset={} {}",
+                offset, token
+            )
+        };
+        assert_eq!(
+            log.iter()
+                .filter(|r| r.starts_with("POST /oauth/token"))
+                .count(),
+            2
+        );
+        // page 5 is asked for again with the new token, and the pages after it use that one
+        let tail: Vec<&str> = log
+            .iter()
+            .skip_while(|r| **r != page(4, "token-1"))
+            .map(String::as_str)
+            .collect();
+        assert_eq!(
+            tail[..3],
+            [
+                page(4, "token-1"),
+                "POST /oauth/token ".to_string(),
+                page(4, "token-2")
+            ]
+        );
+        assert_eq!(tail.last().unwrap(), &page(7, "token-2"));
+    }
+
+    #[tokio::test]
+    async fn a_rejected_fresh_token_is_an_error() {
+        let log = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let rest = mock_rest(mock_api(log.clone()).await);
+
+        let result = rest
+            .get::<Vec<serde_json::Value>>("users/0/beatmapsets/favourite", &[])
+            .await;
+
+        assert!(result.unwrap_err().to_string().contains("401"));
+        let log = log.lock().unwrap();
+        assert_eq!(log.iter().filter(|r| r.starts_with("POST")).count(), 2);
+        assert_eq!(log.iter().filter(|r| r.starts_with("GET")).count(), 2);
+    }
+}
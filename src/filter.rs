@@ -0,0 +1,396 @@
+use anyhow::Result;
+use clap::Args;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::fetcher;
+use crate::naming;
+use crate::query::{Field, Query};
+use crate::types::{BeatmapInfo, Genre, Language};
+
+/// filters shared by the `download`, `all`, `list` and `export` commands
+#[derive(Args, Debug, Clone, Default)]
+pub struct FilterOptions {
+    /// only keep maps by this mapper (can be repeated)
+    #[arg(long, value_name = "NAME")]
+    pub creator: Vec<String>,
+    /// skip maps by this mapper (can be repeated)
+    #[arg(long, value_name = "NAME")]
+    pub exclude_creator: Vec<String>,
+    /// only keep maps at least this long (seconds or m:ss)
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    pub min_length: Option<u32>,
+    /// only keep maps at most this long (seconds or m:ss)
+    #[arg(long, value_name = "LENGTH", value_parser = parse_length)]
+    pub max_length: Option<u32>,
+    /// only keep maps with at least this BPM
+    #[arg(long)]
+    pub min_bpm: Option<f32>,
+    /// only keep maps with at most this BPM
+    #[arg(long)]
+    pub max_bpm: Option<f32>,
+    /// only keep maps of this mode (can be repeated)
+    #[arg(long, value_parser = ["osu", "taiko", "fruits", "mania"])]
+    pub mode: Vec<String>,
+    /// with --mode, also keep osu!standard maps played as converts in the mode (default)
+    #[arg(long, overrides_with = "no_converts")]
+    pub include_converts: bool,
+    /// with --mode, only keep maps made for the mode, not osu!standard maps played as converts
+    #[arg(long, overrides_with = "include_converts")]
+    pub no_converts: bool,
+    /// only keep maps of this genre (can be repeated)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub genre: Vec<Genre>,
+    /// only keep maps whose song is in this language (can be repeated)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub language: Vec<Language>,
+    /// only keep maps fetched from this source (can be repeated or separated by commas). lists
+    /// fetched with several sources tag every map with the ones it came from
+    #[arg(long, value_name = "SOURCE", value_delimiter = ',', value_parser = fetcher::SOURCE_NAMES)]
+    pub source_filter: Vec<String>,
+    /// keep the N most played sets of each artist, after the other filters. artists are
+    /// compared case-insensitively
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub limit_per_artist: Option<u32>,
+    /// only keep maps matching the expression in this file, e.g.
+    /// `play_count > 100 AND (artist CONTAINS "Camellia" OR stars >= 6)`
+    #[arg(long, value_name = "PATH")]
+    pub map_filter_file: Option<PathBuf>,
+    /// don't look up missing metadata, keep maps that lack it instead
+    #[arg(long)]
+    pub lenient: bool,
+}
+
+impl FilterOptions {
+    fn filters_by_creator(&self) -> bool {
+        !self.creator.is_empty() || !self.exclude_creator.is_empty()
+    }
+
+    fn filters_by_length(&self) -> bool {
+        self.min_length.is_some() || self.max_length.is_some()
+    }
+
+    fn filters_by_bpm(&self) -> bool {
+        self.min_bpm.is_some() || self.max_bpm.is_some()
+    }
+
+    /// whether a map lacks a field that one of the active filters or `query` needs
+    fn is_missing_fields(&self, map: &BeatmapInfo, query: Option<&[Field]>) -> bool {
+        query.is_some_and(|fields| fields.iter().any(|f| f.is_missing(map)))
+            || (self.filters_by_creator() && map.creator.is_none())
+            || (self.filters_by_length() && map.length_seconds.is_none())
+            || (self.filters_by_bpm() && map.bpm.is_none())
+            || (!self.mode.is_empty() && map.native_mode().is_none())
+            || (!self.genre.is_empty() && map.genre.is_none())
+            || (!self.language.is_empty() && map.language.is_none())
+    }
+}
+
+/// parse a length given either as plain seconds or as `m:ss`
+fn parse_length(s: &str) -> Result<u32, String> {
+    match s.split_once(':') {
+        Some((mins, secs)) => {
+            let mins: u32 = mins
+                .parse()
+                .map_err(|_| format!("invalid minutes in '{}'", s))?;
+            let secs: u32 = secs
+                .parse()
+                .map_err(|_| format!("invalid seconds in '{}'", s))?;
+            if secs >= 60 {
+                return Err(format!("seconds must be below 60 in '{}'", s));
+            }
+            Ok(mins * 60 + secs)
+        }
+        None => s
+            .parse()
+            .map_err(|_| format!("invalid length '{}', use seconds or m:ss", s)),
+    }
+}
+
+/// whether an optional value lies within the optional bounds, unknown values only pass when lenient
+fn in_range<T: PartialOrd>(
+    value: Option<T>,
+    min: Option<T>,
+    max: Option<T>,
+    lenient: bool,
+) -> bool {
+    match value {
+        Some(v) => min.is_none_or(|min| v >= min) && max.is_none_or(|max| v <= max),
+        None => lenient,
+    }
+}
+
+fn matches_any(value: &str, names: &[String]) -> bool {
+    let value = value.to_lowercase();
+    names.iter().any(|n| n.to_lowercase() == value)
+}
+
+/// the sets of `maps` among the `limit` most played of their artist. an artist counts once per
+/// set however many of its difficulties are listed, maps without an artist aren't limited
+fn within_artist_limit(maps: &[BeatmapInfo], limit: usize) -> HashSet<u32> {
+    let mut ranked: Vec<&BeatmapInfo> = maps.iter().collect();
+    ranked.sort_by_key(|m| std::cmp::Reverse(m.play_count));
+
+    let mut per_artist: HashMap<String, usize> = HashMap::new();
+    let mut kept = HashSet::new();
+    for map in ranked {
+        if kept.contains(&map.beatmapset_id) {
+            continue;
+        }
+        // the name the archive gets, so artists only told apart by characters a filename
+        // can't have count as one
+        let artist = naming::sanitize_filename(&map.artist).to_lowercase();
+        let count = per_artist.entry(artist.clone()).or_default();
+        if artist.is_empty() || *count < limit {
+            *count += 1;
+            kept.insert(map.beatmapset_id);
+        }
+    }
+    kept
+}
+
+/// warn about sources of `--source-filter` that the `lists` never included, which would
+/// quietly match nothing otherwise
+fn check_sources(wanted: &[String], lists: &[PathBuf]) -> Result<()> {
+    let mut included = BTreeSet::new();
+    let mut recorded = false;
+    for list in fetcher::input_files(lists)? {
+        match fetcher::recorded_sources(&list) {
+            Some(sources) => {
+                recorded = true;
+                included.extend(sources);
+            }
+            None => println!(
+                "Warning: {} doesn't record which sources it was fetched from, only maps tagged with a source can match --source-filter",
+                list.display()
+            ),
+        }
+    }
+    if !recorded {
+        return Ok(());
+    }
+    for source in wanted.iter().filter(|s| !included.contains(*s)) {
+        println!(
+            "Warning: no {} maps were ever fetched into the list, fetch with --source {} --merge to add them",
+            source, source
+        );
+    }
+    Ok(())
+}
+
+/// warn about the maps `filter` skips because they still lack its field after the lookup,
+/// e.g. from a list fetched before the field was stored or deleted from osu!
+fn warn_unknown(
+    maps: &[BeatmapInfo],
+    field: &str,
+    filter: &str,
+    lenient: bool,
+    known: impl Fn(&BeatmapInfo) -> bool,
+) {
+    let unknown = maps.iter().filter(|m| !known(m)).count();
+    if unknown > 0 && !lenient {
+        println!(
+            "Warning: {} maps have no {}, skipped by {}",
+            unknown, field, filter
+        );
+    }
+}
+
+/// apply the filters to the `maps` loaded from `lists`, looking up missing metadata first
+/// unless `--lenient` is set, and print how many maps each filter excluded
+pub async fn apply_filters(
+    mut maps: Vec<BeatmapInfo>,
+    opts: &FilterOptions,
+    lists: &[PathBuf],
+) -> Result<Vec<BeatmapInfo>> {
+    if !opts.source_filter.is_empty() {
+        check_sources(&opts.source_filter, lists)?;
+    }
+
+    // parsed first so a mistake in the file shows up before any lookups
+    let query = opts
+        .map_filter_file
+        .as_deref()
+        .map(Query::load)
+        .transpose()?;
+    let queried = query.as_ref().map(|q| q.fields());
+    let is_missing = |m: &BeatmapInfo| opts.is_missing_fields(m, queried.as_deref());
+
+    let missing = maps.iter().filter(|m| is_missing(m)).count();
+    if missing > 0 {
+        if opts.lenient {
+            println!(
+                "Warning: {} maps lack the metadata needed for filtering, keeping them",
+                missing
+            );
+        } else if let Err(e) =
+            fetcher::enrich_beatmaps(&mut maps, is_missing, fetcher::API_PACE).await
+        {
+            // e.g. no API credentials for an old list, the filters then skip those maps
+            println!(
+                "Warning: couldn't look up the metadata {} maps lack ({:#})",
+                missing, e
+            );
+        }
+    }
+
+    let mut excluded: Vec<(&str, usize)> = Vec::new();
+    let mut retain = |maps: &mut Vec<BeatmapInfo>, name, keep: &dyn Fn(&BeatmapInfo) -> bool| {
+        let before = maps.len();
+        maps.retain(|m| keep(m));
+        excluded.push((name, before - maps.len()));
+    };
+
+    if !opts.source_filter.is_empty() {
+        retain(&mut maps, "--source-filter", &|m| {
+            m.sources.iter().any(|s| opts.source_filter.contains(s))
+        });
+    }
+
+    if !opts.creator.is_empty() {
+        retain(&mut maps, "--creator", &|m| match &m.creator {
+            Some(creator) => matches_any(creator, &opts.creator),
+            None => opts.lenient,
+        });
+    }
+    if !opts.exclude_creator.is_empty() {
+        retain(&mut maps, "--exclude-creator", &|m| {
+            m.creator
+                .as_deref()
+                .is_none_or(|c| !matches_any(c, &opts.exclude_creator))
+        });
+    }
+
+    if opts.filters_by_length() {
+        warn_unknown(
+            &maps,
+            "length",
+            "--min-length/--max-length",
+            opts.lenient,
+            |m| m.length_seconds.is_some(),
+        );
+        retain(&mut maps, "--min-length/--max-length", &|m| {
+            in_range(
+                m.length_seconds,
+                opts.min_length,
+                opts.max_length,
+                opts.lenient,
+            )
+        });
+    }
+    if opts.filters_by_bpm() {
+        warn_unknown(&maps, "BPM", "--min-bpm/--max-bpm", opts.lenient, |m| {
+            m.bpm.is_some()
+        });
+        retain(&mut maps, "--min-bpm/--max-bpm", &|m| {
+            in_range(m.bpm, opts.min_bpm, opts.max_bpm, opts.lenient)
+        });
+    }
+
+    if !opts.mode.is_empty() {
+        retain(&mut maps, "--mode", &|m| {
+            let wanted = |mode: &str| opts.mode.iter().any(|w| w == mode);
+            match m.native_mode() {
+                Some(native) => {
+                    let convert = native == "osu" && m.played_mode.as_deref().is_some_and(wanted);
+                    wanted(native) || (convert && !opts.no_converts)
+                }
+                None => opts.lenient,
+            }
+        });
+    }
+
+    if !opts.genre.is_empty() {
+        retain(&mut maps, "--genre", &|m| {
+            m.genre.map_or(opts.lenient, |g| opts.genre.contains(&g))
+        });
+    }
+    if !opts.language.is_empty() {
+        retain(&mut maps, "--language", &|m| {
+            m.language
+                .map_or(opts.lenient, |l| opts.language.contains(&l))
+        });
+    }
+
+    if let Some(query) = &query {
+        let before = maps.len();
+        // with --lenient a map missing a field the expression needs is kept, fields only the
+        // other filters read don't count
+        let lacks_queried = |m: &BeatmapInfo| queried.iter().flatten().any(|f| f.is_missing(m));
+        maps.retain(|m| query.matches(m) || (opts.lenient && lacks_queried(m)));
+        println!("Matched by --map-filter-file: {} of {}", maps.len(), before);
+    }
+
+    // last, so the sets the cap keeps are ones the other filters let through
+    if let Some(limit) = opts.limit_per_artist {
+        let kept = within_artist_limit(&maps, limit as usize);
+        retain(&mut maps, "--limit-per-artist", &|m| {
+            kept.contains(&m.beatmapset_id)
+        });
+    }
+
+    for (name, count) in excluded {
+        println!("Excluded by {}: {}", name, count);
+    }
+
+    Ok(maps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    fn map(set: u32, bpm: Option<f32>, stars: Option<f32>) -> BeatmapInfo {
+        BeatmapInfo {
+            beatmapset_id: set,
+            bpm,
+            stars,
+            ..Default::default()
+        }
+    }
+
+    fn sets(maps: &[BeatmapInfo]) -> Vec<u32> {
+        maps.iter().map(|m| m.beatmapset_id).collect()
+    }
+
+    #[tokio::test]
+    async fn lenient_filters_only_keep_maps_missing_their_own_field() {
+        let dir = temp_dir("filter");
+        let query = dir.join("query.txt");
+        std::fs::write(&query, "stars >= 6").unwrap();
+
+        let opts = FilterOptions {
+            min_bpm: Some(180.0),
+            map_filter_file: Some(query),
+            lenient: true,
+            ..Default::default()
+        };
+        let maps = vec![
+            // no BPM: kept by --min-bpm, but the expression still reads stars
+            map(1, None, Some(4.0)),
+            map(2, None, Some(7.0)),
+            // no stars: kept by the expression, but its BPM is too low
+            map(3, Some(120.0), None),
+            map(4, Some(200.0), None),
+        ];
+        let kept = apply_filters(maps, &opts, &[]).await.unwrap();
+        assert_eq!(sets(&kept), [2, 4]);
+    }
+
+    #[test]
+    fn parses_lengths() {
+        assert_eq!(parse_length("3:05"), Ok(185));
+        assert_eq!(parse_length("90"), Ok(90));
+        assert!(parse_length("1:60").is_err());
+        assert!(parse_length("abc").is_err());
+    }
+
+    #[test]
+    fn range_of_unknown_values() {
+        assert!(in_range(Some(5), Some(1), Some(10), false));
+        assert!(!in_range(Some(11), Some(1), Some(10), false));
+        assert!(!in_range(None, Some(1), None, false));
+        assert!(in_range(None::<u32>, Some(1), None, true));
+    }
+}
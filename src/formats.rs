@@ -0,0 +1,314 @@
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+use crate::types::{BeatmapInfo, DifficultyInfo};
+
+/// the shapes of beatmap lists that are read, besides this tool's own. an example of each
+/// community format is in `fixtures/formats/`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// this tool's list, a JSON array of `BeatmapInfo`
+    Native,
+    /// beatmapset IDs, as a JSON array or one per line (`fetch --fetch-only-ids`)
+    Ids,
+    /// beatmapsets as the osu! API returns them, `{"beatmapsets": [...]}` or a bare array
+    /// (`fixtures/formats/beatmapsets.json`)
+    Beatmapsets,
+    /// beatmaps as the osu! API returns them, each with its `beatmapset`, as `{"beatmaps": [...]}`
+    /// or a bare array (`fixtures/formats/beatmaps.json`). entries of the API's most played list
+    /// (`{"beatmap_id", "count", "beatmap", "beatmapset"}`) are read the same way, with their
+    /// play count (`fixtures/formats/most-played.json`)
+    Beatmaps,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Native => "beatmap list",
+            Format::Ids => "list of beatmapset IDs",
+            Format::Beatmapsets => "list of osu! API beatmapsets",
+            Format::Beatmaps => "list of osu! API beatmaps",
+        }
+    }
+}
+
+/// a list read in whatever format it was, every entry mapped to a map or why it couldn't be
+pub struct Detected {
+    pub format: Format,
+    pub entries: Vec<Result<BeatmapInfo, String>>,
+}
+
+const SUPPORTED: &str = "this tool's beatmap list, beatmapset IDs (a JSON array or one per line), \
+    {\"beatmapsets\": [...]} or {\"beatmaps\": [...]} as the osu! API returns them, see \
+    fixtures/formats for examples";
+
+/// read a beatmap list, recognizing the format from its shape
+pub fn read(content: &str) -> Result<Detected> {
+    let value: Value = match serde_json::from_str(content) {
+        Ok(value) => value,
+        Err(e) => match parse_id_lines(content) {
+            Some(ids) => {
+                return Ok(Detected {
+                    format: Format::Ids,
+                    entries: ids.into_iter().map(from_id).collect(),
+                })
+            }
+            None => bail!(
+                "not JSON or a list of IDs ({}), supported are {}",
+                e,
+                SUPPORTED
+            ),
+        },
+    };
+
+    let (format, items) = match value {
+        Value::Array(items) => (detect_array(&items), items),
+        Value::Object(mut object) => {
+            match (object.remove("beatmapsets"), object.remove("beatmaps")) {
+                (Some(Value::Array(items)), _) => (Format::Beatmapsets, items),
+                (_, Some(Value::Array(items))) => (Format::Beatmaps, items),
+                _ => {
+                    let keys: Vec<&str> = object.keys().map(String::as_str).collect();
+                    bail!(
+                        "unknown JSON object with the keys {}, supported are {}",
+                        keys.join(", "),
+                        SUPPORTED
+                    )
+                }
+            }
+        }
+        other => bail!(
+            "unknown JSON {}, supported are {}",
+            type_name(&other),
+            SUPPORTED
+        ),
+    };
+
+    let entries = items
+        .into_iter()
+        .map(|item| match (format, item) {
+            (_, Value::Number(id)) => from_id(
+                id.as_u64()
+                    .ok_or_else(|| format!("invalid beatmapset_id {}", id))?,
+            ),
+            (Format::Beatmapsets, Value::Object(set)) => from_beatmapset(&set),
+            (Format::Beatmaps, Value::Object(map)) => from_beatmap(&map),
+            (_, item) => serde_json::from_value::<BeatmapInfo>(item).map_err(|e| e.to_string()),
+        })
+        .collect();
+    Ok(Detected { format, entries })
+}
+
+/// the format of a bare array, told apart by its first entry
+fn detect_array(items: &[Value]) -> Format {
+    match items.first() {
+        Some(Value::Number(_)) => Format::Ids,
+        Some(Value::Object(first))
+            if first.contains_key("beatmapset_id") && first.contains_key("title") =>
+        {
+            Format::Native
+        }
+        Some(Value::Object(first)) if first.contains_key("beatmapset") => Format::Beatmaps,
+        Some(Value::Object(first))
+            if first.contains_key("beatmaps") || first.contains_key("title") =>
+        {
+            Format::Beatmapsets
+        }
+        _ => Format::Native,
+    }
+}
+
+/// the IDs of a file with a beatmapset ID per line, `None` when any line is something else
+fn parse_id_lines(content: &str) -> Option<Vec<u64>> {
+    let lines: Vec<&str> = content
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    if lines.is_empty() {
+        return None;
+    }
+    lines.iter().map(|l| l.parse().ok()).collect()
+}
+
+fn from_id(id: u64) -> Result<BeatmapInfo, String> {
+    u32::try_from(id)
+        .map(BeatmapInfo::from_set_id)
+        .map_err(|_| format!("invalid beatmapset_id {}", id))
+}
+
+/// an osu! API beatmapset, named after its first difficulty
+fn from_beatmapset(set: &Map<String, Value>) -> Result<BeatmapInfo, String> {
+    let id = number(set, "id").ok_or("missing id")?;
+    let mut difficulties: Vec<DifficultyInfo> = set
+        .get("beatmaps")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.as_object().and_then(difficulty))
+        .collect();
+    difficulties.sort_by(|a, b| a.stars.total_cmp(&b.stars));
+
+    let mut map = BeatmapInfo::from_set_id(id);
+    map.title = text(set, "title").unwrap_or_default();
+    map.artist = text(set, "artist").unwrap_or_default();
+    map.creator = text(set, "creator");
+    map.status = text(set, "status");
+    if let Some(first) = difficulties.first() {
+        map.beatmap_id = first.beatmap_id;
+        map.version = first.version.clone();
+    }
+    map.difficulties = difficulties;
+    Ok(map)
+}
+
+/// an osu! API beatmap with its `beatmapset`, or an entry of the most played list
+fn from_beatmap(entry: &Map<String, Value>) -> Result<BeatmapInfo, String> {
+    // most played entries wrap the beatmap and carry the play count
+    let beatmap = entry
+        .get("beatmap")
+        .and_then(Value::as_object)
+        .unwrap_or(entry);
+    let set = entry
+        .get("beatmapset")
+        .or_else(|| beatmap.get("beatmapset"))
+        .and_then(Value::as_object);
+    let set_id = number(beatmap, "beatmapset_id")
+        .or_else(|| set.and_then(|s| number(s, "id")))
+        .ok_or("missing beatmapset_id")?;
+
+    let mut map = BeatmapInfo::from_set_id(set_id);
+    map.beatmap_id = number(beatmap, "id")
+        .or_else(|| number(entry, "beatmap_id"))
+        .unwrap_or(0);
+    map.version = text(beatmap, "version").unwrap_or_default();
+    map.play_count = number(entry, "count").unwrap_or(0);
+    map.stars = beatmap
+        .get("difficulty_rating")
+        .and_then(Value::as_f64)
+        .map(|s| s as f32);
+    map.mode = text(beatmap, "mode");
+    map.status = text(beatmap, "status");
+    map.length_seconds = number(beatmap, "total_length");
+    map.bpm = beatmap.get("bpm").and_then(Value::as_f64).map(|b| b as f32);
+    map.checksum = text(beatmap, "checksum");
+    if let Some(set) = set {
+        map.title = text(set, "title").unwrap_or_default();
+        map.artist = text(set, "artist").unwrap_or_default();
+        map.creator = text(set, "creator");
+    }
+    Ok(map)
+}
+
+fn difficulty(beatmap: &Map<String, Value>) -> Option<DifficultyInfo> {
+    Some(DifficultyInfo {
+        beatmap_id: number(beatmap, "id")?,
+        version: text(beatmap, "version").unwrap_or_default(),
+        stars: beatmap
+            .get("difficulty_rating")
+            .and_then(Value::as_f64)
+            .unwrap_or(0.0) as f32,
+        mode: text(beatmap, "mode").unwrap_or_default(),
+    })
+}
+
+fn number(object: &Map<String, Value>, key: &str) -> Option<u32> {
+    object.get(key)?.as_u64()?.try_into().ok()
+}
+
+fn text(object: &Map<String, Value>, key: &str) -> Option<String> {
+    object.get(key)?.as_str().map(str::to_string)
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn maps(detected: Detected) -> Vec<BeatmapInfo> {
+        detected.entries.into_iter().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn reads_api_beatmapsets() {
+        let detected = read(include_str!("../fixtures/formats/beatmapsets.json")).unwrap();
+        assert_eq!(detected.format, Format::Beatmapsets);
+        let maps = maps(detected);
+        assert_eq!(maps.len(), 2);
+        assert_eq!(maps[0].beatmapset_id, 1172819);
+        assert_eq!(maps[0].title, "Exit This Earth's Atomosphere");
+        assert_eq!(maps[0].creator.as_deref(), Some("ProfessionalBox"));
+        // named after the easiest difficulty
+        assert_eq!(maps[0].beatmap_id, 2449133);
+        assert_eq!(maps[0].version, "Hard");
+        assert_eq!(maps[0].difficulties.len(), 2);
+        assert_eq!(maps[1].beatmapset_id, 39804);
+    }
+
+    #[test]
+    fn reads_api_beatmaps() {
+        let detected = read(include_str!("../fixtures/formats/beatmaps.json")).unwrap();
+        assert_eq!(detected.format, Format::Beatmaps);
+        let maps = maps(detected);
+        assert_eq!(maps[0].beatmapset_id, 39804);
+        assert_eq!(maps[0].beatmap_id, 129891);
+        assert_eq!(maps[0].title, "FREEDOM DiVE");
+        assert_eq!(maps[0].artist, "xi");
+        assert_eq!(maps[0].version, "FOUR DIMENSIONS");
+        assert_eq!(maps[0].stars, Some(7.06));
+        assert_eq!(maps[0].length_seconds, Some(257));
+        assert_eq!(
+            maps[0].checksum.as_deref(),
+            Some("da8aae79c8f3306b5d65ec951874a7fb")
+        );
+    }
+
+    #[test]
+    fn reads_most_played_entries() {
+        let detected = read(include_str!("../fixtures/formats/most-played.json")).unwrap();
+        assert_eq!(detected.format, Format::Beatmaps);
+        let maps = maps(detected);
+        assert_eq!(maps[0].beatmapset_id, 39804);
+        assert_eq!(maps[0].beatmap_id, 129891);
+        assert_eq!(maps[0].play_count, 412);
+        assert_eq!(maps[0].title, "FREEDOM DiVE");
+    }
+
+    #[test]
+    fn reads_ids_and_native_lists() {
+        assert_eq!(read("[1, 2]").unwrap().format, Format::Ids);
+        let lines = read("39804\n\n1172819\n").unwrap();
+        assert_eq!(lines.format, Format::Ids);
+        assert_eq!(
+            maps(lines)
+                .iter()
+                .map(|m| m.beatmapset_id)
+                .collect::<Vec<_>>(),
+            [39804, 1172819]
+        );
+        let native = r#"[{"beatmapset_id": 39804, "title": "FREEDOM DiVE", "artist": "xi", "version": "FOUR DIMENSIONS"}]"#;
+        assert_eq!(read(native).unwrap().format, Format::Native);
+    }
+
+    #[test]
+    fn names_the_keys_of_unknown_objects() {
+        let error = read(r#"{"maps": [], "user": 2}"#)
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("keys maps, user"), "{}", error);
+        assert!(error.contains("fixtures/formats"), "{}", error);
+        let error = read("\"text\"").err().unwrap().to_string();
+        assert!(error.starts_with("unknown JSON string"), "{}", error);
+    }
+}
@@ -0,0 +1,146 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use rand::seq::SliceRandom;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::downloader::parse_duration;
+use crate::types::BeatmapInfo;
+use crate::verify;
+
+/// checking the existing archives before downloading and replacing the broken ones
+#[derive(Args, Debug, Clone, Default)]
+pub struct HealOptions {
+    /// check the archives already there of the sets in the list before downloading, broken ones
+    /// are moved to .quarantine in the output directory and downloaded again
+    #[arg(long)]
+    pub heal: bool,
+    /// with --heal, check at most this many archives, picked at random so runs after each
+    /// other cover different ones
+    #[arg(long, value_name = "N", requires = "heal")]
+    pub heal_sample: Option<usize>,
+    /// with --heal, stop checking after this long, e.g. 2m
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration, requires = "heal")]
+    pub heal_budget: Option<Duration>,
+}
+
+/// check the archives of `maps` in `output_dir` within the sample and time budget, and move the
+/// ones that fail to the quarantine. returns the IDs of their sets
+pub fn heal(maps: &[BeatmapInfo], output_dir: &Path, opts: &HealOptions) -> Result<HashSet<u32>> {
+    let listed: HashSet<u32> = maps.iter().map(|m| m.beatmapset_id).collect();
+    let mut archives: Vec<(u32, PathBuf)> = verify::list_archives(output_dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let id = path
+                .file_name()?
+                .to_str()
+                .and_then(verify::parse_mapset_id)?;
+            listed.contains(&id).then_some((id, path))
+        })
+        .collect();
+    archives.shuffle(&mut rand::thread_rng());
+    if let Some(sample) = opts.heal_sample {
+        archives.truncate(sample);
+    }
+
+    println!("Checking {} existing archives...", archives.len());
+    let started = Instant::now();
+    let mut broken: HashMap<u32, Vec<PathBuf>> = HashMap::new();
+    for (checked, (id, path)) in archives.into_iter().enumerate() {
+        if opts
+            .heal_budget
+            .is_some_and(|budget| started.elapsed() > budget)
+        {
+            println!("Stopped after {} archives, --heal-budget ran out", checked);
+            break;
+        }
+        if let Err(e) = verify::check_header(&path) {
+            println!("Broken, will re-download: {} ({:#})", path.display(), e);
+            broken.entry(id).or_default().push(path);
+        }
+    }
+
+    if !broken.is_empty() {
+        let quarantine = output_dir.join(verify::QUARANTINE_DIR);
+        for path in broken.values().flatten() {
+            // under the same path as in the output directory, so archives of the same name in
+            // different folders don't replace each other
+            let relative = path
+                .strip_prefix(output_dir)
+                .context("archive outside the output directory")?;
+            let moved = quarantine.join(relative);
+            if let Some(parent) = moved.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(path, &moved).with_context(|| {
+                format!("Failed to move {} to {}", path.display(), moved.display())
+            })?;
+        }
+        println!(
+            "Moved {} broken archives to {}",
+            broken.values().map(Vec::len).sum::<usize>(),
+            quarantine.display()
+        );
+    }
+    println!();
+    Ok(broken.into_keys().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+    use std::io::Write;
+
+    fn archive(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        zip.start_file("map.osu", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"osu file format v14").unwrap();
+        zip.finish().unwrap();
+    }
+
+    fn broken(path: &Path) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, b"<html>rate limited</html>").unwrap();
+    }
+
+    #[test]
+    fn quarantines_under_the_same_path() {
+        let dir = temp_dir("heal");
+        archive(&dir.join("100 Fine.osz"));
+        broken(&dir.join("ranked/200 Same.osz"));
+        broken(&dir.join("loved/200 Same.osz"));
+        let maps: Vec<BeatmapInfo> = [100, 200]
+            .map(|id| BeatmapInfo {
+                beatmapset_id: id,
+                ..Default::default()
+            })
+            .into();
+
+        let healed = heal(
+            &maps,
+            &dir,
+            &HealOptions {
+                heal: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert_eq!(healed, HashSet::from([200]));
+        assert!(dir.join("100 Fine.osz").exists());
+        let quarantine = dir.join(verify::QUARANTINE_DIR);
+        assert!(quarantine.join("ranked/200 Same.osz").exists());
+        assert!(quarantine.join("loved/200 Same.osz").exists());
+        // the quarantine isn't scanned again
+        assert_eq!(
+            verify::list_archives(&dir).unwrap(),
+            [dir.join("100 Fine.osz")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use rusqlite::{params, params_from_iter, Connection, ToSql};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::settings;
+use crate::types::BeatmapInfo;
+use crate::units;
+
+/// database in the output directory that `download --index` records every archive in
+pub const INDEX_FILE: &str = "index.sqlite";
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS maps (
+        beatmapset_id INTEGER PRIMARY KEY,
+        beatmap_id INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        creator TEXT,
+        mode TEXT,
+        stars REAL,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        filename TEXT NOT NULL,
+        downloaded_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS maps_artist ON maps (artist COLLATE NOCASE);
+    CREATE INDEX IF NOT EXISTS maps_stars ON maps (stars);
+";
+
+fn open(dir: &Path) -> Result<Connection> {
+    let path = dir.join(INDEX_FILE);
+    let conn =
+        Connection::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    // downloads finishing at the same time take turns instead of failing
+    conn.busy_timeout(Duration::from_secs(10))?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+/// the index of an output directory, with one connection for the downloads of a run. the
+/// queries run on the blocking pool rather than the downloads' tasks
+#[derive(Clone)]
+pub struct Index {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Index {
+    pub async fn open(dir: &Path) -> Result<Self> {
+        let dir = dir.to_path_buf();
+        let conn = tokio::task::spawn_blocking(move || open(&dir)).await??;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// record the archive of `beatmap` at `filename`, `size` bytes with the SHA-256 `sha256`,
+    /// replacing an earlier record of its set
+    pub async fn record(
+        &self,
+        beatmap: &BeatmapInfo,
+        filename: &str,
+        size: u64,
+        sha256: String,
+    ) -> Result<()> {
+        let conn = Arc::clone(&self.conn);
+        let beatmap = beatmap.clone();
+        let filename = filename.to_string();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        tokio::task::spawn_blocking(move || {
+            conn.lock().unwrap().execute(
+                "INSERT OR REPLACE INTO maps
+                    (beatmapset_id, beatmap_id, title, artist, creator, mode, stars, size, sha256, filename, downloaded_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                params![
+                    beatmap.beatmapset_id,
+                    beatmap.beatmap_id,
+                    beatmap.title,
+                    beatmap.artist,
+                    beatmap.creator,
+                    beatmap.native_mode(),
+                    // as shown, so --max-stars 5.2 takes a 5.2★ map that's 5.1999998 as an f64
+                    beatmap.stars.map(|s| (s as f64 * 100.0).round() / 100.0),
+                    size,
+                    sha256,
+                    filename,
+                    now
+                ],
+            )
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+/// point the records of renamed archives at their new names, when there's an index
+pub fn rename(dir: &Path, renames: &[(String, String)]) -> Result<()> {
+    if renames.is_empty() || !dir.join(INDEX_FILE).exists() {
+        return Ok(());
+    }
+    let mut conn = open(dir)?;
+    let tx = conn.transaction()?;
+    for (old, new) in renames {
+        tx.execute(
+            "UPDATE maps SET filename = ?1 WHERE filename = ?2",
+            params![new, old],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// what `query` looks for, every given condition has to match
+#[derive(Args, Debug, Clone, Default)]
+pub struct IndexQuery {
+    /// artist containing this, case-insensitively
+    #[arg(long)]
+    pub artist: Option<String>,
+    /// title containing this, case-insensitively
+    #[arg(long)]
+    pub title: Option<String>,
+    /// mapper containing this, case-insensitively
+    #[arg(long)]
+    pub creator: Option<String>,
+    /// only maps of this mode
+    #[arg(long, value_parser = ["osu", "taiko", "fruits", "mania"])]
+    pub mode: Option<String>,
+    /// only maps with at least this star rating
+    #[arg(long)]
+    pub min_stars: Option<f64>,
+    /// only maps with at most this star rating
+    #[arg(long)]
+    pub max_stars: Option<f64>,
+    /// show at most this many maps
+    #[arg(long, value_name = "N")]
+    pub limit: Option<u32>,
+}
+
+/// a record of the index
+pub struct Entry {
+    pub beatmapset_id: u32,
+    pub title: String,
+    pub artist: String,
+    pub mode: Option<String>,
+    pub stars: Option<f64>,
+    pub size: u64,
+    pub filename: String,
+    /// when it was downloaded, as `YYYY-MM-DD HH:MM:SS` in UTC
+    pub downloaded_at: String,
+}
+
+/// the records matching `query`, by artist and title
+pub fn search(dir: &Path, query: &IndexQuery) -> Result<Vec<Entry>> {
+    let mut conditions = Vec::new();
+    let mut values: Vec<Box<dyn ToSql>> = Vec::new();
+    for (column, text) in [
+        ("artist", &query.artist),
+        ("title", &query.title),
+        ("creator", &query.creator),
+    ] {
+        if let Some(text) = text {
+            // instr rather than LIKE so % and _ match themselves. like LIKE, sqlite's lower
+            // only folds ASCII letters
+            conditions.push(format!("instr(lower({}), lower(?)) > 0", column));
+            values.push(Box::new(text.clone()));
+        }
+    }
+    if let Some(mode) = &query.mode {
+        conditions.push("mode = ?".to_string());
+        values.push(Box::new(mode.clone()));
+    }
+    if let Some(min) = query.min_stars {
+        conditions.push("stars >= ?".to_string());
+        values.push(Box::new(min));
+    }
+    if let Some(max) = query.max_stars {
+        conditions.push("stars <= ?".to_string());
+        values.push(Box::new(max));
+    }
+
+    let mut sql = "SELECT beatmapset_id, title, artist, mode, stars, size, filename, \
+                   datetime(downloaded_at, 'unixepoch') FROM maps"
+        .to_string();
+    if !conditions.is_empty() {
+        sql += &format!(" WHERE {}", conditions.join(" AND "));
+    }
+    sql += " ORDER BY artist COLLATE NOCASE, title COLLATE NOCASE";
+    if let Some(limit) = query.limit {
+        sql += &format!(" LIMIT {}", limit);
+    }
+
+    let conn = open_existing(dir)?;
+    let mut statement = conn.prepare(&sql)?;
+    let rows = statement.query_map(params_from_iter(values.iter()), |row| {
+        Ok(Entry {
+            beatmapset_id: row.get(0)?,
+            title: row.get(1)?,
+            artist: row.get(2)?,
+            mode: row.get(3)?,
+            stars: row.get(4)?,
+            size: row.get(5)?,
+            filename: row.get(6)?,
+            downloaded_at: row.get(7)?,
+        })
+    })?;
+    Ok(rows.collect::<Result<_, _>>()?)
+}
+
+fn open_existing(dir: &Path) -> Result<Connection> {
+    if !dir.join(INDEX_FILE).exists() {
+        anyhow::bail!(
+            "no {} in {}, download with --index to create it",
+            INDEX_FILE,
+            dir.display()
+        );
+    }
+    open(dir)
+}
+
+/// list the entries, one per line
+pub fn print(entries: &[Entry]) {
+    let units = settings::get().units;
+    for entry in entries {
+        let stars = entry
+            .stars
+            .map(|s| format!("{:.2}★", s))
+            .unwrap_or_else(|| "?".to_string());
+        println!(
+            "{:>8}  {:<6} {:>6}  {} - {}  {:>10}  {}  {}",
+            entry.beatmapset_id,
+            entry.mode.as_deref().unwrap_or("?"),
+            stars,
+            entry.artist,
+            entry.title,
+            units::bytes(entry.size, units),
+            entry.downloaded_at,
+            entry.filename
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[tokio::test]
+    async fn records_and_finds_archives() {
+        let dir = temp_dir("index");
+
+        let index = Index::open(&dir).await.unwrap();
+        let map = |set: u32, artist: &str, stars: f32| BeatmapInfo {
+            beatmapset_id: set,
+            artist: artist.to_string(),
+            stars: Some(stars),
+            ..Default::default()
+        };
+        index
+            .record(&map(1, "Camellia", 6.5), "1 a.osz", 10, "aa".to_string())
+            .await
+            .unwrap();
+        index
+            .record(&map(2, "xi", 4.0), "2 b.osz", 20, "bb".to_string())
+            .await
+            .unwrap();
+        // a set downloaded again replaces its record
+        index
+            .record(&map(1, "Camellia", 6.5), "1 c.osz", 30, "cc".to_string())
+            .await
+            .unwrap();
+
+        let all = search(&dir, &IndexQuery::default()).unwrap();
+        assert_eq!(all.len(), 2);
+        let found = search(
+            &dir,
+            &IndexQuery {
+                artist: Some("camel".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].filename, "1 c.osz");
+        assert_eq!(found[0].size, 30);
+        let hard = search(
+            &dir,
+            &IndexQuery {
+                min_stars: Some(5.0),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(hard.len(), 1);
+    }
+}
@@ -0,0 +1,82 @@
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::persist;
+use crate::settings;
+
+// fast enough for appending a frame per download, still far smaller than plain text
+const LEVEL: i32 = 3;
+
+/// whether `path` is a zstd compressed state file, by its `.zst` extension
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|e| e.eq_ignore_ascii_case("zst"))
+}
+
+/// where a state file normally at `plain` (e.g. `http_validators.jsonl`) is kept: with `.zst`
+/// added when COMPRESS_STATE is set. a file already there in the other format keeps being used
+/// until `state compact` converts it
+pub fn state_path(plain: PathBuf) -> PathBuf {
+    let compressed = compressed_path(&plain);
+    let (preferred, other) = if settings::get().compress_state {
+        (compressed, plain)
+    } else {
+        (plain, compressed)
+    };
+    if !preferred.exists() && other.exists() {
+        other
+    } else {
+        preferred
+    }
+}
+
+/// `plain` with `.zst` added
+pub fn compressed_path(plain: &Path) -> PathBuf {
+    let mut name = plain.as_os_str().to_os_string();
+    name.push(".zst");
+    PathBuf::from(name)
+}
+
+/// the lines of a state file, decompressed when it's a `.zst`. a frame cut short by a crash is
+/// dropped like a line cut short in a plain file
+pub fn read(path: &Path) -> std::io::Result<String> {
+    if !is_compressed(path) {
+        return fs::read_to_string(path);
+    }
+    // the decoder reads every frame of the file, one per append
+    let mut decoder = zstd::Decoder::new(File::open(path)?)?;
+    let mut bytes = Vec::new();
+    if decoder.read_to_end(&mut bytes).is_err() {
+        // keep the complete lines decoded before the broken frame
+        let end = bytes.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+        bytes.truncate(end);
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// append `line` and a newline to the state file, as a frame of its own when it's a `.zst`
+pub fn append(path: &Path, line: &str) -> Result<()> {
+    let mut bytes = format!("{}\n", line).into_bytes();
+    if is_compressed(path) {
+        bytes = zstd::encode_all(bytes.as_slice(), LEVEL)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    // a single write so concurrent appends don't interleave
+    file.write_all(&bytes)?;
+    Ok(())
+}
+
+/// replace the state file with `lines`, as a single frame when it's a `.zst`
+pub fn write(path: &Path, lines: &[String]) -> Result<()> {
+    let mut bytes: Vec<u8> = lines
+        .iter()
+        .flat_map(|l| [l.as_bytes(), b"\n"].concat())
+        .collect();
+    if is_compressed(path) {
+        bytes = zstd::encode_all(bytes.as_slice(), LEVEL)?;
+    }
+    persist::write_atomic(path, &bytes)
+        .with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,144 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// how long the listener waits for a line before checking whether it was stopped
+const POLL: Duration = Duration::from_millis(100);
+
+/// commands that can be given while downloads are running
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    Pause,
+    Resume,
+    Status,
+    /// one more download at a time, SIGUSR1
+    MoreConcurrency,
+    /// one download fewer at a time, SIGUSR2
+    LessConcurrency,
+}
+
+/// the lines typed on stdin. one thread reads them for the rest of the run, locking stdin for
+/// one line at a time, so the listener and the prompts take turns instead of one of them
+/// holding stdin until EOF
+fn lines() -> &'static Mutex<Receiver<String>> {
+    static LINES: OnceLock<Mutex<Receiver<String>>> = OnceLock::new();
+    LINES.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        // a plain thread rather than a blocking task, so a pending read never holds up shutdown
+        std::thread::spawn(move || loop {
+            let mut line = String::new();
+            match std::io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if tx.send(line).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        Mutex::new(rx)
+    })
+}
+
+/// the next line typed on stdin, None at the end of input
+pub fn read_line() -> Option<String> {
+    lines().lock().unwrap().recv().ok()
+}
+
+/// the commands of a download run, stopped when the downloads are done so that prompts after
+/// them (e.g. --dedupe-output) get what's typed next
+pub struct Listener {
+    pub commands: mpsc::UnboundedReceiver<Command>,
+    /// whether stdin is read, it isn't when it's not a terminal so piped input is left alone
+    pub interactive: bool,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// stop reading commands from stdin, returning once the listener took its last line
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// receive commands typed on stdin, one letter per line, and on unix the concurrency signals
+pub fn listen() -> Listener {
+    let (tx, commands) = mpsc::unbounded_channel();
+    listen_signals(tx.clone());
+    let stop = Arc::new(AtomicBool::new(false));
+    if !std::io::stdin().is_terminal() {
+        return Listener {
+            commands,
+            interactive: false,
+            stop,
+            thread: None,
+        };
+    }
+
+    let stopped = Arc::clone(&stop);
+    let thread = std::thread::spawn(move || {
+        while !stopped.load(Ordering::SeqCst) {
+            // only lock the lines while waiting for one, so a stop is noticed within POLL
+            let line = lines().lock().unwrap().recv_timeout(POLL);
+            let line = match line {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let command = match line.trim().to_ascii_lowercase().as_str() {
+                "p" => Command::Pause,
+                "r" => Command::Resume,
+                "s" => Command::Status,
+                _ => continue,
+            };
+            if tx.send(command).is_err() {
+                break;
+            }
+        }
+    });
+    Listener {
+        commands,
+        interactive: true,
+        stop,
+        thread: Some(thread),
+    }
+}
+
+#[cfg(unix)]
+fn listen_signals(tx: mpsc::UnboundedSender<Command>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    for (kind, command) in [
+        (SignalKind::user_defined1(), Command::MoreConcurrency),
+        (SignalKind::user_defined2(), Command::LessConcurrency),
+    ] {
+        let Ok(mut signals) = signal(kind) else {
+            continue;
+        };
+        let tx = tx.clone();
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                if tx.send(command).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+fn listen_signals(_tx: mpsc::UnboundedSender<Command>) {}
@@ -0,0 +1,134 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use crate::persist;
+use crate::types::ArchiveExtension;
+use crate::verify;
+
+// keeps track of what was already handed to lazer, inside the beatmap directory
+const IMPORTED_FILE: &str = ".lazer_imported";
+
+/// default osu!lazer data directory for the current platform
+pub fn default_data_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME").map(PathBuf::from);
+    if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(|p| PathBuf::from(p).join("osu"))
+    } else if cfg!(target_os = "macos") {
+        home.map(|h| h.join("Library/Application Support/osu"))
+    } else {
+        std::env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home.map(|h| h.join(".local/share")))
+            .map(|p| p.join("osu"))
+    }
+}
+
+/// hand an archive to whatever the OS has registered for `.osz` files (lazer, once installed)
+fn open_with_os(path: &Path) -> Result<()> {
+    let status = if cfg!(target_os = "windows") {
+        Command::new("cmd")
+            .arg("/C")
+            .arg("start")
+            .arg("")
+            .arg(path)
+            .status()
+    } else if cfg!(target_os = "macos") {
+        Command::new("open").arg(path).status()
+    } else {
+        Command::new("xdg-open").arg(path).status()
+    }
+    .context("Failed to run the OS file handler")?;
+
+    if !status.success() {
+        bail!("file handler exited with {}", status);
+    }
+    Ok(())
+}
+
+fn load_imported(dir: &Path) -> BTreeSet<u32> {
+    std::fs::read_to_string(dir.join(IMPORTED_FILE))
+        .map(|s| s.lines().filter_map(|l| l.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+fn save_imported(dir: &Path, imported: &BTreeSet<u32>) -> Result<()> {
+    let contents: String = imported.iter().map(|id| format!("{}\n", id)).collect();
+    persist::write_atomic(&dir.join(IMPORTED_FILE), contents.as_bytes())
+}
+
+/// open every valid archive in `dir` with osu!lazer, `batch` files at a time with a pause in
+/// between so the client can keep up, skipping the ones imported by an earlier run
+pub async fn import(
+    dir: &Path,
+    lazer_data: Option<PathBuf>,
+    batch: usize,
+    pause: Duration,
+) -> Result<()> {
+    match lazer_data.or_else(default_data_dir) {
+        Some(data_dir) if data_dir.is_dir() => {
+            println!("Found osu!lazer data at {}", data_dir.display())
+        }
+        Some(data_dir) => println!(
+            "Warning: no osu!lazer data at {}, make sure lazer is installed and handles .osz files",
+            data_dir.display()
+        ),
+        None => println!("Warning: couldn't determine the osu!lazer data directory"),
+    }
+
+    let mut imported = load_imported(dir);
+    let mut pending: Vec<(u32, PathBuf)> = verify::list_archives(dir)?
+        .into_iter()
+        .filter_map(|p| {
+            let id = p.file_name()?.to_str().and_then(verify::parse_mapset_id)?;
+            Some((id, p))
+        })
+        .filter(|(id, _)| !imported.contains(id))
+        .collect();
+    pending.sort();
+
+    println!("Already imported:  {}", imported.len());
+    println!("To import:         {}\n", pending.len());
+
+    let total = pending.len();
+    // only this run's, `imported` holds the earlier runs' as well
+    let mut opened = 0;
+    for (i, chunk) in pending.chunks(batch.max(1)).enumerate() {
+        for (id, path) in chunk {
+            if ArchiveExtension::of(path).is_some_and(|ext| !ext.is_zip()) {
+                println!(
+                    "Skipping {}, osu!lazer can't import .osz2 packages",
+                    path.display()
+                );
+                continue;
+            }
+            if let Err(e) = verify::verify_archive(path) {
+                println!("Skipping corrupt archive {} ({:#})", path.display(), e);
+                continue;
+            }
+            match open_with_os(path) {
+                Ok(_) => {
+                    imported.insert(*id);
+                    opened += 1;
+                }
+                Err(e) => println!("Failed to open {}: {:#}", path.display(), e),
+            }
+        }
+        save_imported(dir, &imported)?;
+
+        // the last batch may be smaller
+        let done = ((i + 1) * batch.max(1)).min(total);
+        if done < total {
+            println!(
+                "Went through {}/{}, pausing for {:?}...",
+                done, total, pause
+            );
+            tokio::time::sleep(pause).await;
+        }
+    }
+
+    println!("\nDone! {} archives handed to osu!lazer", opened);
+    Ok(())
+}
@@ -1,106 +1,1001 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 use std::io::Write;
 use std::path::PathBuf;
+use std::process::ExitCode;
 
+use checkpoint::Checkpoint;
+use downloader::{DownloadOptions, DownloadReport};
+use exit::Failure;
+use fetcher::Source;
+use filter::FilterOptions;
+use naming::NamingOptions;
+use types::BeatmapInfo;
+use units::Units;
+
+mod checkpoint;
+mod checksum;
+mod concurrency;
+mod dedupe;
+mod doctor;
 mod downloader;
+mod enrich;
+mod eta;
+mod exit;
+mod export;
+mod failures;
 mod fetcher;
+mod filter;
+mod formats;
+mod heal;
+mod index;
+mod jsonl;
+mod keyboard;
+mod lazer;
+mod mirror;
+mod naming;
+mod notify;
+mod osudb;
+mod persist;
+mod pins;
+mod plan;
+mod playcount;
+mod progress;
+mod query;
+mod realm;
+mod reliability;
+mod rename;
+mod replay;
+mod retention;
+#[cfg(feature = "web")]
+mod serve;
+mod session;
+mod settings;
+mod setup;
+mod stats;
+mod status;
+mod tagging;
+#[cfg(test)]
+mod testutil;
+mod top;
 mod types;
+mod units;
+mod validators;
+mod verify;
+mod webhook;
 
 #[derive(Parser)]
 #[command(name = "osu-beatmap-backup")]
 #[command(about = "Fetch and download your osu! most played beatmaps", long_about = None)]
 struct Cli {
     #[command(subcommand)]
-    command: Commands,
+    command: Option<Commands>,
+    /// show sizes in decimal units (kB, MB, GB) instead of binary ones (KiB, MiB, GiB)
+    #[arg(long, global = true)]
+    si_units: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
+    /// save your osu! API credentials to .env, asking for each of them
+    Init,
     /// fetch most played beatmaps from the osu! API
     Fetch {
         /// output JSON file path
         #[arg(short, long, default_value = "osu_most_played_maps.json")]
         output: PathBuf,
+        /// which beatmaps to fetch, several separated by commas are merged into one list
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "most-played")]
+        source: Vec<Source>,
+        /// merge the fetched maps into the list saved at the output instead of replacing it,
+        /// a set fetched again keeps the sources it was fetched from before. always done for
+        /// --source recent
+        #[arg(long, conflicts_with = "split_by_mode")]
+        merge: bool,
+        #[command(flatten)]
+        recent: fetcher::RecentOptions,
+        /// fetch the maps the .osr replays in this directory were set on, instead of a source
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["source", "from_collection"])]
+        from_replays: Option<PathBuf>,
+        /// fetch the maps of a collection in osu!stable's collection.db, instead of a source
+        #[arg(long, value_name = "PATH", conflicts_with = "source")]
+        from_collection: Option<PathBuf>,
+        /// with --from-collection, the name of the collection (lists them when left out)
+        #[arg(long, requires = "from_collection")]
+        collection: Option<String>,
+        /// when the API fails partway through, save the maps fetched so far instead of nothing
+        #[arg(long)]
+        continue_on_partial_fetch: bool,
+        /// continue a fetch that crashed or failed after the last page it saved, from
+        /// <output>.checkpoint.jsonl
+        #[arg(long, conflicts_with_all = ["from_replays", "from_collection"])]
+        resume: bool,
+        /// also store every difficulty of each set with its star rating, looking up the sets
+        /// the API didn't send them for (one request per set)
+        #[arg(long)]
+        difficulties: bool,
+        /// before saving, show the maps whose play count grew the most since the saved list and
+        /// the ones that are new in it
+        #[arg(long)]
+        check_updates: bool,
+        /// only write the beatmapset IDs, without duplicates: a JSON array when the output ends
+        /// in .json, one per line otherwise
+        #[arg(long, conflicts_with = "difficulties")]
+        fetch_only_ids: bool,
+        /// write a list per game mode next to the output instead of one, e.g.
+        /// osu_most_played_maps_mania.json. maps without a mode are looked up first, those the
+        /// API doesn't know go to <output>_unknown.json
+        #[arg(long)]
+        split_by_mode: bool,
+        /// also add the sets of your best and recent scores from the last --deep-window, to
+        /// find maps the most played list no longer reaches. the osu! API only returns your
+        /// top 200 scores and the recent ones of the last 24 hours, older maps without a top
+        /// score stay out of reach. play counts aren't known for these, the entries are tagged
+        /// with the "deep" source
+        #[arg(long, conflicts_with_all = ["from_replays", "from_collection"])]
+        deep: bool,
+        /// with --deep, how far back scores count, e.g. 365d
+        #[arg(long, value_name = "DURATION", default_value = "365d", value_parser = downloader::parse_duration, requires = "deep")]
+        deep_window: std::time::Duration,
+        #[command(flatten)]
+        shrink: fetcher::ShrinkOptions,
     },
     /// download beatmaps from the JSON file
     Download {
+        /// input JSON file path, or a directory of them (can be repeated, the lists are merged)
+        #[arg(short, long, default_value = "osu_most_played_maps.json", num_args = 1..)]
+        input: Vec<PathBuf>,
+        /// output directory for beatmaps
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// download exactly what a plan file made by `plan` lists, instead of the input file
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["input", "FilterOptions"])]
+        plan: Option<PathBuf>,
+        /// with --plan, scan the output directory and download the planned maps still missing,
+        /// instead of refusing when some are already there
+        #[arg(long, requires = "plan")]
+        replan: bool,
+        /// write the download URLs of the missing maps to this file instead of downloading them
+        #[arg(long, value_name = "PATH", conflicts_with = "plan")]
+        export_urls: Option<PathBuf>,
+        /// format of the --export-urls file
+        #[arg(long, value_enum, default_value_t = export::UrlFormat::Plain, requires = "export_urls")]
+        url_format: export::UrlFormat,
+        /// only download the sets whose ranked status changed to one of these since the
+        /// previous list, e.g. ranked,loved, replacing the archives already there
+        #[arg(long, value_name = "STATUS", value_delimiter = ',', value_parser = status::STATUSES, conflicts_with_all = ["plan", "export_urls"])]
+        only_status_changed: Vec<String>,
+        /// with --only-status-changed, the list to compare with (the <input>.bak that fetch
+        /// keeps of the list it replaced by default)
+        #[arg(long, value_name = "PATH", requires = "only_status_changed")]
+        previous: Option<PathBuf>,
+        #[command(flatten)]
+        opts: DownloadOptions,
+        #[command(flatten)]
+        filters: FilterOptions,
+    },
+    /// work out what `download` would do and save it as a plan to run later or elsewhere
+    Plan {
         /// input JSON file path
         #[arg(short, long, default_value = "osu_most_played_maps.json")]
         input: PathBuf,
         /// output directory for beatmaps
         #[arg(short, long)]
-        output: Option<PathBuf>,
+        dir: Option<PathBuf>,
+        /// plan file path
+        #[arg(short, long, default_value = "plan.json")]
+        output: PathBuf,
+        #[command(flatten)]
+        opts: DownloadOptions,
+        #[command(flatten)]
+        filters: FilterOptions,
     },
     /// fetch and download in one command
     All {
         /// output directory for beatmaps
         #[arg(short, long)]
         output: Option<PathBuf>,
+        /// which beatmaps to fetch, several separated by commas are merged into one list
+        #[arg(long, value_enum, value_delimiter = ',', default_value = "most-played")]
+        source: Vec<Source>,
+        #[command(flatten)]
+        recent: fetcher::RecentOptions,
+        #[command(flatten)]
+        shrink: fetcher::ShrinkOptions,
+        #[command(flatten)]
+        opts: DownloadOptions,
+        #[command(flatten)]
+        filters: FilterOptions,
+    },
+    /// list the beatmaps in the JSON file
+    List {
+        /// input JSON file path
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// show the invalid entries that get skipped instead
+        #[arg(long)]
+        invalid: bool,
+        /// look up missing set details (difficulties, star range, mapper) and save them to the input file
+        #[arg(long)]
+        enrich: bool,
+        /// only list maps fetched from this source, same as --source-filter
+        #[arg(long, value_name = "SOURCE", value_delimiter = ',', value_parser = fetcher::SOURCE_NAMES)]
+        source: Vec<String>,
+        #[command(flatten)]
+        filters: FilterOptions,
+    },
+    /// look up metadata missing from the entries of the JSON file and save it there
+    Enrich {
+        /// input JSON file path
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// which fields to fill in (all by default)
+        #[arg(long, value_enum, value_delimiter = ',')]
+        fields: Vec<enrich::Field>,
+        /// at most this many API requests per minute
+        #[arg(long, value_name = "REQ/MIN", value_parser = clap::value_parser!(u32).range(1..))]
+        rate: Option<u32>,
+    },
+    /// chart the most played sets, or the sets, artists or mappers that top another ranking
+    Top {
+        /// input JSON file path
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// what to rank by
+        #[arg(long, value_enum, default_value_t = top::TopBy::Playcount)]
+        by: top::TopBy,
+        /// how many to show
+        #[arg(short, long = "n", default_value_t = 25)]
+        n: usize,
+        /// aligned text without bars or colors
+        #[arg(long, visible_alias = "no-color")]
+        plain: bool,
+        #[command(flatten)]
+        filters: FilterOptions,
+    },
+    /// write the beatmap list in another format
+    Export {
+        /// input JSON file path
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// output file path
+        #[arg(short, long)]
+        output: PathBuf,
+        #[arg(long, value_enum)]
+        format: export::ExportFormat,
+        #[command(flatten)]
+        filters: FilterOptions,
+    },
+    /// open downloaded beatmaps with osu!lazer so it imports them
+    ImportLazer {
+        /// directory with the downloaded beatmaps
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+        /// osu!lazer data directory (detected by default)
+        #[arg(long)]
+        lazer_data: Option<PathBuf>,
+        /// how many archives to open before pausing
+        #[arg(long, default_value_t = 20)]
+        open_batch: usize,
+        /// how long to pause between batches, e.g. 30s
+        #[arg(long, default_value = "30s", value_parser = downloader::parse_duration)]
+        pause: std::time::Duration,
+    },
+    /// rename downloaded archives whose beatmapset title or artist changed since they were saved
+    RenameExisting {
+        /// input JSON file path
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// directory with the downloaded beatmaps
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+        /// only print the renames
+        #[arg(long)]
+        dry_run: bool,
+        #[command(flatten)]
+        naming: NamingOptions,
+    },
+    /// check the configuration for common problems
+    Doctor {
+        /// output directory for beatmaps
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// user agent to check the mirror with, as for download
+        #[arg(long, value_name = "UA", value_parser = downloader::parse_user_agent)]
+        user_agent: Option<String>,
+    },
+    /// maintain the state files kept in the output directory
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// search the index.sqlite that download --index keeps, e.g. by artist or star range
+    Query {
+        /// directory with the downloaded beatmaps
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+        #[command(flatten)]
+        query: index::IndexQuery,
+    },
+    /// serve a web UI to browse the beatmap list and start fetches and downloads, asking for
+    /// SERVE_TOKEN from the .env
+    #[cfg(feature = "web")]
+    Serve {
+        /// port to listen on
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// address to listen on, e.g. 0.0.0.0 for every network interface
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: std::net::IpAddr,
+        /// beatmap list to show, and to save fetches to
+        #[arg(short, long, default_value = "osu_most_played_maps.json")]
+        input: PathBuf,
+        /// output directory for beatmaps
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// manage the mirror certificates pinned by --pin-certs
+    Pins {
+        #[command(subcommand)]
+        command: PinsCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    /// rewrite http_validators.jsonl with only the latest record of each set and mirror, in
+    /// the format COMPRESS_STATE asks for
+    Compact {
+        /// directory with the downloaded beatmaps
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand)]
+enum PinsCommand {
+    /// forget the pinned certificate of a mirror host, e.g. after it changed on purpose. the
+    /// next --pin-certs run pins the one it sends then
+    Reset {
+        /// host name of the mirror, e.g. api.nerinyan.moe
+        host: String,
+        /// directory with the downloaded beatmaps
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
     },
 }
 
 fn get_default_output_dir() -> PathBuf {
-    std::env::var("BEATMAP_OUTPUT_DIR")
-        .map(PathBuf::from)
-        .unwrap_or_else(|_| PathBuf::from("beatmaps"))
+    settings::get()
+        .output_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("beatmaps"))
 }
 
 fn prompt_confirm(msg: &str) -> Result<bool> {
     print!("{} (y/N): ", msg);
     std::io::stdout().flush()?;
-    
-    let mut input = String::new();
-    std::io::stdin().read_line(&mut input)?;
-    let input = input.trim().to_lowercase();
+
+    // through the keyboard listener's reader, which may still be waiting on stdin
+    let input = keyboard::read_line()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
     Ok(input == "y" || input == "yes")
 }
 
+fn check_report(report: DownloadReport) -> Result<()> {
+    match report.failure() {
+        Some(failure) => Err(failure.into()),
+        None => Ok(()),
+    }
+}
+
 #[tokio::main]
-async fn main() -> Result<()> {
-    dotenvy::dotenv().ok();
-    
+async fn main() -> ExitCode {
+    settings::load();
+
     let cli = Cli::parse();
+    settings::update(|s| {
+        s.units = if cli.si_units {
+            Units::Si
+        } else {
+            Units::Binary
+        }
+    });
+
+    let result = tokio::select! {
+        result = run(cli) => result,
+        _ = exit::interrupted() => Err(Failure::Cancelled.into()),
+    };
 
-    match cli.command {
-        Commands::Fetch { output } => {
+    if let Err(e) = &result {
+        eprintln!("Error: {:?}", e);
+    }
+    ExitCode::from(exit::code(&result))
+}
+
+async fn run(cli: Cli) -> Result<()> {
+    let Some(command) = cli.command else {
+        if setup::missing_credentials().is_empty() {
+            Cli::command().print_help()?;
+        } else {
+            setup::first_run()?;
+        }
+        return Ok(());
+    };
+
+    // doctor lists the invalid settings itself and init replaces them
+    if !matches!(command, Commands::Doctor { .. } | Commands::Init) {
+        settings::get().check()?;
+    }
+
+    match command {
+        Commands::Init => {
+            setup::init(std::path::Path::new(setup::ENV_FILE))?;
+        }
+        Commands::Fetch {
+            output,
+            source,
+            merge,
+            recent,
+            from_replays,
+            from_collection,
+            collection,
+            continue_on_partial_fetch,
+            resume,
+            difficulties,
+            check_updates,
+            fetch_only_ids,
+            split_by_mode,
+            deep,
+            deep_window,
+            shrink,
+        } => {
+            fetcher::check_list_path(&output)?;
             println!("Fetching beatmaps from osu! API...");
-            let maps = fetcher::fetch_most_played().await?;
-            fetcher::save_beatmaps(&maps, &output)?;
-            println!("Saved {} beatmaps to {}", maps.len(), output.display());
+            let mut checkpoint = None;
+            let mut maps = if let Some(dir) = from_replays {
+                fetcher::fetch_from_replays(&dir).await?
+            } else if let Some(path) = from_collection {
+                fetcher::fetch_from_collection(&path, collection.as_deref()).await?
+            } else {
+                let opened = checkpoint.insert(Checkpoint::open(
+                    Checkpoint::path_for(&output),
+                    &fetcher::username()?,
+                    resume,
+                )?);
+                match fetcher::fetch(&source, &recent, Some(opened)).await {
+                    Ok(maps) => maps,
+                    Err(e) if continue_on_partial_fetch => {
+                        let partial = e.downcast::<fetcher::PartialFetch>()?;
+                        if fetch_only_ids {
+                            fetcher::save_ids(&partial.maps, &output)?;
+                        } else {
+                            fetcher::save_beatmaps(&partial.maps, &output)?;
+                        }
+                        println!("Warning: {} ({:#})", partial, partial.error);
+                        println!(
+                            "Saved {} beatmaps fetched so far to {}",
+                            partial.maps.len(),
+                            output.display()
+                        );
+                        println!("Run fetch --resume to continue from where it stopped");
+                        return Err(Failure::PartialFetch.into());
+                    }
+                    Err(e) => {
+                        // nothing was fetched when the credentials were rejected
+                        if Failure::of(&e) != Some(Failure::AuthFailed) {
+                            println!("Fetched pages are kept, run fetch --resume to continue from where it stopped");
+                        }
+                        return Err(e);
+                    }
+                }
+            };
+            if deep {
+                fetcher::fetch_deep(&mut maps, deep_window).await?;
+            }
+            if difficulties {
+                fetcher::enrich_beatmaps(
+                    &mut maps,
+                    |m| m.difficulties.is_empty(),
+                    fetcher::API_PACE,
+                )
+                .await?;
+            }
+            if split_by_mode {
+                fetcher::enrich_difficulties(
+                    &mut maps,
+                    |m| m.native_mode().is_none(),
+                    fetcher::API_PACE,
+                )
+                .await?;
+            }
+            let outputs: Vec<(PathBuf, Vec<BeatmapInfo>)> = if split_by_mode {
+                fetcher::split_by_mode(maps)
+                    .into_iter()
+                    .map(|(mode, maps)| (fetcher::mode_path(&output, mode), maps))
+                    .collect()
+            } else {
+                vec![(output.clone(), maps)]
+            };
+            if check_updates {
+                let saved_paths: Vec<PathBuf> = if split_by_mode {
+                    fetcher::SPLIT_MODES
+                        .iter()
+                        .map(|m| fetcher::mode_path(&output, m))
+                        .collect()
+                } else {
+                    vec![output.clone()]
+                };
+                let saved: Vec<BeatmapInfo> = saved_paths
+                    .iter()
+                    .filter_map(|p| fetcher::load_beatmaps_checked(p).ok())
+                    .flat_map(|(maps, _)| maps)
+                    .collect();
+                if saved.is_empty() {
+                    println!("No saved list at {} to compare with", output.display());
+                } else {
+                    let fetched: Vec<BeatmapInfo> =
+                        outputs.iter().flat_map(|(_, maps)| maps.clone()).collect();
+                    playcount::print_updates(&saved, &fetched);
+                }
+            }
+            // maps from replays or a collection don't come from any source, only a fetch from
+            // the API has a checkpoint
+            let fetched = if checkpoint.is_none() {
+                Vec::new()
+            } else {
+                let mut fetched = fetcher::expand_sources(&source);
+                if deep {
+                    fetched.push(Source::Deep);
+                }
+                fetched
+            };
+            // a day of recent scores is only worth adding to the list, never replacing it
+            let merge = merge || fetched.contains(&Source::Recent);
+            for (path, maps) in outputs {
+                let maps = if merge {
+                    fetcher::merge_saved(maps, &path)?
+                } else {
+                    fetcher::guard_shrink(maps, &path, &shrink)
+                };
+                if fetch_only_ids {
+                    let saved = fetcher::save_ids(&maps, &path)?;
+                    println!("Saved {} beatmapset IDs to {}", saved, path.display());
+                } else {
+                    fetcher::save_beatmaps(&maps, &path)?;
+                    fetcher::record_sources(&path, &fetched, &maps, merge)?;
+                    println!("Saved {} beatmaps to {}", maps.len(), path.display());
+                }
+            }
+            if let Some(checkpoint) = checkpoint {
+                checkpoint.finish()?;
+            }
         }
-        Commands::Download { input, output } => {
-            println!("Loading beatmaps from {}...", input.display());
-            let maps = fetcher::load_beatmaps(&input)?;
-            println!("Found {} beatmaps", maps.len());
-            
+        Commands::Download { output, opts, .. } | Commands::All { output, opts, .. }
+            if opts.list_mirrors =>
+        {
+            reliability::list(&output.unwrap_or_else(get_default_output_dir));
+        }
+        Commands::Download {
+            output,
+            plan: Some(path),
+            replan,
+            opts,
+            ..
+        } => {
+            let plan = plan::load(&path)?;
+            let output_dir = output.unwrap_or_else(|| plan.dir.clone());
+            persist::check_output_dir(&output_dir, std::slice::from_ref(&path))?;
+            let opts = plan.options.apply(&opts)?;
+            let report = if replan {
+                downloader::download_beatmaps(&plan.beatmaps(), &output_dir, &opts).await?
+            } else {
+                plan.check(&output_dir, &opts)?;
+                downloader::download_planned(&plan.beatmaps(), &plan.refresh(), &output_dir, &opts)
+                    .await?
+            };
+            check_report(report)?;
+        }
+        Commands::Download {
+            input,
+            output,
+            export_urls: Some(path),
+            url_format,
+            opts,
+            filters,
+            ..
+        } => {
+            let output_dir = output.unwrap_or_else(get_default_output_dir);
+            persist::check_output_dir(&output_dir, &input)?;
+            let maps = fetcher::load_inputs(&input)?;
+            let maps = filter::apply_filters(maps, &filters, &input).await?;
+            let plan = plan::create(&maps, &output_dir, &opts)?;
+            export::export_urls(&plan, &output_dir, url_format, &path)?;
+            println!(
+                "Wrote the URLs of {} beatmaps to {}",
+                plan.entries.len(),
+                path.display()
+            );
+        }
+        Commands::Download {
+            input,
+            output,
+            only_status_changed,
+            previous,
+            opts,
+            filters,
+            ..
+        } if !only_status_changed.is_empty() => {
+            let output_dir = output.unwrap_or_else(get_default_output_dir);
+            persist::check_output_dir(&output_dir, &input)?;
+            // every list is compared with its own backup by default
+            let previous = match previous {
+                Some(path) => vec![path],
+                None => fetcher::input_files(&input)?
+                    .iter()
+                    .map(|p| persist::backup_path(p))
+                    .collect::<Result<_>>()?,
+            };
+            let mut before = Vec::new();
+            for path in &previous {
+                if !path.exists() {
+                    anyhow::bail!(
+                        "no previous list at {} to compare statuses with, pass --previous",
+                        path.display()
+                    );
+                }
+                before.extend(fetcher::load_beatmaps_checked(path)?.0);
+            }
+            let previous = previous
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            let maps =
+                filter::apply_filters(fetcher::load_inputs(&input)?, &filters, &input).await?;
+            let changes = status::changed(&before, &maps, &only_status_changed);
+            if changes.is_empty() {
+                println!(
+                    "No set changed to {} since {}",
+                    only_status_changed.join(" or "),
+                    previous
+                );
+                return Ok(());
+            }
+            println!("{} sets changed status since {}:", changes.len(), previous);
+            status::print_changes(&changes);
+            let changed: Vec<BeatmapInfo> = changes.iter().map(|c| c.map.clone()).collect();
+            check_report(downloader::download_replacing(&changed, &output_dir, &opts).await?)?;
+        }
+        Commands::Download {
+            input,
+            output,
+            opts,
+            filters,
+            ..
+        } => {
             let output_dir = output.unwrap_or_else(get_default_output_dir);
-            downloader::download_beatmaps(&maps, &output_dir).await?;
+            persist::check_output_dir(&output_dir, &input)?;
+            let files = fetcher::input_files(&input)?;
+            let names: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+            println!("Loading beatmaps from {}...", names.join(", "));
+            let maps = fetcher::load_inputs(&files)?;
+            println!("Found {} beatmaps", maps.len());
+            let maps = filter::apply_filters(maps, &filters, &files).await?;
+
+            check_report(downloader::download_beatmaps(&maps, &output_dir, &opts).await?)?;
+        }
+        Commands::Plan {
+            input,
+            dir,
+            output,
+            opts,
+            filters,
+        } => {
+            let maps = fetcher::load_beatmaps(&input)?;
+            let maps = filter::apply_filters(maps, &filters, std::slice::from_ref(&input)).await?;
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            let plan = plan::create(&maps, &dir, &opts)?;
+            plan::save(&plan, &output)?;
+            println!(
+                "Saved a plan to download {} beatmaps to {}",
+                plan.entries.len(),
+                output.display()
+            );
         }
-        Commands::All { output } => {
+        Commands::All {
+            output,
+            source,
+            recent,
+            shrink,
+            opts,
+            filters,
+        } => {
             let json_path = PathBuf::from("osu_most_played_maps.json");
-            let mut maps = Vec::new();
+            let output_dir = output.unwrap_or_else(get_default_output_dir);
+            persist::check_output_dir(&output_dir, std::slice::from_ref(&json_path))?;
+            if !json_path.exists() && !setup::first_run()? {
+                anyhow::bail!("the osu! API credentials are needed to fetch your maps");
+            }
 
-            if json_path.exists() {
+            let maps = if json_path.exists() {
                 println!("Found existing beatmap list at {}", json_path.display());
                 if prompt_confirm("Do you want to re-fetch from osu! API?")? {
-                    maps = fetcher::fetch_most_played().await?;
+                    let sources = fetcher::expand_sources(&source);
+                    let maps = fetcher::fetch(&sources, &recent, None).await?;
+                    let merge = sources.contains(&Source::Recent);
+                    let maps = if merge {
+                        fetcher::merge_saved(maps, &json_path)?
+                    } else {
+                        fetcher::guard_shrink(maps, &json_path, &shrink)
+                    };
                     fetcher::save_beatmaps(&maps, &json_path)?;
+                    fetcher::record_sources(&json_path, &sources, &maps, merge)?;
                     println!("Updated list saved to {}\n", json_path.display());
+                    maps
                 } else {
                     println!("Using existing beatmap list...");
-                    maps = fetcher::load_beatmaps(&json_path)?;
+                    fetcher::load_beatmaps(&json_path)?
                 }
             } else {
-                maps = fetcher::fetch_most_played().await?;
+                let maps = fetcher::fetch(&source, &recent, None).await?;
                 fetcher::save_beatmaps(&maps, &json_path)?;
+                fetcher::record_sources(
+                    &json_path,
+                    &fetcher::expand_sources(&source),
+                    &maps,
+                    false,
+                )?;
                 println!("Saved to {}\n", json_path.display());
+                maps
+            };
+            let maps =
+                filter::apply_filters(maps, &filters, std::slice::from_ref(&json_path)).await?;
+
+            check_report(downloader::download_beatmaps(&maps, &output_dir, &opts).await?)?;
+        }
+        Commands::List {
+            input,
+            invalid: true,
+            ..
+        } => {
+            let (_, invalid) = fetcher::load_beatmaps_checked(&input)?;
+            for entry in &invalid {
+                println!("#{}: {}", entry.index, entry.reason);
             }
-            
+            println!("\n{} invalid entries", invalid.len());
+        }
+        Commands::List {
+            input,
+            enrich,
+            source,
+            mut filters,
+            ..
+        } => {
+            filters.source_filter.extend(source);
+            let mut maps = fetcher::load_beatmaps(&input)?;
+            if enrich {
+                let resolved = fetcher::enrich_beatmaps(
+                    &mut maps,
+                    |m| m.set_summary.is_none() || m.creator.is_none(),
+                    fetcher::API_PACE,
+                )
+                .await?;
+                if resolved > 0 {
+                    fetcher::save_beatmaps(&maps, &input)?;
+                    println!(
+                        "Saved details of {} beatmapsets to {}\n",
+                        resolved,
+                        input.display()
+                    );
+                }
+            }
+            let maps = filter::apply_filters(maps, &filters, std::slice::from_ref(&input)).await?;
+
+            for map in &maps {
+                let details = map
+                    .set_description()
+                    .map(|d| format!(" ({})", d))
+                    .unwrap_or_default();
+                // a convert shows the mode it was made for and the one it was played in
+                let mode = match (map.native_mode(), &map.played_mode) {
+                    (Some(native), Some(played)) => format!("{}→{}", native, played),
+                    (native, _) => native.unwrap_or("?").to_string(),
+                };
+                println!(
+                    "{:>8}  {:<12} {} - {} [{}] {} plays{}",
+                    map.beatmapset_id,
+                    mode,
+                    map.artist,
+                    map.title,
+                    map.version,
+                    units::count(map.play_count as usize),
+                    details
+                );
+            }
+            println!("\n{} maps", units::count(maps.len()));
+        }
+        Commands::Enrich {
+            input,
+            fields,
+            rate,
+        } => {
+            enrich::enrich(&input, &fields, rate).await?;
+        }
+        Commands::Top {
+            input,
+            by,
+            n,
+            plain,
+            filters,
+        } => {
+            let maps = fetcher::load_beatmaps(&input)?;
+            let maps = filter::apply_filters(maps, &filters, std::slice::from_ref(&input)).await?;
+            top::print(&maps, by, n, plain);
+        }
+        Commands::Export {
+            input,
+            output,
+            format,
+            filters,
+        } => {
+            let maps = fetcher::load_beatmaps(&input)?;
+            let maps = filter::apply_filters(maps, &filters, std::slice::from_ref(&input)).await?;
+            export::export(&maps, format, &output)?;
+            println!("Exported {} beatmaps to {}", maps.len(), output.display());
+        }
+        Commands::ImportLazer {
+            dir,
+            lazer_data,
+            open_batch,
+            pause,
+        } => {
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            lazer::import(&dir, lazer_data, open_batch, pause).await?;
+        }
+        Commands::RenameExisting {
+            input,
+            dir,
+            dry_run,
+            naming,
+        } => {
+            let maps = fetcher::load_beatmaps(&input)?;
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            rename::rename_existing(&dir, &maps, &naming, dry_run)?;
+        }
+        Commands::Doctor { output, user_agent } => {
             let output_dir = output.unwrap_or_else(get_default_output_dir);
-            downloader::download_beatmaps(&maps, &output_dir).await?;
+            doctor::run(&output_dir, user_agent.as_deref()).await?;
+        }
+        Commands::State {
+            command: StateCommand::Compact { dir },
+        } => {
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            match validators::compact(&dir)? {
+                Some(done) => {
+                    let units = settings::get().units;
+                    println!(
+                        "Compacted {} records to {} in {} ({} → {})",
+                        units::count(done.lines_before),
+                        units::count(done.lines_after),
+                        done.path.display(),
+                        units::bytes(done.bytes_before, units),
+                        units::bytes(done.bytes_after, units)
+                    )
+                }
+                None => println!("No {} in {}", validators::VALIDATORS_FILE, dir.display()),
+            }
+        }
+        Commands::Query { dir, query } => {
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            let entries = index::search(&dir, &query)?;
+            index::print(&entries);
+            println!("\n{} maps", units::count(entries.len()));
+        }
+        #[cfg(feature = "web")]
+        Commands::Serve {
+            port,
+            bind,
+            input,
+            output,
+        } => {
+            let output_dir = output.unwrap_or_else(get_default_output_dir);
+            serve::serve((bind, port).into(), input, output_dir).await?;
+        }
+        Commands::Pins {
+            command: PinsCommand::Reset { host, dir },
+        } => {
+            let dir = dir.unwrap_or_else(get_default_output_dir);
+            if pins::reset(&dir, &host)? {
+                println!("Removed the pin of {}", host);
+            } else {
+                println!("No pin recorded for {} in {}", host, dir.display());
+            }
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{archive, ok, serve, temp_dir};
+    use std::fs;
+    use std::net::SocketAddr;
+    use std::path::Path;
+
+    /// the exit code of `download` with a list of `sets` from a mirror that only has the 425x ones
+    async fn download_exit_code(name: &str, sets: &[u32]) -> u8 {
+        let (addr, _) = serve(|_, request| {
+            if request.contains("/b/425") {
+                ok(&archive())
+            } else {
+                ok(b"<html>not here</html>")
+            }
+        })
+        .await;
+        let dir = temp_dir(&format!("exit-{}", name));
+        let list = dir.join("list.json");
+        let maps: Vec<BeatmapInfo> = sets
+            .iter()
+            .map(|&id| BeatmapInfo {
+                beatmapset_id: id,
+                title: "song".to_string(),
+                ..Default::default()
+            })
+            .collect();
+        fetcher::save_beatmaps(&maps, &list).unwrap();
+        let code = run_exit_code(&dir, &list, addr).await;
+        fs::remove_dir_all(&dir).unwrap();
+        code
+    }
+
+    async fn run_exit_code(dir: &Path, list: &Path, addr: SocketAddr) -> u8 {
+        let rewrite = format!("^https://beatconnect.io=>http://{}", addr);
+        let (out, failed) = (dir.join("out"), dir.join("failed.json"));
+        let cli = Cli::parse_from([
+            "osu-beatmap-downloader",
+            "download",
+            "-i",
+            &list.to_string_lossy(),
+            "-o",
+            &out.to_string_lossy(),
+            "--mirrors",
+            "beatconnect",
+            "--url-rewrite",
+            &rewrite,
+            "--min-valid-size",
+            "1",
+            "--retries",
+            "1",
+            "--failed-file",
+            &failed.to_string_lossy(),
+        ]);
+        exit::code(&run(cli).await)
+    }
+
+    #[tokio::test]
+    async fn exit_codes_by_failure() {
+        assert_eq!(download_exit_code("all", &[4251, 4252]).await, 0);
+        assert_eq!(
+            download_exit_code("partial", &[4253, 4261]).await,
+            Failure::PartialDownload.code()
+        );
+        assert_eq!(
+            download_exit_code("none", &[4262, 4263]).await,
+            Failure::DownloadFailed.code()
+        );
+
+        let dir = temp_dir("exit-invalid");
+        fs::write(dir.join("list.json"), "not a list").unwrap();
+        let (addr, _) = serve(|_, _| unreachable!()).await;
+        assert_eq!(
+            run_exit_code(&dir, &dir.join("list.json"), addr).await,
+            Failure::InvalidInput.code()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
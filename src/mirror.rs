@@ -0,0 +1,544 @@
+use anyhow::Result;
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use reqwest::{Client, Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Semaphore, SemaphorePermit};
+
+use crate::progress;
+use crate::settings;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mirror {
+    Nerinyan,
+    Catboy,
+    Beatconnect,
+}
+
+impl Mirror {
+    pub fn from_env() -> Self {
+        if settings::get().use_alternative_mirror {
+            Mirror::Catboy
+        } else {
+            Mirror::Nerinyan
+        }
+    }
+
+    pub fn download_url(&self, beatmapset_id: u32) -> String {
+        match self {
+            Mirror::Nerinyan => format!("https://api.nerinyan.moe/d/{}", beatmapset_id),
+            Mirror::Catboy => format!("https://catboy.best/d/{}", beatmapset_id),
+            Mirror::Beatconnect => format!("https://beatconnect.io/b/{}", beatmapset_id),
+        }
+    }
+
+    /// how the mirror expects downloads to be requested
+    pub fn config(&self) -> MirrorConfig {
+        match self {
+            Mirror::Nerinyan | Mirror::Catboy | Mirror::Beatconnect => MirrorConfig {
+                method: Method::GET,
+                body_template: None,
+            },
+        }
+    }
+
+    /// the request for a set's archive at `url`, which may have been rewritten
+    pub fn download_request(
+        &self,
+        client: &Client,
+        url: &str,
+        beatmapset_id: u32,
+    ) -> RequestBuilder {
+        self.config().request(client, url, beatmapset_id)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mirror::Nerinyan => "Nerinyan",
+            Mirror::Catboy => "Catboy",
+            Mirror::Beatconnect => "Beatconnect",
+        }
+    }
+
+    /// cheap endpoint used to check the mirror is reachable
+    pub fn status_url(&self) -> &'static str {
+        match self {
+            Mirror::Nerinyan => "https://api.nerinyan.moe/",
+            Mirror::Catboy => "https://catboy.best/api/ratelimits",
+            Mirror::Beatconnect => "https://beatconnect.io/",
+        }
+    }
+
+    /// how many downloads may run against the mirror at once
+    pub fn max_concurrent(&self) -> usize {
+        match self {
+            Mirror::Catboy => 1,
+            Mirror::Nerinyan => 3,
+            Mirror::Beatconnect => 2,
+        }
+    }
+}
+
+// catboy.best API structs
+#[derive(Debug, Deserialize)]
+struct CatboyRateLimitResponse {
+    remaining: CatboyRemaining,
+    types: CatboyTypes,
+}
+
+#[derive(Debug, Deserialize)]
+struct CatboyRemaining {
+    download: u32,
+}
+#[derive(Debug, Deserialize)]
+struct CatboyTypes {
+    download: u32,
+}
+
+/// request details of a mirror's download endpoint
+pub struct MirrorConfig {
+    pub method: Method,
+    /// JSON body sent with the request, `{id}` is replaced with the beatmapset ID
+    pub body_template: Option<&'static str>,
+}
+
+impl MirrorConfig {
+    /// the request for a set's archive at `url`
+    pub fn request(&self, client: &Client, url: &str, beatmapset_id: u32) -> RequestBuilder {
+        let request = client.request(self.method.clone(), url);
+        match self.body_template {
+            Some(template) => request
+                .header(CONTENT_TYPE, "application/json")
+                .body(template.replace("{id}", &beatmapset_id.to_string())),
+            None => request,
+        }
+    }
+}
+
+/// rate limiter
+pub struct RateLimiter {
+    mirror: Mirror,
+    // group mutable state into one mutex to reduce lock contention and complexity
+    state: Mutex<RateLimitState>,
+    client: Client,
+}
+
+struct RateLimitState {
+    remaining: u32,
+    reset_at: Instant,
+    limit_cap: u32,
+    download_count: u32,
+    // --pretend-rate-limit, the limits are never allowed above it
+    pretend_cap: Option<u32>,
+    // the last cap the mirror itself reported, None until it reports one
+    reported_cap: Option<u32>,
+    // set when the mirror answers 429, no request goes out before it passes
+    cooldown_until: Option<Instant>,
+}
+
+// what we assume until a mirror tells us otherwise
+const DEFAULT_LIMIT: u32 = 60;
+
+impl RateLimitState {
+    /// take the cap reported by the mirror, mentioning it above `pb` when it differs from what
+    /// we assumed
+    fn set_reported_cap(&mut self, mirror: Mirror, cap: u32, pb: &ProgressBar) {
+        let assumed = self.reported_cap.unwrap_or(DEFAULT_LIMIT);
+        if cap != assumed {
+            progress::println(
+                pb,
+                format!(
+                    "{} reports a limit of {} requests per minute (assumed {})",
+                    mirror.name(),
+                    cap,
+                    assumed
+                ),
+            );
+        }
+        self.reported_cap = Some(cap);
+        self.limit_cap = cap;
+    }
+
+    fn apply_pretend_cap(&mut self) {
+        if let Some(cap) = self.pretend_cap {
+            self.limit_cap = self.limit_cap.min(cap);
+            self.remaining = self.remaining.min(cap);
+        }
+    }
+}
+
+impl RateLimiter {
+    fn new(mirror: Mirror, client: Client, pretend_cap: Option<u32>) -> Self {
+        let mut state = RateLimitState {
+            remaining: DEFAULT_LIMIT,
+            reset_at: Instant::now() + Duration::from_secs(60),
+            limit_cap: DEFAULT_LIMIT,
+            download_count: 0,
+            pretend_cap,
+            reported_cap: None,
+            cooldown_until: None,
+        };
+        state.apply_pretend_cap();
+        Self {
+            mirror,
+            state: Mutex::new(state),
+            client,
+        }
+    }
+
+    /// wait until we're allowed to make a request
+    pub async fn wait(&self) {
+        loop {
+            let mut state = self.state.lock().await;
+
+            // the mirror rate limited one of the downloads, so every download waits
+            if let Some(until) = state.cooldown_until {
+                let now = Instant::now();
+                if now < until {
+                    drop(state);
+                    tokio::time::sleep(until - now).await;
+                    continue;
+                }
+                state.cooldown_until = None;
+            }
+
+            // if tokens remaining, consume one and proceed
+            if state.remaining > 0 {
+                state.remaining -= 1;
+                return;
+            }
+
+            // if time window passed, reset
+            if Instant::now() >= state.reset_at {
+                state.remaining = state.limit_cap;
+                state.reset_at = Instant::now() + Duration::from_secs(60);
+                continue;
+            }
+
+            // otherwise wait until reset
+            let sleep_time =
+                state.reset_at.duration_since(Instant::now()) + Duration::from_millis(100);
+            drop(state); // drop lock before sleeping
+            tokio::time::sleep(sleep_time).await;
+        }
+    }
+
+    /// hold back every request to the mirror for `duration` after it answered 429, returns
+    /// how long until the cooldown ends, which an earlier 429 may have set later
+    pub async fn cool_down(&self, duration: Duration) -> Duration {
+        let mut state = self.state.lock().await;
+        let now = Instant::now();
+        let until = state
+            .cooldown_until
+            .map_or(now + duration, |until| until.max(now + duration));
+        state.cooldown_until = Some(until);
+        until - now
+    }
+
+    /// update limits based on response headers (nerinyan.moe)
+    pub async fn update_from_headers(&self, headers: &HeaderMap, pb: &ProgressBar) {
+        if self.mirror == Mirror::Nerinyan {
+            let mut state = self.state.lock().await;
+
+            if let Some(rem) = get_header_u32(headers, "x-ratelimit-remaining-minute") {
+                state.remaining = rem;
+            }
+            if let Some(cap) = get_header_u32(headers, "x-ratelimit-limit-minute") {
+                state.set_reported_cap(self.mirror, cap, pb);
+            }
+            if let Some(secs) = get_header_u64(headers, "x-ratelimit-reset")
+                .or_else(|| get_header_u64(headers, "retry-after"))
+            {
+                state.reset_at = Instant::now() + Duration::from_secs(secs + 1);
+            }
+            state.apply_pretend_cap();
+        }
+    }
+
+    /// explicitly fetch limits (catboy.best)
+    pub async fn refresh_catboy_limits(&self, pb: &ProgressBar) -> Result<()> {
+        if self.mirror == Mirror::Catboy {
+            let response = self
+                .client
+                .get("https://catboy.best/api/ratelimits")
+                .send()
+                .await?;
+            if response.status().is_success() {
+                let data: CatboyRateLimitResponse = response.json().await?;
+                let mut state = self.state.lock().await;
+                state.remaining = data.remaining.download;
+                state.set_reported_cap(self.mirror, data.types.download, pb);
+                state.reset_at = Instant::now() + Duration::from_secs(60);
+                state.apply_pretend_cap();
+            }
+        }
+        Ok(())
+    }
+
+    /// seed the limits from a cheap request (nerinyan.moe), so the run doesn't start blind
+    pub async fn probe_nerinyan_limits(&self, pb: &ProgressBar) -> Result<()> {
+        if self.mirror == Mirror::Nerinyan {
+            let response = self.client.head(self.mirror.status_url()).send().await?;
+            self.update_from_headers(response.headers(), pb).await;
+        }
+        Ok(())
+    }
+
+    pub async fn on_download_complete(&self, pb: &ProgressBar) {
+        if self.mirror == Mirror::Catboy {
+            let mut needs_refresh = false;
+            {
+                let mut state = self.state.lock().await;
+                state.download_count += 1;
+                if state.download_count % 50 == 0 {
+                    needs_refresh = true;
+                }
+            }
+            if needs_refresh {
+                let _ = self.refresh_catboy_limits(pb).await;
+            }
+        }
+    }
+
+    /// requests left in the current window and the cap, once the mirror has reported them
+    pub async fn quota(&self) -> Option<(u32, u32)> {
+        let state = self.state.lock().await;
+        state.reported_cap?;
+        Some((state.remaining, state.limit_cap))
+    }
+
+    /// requests left in the current window, the cap and how long until it resets
+    async fn window(&self) -> (u32, u32, Duration) {
+        let state = self.state.lock().await;
+        let now = Instant::now();
+        if now >= state.reset_at {
+            (state.limit_cap, state.limit_cap, Duration::from_secs(60))
+        } else {
+            (state.remaining, state.limit_cap, state.reset_at - now)
+        }
+    }
+
+    /// requests left in the current window
+    async fn remaining(&self) -> u32 {
+        let state = self.state.lock().await;
+        if Instant::now() >= state.reset_at {
+            state.limit_cap
+        } else {
+            state.remaining
+        }
+    }
+}
+
+// helper to parse headers
+fn get_header_u32(h: &HeaderMap, key: &str) -> Option<u32> {
+    h.get(key)?.to_str().ok()?.parse().ok()
+}
+pub fn get_header_u64(h: &HeaderMap, key: &str) -> Option<u64> {
+    h.get(key)?.to_str().ok()?.parse().ok()
+}
+
+/// the filename a response's Content-Disposition gives its body
+pub fn server_filename(h: &HeaderMap) -> Option<String> {
+    let value = h.get(reqwest::header::CONTENT_DISPOSITION)?.to_str().ok()?;
+    let (_, name) = value
+        .split_once("filename*=")
+        .or_else(|| value.split_once("filename="))?;
+    let name = name.split(';').next()?.trim().trim_matches('"');
+    // the extended form starts with the charset, `UTF-8''name`
+    Some(name.rsplit("''").next().unwrap_or(name).to_string())
+}
+
+/// requests the mirrors in use can make right away and how fast that refills
+#[derive(Debug, Clone, Copy)]
+pub struct RateBudget {
+    tokens: u32,
+    per_minute: u32,
+    reset_in: Duration,
+}
+
+impl RateBudget {
+    /// the least time `requests` requests take under the rate limits
+    pub fn time_for(&self, requests: usize) -> Duration {
+        let over = (requests as u64).saturating_sub(self.tokens as u64);
+        if over == 0 {
+            return Duration::ZERO;
+        }
+        let windows = over.div_ceil(self.per_minute.max(1) as u64);
+        self.reset_in + Duration::from_secs(60 * (windows - 1))
+    }
+}
+
+/// one mirror with its own rate limiter, concurrency limit and stats
+pub struct MirrorSlot {
+    pub mirror: Mirror,
+    pub limiter: RateLimiter,
+    permits: Semaphore,
+    downloaded: AtomicUsize,
+    failed: AtomicUsize,
+    bytes: AtomicU64,
+    // reliability score weighting balanced picks, 1.0 unless --prefer-reliable is set
+    score: f64,
+}
+
+impl MirrorSlot {
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>> {
+        Ok(self.permits.acquire().await?)
+    }
+
+    pub fn record_download(&self, bytes: u64) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// the mirrors used for a run, in order of preference
+pub struct MirrorPool {
+    slots: Vec<MirrorSlot>,
+    // spread downloads over all mirrors instead of only falling back on failure
+    balance: bool,
+}
+
+impl MirrorPool {
+    /// `pretend_cap` limits every mirror to that many requests per minute, for testing.
+    /// `score` weights how much balanced picks favour a mirror
+    pub async fn new(
+        mirrors: &[Mirror],
+        balance: bool,
+        pretend_cap: Option<u32>,
+        score: impl Fn(Mirror) -> f64,
+        client: &Client,
+    ) -> Self {
+        let mut slots = Vec::new();
+        for &mirror in mirrors {
+            if slots.iter().any(|s: &MirrorSlot| s.mirror == mirror) {
+                continue;
+            }
+            let limiter = RateLimiter::new(mirror, client.clone(), pretend_cap);
+            // no bars are drawn yet, so the limits the mirrors report are plain lines
+            let pb = ProgressBar::hidden();
+            // only hints, the first downloads will update the limits anyway
+            if let Err(e) = limiter.refresh_catboy_limits(&pb).await {
+                progress::println(
+                    &pb,
+                    format!(
+                        "Warning: couldn't get the rate limits of {}: {:#}",
+                        mirror.name(),
+                        e
+                    ),
+                );
+            }
+            let _ = limiter.probe_nerinyan_limits(&pb).await;
+            slots.push(MirrorSlot {
+                mirror,
+                limiter,
+                permits: Semaphore::new(mirror.max_concurrent()),
+                downloaded: AtomicUsize::new(0),
+                failed: AtomicUsize::new(0),
+                bytes: AtomicU64::new(0),
+                score: score(mirror),
+            });
+        }
+        Self { slots, balance }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// total downloads that may run at once
+    pub fn max_concurrent(&self) -> usize {
+        if self.balance {
+            self.slots.iter().map(|s| s.mirror.max_concurrent()).sum()
+        } else {
+            self.slots[0].mirror.max_concurrent()
+        }
+    }
+
+    /// the next mirror to try that isn't in `tried`: the first one in order, or when balancing,
+    /// the one with the most free download slots and rate limit tokens
+    pub async fn pick(&self, tried: &[Mirror]) -> Option<&MirrorSlot> {
+        let mut candidates = self.slots.iter().filter(|s| !tried.contains(&s.mirror));
+        if !self.balance {
+            return candidates.next();
+        }
+
+        let mut best: Option<(&MirrorSlot, (usize, f64))> = None;
+        for slot in candidates {
+            let tokens = slot.limiter.remaining().await as f64 * slot.score;
+            let headroom = (slot.permits.available_permits(), tokens);
+            if best.is_none_or(|(_, h)| headroom > h) {
+                best = Some((slot, headroom));
+            }
+        }
+        best.map(|(slot, _)| slot)
+    }
+
+    /// rate limit budget of the mirrors downloads go to: all of them when balancing, otherwise
+    /// the first, which every download is tried on before falling back
+    pub async fn rate_budget(&self) -> RateBudget {
+        let active = if self.balance {
+            &self.slots[..]
+        } else {
+            &self.slots[..1]
+        };
+        let mut budget = RateBudget {
+            tokens: 0,
+            per_minute: 0,
+            reset_in: Duration::MAX,
+        };
+        for slot in active {
+            let (tokens, cap, reset_in) = slot.limiter.window().await;
+            budget.tokens += tokens;
+            budget.per_minute += cap;
+            budget.reset_in = budget.reset_in.min(reset_in);
+        }
+        budget
+    }
+
+    /// remaining requests this minute and the cap of the mirrors that reported them
+    pub async fn quotas(&self) -> Vec<(Mirror, u32, u32)> {
+        let mut quotas = Vec::new();
+        for slot in &self.slots {
+            if let Some((remaining, cap)) = slot.limiter.quota().await {
+                quotas.push((slot.mirror, remaining, cap));
+            }
+        }
+        quotas
+    }
+
+    /// per mirror successful and failed download attempts
+    pub fn outcomes(&self) -> Vec<(Mirror, usize, usize)> {
+        self.slots
+            .iter()
+            .map(|s| {
+                (
+                    s.mirror,
+                    s.downloaded.load(Ordering::Relaxed),
+                    s.failed.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+
+    /// per mirror download count and bytes
+    pub fn stats(&self) -> Vec<(Mirror, usize, u64)> {
+        self.slots
+            .iter()
+            .map(|s| {
+                (
+                    s.mirror,
+                    s.downloaded.load(Ordering::Relaxed),
+                    s.bytes.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,282 @@
+use clap::{Args, ValueEnum};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::types::{ArchiveExtension, BeatmapInfo};
+
+pub const DEFAULT_FILENAME_FORMAT: &str = "{id} {artist} - {title}";
+// --filename-format value for the names the osu! website and client give archives
+const OSU_NATIVE: &str = "osu-native";
+
+/// how archives are named, --filename-format. kept in plans as it was given
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub enum FilenameFormat {
+    /// the names osu! itself gives archives
+    OsuNative,
+    /// tokens filled in for each set, starting with {id}
+    Template(String),
+}
+
+impl fmt::Display for FilenameFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FilenameFormat::OsuNative => f.write_str(OSU_NATIVE),
+            FilenameFormat::Template(template) => f.write_str(template),
+        }
+    }
+}
+
+impl From<FilenameFormat> for String {
+    fn from(format: FilenameFormat) -> Self {
+        format.to_string()
+    }
+}
+
+impl TryFrom<String> for FilenameFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, String> {
+        parse_filename_format(&s)
+    }
+}
+
+/// how archives are named and where in the output directory they go
+#[derive(Args, Debug, Clone)]
+pub struct NamingOptions {
+    /// extension of the downloaded archives, existing maps are found with either
+    #[arg(long, value_enum, default_value_t = ArchiveExtension::Osz)]
+    pub extension: ArchiveExtension,
+    /// archive name without the extension, tokens: {id} {artist} {title} {creator} {mode}.
+    /// must start with {id} so existing archives can be recognized. "osu-native" gives the
+    /// names osu! itself uses
+    #[arg(long, default_value = DEFAULT_FILENAME_FORMAT, value_parser = parse_filename_format)]
+    pub filename_format: FilenameFormat,
+    /// subdirectory of the output directory for each archive, e.g. "{mode}", same tokens
+    #[arg(long, value_name = "TEMPLATE")]
+    pub dir_template: Option<String>,
+    /// what {mode} stands for when a set has difficulties of several modes
+    #[arg(long, value_enum, default_value_t = ModeTokenPolicy::Dominant)]
+    pub mode_token_policy: ModeTokenPolicy,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModeTokenPolicy {
+    /// the mode most of the set's difficulties are in
+    Dominant,
+    /// the mode of the difficulty in the beatmap list
+    First,
+    /// every mode in the set, e.g. osu+mania
+    All,
+}
+
+fn parse_filename_format(s: &str) -> Result<FilenameFormat, String> {
+    if s == OSU_NATIVE {
+        return Ok(FilenameFormat::OsuNative);
+    }
+    if !s.starts_with("{id}") {
+        return Err("the filename format must start with {id}".to_string());
+    }
+    Ok(FilenameFormat::Template(s.to_string()))
+}
+
+impl NamingOptions {
+    /// archive name for `beatmap`
+    pub fn filename(&self, beatmap: &BeatmapInfo, extension: ArchiveExtension) -> String {
+        // entries of a list of bare IDs have nothing else to name the archive after
+        let name = if beatmap.artist.is_empty() && beatmap.title.is_empty() {
+            beatmap.beatmapset_id.to_string()
+        } else {
+            match &self.filename_format {
+                FilenameFormat::OsuNative => osu_native_name(beatmap),
+                FilenameFormat::Template(template) => self.render(template, beatmap),
+            }
+        };
+        format!("{}.{}", name, extension.as_str())
+    }
+
+    /// path of the archive relative to the output directory
+    pub fn relative_path(&self, beatmap: &BeatmapInfo, extension: ArchiveExtension) -> PathBuf {
+        let mut path = PathBuf::new();
+        if let Some(template) = &self.dir_template {
+            // tokens that render empty (an unknown mode) drop the component
+            for component in template.split(['/', '\\']) {
+                let component = self.render(component, beatmap);
+                if !component.is_empty() {
+                    path.push(component);
+                }
+            }
+        }
+        path.push(self.filename(beatmap, extension));
+        path
+    }
+
+    fn mode_token(&self, beatmap: &BeatmapInfo) -> Option<String> {
+        let counts = beatmap
+            .set_summary
+            .as_ref()
+            .map(|s| &s.mode_counts)
+            .filter(|c| !c.is_empty());
+        let Some(counts) = counts else {
+            return beatmap.mode.clone();
+        };
+
+        let mut modes: Vec<(&String, u32)> = counts.iter().map(|(mode, n)| (mode, *n)).collect();
+        // most difficulties first, ties keep the mode of the listed difficulty in front
+        modes
+            .sort_by_key(|(mode, n)| (std::cmp::Reverse(*n), beatmap.mode.as_ref() != Some(*mode)));
+
+        match self.mode_token_policy {
+            ModeTokenPolicy::First => beatmap.mode.clone().or_else(|| Some(modes[0].0.clone())),
+            ModeTokenPolicy::Dominant => Some(modes[0].0.clone()),
+            ModeTokenPolicy::All => Some(
+                modes
+                    .iter()
+                    .map(|(mode, _)| mode.as_str())
+                    .collect::<Vec<_>>()
+                    .join("+"),
+            ),
+        }
+    }
+
+    fn render(&self, template: &str, beatmap: &BeatmapInfo) -> String {
+        let mode = self.mode_token(beatmap);
+        let template = match &mode {
+            Some(_) => template.to_string(),
+            // leave out an unknown mode along with its brackets, e.g. "{title} [{mode}]"
+            None => template
+                .replace("[{mode}]", "")
+                .replace("({mode})", "")
+                .replace("{mode}", "")
+                .split(' ')
+                .filter(|part| !part.is_empty())
+                .collect::<Vec<_>>()
+                .join(" "),
+        };
+
+        template
+            .replace("{id}", &beatmap.beatmapset_id.to_string())
+            .replace("{artist}", &sanitize_filename(&beatmap.artist))
+            .replace("{title}", &sanitize_filename(&beatmap.title))
+            .replace(
+                "{creator}",
+                &sanitize_filename(beatmap.creator.as_deref().unwrap_or_default()),
+            )
+            .replace("{mode}", &mode.unwrap_or_default())
+    }
+}
+
+/// `s` made safe for filenames, in NFC so lists saved before titles were normalized give the
+/// same names as new ones
+pub fn sanitize_filename(s: &str) -> String {
+    s.nfc()
+        .map(|c| match c {
+            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim_matches(|c| c == '.' || c == ' ')
+        .to_string()
+}
+
+/// the name osu! gives a set's archive when it's downloaded from the website or exported from
+/// the game. unlike `sanitize_filename` the characters Windows doesn't allow are dropped rather
+/// than replaced, the rest is only normalized to NFC like every other name
+fn osu_native_name(beatmap: &BeatmapInfo) -> String {
+    format!(
+        "{} {} - {}",
+        beatmap.beatmapset_id, beatmap.artist, beatmap.title
+    )
+    .nfc()
+    .filter(|c| {
+        !matches!(c, '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*') && !c.is_control()
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn naming(format: &str) -> NamingOptions {
+        NamingOptions {
+            extension: ArchiveExtension::Osz,
+            filename_format: parse_filename_format(format).unwrap(),
+            dir_template: None,
+            mode_token_policy: ModeTokenPolicy::Dominant,
+        }
+    }
+
+    #[test]
+    fn parses_filename_formats() {
+        assert_eq!(
+            parse_filename_format("osu-native"),
+            Ok(FilenameFormat::OsuNative)
+        );
+        assert_eq!(
+            parse_filename_format("{id} {title}"),
+            Ok(FilenameFormat::Template("{id} {title}".to_string()))
+        );
+        assert!(parse_filename_format("{title} {id}").is_err());
+        assert!(parse_filename_format("osu-native ").is_err());
+    }
+
+    #[test]
+    fn normalization_forms_give_one_filename() {
+        let title = |title: &str| BeatmapInfo {
+            beatmapset_id: 434,
+            artist: "Mu\u{308}ller".to_string(),
+            title: title.to_string(),
+            ..Default::default()
+        };
+        // "é" as one code point and as "e" with a combining accent
+        let (composed, decomposed) = (title("Caf\u{e9}"), title("Cafe\u{301}"));
+        assert_ne!(composed.title, decomposed.title);
+        for format in [DEFAULT_FILENAME_FORMAT, "osu-native"] {
+            let naming = naming(format);
+            let name = naming.filename(&composed, ArchiveExtension::Osz);
+            assert_eq!(naming.filename(&decomposed, ArchiveExtension::Osz), name);
+        }
+        assert_eq!(
+            naming(DEFAULT_FILENAME_FORMAT).filename(&decomposed, ArchiveExtension::Osz),
+            "434 M\u{fc}ller - Caf\u{e9}.osz"
+        );
+    }
+
+    #[test]
+    fn names_archives() {
+        let beatmap = BeatmapInfo {
+            beatmapset_id: 442,
+            artist: "AC/DC".to_string(),
+            title: "What?".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            naming(DEFAULT_FILENAME_FORMAT).filename(&beatmap, ArchiveExtension::Osz),
+            "442 AC_DC - What_.osz"
+        );
+        assert_eq!(
+            naming("osu-native").filename(&beatmap, ArchiveExtension::Osz),
+            "442 ACDC - What.osz"
+        );
+    }
+
+    #[test]
+    fn keeps_the_format_as_given_in_plans() {
+        for format in ["osu-native", "{id} {creator}"] {
+            let json = serde_json::to_string(&parse_filename_format(format).unwrap()).unwrap();
+            assert_eq!(json, serde_json::to_string(format).unwrap());
+            assert_eq!(
+                serde_json::from_str::<FilenameFormat>(&json)
+                    .unwrap()
+                    .to_string(),
+                format
+            );
+        }
+        assert!(serde_json::from_str::<FilenameFormat>("\"{title}\"").is_err());
+    }
+}
@@ -0,0 +1,62 @@
+use anyhow::{bail, Context, Result};
+use notify_rust::Notification;
+use std::path::Path;
+use std::process::Command;
+
+use crate::downloader::DownloadReport;
+
+/// run the --notify command through the shell once downloading finished, with the counts of the
+/// run in its environment. a failing command is reported but doesn't change how the run ends
+pub fn run(command: &str, report: &DownloadReport, output_dir: &Path) {
+    if let Err(e) = spawn(command, report, output_dir) {
+        println!("Notify command failed: {:#}", e);
+    }
+}
+
+fn spawn(command: &str, report: &DownloadReport, output_dir: &Path) -> Result<()> {
+    let mut shell = if cfg!(target_os = "windows") {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(command);
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        cmd
+    };
+    let status = shell
+        .env("OSU_DOWNLOADED", report.downloaded.to_string())
+        .env("OSU_FAILED", report.failed.to_string())
+        .env("OSU_BYTES", report.bytes.to_string())
+        .env("OSU_OUTPUT_DIR", output_dir)
+        .status()
+        .context("Failed to start the shell")?;
+
+    if !status.success() {
+        bail!("{} exited with {}", command, status);
+    }
+    Ok(())
+}
+
+/// show a desktop notification with the results of the run for --desktop-notify. does nothing
+/// where no notification service runs, e.g. over SSH
+pub fn desktop(report: &DownloadReport) {
+    let summary = if report.failed > 0 {
+        "Backup finished with failures"
+    } else {
+        "Backup finished"
+    };
+    let mut notification = Notification::new();
+    notification
+        .appname("osu! beatmap downloader")
+        .summary(summary)
+        .body(&format!(
+            "{} new maps, {} failed",
+            report.downloaded, report.failed
+        ));
+    // only the freedesktop notification spec has urgencies
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if report.failed > 0 {
+        notification.urgency(notify_rust::Urgency::Critical);
+    }
+    let _ = notification.show();
+}
@@ -0,0 +1,87 @@
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+// readers for the binary formats osu!stable uses for replays and its .db files
+
+pub fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub fn read_i32(reader: &mut impl Read) -> Result<i32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+
+/// a string in the osu! binary format: 0x00 for none, or 0x0b followed by a ULEB128 length
+pub fn read_string(reader: &mut impl Read) -> Result<Option<String>> {
+    match read_u8(reader)? {
+        0x00 => Ok(None),
+        0x0b => {
+            let mut len = 0usize;
+            let mut shift = 0;
+            loop {
+                let byte = read_u8(reader)?;
+                len |= ((byte & 0x7f) as usize) << shift;
+                if byte & 0x80 == 0 {
+                    break;
+                }
+                shift += 7;
+                if shift > 28 {
+                    bail!("string length too long");
+                }
+            }
+            let mut buf = vec![0; len];
+            reader.read_exact(&mut buf)?;
+            Ok(Some(String::from_utf8(buf)?))
+        }
+        other => bail!("unexpected string marker 0x{:02x}", other),
+    }
+}
+
+/// a beatmap MD5 as stored in replays and collections, lowercased
+pub fn read_hash(reader: &mut impl Read) -> Result<Option<String>> {
+    match read_string(reader)? {
+        Some(hash) if hash.len() == 32 && hash.chars().all(|c| c.is_ascii_hexdigit()) => {
+            Ok(Some(hash.to_ascii_lowercase()))
+        }
+        Some(hash) => bail!("invalid beatmap hash '{}'", hash),
+        None => Ok(None),
+    }
+}
+
+/// a named collection from `collection.db`
+pub struct Collection {
+    pub name: String,
+    pub hashes: Vec<String>,
+}
+
+/// every collection in an osu!stable `collection.db`
+pub fn read_collections(path: &Path) -> Result<Vec<Collection>> {
+    let mut reader = BufReader::new(
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?,
+    );
+    let _version = read_i32(&mut reader)?;
+    let count = read_i32(&mut reader)?;
+
+    let mut collections = Vec::new();
+    for i in 0..count {
+        let name = read_string(&mut reader)?.unwrap_or_default();
+        let maps = read_i32(&mut reader)?;
+        let mut hashes = Vec::new();
+        for _ in 0..maps {
+            // entries can be empty when a map was removed from the collection
+            if let Some(hash) =
+                read_hash(&mut reader).with_context(|| format!("in collection {} '{}'", i, name))?
+            {
+                hashes.push(hash);
+            }
+        }
+        collections.push(Collection { name, hashes });
+    }
+    Ok(collections)
+}
@@ -0,0 +1,412 @@
+use anyhow::{bail, Context, Result};
+use fs2::FileExt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// `path` with `suffix` added to its file name
+pub fn sibling_path(path: &Path, suffix: &str) -> Result<PathBuf> {
+    let file_name = path.file_name().context("path has no file name")?;
+    let mut name = file_name.to_os_string();
+    name.push(suffix);
+    Ok(path.with_file_name(name))
+}
+
+/// where `write_atomic` keeps the previous version of `path`
+pub fn backup_path(path: &Path) -> Result<PathBuf> {
+    sibling_path(path, ".bak")
+}
+
+/// write `contents` to `path` so that a crash never leaves a truncated file behind:
+/// the data goes to a temporary file next to the target, gets synced to disk and is then
+/// renamed over the target, the rename synced as well. the previous version is kept as
+/// `<path>.bak`
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    write_atomic_with(path, |out| Ok(out.write_all(contents)?))
+}
+
+/// `write_atomic` for contents that `write` streams to the file piece by piece, so a large
+/// file is never held in memory as a whole
+pub fn write_atomic_with(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<()>,
+) -> Result<()> {
+    // a name of its own, so two writes of the same file never share the temporary one
+    let (tmp, file) = TempFile::create(path, &format!(".{:08x}.tmp", rand::random::<u32>()))?;
+    let mut out = BufWriter::new(file);
+    write(&mut out).with_context(|| format!("Failed to write {}", tmp.path().display()))?;
+    out.into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("Failed to write {}", tmp.path().display()))?;
+
+    if path.is_file() {
+        // copy rather than rename so the target exists at every point in time
+        fs::copy(path, backup_path(path)?).context("Failed to back up previous version")?;
+    }
+    tmp.commit()
+        .with_context(|| format!("Failed to replace {}", path.display()))?;
+    sync_parent(path).with_context(|| format!("Failed to sync the directory of {}", path.display()))
+}
+
+/// sync the directory entry of `path`, so a rename into it survives a crash. only possible
+/// on unix, where a directory can be opened like a file
+fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// a file written next to its final path under a temporary name. it's deleted when dropped
+/// unless `commit` moved it into place, so an error or a cancelled task never leaves a partial
+/// file behind
+pub struct TempFile {
+    path: PathBuf,
+    target: PathBuf,
+    committed: bool,
+}
+
+impl TempFile {
+    /// create `<target><suffix>`, truncating a leftover from an earlier run
+    pub fn create(target: &Path, suffix: &str) -> Result<(Self, File)> {
+        Self::create_at(sibling_path(target, suffix)?, target)
+    }
+
+    /// create `path` for a file that goes to `target` once committed, truncating a leftover
+    /// from an earlier run
+    pub fn create_at(path: PathBuf, target: &Path) -> Result<(Self, File)> {
+        let file =
+            File::create(&path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let guard = Self {
+            path,
+            target: target.to_path_buf(),
+            committed: false,
+        };
+        Ok((guard, file))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// move the file to `target` instead of the path it was created for
+    pub fn set_target(&mut self, target: PathBuf) {
+        self.target = target;
+    }
+
+    /// move the file to its final path, replacing what's there
+    pub fn commit(mut self) -> Result<()> {
+        fs::rename(&self.path, &self.target)
+            .with_context(|| format!("Failed to move {} into place", self.path.display()))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// before anything is loaded or fetched, make sure the output directory `dir` isn't one of the
+/// beatmap lists given as `inputs` or another file, which would only fail later with a
+/// confusing error
+pub fn check_output_dir(dir: &Path, inputs: &[PathBuf]) -> Result<()> {
+    if let Ok(output) = fs::canonicalize(dir) {
+        for input in inputs {
+            if input.is_file() && fs::canonicalize(input).is_ok_and(|i| i == output) {
+                bail!(
+                    "the output directory {} is the beatmap list {}, pass a directory for the archives with --output",
+                    dir.display(),
+                    input.display()
+                );
+            }
+        }
+    }
+    if dir.is_file() {
+        bail!(
+            "output directory {} is a file, pick another path with --output",
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+/// make sure `dir` is a directory this run can write to, creating it if needed. a path that's
+/// a file, can't be created or can't be written to gets an error saying which
+pub fn ensure_output_dir(dir: &Path) -> Result<()> {
+    // name symlinks along with where they point, that's the directory that gets written to
+    let shown = match fs::symlink_metadata(dir) {
+        Ok(m) if m.file_type().is_symlink() => match fs::canonicalize(dir) {
+            Ok(target) => format!("{} (-> {})", dir.display(), target.display()),
+            Err(_) => bail!(
+                "output directory {} is a symlink to a path that doesn't exist",
+                dir.display()
+            ),
+        },
+        _ => dir.display().to_string(),
+    };
+
+    if let Ok(metadata) = fs::metadata(dir) {
+        if !metadata.is_dir() {
+            let kind = if metadata.is_file() {
+                "a file"
+            } else {
+                "not a regular directory"
+            };
+            bail!(
+                "output directory {} is {}, pick another path with --output",
+                shown,
+                kind
+            );
+        }
+    }
+
+    let explain = |action: &str, e: io::Error| {
+        let reason = match e.kind() {
+            io::ErrorKind::PermissionDenied => "permission denied".to_string(),
+            io::ErrorKind::ReadOnlyFilesystem => "it's on a read-only filesystem".to_string(),
+            _ => e.to_string(),
+        };
+        anyhow::anyhow!("can't {} output directory {}: {}", action, shown, reason)
+    };
+    fs::create_dir_all(dir).map_err(|e| explain("create", e))?;
+    let probe = dir.join(".osu-downloader-write-test");
+    File::create(&probe)
+        .and_then(|_| fs::remove_file(&probe))
+        .map_err(|e| explain("write to", e))?;
+    Ok(())
+}
+
+/// lock file in the output directory, held by the run downloading into it
+pub const LOCK_FILE: &str = ".osu-downloader.lock";
+
+/// an advisory lock on a directory, released when dropped or when the process exits, so a
+/// crashed run never leaves the directory locked
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// lock `dir`, failing if another run holds the lock. with `force` that's only a warning
+    /// and `None` is returned
+    pub fn acquire(dir: &Path, force: bool) -> Result<Option<Self>> {
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+
+        if file.try_lock_exclusive().is_err() {
+            let mut pid = String::new();
+            let _ = file.read_to_string(&mut pid);
+            let holder = match pid.trim() {
+                "" => "another run".to_string(),
+                pid => format!("another run (process {})", pid),
+            };
+            if !force {
+                bail!(
+                    "{} is already downloading into {}, wait for it to finish or pass --force-lock",
+                    holder,
+                    dir.display()
+                );
+            }
+            println!(
+                "Warning: {} is downloading into {} as well (--force-lock)",
+                holder,
+                dir.display()
+            );
+            return Ok(None);
+        }
+
+        // the lock is on the file, not its contents, so truncating it now is safe
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Some(Self { file }))
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+        let _ = FileExt::unlock(&self.file);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{names, temp_dir};
+
+    #[test]
+    fn replaces_and_keeps_a_backup() {
+        let dir = temp_dir("write-atomic");
+        let path = dir.join("maps.json");
+        write_atomic(&path, b"first").unwrap();
+        assert_eq!(names(&dir), ["maps.json"]);
+        write_atomic(&path, b"second").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"second");
+        assert_eq!(fs::read(backup_path(&path).unwrap()).unwrap(), b"first");
+        assert_eq!(names(&dir), ["maps.json", "maps.json.bak"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_writes_leave_the_file_alone() {
+        let dir = temp_dir("write-atomic-fail");
+        let path = dir.join("maps.json");
+        write_atomic(&path, b"kept").unwrap();
+        let result = write_atomic_with(&path, |out| {
+            out.write_all(b"partial")?;
+            bail!("interrupted")
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"kept");
+        assert_eq!(names(&dir), ["maps.json"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes_dont_collide() {
+        let dir = temp_dir("write-atomic-race");
+        let path = dir.join("state.json");
+        let contents: Vec<Vec<u8>> = (0..8).map(|i| vec![b'a' + i; 64 * 1024]).collect();
+        std::thread::scope(|scope| {
+            for contents in &contents {
+                let path = &path;
+                scope.spawn(move || {
+                    for _ in 0..10 {
+                        write_atomic(path, contents).unwrap();
+                    }
+                });
+            }
+        });
+        // whole versions only, and no temporary file left over
+        assert!(contents.contains(&fs::read(&path).unwrap()));
+        assert_eq!(names(&dir), ["state.json", "state.json.bak"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn temp_files_are_removed_unless_committed() {
+        let dir = temp_dir("temp-file");
+        let target = dir.join("1 set.osz");
+        let (dropped, mut file) = TempFile::create(&target, ".part").unwrap();
+        file.write_all(b"half").unwrap();
+        assert_eq!(names(&dir), ["1 set.osz.part"]);
+        drop(dropped);
+        assert_eq!(names(&dir), Vec::<String>::new());
+
+        let (committed, mut file) = TempFile::create(&target, ".part").unwrap();
+        file.write_all(b"whole").unwrap();
+        committed.commit().unwrap();
+        assert_eq!(names(&dir), ["1 set.osz"]);
+        assert_eq!(fs::read(&target).unwrap(), b"whole");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn output_dirs_are_created() {
+        let dir = temp_dir("output-dir").join("new").join("archives");
+        ensure_output_dir(&dir).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(names(&dir), Vec::<String>::new());
+        fs::remove_dir_all(dir.parent().unwrap().parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn output_dirs_that_are_files_are_refused() {
+        let dir = temp_dir("output-file");
+        let file = dir.join("maps.json");
+        fs::write(&file, "[]").unwrap();
+        let error = ensure_output_dir(&file).unwrap_err().to_string();
+        assert_eq!(
+            error,
+            format!(
+                "output directory {} is a file, pick another path with --output",
+                file.display()
+            )
+        );
+
+        // and nothing can be created below it either
+        let below = file.join("archives");
+        let error = ensure_output_dir(&below).unwrap_err().to_string();
+        assert!(
+            error.starts_with(&format!(
+                "can't create output directory {}: ",
+                below.display()
+            )),
+            "{}",
+            error
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_dir_symlinks_name_their_target() {
+        let dir = temp_dir("output-symlink");
+        let target = dir.join("real");
+        fs::write(&target, "").unwrap();
+        let link = dir.join("link");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+        let error = ensure_output_dir(&link).unwrap_err().to_string();
+        assert!(
+            error.contains(&format!("{} (-> {})", link.display(), target.display())),
+            "{}",
+            error
+        );
+
+        let dangling = dir.join("dangling");
+        std::os::unix::fs::symlink(dir.join("gone"), &dangling).unwrap();
+        let error = ensure_output_dir(&dangling).unwrap_err().to_string();
+        assert!(
+            error.ends_with("is a symlink to a path that doesn't exist"),
+            "{}",
+            error
+        );
+
+        fs::remove_file(&target).unwrap();
+        fs::create_dir(&target).unwrap();
+        ensure_output_dir(&link).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn read_only_output_dirs_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("output-read-only");
+        let read_only = dir.join("archives");
+        fs::create_dir(&read_only).unwrap();
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555)).unwrap();
+        // root writes whatever the permissions say, there's nothing to check then
+        if File::create(read_only.join("probe")).is_err() {
+            let error = ensure_output_dir(&read_only).unwrap_err().to_string();
+            assert_eq!(
+                error,
+                format!(
+                    "can't write to output directory {}: permission denied",
+                    read_only.display()
+                )
+            );
+        }
+        fs::set_permissions(&read_only, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
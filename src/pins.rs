@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+use clap::Args;
+use indicatif::ProgressBar;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::persist;
+use crate::progress;
+
+// certificate pins per mirror host, inside the beatmap directory
+const PINS_FILE: &str = ".mirror_pins.json";
+
+/// trust on first use pinning of the mirrors' certificates
+#[derive(Args, Debug, Clone, Default)]
+pub struct PinOptions {
+    /// remember the public key of each mirror's certificate the first time it's seen and refuse
+    /// to download from it once it doesn't match anymore
+    #[arg(long)]
+    pub pin_certs: bool,
+    /// with --pin-certs, only warn about a changed certificate and download anyway
+    #[arg(long, requires = "pin_certs")]
+    pub pin_warn_only: bool,
+}
+
+/// the recorded pins, the SHA-256 of the public key (SPKI) of the certificate a host sent first
+struct Pins {
+    path: PathBuf,
+    hosts: Mutex<BTreeMap<String, String>>,
+    warn_only: bool,
+    warned: Mutex<HashSet<String>>,
+    // hosts pinned and warnings raised during handshakes, saved and printed by `PinStore::flush`
+    // so the handshake never waits on the disk or draws over the progress bars
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    unsaved: bool,
+    notices: Vec<String>,
+}
+
+impl Pins {
+    fn load(dir: &Path, warn_only: bool) -> Self {
+        let path = dir.join(PINS_FILE);
+        let hosts = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        Self {
+            path,
+            hosts: Mutex::new(hosts),
+            warn_only,
+            warned: Mutex::new(HashSet::new()),
+            pending: Mutex::new(Pending::default()),
+        }
+    }
+
+    /// compare the key a host sent with its pin, pinning it when the host is new
+    fn check(&self, host: &str, key: String) -> Result<(), rustls::Error> {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get(host) {
+            Some(pinned) if *pinned == key => Ok(()),
+            Some(_) if self.warn_only => {
+                if self.warned.lock().unwrap().insert(host.to_string()) {
+                    self.pending.lock().unwrap().notices.push(format!(
+                        "Warning: the certificate of {} changed since it was pinned, downloading anyway (--pin-warn-only)",
+                        host
+                    ));
+                }
+                Ok(())
+            }
+            Some(_) => Err(rustls::Error::General(format!(
+                "the certificate of {} changed since it was pinned, run `pins reset {}` if that's expected",
+                host, host
+            ))),
+            None => {
+                hosts.insert(host.to_string(), key);
+                let mut pending = self.pending.lock().unwrap();
+                pending.unsaved = true;
+                pending.notices.push(format!("Pinned the certificate of {}", host));
+                Ok(())
+            }
+        }
+    }
+
+    /// write the pins when a host was pinned since the last save
+    fn save(&self) -> Result<()> {
+        if !std::mem::take(&mut self.pending.lock().unwrap().unsaved) {
+            return Ok(());
+        }
+        let json = serde_json::to_string_pretty(&*self.hosts.lock().unwrap())?;
+        persist::write_atomic(&self.path, json.as_bytes())
+            .with_context(|| format!("Failed to save the pins to {}", self.path.display()))
+    }
+}
+
+/// the pins used by the client of a --pin-certs run
+pub struct PinStore(Arc<Pins>);
+
+impl PinStore {
+    /// save the hosts pinned since the last call and print what happened during the handshakes
+    pub fn flush(&self, pb: &ProgressBar) {
+        let notices = std::mem::take(&mut self.0.pending.lock().unwrap().notices);
+        for notice in notices {
+            progress::println(pb, notice);
+        }
+        // a failed save is tried again with the next host pinned, which writes them all
+        if let Err(e) = self.0.save() {
+            progress::println(pb, format!("{:#}", e));
+        }
+    }
+}
+
+/// the usual certificate checks, then the pins
+struct PinningVerifier {
+    webpki: WebPkiVerifier,
+    pins: Arc<Pins>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.webpki.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let host = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => ip.to_string(),
+            _ => {
+                return Err(rustls::Error::General(
+                    "unsupported server name".to_string(),
+                ))
+            }
+        };
+        self.pins.check(&host, spki_hash(end_entity)?)?;
+        Ok(verified)
+    }
+}
+
+/// hex SHA-256 of the certificate's SubjectPublicKeyInfo
+fn spki_hash(cert: &Certificate) -> Result<String, rustls::Error> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(&cert.0)
+        .map_err(|_| rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding))?;
+    let digest = Sha256::digest(parsed.public_key().raw);
+    Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// the TLS setup of the mirror client with --pin-certs, checking against the pins kept in
+/// `dir`, and the pins to flush while it's used. without it the client keeps the system's TLS
+pub fn tls_config(dir: &Path, opts: &PinOptions) -> Option<(ClientConfig, PinStore)> {
+    if !opts.pin_certs {
+        return None;
+    }
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    let pins = Arc::new(Pins::load(dir, opts.pin_warn_only));
+    let verifier = PinningVerifier {
+        webpki: WebPkiVerifier::new(roots, None),
+        pins: pins.clone(),
+    };
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Some((config, PinStore(pins)))
+}
+
+/// forget the pin of `host` so the next --pin-certs run pins its current certificate. returns
+/// whether it had one
+pub fn reset(dir: &Path, host: &str) -> Result<bool> {
+    let pins = Pins::load(dir, false);
+    let mut hosts = pins.hosts.into_inner().unwrap();
+    if hosts.remove(host).is_none() {
+        return Ok(false);
+    }
+    persist::write_atomic(&pins.path, serde_json::to_string_pretty(&hosts)?.as_bytes())
+        .with_context(|| format!("Failed to write {}", pins.path.display()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_dir;
+
+    #[test]
+    fn pins_new_hosts_and_saves_them_on_flush() {
+        let dir = temp_dir("pins");
+        let store = PinStore(Arc::new(Pins::load(&dir, false)));
+        store.0.check("catboy.best", "leaf".to_string()).unwrap();
+        // nothing is written during the handshake
+        assert!(!dir.join(PINS_FILE).exists());
+
+        store.flush(&ProgressBar::hidden());
+        let pins = Pins::load(&dir, false);
+        assert_eq!(
+            pins.hosts
+                .lock()
+                .unwrap()
+                .get("catboy.best")
+                .map(String::as_str),
+            Some("leaf")
+        );
+        pins.check("catboy.best", "leaf".to_string()).unwrap();
+        assert!(pins.check("catboy.best", "other".to_string()).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn warn_only_lets_changed_keys_through_once_noted() {
+        let dir = temp_dir("pins-warn");
+        let pins = Pins::load(&dir, true);
+        pins.check("catboy.best", "leaf".to_string()).unwrap();
+        pins.check("catboy.best", "other".to_string()).unwrap();
+        pins.check("catboy.best", "another".to_string()).unwrap();
+        let notices = &pins.pending.lock().unwrap().notices;
+        assert_eq!(notices.len(), 2);
+        assert!(notices[1].starts_with("Warning: the certificate of catboy.best changed"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::downloader::{self, DownloadOptions, UrlRewrite};
+use crate::exit::Failure;
+use crate::mirror::Mirror;
+use crate::naming::{FilenameFormat, ModeTokenPolicy};
+use crate::persist;
+use crate::types::{ArchiveExtension, BeatmapInfo};
+
+const PLAN_VERSION: u32 = 1;
+
+/// a download run worked out ahead of time by `plan`, executed by `download --plan` without
+/// scanning or filtering again
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    /// output directory the plan was made for
+    pub dir: PathBuf,
+    pub options: PlanOptions,
+    pub entries: Vec<PlanEntry>,
+}
+
+/// the options that decide what a run downloads, where to and from where
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanOptions {
+    /// in the order they're tried
+    mirrors: Vec<Mirror>,
+    balance: bool,
+    extension: ArchiveExtension,
+    filename_format: FilenameFormat,
+    dir_template: Option<String>,
+    mode_token_policy: ModeTokenPolicy,
+    url_rewrite: Vec<String>,
+    max_age_secs: Option<u64>,
+    min_valid_size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanEntry {
+    /// archive path relative to the output directory
+    pub path: PathBuf,
+    /// download URL on each mirror, in the order they're tried
+    pub urls: Vec<String>,
+    /// the set has an archive older than --max-age that gets replaced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub refresh: bool,
+    pub beatmap: BeatmapInfo,
+}
+
+impl PlanOptions {
+    fn of(mirrors: Vec<Mirror>, opts: &DownloadOptions) -> Self {
+        Self {
+            mirrors,
+            balance: opts.balance,
+            extension: opts.naming.extension,
+            filename_format: opts.naming.filename_format.clone(),
+            dir_template: opts.naming.dir_template.clone(),
+            mode_token_policy: opts.naming.mode_token_policy,
+            url_rewrite: opts
+                .url_rewrite
+                .iter()
+                .map(|r| r.rule().to_string())
+                .collect(),
+            max_age_secs: opts.max_age.map(|age| age.as_secs()),
+            min_valid_size: opts.min_valid_size,
+        }
+    }
+
+    /// `opts` with the recorded options in place of the given ones
+    pub fn apply(&self, opts: &DownloadOptions) -> Result<DownloadOptions> {
+        let mut opts = opts.clone();
+        opts.mirrors = self.mirrors.clone();
+        opts.balance = self.balance;
+        // the mirrors were put in order when planning
+        opts.prefer_reliable = false;
+        opts.naming.extension = self.extension;
+        opts.naming.filename_format = self.filename_format.clone();
+        opts.naming.dir_template = self.dir_template.clone();
+        opts.naming.mode_token_policy = self.mode_token_policy;
+        opts.url_rewrite = self
+            .url_rewrite
+            .iter()
+            .map(|rule| UrlRewrite::parse(rule))
+            .collect::<Result<_, _>>()
+            .map_err(anyhow::Error::msg)
+            .context(Failure::InvalidInput)?;
+        opts.max_age = self.max_age_secs.map(Duration::from_secs);
+        opts.min_valid_size = self.min_valid_size;
+        Ok(opts)
+    }
+}
+
+fn planned_urls(mirrors: &[Mirror], beatmap: &BeatmapInfo, opts: &DownloadOptions) -> Vec<String> {
+    mirrors
+        .iter()
+        .map(|mirror| {
+            UrlRewrite::apply(
+                &opts.url_rewrite,
+                mirror.download_url(beatmap.beatmapset_id),
+            )
+        })
+        .collect()
+}
+
+/// work out which of `maps` are missing from `dir` and where each of them goes
+pub fn create(maps: &[BeatmapInfo], dir: &Path, opts: &DownloadOptions) -> Result<Plan> {
+    persist::ensure_output_dir(dir)?;
+    let (mirrors, _) = downloader::mirror_order(dir, opts);
+    let (missing, refresh) = downloader::scan_missing(maps, dir, opts)?;
+
+    let entries = missing
+        .into_iter()
+        .map(|beatmap| PlanEntry {
+            path: opts.naming.relative_path(beatmap, opts.naming.extension),
+            urls: planned_urls(&mirrors, beatmap, opts),
+            refresh: refresh.contains(&beatmap.beatmapset_id),
+            beatmap: beatmap.clone(),
+        })
+        .collect();
+
+    Ok(Plan {
+        version: PLAN_VERSION,
+        dir: dir.to_path_buf(),
+        options: PlanOptions::of(mirrors, opts),
+        entries,
+    })
+}
+
+pub fn save(plan: &Plan, path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(plan)?;
+    persist::write_atomic(path, json.as_bytes())
+}
+
+pub fn load(path: &Path) -> Result<Plan> {
+    let json = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {}", path.display()))
+        .context(Failure::InvalidInput)?;
+    let plan: Plan = serde_json::from_str(&json)
+        .with_context(|| format!("{} is not a valid plan", path.display()))
+        .context(Failure::InvalidInput)?;
+    if plan.version != PLAN_VERSION {
+        return Err(anyhow::anyhow!(
+            "{} has plan version {}, expected {}",
+            path.display(),
+            plan.version,
+            PLAN_VERSION
+        ))
+        .context(Failure::InvalidInput);
+    }
+    Ok(plan)
+}
+
+impl Plan {
+    pub fn beatmaps(&self) -> Vec<BeatmapInfo> {
+        self.entries.iter().map(|e| e.beatmap.clone()).collect()
+    }
+
+    pub fn refresh(&self) -> HashSet<u32> {
+        self.entries
+            .iter()
+            .filter(|e| e.refresh)
+            .map(|e| e.beatmap.beatmapset_id)
+            .collect()
+    }
+
+    /// make sure executing the plan with `opts` (from `PlanOptions::apply`) downloads exactly
+    /// what was planned, and that `dir` doesn't already have archives it would download
+    pub fn check(&self, dir: &Path, opts: &DownloadOptions) -> Result<()> {
+        for entry in &self.entries {
+            let path = opts
+                .naming
+                .relative_path(&entry.beatmap, opts.naming.extension);
+            if path != entry.path || planned_urls(&opts.mirrors, &entry.beatmap, opts) != entry.urls
+            {
+                return Err(anyhow::anyhow!(
+                    "the plan for {} doesn't match what this version would download, make the plan again",
+                    entry.beatmap.beatmapset_id
+                ))
+                .context(Failure::InvalidInput);
+            }
+        }
+
+        let present: Vec<&PlanEntry> = self
+            .entries
+            .iter()
+            .filter(|e| !e.refresh)
+            .filter(|e| {
+                std::fs::metadata(dir.join(&e.path))
+                    .is_ok_and(|m| m.len() >= opts.min_valid_size.max(1))
+            })
+            .collect();
+        if !present.is_empty() {
+            for entry in present.iter().take(10) {
+                println!("  already present: {}", entry.path.display());
+            }
+            if present.len() > 10 {
+                println!("  ...and {} more", present.len() - 10);
+            }
+            return Err(anyhow::anyhow!(
+                "{} planned archives already exist in {}, pass --replan to scan it and download what's missing",
+                present.len(),
+                dir.display()
+            ))
+            .context(Failure::InvalidInput);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use crate::types::BeatmapInfo;
+
+// how many maps each list of the report shows
+const SHOWN: usize = 20;
+
+/// list the maps whose play count grew the most between the saved list and a fresh fetch, and
+/// the maps that weren't in the saved list at all
+pub fn print_updates(saved: &[BeatmapInfo], fetched: &[BeatmapInfo]) {
+    let before: HashMap<u32, u32> = saved.iter().map(|m| (m.beatmap_id, m.play_count)).collect();
+
+    let mut grown: Vec<(&BeatmapInfo, u32)> = fetched
+        .iter()
+        .filter_map(|m| {
+            let increase = m.play_count.saturating_sub(*before.get(&m.beatmap_id)?);
+            (increase > 0).then_some((m, increase))
+        })
+        .collect();
+    grown.sort_by_key(|(m, increase)| {
+        (
+            std::cmp::Reverse(*increase),
+            std::cmp::Reverse(m.play_count),
+        )
+    });
+
+    let mut new: Vec<&BeatmapInfo> = fetched
+        .iter()
+        .filter(|m| !before.contains_key(&m.beatmap_id))
+        .collect();
+    new.sort_by_key(|m| std::cmp::Reverse(m.play_count));
+
+    if grown.is_empty() {
+        println!("\nNo plays since the last fetch");
+    } else {
+        println!("\nMost played since the last fetch:");
+        for (rank, (map, increase)) in grown.iter().take(SHOWN).enumerate() {
+            println!(
+                "{:>4}. +{:<5} {} - {} [{}] ({} plays)",
+                rank + 1,
+                increase,
+                map.artist,
+                map.title,
+                map.version,
+                map.play_count
+            );
+        }
+        print_more(grown.len());
+    }
+
+    if !new.is_empty() {
+        println!("\nNew since the last fetch:");
+        for (rank, map) in new.iter().take(SHOWN).enumerate() {
+            println!(
+                "{:>4}. {} - {} [{}] ({} plays)",
+                rank + 1,
+                map.artist,
+                map.title,
+                map.version,
+                map.play_count
+            );
+        }
+        print_more(new.len());
+    }
+    println!();
+}
+
+fn print_more(len: usize) {
+    if len > SHOWN {
+        println!("      ...and {} more", len - SHOWN);
+    }
+}
@@ -0,0 +1,44 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::io::IsTerminal;
+
+/// bar style from a template, falling back to the default style if the template doesn't parse
+pub fn bar_style(template: &str) -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_bar())
+        .progress_chars("#>-")
+}
+
+pub fn spinner_style(template: &str) -> ProgressStyle {
+    ProgressStyle::default_spinner()
+        .template(template)
+        .unwrap_or_else(|_| ProgressStyle::default_spinner())
+}
+
+/// bars drawn on stderr when it's a terminal and hidden otherwise (CI logs, pipes), in which
+/// case `println` and `status` fall back to plain lines
+pub fn multi() -> MultiProgress {
+    if std::io::stderr().is_terminal() {
+        MultiProgress::new()
+    } else {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    }
+}
+
+/// print a line above the bars, or straight to stdout when they aren't drawn
+pub fn println(pb: &ProgressBar, msg: impl AsRef<str>) {
+    if pb.is_hidden() {
+        println!("{}", msg.as_ref());
+    } else {
+        pb.println(msg);
+    }
+}
+
+/// show a message on the bar, printed as a line when it isn't drawn
+pub fn status(pb: &ProgressBar, msg: impl Into<String>) {
+    let msg = msg.into();
+    if pb.is_hidden() && !msg.is_empty() {
+        println!("{}", msg);
+    }
+    pb.set_message(msg);
+}
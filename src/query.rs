@@ -0,0 +1,473 @@
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::types::BeatmapInfo;
+
+/// a filter expression from a --map-filter-file, e.g.
+/// `play_count > 100 AND (artist CONTAINS "Camellia" OR stars >= 6)`
+#[derive(Debug)]
+pub enum Query {
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+    Compare(Field, Op, Literal),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    BeatmapId,
+    BeatmapsetId,
+    Title,
+    Artist,
+    Version,
+    PlayCount,
+    Creator,
+    Length,
+    Bpm,
+    Mode,
+    Stars,
+    MaxStars,
+    Difficulties,
+    Status,
+    Genre,
+    Language,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    Text(String),
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("beatmap_id", Field::BeatmapId),
+    ("beatmapset_id", Field::BeatmapsetId),
+    ("title", Field::Title),
+    ("artist", Field::Artist),
+    ("version", Field::Version),
+    ("play_count", Field::PlayCount),
+    ("creator", Field::Creator),
+    ("length", Field::Length),
+    ("bpm", Field::Bpm),
+    ("mode", Field::Mode),
+    ("stars", Field::Stars),
+    ("max_stars", Field::MaxStars),
+    ("difficulties", Field::Difficulties),
+    ("status", Field::Status),
+    ("genre", Field::Genre),
+    ("language", Field::Language),
+];
+
+impl Field {
+    /// whether `map` lacks the field, e.g. in a list fetched by an older version
+    pub fn is_missing(self, map: &BeatmapInfo) -> bool {
+        self.value(map).is_none()
+    }
+
+    /// the field's value for `map`, `None` when the list doesn't have it
+    fn value(self, map: &BeatmapInfo) -> Option<Literal> {
+        let number = |n: f64| Some(Literal::Number(n));
+        let text = |s: &str| Some(Literal::Text(s.to_string()));
+        // genre and language compare by the names they have in the JSON
+        let name = |v: serde_json::Value| v.as_str().map(|s| Literal::Text(s.to_string()));
+        match self {
+            Field::BeatmapId => number(map.beatmap_id as f64),
+            Field::BeatmapsetId => number(map.beatmapset_id as f64),
+            Field::Title => text(&map.title),
+            Field::Artist => text(&map.artist),
+            Field::Version => text(&map.version),
+            Field::PlayCount => number(map.play_count as f64),
+            Field::Creator => map.creator.as_deref().and_then(text),
+            Field::Length => map.length_seconds.and_then(|l| number(l as f64)),
+            Field::Bpm => map.bpm.and_then(|b| number(b as f64)),
+            Field::Mode => map.mode.as_deref().and_then(text),
+            Field::Stars => map.stars.and_then(|s| number(s as f64)),
+            Field::MaxStars => map.max_stars().and_then(|s| number(s as f64)),
+            Field::Difficulties => match map.difficulties.len() {
+                0 => map
+                    .set_summary
+                    .as_ref()
+                    .and_then(|s| number(s.difficulties as f64)),
+                n => number(n as f64),
+            },
+            Field::Status => map.status.as_deref().and_then(text),
+            Field::Genre => map.genre.and_then(|g| name(serde_json::to_value(g).ok()?)),
+            Field::Language => map
+                .language
+                .and_then(|l| name(serde_json::to_value(l).ok()?)),
+        }
+    }
+}
+
+impl Query {
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&source)
+            .map_err(|e| anyhow::anyhow!("invalid filter in {}: {:#}", path.display(), e))
+    }
+
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+            depth: 0,
+        };
+        let query = parser.or()?;
+        if let Some(token) = parser.peek() {
+            bail!("unexpected {} after the end of the expression", token);
+        }
+        Ok(query)
+    }
+
+    /// whether `map` matches. a comparison with a field the map doesn't have is false
+    pub fn matches(&self, map: &BeatmapInfo) -> bool {
+        match self {
+            Query::And(a, b) => a.matches(map) && b.matches(map),
+            Query::Or(a, b) => a.matches(map) || b.matches(map),
+            Query::Not(q) => !q.matches(map),
+            Query::Compare(field, op, literal) => field
+                .value(map)
+                .is_some_and(|value| compare(&value, *op, literal)),
+        }
+    }
+
+    /// the fields the expression looks at
+    pub fn fields(&self) -> Vec<Field> {
+        match self {
+            Query::And(a, b) | Query::Or(a, b) => [a.fields(), b.fields()].concat(),
+            Query::Not(q) => q.fields(),
+            Query::Compare(field, _, _) => vec![*field],
+        }
+    }
+}
+
+fn compare(value: &Literal, op: Op, literal: &Literal) -> bool {
+    match (value, literal) {
+        (Literal::Number(a), Literal::Number(b)) => match op {
+            Op::Eq => a == b,
+            Op::Ne => a != b,
+            Op::Lt => a < b,
+            Op::Le => a <= b,
+            Op::Gt => a > b,
+            Op::Ge => a >= b,
+            Op::Contains => a.to_string().contains(&b.to_string()),
+        },
+        // text compares case-insensitively, a number is compared as it's written
+        (Literal::Text(a), b) => {
+            let a = a.to_lowercase();
+            let b = match b {
+                Literal::Text(b) => b.to_lowercase(),
+                Literal::Number(b) => b.to_string(),
+            };
+            match op {
+                Op::Eq => a == b,
+                Op::Ne => a != b,
+                Op::Lt => a < b,
+                Op::Le => a <= b,
+                Op::Gt => a > b,
+                Op::Ge => a >= b,
+                Op::Contains => a.contains(&b),
+            }
+        }
+        (Literal::Number(_), Literal::Text(_)) => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Text(String),
+    Number(f64),
+    Op(Op),
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "'{}'", w),
+            Token::Text(s) => write!(f, "\"{}\"", s),
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Op(op) => write!(f, "{:?}", op),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = source.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            // comments run to the end of the line
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '(' | ')' => {
+                chars.next();
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            '"' => {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => text.extend(chars.next()),
+                        Some(c) => text.push(c),
+                        None => bail!("unterminated string \"{}", text),
+                    }
+                }
+                tokens.push(Token::Text(text));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                tokens.push(Token::Op(match (c, eq) {
+                    ('=', _) => Op::Eq,
+                    ('!', true) => Op::Ne,
+                    ('<', false) => Op::Lt,
+                    ('<', true) => Op::Le,
+                    ('>', false) => Op::Gt,
+                    ('>', true) => Op::Ge,
+                    _ => bail!("unknown operator '!', use != or NOT"),
+                }));
+            }
+            c if c.is_ascii_digit() || c == '-' || c == '.' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_ascii_digit() || *c == '-' || *c == '.')
+                {
+                    number.push(c);
+                }
+                tokens.push(Token::Number(
+                    number
+                        .parse()
+                        .with_context(|| format!("invalid number {}", number))?,
+                ));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| c.is_alphanumeric() || *c == '_') {
+                    word.push(c);
+                }
+                tokens.push(match word.to_ascii_uppercase().as_str() {
+                    "CONTAINS" => Token::Op(Op::Contains),
+                    _ => Token::Word(word),
+                });
+            }
+            c => bail!("unexpected character '{}'", c),
+        }
+    }
+    Ok(tokens)
+}
+
+/// `parts` (at least one) joined by `join` as a balanced tree, in order, so a long chain like
+/// hundreds of IDs joined with OR doesn't nest as deep as it's long
+fn balanced(mut parts: Vec<Query>, join: fn(Box<Query>, Box<Query>) -> Query) -> Query {
+    while parts.len() > 1 {
+        let mut joined = Vec::with_capacity(parts.len().div_ceil(2));
+        let mut rest = parts.into_iter();
+        while let Some(a) = rest.next() {
+            joined.push(match rest.next() {
+                Some(b) => join(Box::new(a), Box::new(b)),
+                None => a,
+            });
+        }
+        parts = joined;
+    }
+    parts.pop().expect("an expression has at least one part")
+}
+
+/// how deep parentheses and NOTs may nest, so a malformed file can't overflow the stack
+const MAX_NESTING: usize = 64;
+
+/// recursive descent over the tokens, NOT binds tighter than AND, AND tighter than OR
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// parentheses and NOTs around the current token
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword));
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    /// `parse` one level of nesting deeper
+    fn nested(&mut self, parse: impl FnOnce(&mut Self) -> Result<Query>) -> Result<Query> {
+        if self.depth >= MAX_NESTING {
+            bail!("parentheses and NOTs nest more than {} deep", MAX_NESTING);
+        }
+        self.depth += 1;
+        let query = parse(self);
+        self.depth -= 1;
+        query
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut parts = vec![self.and()?];
+        while self.keyword("OR") {
+            parts.push(self.and()?);
+        }
+        Ok(balanced(parts, Query::Or))
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut parts = vec![self.not()?];
+        while self.keyword("AND") {
+            parts.push(self.not()?);
+        }
+        Ok(balanced(parts, Query::And))
+    }
+
+    fn not(&mut self) -> Result<Query> {
+        if self.keyword("NOT") {
+            return Ok(Query::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Query> {
+        match self.next() {
+            Some(Token::Open) => {
+                let query = self.nested(Self::or)?;
+                match self.next() {
+                    Some(Token::Close) => Ok(query),
+                    Some(token) => bail!("expected ')', found {}", token),
+                    None => bail!("missing ')' at the end"),
+                }
+            }
+            Some(Token::Word(name)) => {
+                let field = FIELDS
+                    .iter()
+                    .find(|(n, _)| n.eq_ignore_ascii_case(&name))
+                    .map(|(_, f)| *f)
+                    .with_context(|| {
+                        let names: Vec<&str> = FIELDS.iter().map(|(n, _)| *n).collect();
+                        format!("unknown field '{}', use one of {}", name, names.join(", "))
+                    })?;
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    Some(token) => bail!("expected a comparison after {}, found {}", name, token),
+                    None => bail!("expected a comparison after {}", name),
+                };
+                let literal = match self.next() {
+                    Some(Token::Number(n)) => Literal::Number(n),
+                    Some(Token::Text(s)) => Literal::Text(s),
+                    // bare words like `mode = mania`
+                    Some(Token::Word(w)) => Literal::Text(w),
+                    Some(token) => {
+                        bail!("expected a value after {} {:?}, found {}", name, op, token)
+                    }
+                    None => bail!("expected a value after {} {:?}", name, op),
+                };
+                Ok(Query::Compare(field, op, literal))
+            }
+            Some(token) => bail!("expected a field or '(', found {}", token),
+            None => bail!("the expression is empty or ends early"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn map(artist: &str, play_count: u32, stars: Option<f32>) -> BeatmapInfo {
+        BeatmapInfo {
+            artist: artist.to_string(),
+            play_count,
+            stars,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_expression() {
+        let query = Query::parse(
+            r#"play_count > 100 AND (artist CONTAINS "camellia" OR stars >= 6) # comment"#,
+        )
+        .unwrap();
+        assert!(query.matches(&map("Camellia", 150, None)));
+        assert!(query.matches(&map("xi", 150, Some(6.5))));
+        assert!(!query.matches(&map("xi", 150, Some(5.0))));
+        assert!(!query.matches(&map("Camellia", 50, None)));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let query = Query::parse("NOT stars > 5 AND play_count = 1").unwrap();
+        assert!(query.matches(&map("", 1, Some(4.0))));
+        assert!(!query.matches(&map("", 1, Some(6.0))));
+        // a missing field makes the comparison false, so NOT keeps the map
+        assert!(query.matches(&map("", 1, None)));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for source in [
+            "",
+            "stars >",
+            "(stars > 5",
+            "stars > 5)",
+            "bogus = 1",
+            "stars ! 5",
+            "title = \"open",
+        ] {
+            assert!(Query::parse(source).is_err(), "{}", source);
+        }
+    }
+
+    #[test]
+    fn caps_nesting() {
+        let deep = |n: usize| format!("{}stars > 5{}", "(".repeat(n), ")".repeat(n));
+        assert!(Query::parse(&deep(MAX_NESTING)).is_ok());
+        let e = Query::parse(&deep(100_000)).unwrap_err();
+        assert!(e.to_string().contains("nest more than"), "{}", e);
+        assert!(Query::parse(&format!("{}stars > 5", "NOT ".repeat(100_000))).is_err());
+    }
+
+    #[test]
+    fn long_chains_stay_shallow() {
+        let ids: Vec<String> = (1..=100_000)
+            .map(|id| format!("beatmapset_id = {}", id))
+            .collect();
+        let query = Query::parse(&ids.join(" OR ")).unwrap();
+        let set = |id| BeatmapInfo {
+            beatmapset_id: id,
+            ..Default::default()
+        };
+        assert!(query.matches(&set(99_999)));
+        assert!(!query.matches(&set(100_001)));
+    }
+}
@@ -0,0 +1,405 @@
+use anyhow::{bail, ensure, Context, Result};
+use std::collections::HashSet;
+use std::path::Path;
+
+// a reader for just enough of realm's file format (osu!lazer's client.realm) to list the
+// beatmap sets it has: the group's tables, the column spec of one table and the clusters
+// holding its rows. anything it doesn't recognise (a newer file format, compressed arrays,
+// a schema without the columns) is an error, so callers fall back to not knowing the sets
+
+/// the table lazer keeps beatmap sets in
+const SET_TABLE: &str = "class_BeatmapSet";
+/// the oldest and newest realm file formats the reader knows
+const FORMATS: std::ops::RangeInclusive<u8> = 20..=24;
+/// the end of a file written in streaming form, after its top ref
+const STREAMING_COOKIE: u64 = 0x3034_1252_37E5_26C8;
+/// deeper cluster trees would need billions of rows, so this only stops a corrupt file
+const MAX_DEPTH: usize = 16;
+
+/// positions in the group, table and spec arrays and the parts of a column key
+const GROUP_TABLE_NAMES: usize = 0;
+const GROUP_TABLES: usize = 1;
+const TABLE_SPEC: usize = 0;
+const TABLE_CLUSTERS: usize = 2;
+const SPEC_NAMES: usize = 1;
+const SPEC_COLUMN_KEYS: usize = 5;
+const COLUMN_TYPE_INT: i64 = 0;
+const COLUMN_TYPE_BOOL: i64 = 1;
+const COLUMN_NULLABLE: i64 = 16;
+
+/// the online IDs of the beatmap sets imported into osu!lazer with its data at `data_dir`,
+/// without the ones pending deletion and the ones not on osu! (e.g. a set being edited)
+pub fn imported_sets(data_dir: &Path) -> Result<HashSet<u32>> {
+    let path = data_dir.join("client.realm");
+    // read once and parsed from memory, lazer only ever appends while running
+    let data =
+        std::fs::read(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+    Realm::open(&data)?.sets()
+}
+
+struct Realm<'a> {
+    data: &'a [u8],
+    top: u64,
+}
+
+/// an array of the file, with its payload
+struct Node<'a> {
+    payload: &'a [u8],
+    width: usize,
+    size: usize,
+    inner: bool,
+}
+
+/// a column of the set table, found by name in its spec
+struct Column {
+    /// position of its leaf in each cluster, after the keys
+    leaf: usize,
+    nullable: bool,
+}
+
+impl<'a> Realm<'a> {
+    fn open(data: &'a [u8]) -> Result<Self> {
+        ensure!(
+            data.len() >= 24 && &data[16..20] == b"T-DB",
+            "not a realm file"
+        );
+        let select = usize::from(data[23] & 1);
+        let format = data[20 + select];
+        ensure!(
+            FORMATS.contains(&format),
+            "realm file format {} isn't supported (this version reads {} to {}), osu!lazer may be newer",
+            format,
+            FORMATS.start(),
+            FORMATS.end()
+        );
+
+        let mut top = read_u64(data, select * 8)?;
+        if top == u64::MAX {
+            let footer = data.len().checked_sub(16).context("truncated realm file")?;
+            ensure!(
+                read_u64(data, footer + 8)? == STREAMING_COOKIE,
+                "truncated realm file"
+            );
+            top = read_u64(data, footer)?;
+        }
+        Ok(Self { data, top })
+    }
+
+    fn node(&self, r: u64) -> Result<Node<'a>> {
+        ensure!(r != 0 && r.is_multiple_of(8), "invalid ref {}", r);
+        let start = usize::try_from(r)?;
+        let header = self
+            .data
+            .get(start..start + 8)
+            .context("ref past the end of the file")?;
+        ensure!(&header[..4] == b"AAAA", "no array at {}", r);
+
+        let flags = header[4];
+        let width = (1usize << (flags & 0x07)) >> 1;
+        let size = u32::from_be_bytes([0, header[5], header[6], header[7]]) as usize;
+        let bytes = match (flags & 0x18) >> 3 {
+            // bits per element
+            0 => (size * width).div_ceil(8),
+            // bytes per element
+            1 => size * width,
+            // bytes in total
+            2 => size,
+            encoding => bail!(
+                "array encoding {} isn't supported, osu!lazer may be newer",
+                encoding
+            ),
+        };
+        let payload = self
+            .data
+            .get(start + 8..start + 8 + bytes)
+            .context("array past the end of the file")?;
+        Ok(Node {
+            payload,
+            width,
+            size,
+            inner: flags & 0x80 != 0,
+        })
+    }
+
+    /// the node the `index`th element of `node` refers to
+    fn child(&self, node: &Node, index: usize) -> Result<Node<'a>> {
+        let r = node.get(index)?;
+        ensure!(r > 0 && r % 2 == 0, "element {} isn't a ref", index);
+        self.node(r as u64)
+    }
+
+    fn sets(&self) -> Result<HashSet<u32>> {
+        let group = self.node(self.top)?;
+        let names = self.child(&group, GROUP_TABLE_NAMES)?;
+        let index = (0..names.size)
+            .find(|&i| names.string(i).ok().flatten() == Some(SET_TABLE.as_bytes()))
+            .context("client.realm has no beatmap sets table")?;
+        let table = self.child(&self.child(&group, GROUP_TABLES)?, index)?;
+
+        let spec = self.child(&table, TABLE_SPEC)?;
+        let online_id = self
+            .column(&spec, "OnlineID", COLUMN_TYPE_INT)?
+            .context("no OnlineID column")?;
+        // a set deleted in lazer stays in the file until it restarts
+        let delete_pending = self.column(&spec, "DeletePending", COLUMN_TYPE_BOOL)?;
+
+        let mut sets = HashSet::new();
+        let clusters = self.child(&table, TABLE_CLUSTERS)?;
+        self.visit(&clusters, 0, &mut |cluster| {
+            let ids = values(&self.child(cluster, online_id.leaf)?, online_id.nullable)?;
+            let deleted = match &delete_pending {
+                Some(column) => values(&self.child(cluster, column.leaf)?, column.nullable)?,
+                None => vec![None; ids.len()],
+            };
+            ensure!(ids.len() == deleted.len(), "columns of different lengths");
+            for (id, deleted) in ids.into_iter().zip(deleted) {
+                if let (Some(id), false) = (id, deleted.is_some_and(|d| d != 0)) {
+                    sets.extend(u32::try_from(id).ok().filter(|&id| id > 0));
+                }
+            }
+            Ok(())
+        })?;
+        Ok(sets)
+    }
+
+    /// the column called `name` of a table's spec, None if the table has none by that name
+    fn column(&self, spec: &Node, name: &str, kind: i64) -> Result<Option<Column>> {
+        let names = self.child(spec, SPEC_NAMES)?;
+        let keys = self.child(spec, SPEC_COLUMN_KEYS)?;
+        for i in 0..names.size {
+            if names.string(i)? != Some(name.as_bytes()) {
+                continue;
+            }
+            let key = keys.get(i)?;
+            ensure!(
+                (key >> 16) & 0x3F == kind,
+                "{} has an unexpected type",
+                name
+            );
+            // the cluster's first element is its keys
+            let leaf = (key & 0xFFFF) as usize + 1;
+            return Ok(Some(Column {
+                leaf,
+                nullable: (key >> 22) & COLUMN_NULLABLE != 0,
+            }));
+        }
+        Ok(None)
+    }
+
+    /// call `leaf` with every leaf cluster of the tree under `node`
+    fn visit(
+        &self,
+        node: &Node,
+        depth: usize,
+        leaf: &mut impl FnMut(&Node) -> Result<()>,
+    ) -> Result<()> {
+        ensure!(depth < MAX_DEPTH, "cluster tree too deep");
+        if !node.inner {
+            return leaf(node);
+        }
+        // an inner node's first two elements are its keys and the depth of the tree below
+        for i in 2..node.size {
+            self.visit(&self.child(node, i)?, depth + 1, leaf)?;
+        }
+        Ok(())
+    }
+}
+
+impl Node<'_> {
+    /// the `index`th integer (or ref), elements under a byte wide are unsigned
+    fn get(&self, index: usize) -> Result<i64> {
+        ensure!(index < self.size, "element {} of {}", index, self.size);
+        let bit = index * self.width;
+        let byte = |offset: usize| {
+            self.payload
+                .get(bit / 8 + offset)
+                .copied()
+                .context("array too short")
+        };
+        Ok(match self.width {
+            0 => 0,
+            1 | 2 | 4 => ((byte(0)? >> (bit % 8)) & ((1 << self.width) - 1)) as i64,
+            8 => byte(0)? as i8 as i64,
+            16 => i16::from_le_bytes([byte(0)?, byte(1)?]) as i64,
+            32 => i32::from_le_bytes([byte(0)?, byte(1)?, byte(2)?, byte(3)?]) as i64,
+            _ => i64::from_le_bytes(
+                self.payload
+                    .get(bit / 8..bit / 8 + 8)
+                    .context("array too short")?
+                    .try_into()?,
+            ),
+        })
+    }
+
+    /// the `index`th string of a short string array, None for null
+    fn string(&self, index: usize) -> Result<Option<&[u8]>> {
+        ensure!(index < self.size, "element {} of {}", index, self.size);
+        if self.width == 0 {
+            return Ok(Some(&[]));
+        }
+        let element = self
+            .payload
+            .get(index * self.width..(index + 1) * self.width)
+            .context("array too short")?;
+        // the last byte says how much of the element is padding
+        let padding = element[self.width - 1] as usize;
+        if padding == self.width {
+            return Ok(None);
+        }
+        let len = (self.width - 1)
+            .checked_sub(padding)
+            .context("invalid string")?;
+        Ok(Some(&element[..len]))
+    }
+}
+
+/// the values of an integer column's leaf, a nullable one stores its null value first
+fn values(leaf: &Node, nullable: bool) -> Result<Vec<Option<i64>>> {
+    if !nullable {
+        return (0..leaf.size).map(|i| leaf.get(i).map(Some)).collect();
+    }
+    let null = leaf.get(0)?;
+    (1..leaf.size)
+        .map(|i| leaf.get(i).map(|v| Some(v).filter(|&v| v != null)))
+        .collect()
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64> {
+    let bytes = data
+        .get(offset..offset + 8)
+        .context("truncated realm file")?;
+    Ok(u64::from_le_bytes(bytes.try_into()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// writes the arrays of a realm file, 64 bit integers or 32 byte strings
+    struct Writer(Vec<u8>);
+
+    impl Writer {
+        fn new() -> Self {
+            Self(vec![0; 24])
+        }
+
+        fn array(&mut self, flags: u8, payload: &[u8], size: usize) -> i64 {
+            let r = self.0.len();
+            self.0.extend_from_slice(b"AAAA");
+            self.0.push(flags);
+            self.0.extend_from_slice(&(size as u32).to_be_bytes()[1..]);
+            self.0.extend_from_slice(payload);
+            self.0.resize(self.0.len().next_multiple_of(8), 0);
+            r as i64
+        }
+
+        fn ints(&mut self, values: &[i64]) -> i64 {
+            let payload: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            self.array(0x07, &payload, values.len())
+        }
+
+        fn refs(&mut self, values: &[i64], inner: bool) -> i64 {
+            let payload: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+            self.array(if inner { 0xC7 } else { 0x47 }, &payload, values.len())
+        }
+
+        fn strings(&mut self, values: &[&str]) -> i64 {
+            let payload: Vec<u8> = values
+                .iter()
+                .flat_map(|s| {
+                    let mut element = s.as_bytes().to_vec();
+                    element.resize(31, 0);
+                    element.push((31 - s.len()) as u8);
+                    element
+                })
+                .collect();
+            self.array(0x0E, &payload, values.len())
+        }
+
+        fn finish(mut self, top: i64, format: u8) -> Vec<u8> {
+            self.0[..8].copy_from_slice(&(top as u64).to_le_bytes());
+            self.0[16..20].copy_from_slice(b"T-DB");
+            self.0[20] = format;
+            self.0[21] = format;
+            self.0
+        }
+    }
+
+    fn column_key(index: i64, kind: i64, attrs: i64) -> i64 {
+        index | kind << 16 | attrs << 22 | 1 << 30
+    }
+
+    /// a file with a set table holding `clusters`, each the online IDs and deletion flags of
+    /// its rows
+    fn realm(clusters: &[(&[i64], &[i64])], format: u8) -> Vec<u8> {
+        let mut w = Writer::new();
+        let types = w.ints(&[COLUMN_TYPE_INT, COLUMN_TYPE_BOOL]);
+        let names = w.strings(&["DeletePending", "OnlineID"]);
+        let attrs = w.ints(&[0, 0]);
+        let keys = w.ints(&[
+            column_key(1, COLUMN_TYPE_BOOL, 0),
+            column_key(0, COLUMN_TYPE_INT, 1),
+        ]);
+        let spec = w.refs(&[types, names, attrs, 0, 0, keys], false);
+
+        let mut leaves = Vec::new();
+        for (ids, deleted) in clusters {
+            let rows = ((ids.len() as i64) << 1) | 1;
+            let ids = w.ints(ids);
+            let deleted = w.ints(deleted);
+            leaves.push(w.refs(&[rows, ids, deleted], false));
+        }
+        let clusters = match leaves[..] {
+            [leaf] => leaf,
+            _ => w.refs(&[[1, 3].as_slice(), &leaves].concat(), true),
+        };
+        let sets = w.refs(&[spec, 0, clusters], false);
+        let other = w.refs(&[spec, 0, 0], false);
+
+        let table_names = w.strings(&["class_Beatmap", SET_TABLE]);
+        let tables = w.refs(&[other, sets], false);
+        let top = w.refs(&[table_names, tables, 1], false);
+        w.finish(top, format)
+    }
+
+    #[test]
+    fn lists_online_sets() {
+        let data = realm(&[(&[100, -1, 200, 300], &[0, 0, 1, 0])], 23);
+        let sets = Realm::open(&data).unwrap().sets().unwrap();
+        assert_eq!(sets, HashSet::from([100, 300]));
+    }
+
+    #[test]
+    fn walks_inner_clusters() {
+        let data = realm(&[(&[1, 2], &[0, 0]), (&[3], &[0])], 24);
+        let sets = Realm::open(&data).unwrap().sets().unwrap();
+        assert_eq!(sets, HashSet::from([1, 2, 3]));
+    }
+
+    #[test]
+    fn rejects_newer_formats() {
+        let data = realm(&[(&[1], &[0])], 25);
+        let e = Realm::open(&data).err().unwrap();
+        assert!(e.to_string().contains("isn't supported"), "{}", e);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(Realm::open(b"not a realm file at all, just text").is_err());
+        let mut data = realm(&[(&[1], &[0])], 23);
+        data.truncate(data.len() - 16);
+        assert!(Realm::open(&data).and_then(|realm| realm.sets()).is_err());
+    }
+
+    #[test]
+    fn reads_narrow_elements() {
+        let node = Node {
+            payload: &[0b1110_0100],
+            width: 2,
+            size: 4,
+            inner: false,
+        };
+        let values: Vec<i64> = (0..4).map(|i| node.get(i).unwrap()).collect();
+        assert_eq!(values, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::mirror::Mirror;
+use crate::persist;
+
+// download outcomes per mirror over all runs, inside the beatmap directory
+const STATS_FILE: &str = ".mirror_stats.json";
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct MirrorRecord {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl MirrorRecord {
+    /// success rate smoothed towards 50% so a handful of downloads doesn't swing it to 0 or 1
+    pub fn score(&self) -> f64 {
+        (self.successes as f64 + 1.0) / ((self.successes + self.failures) as f64 + 2.0)
+    }
+}
+
+pub struct Reliability {
+    path: PathBuf,
+    records: BTreeMap<String, MirrorRecord>,
+}
+
+impl Reliability {
+    pub fn load(dir: &Path) -> Self {
+        let path = dir.join(STATS_FILE);
+        let records = std::fs::read_to_string(&path)
+            .ok()
+            .and_then(|s| serde_json::from_str(&s).ok())
+            .unwrap_or_default();
+        Self { path, records }
+    }
+
+    pub fn record(&self, mirror: Mirror) -> MirrorRecord {
+        self.records.get(&key(mirror)).copied().unwrap_or_default()
+    }
+
+    pub fn score(&self, mirror: Mirror) -> f64 {
+        self.record(mirror).score()
+    }
+
+    /// add the outcomes of a run
+    pub fn add(&mut self, mirror: Mirror, successes: usize, failures: usize) {
+        let record = self.records.entry(key(mirror)).or_default();
+        record.successes += successes as u64;
+        record.failures += failures as u64;
+    }
+
+    pub fn save(&self) -> Result<()> {
+        persist::write_atomic(
+            &self.path,
+            serde_json::to_string_pretty(&self.records)?.as_bytes(),
+        )
+    }
+}
+
+fn key(mirror: Mirror) -> String {
+    mirror.name().to_lowercase()
+}
+
+/// every mirror with its recorded downloads
+pub fn records(dir: &Path) -> Vec<(Mirror, MirrorRecord)> {
+    let reliability = Reliability::load(dir);
+    [Mirror::Nerinyan, Mirror::Catboy, Mirror::Beatconnect]
+        .into_iter()
+        .map(|mirror| (mirror, reliability.record(mirror)))
+        .collect()
+}
+
+/// print every mirror with its recorded downloads and score
+pub fn list(dir: &Path) {
+    println!(
+        "{:<12} {:>10} {:>10} {:>7}",
+        "mirror", "successes", "failures", "score"
+    );
+    for (mirror, record) in records(dir) {
+        println!(
+            "{:<12} {:>10} {:>10} {:>6.0}%",
+            mirror.name(),
+            record.successes,
+            record.failures,
+            record.score() * 100.0
+        );
+    }
+}
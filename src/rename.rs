@@ -0,0 +1,86 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::checksum;
+use crate::index;
+use crate::naming::NamingOptions;
+use crate::types::{ArchiveExtension, BeatmapInfo};
+use crate::verify;
+
+/// archives in `dir` whose path no longer matches their entry in `maps`, with the path
+/// relative to `dir` they should have. the extension of each file is kept
+pub fn drifted(
+    dir: &Path,
+    maps: &[BeatmapInfo],
+    naming: &NamingOptions,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let by_id: HashMap<u32, &BeatmapInfo> = maps.iter().map(|m| (m.beatmapset_id, m)).collect();
+
+    let mut drifted: Vec<(PathBuf, PathBuf)> = verify::list_archives(dir)?
+        .into_iter()
+        .filter_map(|path| {
+            let name = path.file_name()?.to_str()?;
+            let beatmap = by_id.get(&verify::parse_mapset_id(name)?)?;
+            let expected = naming.relative_path(beatmap, ArchiveExtension::of(&path)?);
+            (path.strip_prefix(dir).ok()? != expected).then_some((path, expected))
+        })
+        .collect();
+    drifted.sort();
+    Ok(drifted)
+}
+
+/// rename archives in `dir` to the paths their entries in `maps` give them, keeping
+/// checksums.txt in step. with `dry_run` only the planned renames are printed
+pub fn rename_existing(
+    dir: &Path,
+    maps: &[BeatmapInfo],
+    naming: &NamingOptions,
+    dry_run: bool,
+) -> Result<()> {
+    let drifted = drifted(dir, maps, naming)?;
+    let mut renamed = Vec::new();
+    let mut collisions = 0;
+
+    for (path, expected) in &drifted {
+        let old = path
+            .strip_prefix(dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned();
+        let new = expected.to_string_lossy().into_owned();
+        let target = dir.join(expected);
+        // a rename that only changes case is fine on case-insensitive filesystems
+        if target.exists() && !old.eq_ignore_ascii_case(&new) {
+            println!("Skipping {}: {} already exists", old, new);
+            collisions += 1;
+            continue;
+        }
+
+        println!("{} -> {}", old, new);
+        if !dry_run {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::rename(path, &target)?;
+            renamed.push((old, new));
+        }
+    }
+
+    if dry_run {
+        println!(
+            "\n{} archives would be renamed, {} collisions",
+            drifted.len() - collisions,
+            collisions
+        );
+        return Ok(());
+    }
+    checksum::rename(dir, &renamed)?;
+    index::rename(dir, &renamed)?;
+    println!(
+        "\nRenamed {} archives, {} collisions",
+        renamed.len(),
+        collisions
+    );
+    Ok(())
+}
@@ -0,0 +1,41 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use crate::osudb;
+
+/// the MD5 of the beatmap a replay was set on, from the `.osr` header
+/// (game mode byte, version int, then the hash string)
+pub fn beatmap_hash(path: &Path) -> Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; 5];
+    reader
+        .read_exact(&mut header)
+        .context("truncated replay header")?;
+
+    osudb::read_hash(&mut reader)?.context("replay has no beatmap hash")
+}
+
+/// number of replays per beatmap hash for every `.osr` file in `dir`, unreadable replays
+/// are reported and skipped
+pub fn scan(dir: &Path) -> Result<BTreeMap<String, u32>> {
+    let mut counts = BTreeMap::new();
+    for entry in
+        std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+    {
+        let path = entry?.path();
+        if !path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("osr"))
+        {
+            continue;
+        }
+        match beatmap_hash(&path) {
+            Ok(hash) => *counts.entry(hash).or_insert(0) += 1,
+            Err(e) => println!("Skipping {} ({:#})", path.display(), e),
+        }
+    }
+    Ok(counts)
+}
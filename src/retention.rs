@@ -0,0 +1,141 @@
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// pruning a directory of dated backups (packed archives, saved lists) after a run
+#[derive(Args, Debug, Clone)]
+pub struct RetentionOptions {
+    /// directory of dated backups to prune after a run without failures, every file or folder
+    /// directly in it counts as one backup
+    #[arg(long, value_name = "DIR")]
+    pub snapshots_dir: Option<PathBuf>,
+    /// keep the N newest backups in --snapshots-dir
+    #[arg(long, value_name = "N", requires = "snapshots_dir", value_parser = clap::value_parser!(u32).range(1..))]
+    pub keep_n_newest: Option<u32>,
+    /// keep the backups in --snapshots-dir from the last DAYS days
+    #[arg(long, value_name = "DAYS", requires = "snapshots_dir")]
+    pub keep_days: Option<u64>,
+    /// only list the backups the retention policy would delete
+    #[arg(long, requires = "snapshots_dir")]
+    pub retention_dry_run: bool,
+}
+
+struct Snapshot {
+    path: PathBuf,
+    modified: SystemTime,
+}
+
+impl RetentionOptions {
+    /// fail before the run when the policy is incomplete or could delete the downloads
+    pub fn check(&self, output_dir: &Path) -> Result<()> {
+        let Some(dir) = &self.snapshots_dir else {
+            return Ok(());
+        };
+        if self.keep_n_newest.is_none() && self.keep_days.is_none() {
+            bail!("--snapshots-dir needs --keep-n-newest or --keep-days");
+        }
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to open {}", dir.display()))?;
+        // the output directory may not exist yet
+        let output = output_dir
+            .canonicalize()
+            .or_else(|_| std::path::absolute(output_dir))?;
+        if output.starts_with(&dir) {
+            bail!(
+                "{} contains the output directory, the retention policy would delete it",
+                dir.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// delete the backups beyond the policy, or list them with --retention-dry-run. nothing is
+    /// deleted when `failed` downloads of the run failed
+    pub fn apply(&self, failed: usize) -> Result<()> {
+        let Some(dir) = &self.snapshots_dir else {
+            return Ok(());
+        };
+        if failed > 0 {
+            println!(
+                "\nNot applying the retention policy to {}, {} downloads failed",
+                dir.display(),
+                failed
+            );
+            return Ok(());
+        }
+
+        let expired = self.expired(dir)?;
+        if expired.is_empty() {
+            println!("\nRetention: nothing to delete in {}", dir.display());
+            return Ok(());
+        }
+        if self.retention_dry_run {
+            println!(
+                "\nRetention: would delete {} backups from {}",
+                expired.len(),
+                dir.display()
+            );
+        } else {
+            println!(
+                "\nRetention: deleting {} backups from {}",
+                expired.len(),
+                dir.display()
+            );
+        }
+        for snapshot in &expired {
+            println!("  {}", snapshot.path.display());
+            if self.retention_dry_run {
+                continue;
+            }
+            let removed = if snapshot.path.is_dir() {
+                fs::remove_dir_all(&snapshot.path)
+            } else {
+                fs::remove_file(&snapshot.path)
+            };
+            if let Err(e) = removed {
+                println!("  Failed to delete {}: {}", snapshot.path.display(), e);
+            }
+        }
+        Ok(())
+    }
+
+    /// the backups in `dir` the policy doesn't keep, the newest one is always kept
+    fn expired(&self, dir: &Path) -> Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let entry = entry?;
+            // lock files and the like aren't backups
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            snapshots.push(Snapshot {
+                path: entry.path(),
+                modified,
+            });
+        }
+        snapshots.sort_by_key(|s| std::cmp::Reverse(s.modified));
+
+        let max_age = self
+            .keep_days
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+        let now = SystemTime::now();
+        Ok(snapshots
+            .into_iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(i, s)| {
+                let beyond_count = self.keep_n_newest.is_some_and(|n| *i >= n as usize);
+                let too_old = max_age
+                    .is_some_and(|max| now.duration_since(s.modified).is_ok_and(|age| age > max));
+                beyond_count || too_old
+            })
+            .map(|(_, s)| s)
+            .collect())
+    }
+}
@@ -0,0 +1,505 @@
+use anyhow::{bail, Context, Result};
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Html;
+use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task;
+
+use crate::fetcher;
+use crate::persist::DirLock;
+use crate::reliability;
+use crate::settings;
+use crate::types::BeatmapInfo;
+use crate::verify;
+
+// the whole UI, built into the binary
+const PAGE: &str = include_str!("../assets/serve/index.html");
+// lines of output kept of every run
+const KEPT_LINES: usize = 500;
+// runs kept in the history, the oldest are dropped first
+const HISTORY: usize = 50;
+// maps the library view returns at most
+const MAX_MAPS: usize = 500;
+
+type ApiError = (StatusCode, String);
+
+struct Server {
+    token: String,
+    input: PathBuf,
+    output_dir: PathBuf,
+    runs: Mutex<Vec<Run>>,
+    /// held by the request starting a run, over the check for one going on until it's added
+    starting: tokio::sync::Mutex<()>,
+    events: broadcast::Sender<RunEvent>,
+}
+
+/// a fetch or download started from the UI, run as a child process of this binary
+#[derive(Debug, Clone, Serialize)]
+struct Run {
+    id: usize,
+    args: Vec<String>,
+    /// unix seconds
+    started: u64,
+    finished: Option<u64>,
+    /// the exit code, see the README for what they mean
+    exit_code: Option<i32>,
+    /// the last KEPT_LINES lines it printed
+    output: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RunEvent {
+    Line { run: usize, line: String },
+    Done { run: usize, exit_code: Option<i32> },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RunCommand {
+    Fetch,
+    Download,
+}
+
+/// the options the UI offers, passed on as the flags of the same name
+#[derive(Debug, Deserialize)]
+struct RunRequest {
+    command: RunCommand,
+    source: Option<String>,
+    max_downloads: Option<usize>,
+    #[serde(default)]
+    mirrors: Vec<String>,
+    #[serde(default)]
+    verify_after: bool,
+    #[serde(default)]
+    checksums: bool,
+    #[serde(default)]
+    index: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MapsQuery {
+    /// text the artist, title, version or mapper contains
+    q: Option<String>,
+    mode: Option<String>,
+    min_stars: Option<f32>,
+    max_stars: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+struct MapRow {
+    #[serde(flatten)]
+    map: BeatmapInfo,
+    downloaded: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct MapsPage {
+    /// maps matching the filters, only the first MAX_MAPS are in `maps`
+    matched: usize,
+    total: usize,
+    maps: Vec<MapRow>,
+}
+
+#[derive(Debug, Serialize)]
+struct MirrorRow {
+    mirror: &'static str,
+    successes: u64,
+    failures: u64,
+    score: f64,
+}
+
+/// compare without stopping at the first difference, so how long a wrong guess takes doesn't
+/// tell how much of it was right
+fn same_token(guess: &str, token: &str) -> bool {
+    let diff = guess
+        .bytes()
+        .zip(token.bytes())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    guess.len() == token.len() && std::hint::black_box(diff) == 0
+}
+
+/// run `f`, which reads or writes files, off the threads serving requests
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    task::spawn_blocking(f).await?
+}
+
+/// the set IDs of the archives in `dir`, none when it doesn't exist yet
+fn downloaded_sets(dir: &Path) -> HashSet<u32> {
+    verify::list_archives(dir)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|p| p.file_name()?.to_str().and_then(verify::parse_mapset_id))
+        .collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Server {
+    /// every request but the page itself needs the token as a bearer token. only the event
+    /// stream, which browsers can't add headers to, takes it from the `query` too, so it
+    /// doesn't end up in logs of the other routes' URLs
+    fn authorize(&self, headers: &HeaderMap, query: Option<&str>) -> Result<(), ApiError> {
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        match bearer.or(query) {
+            Some(token) if same_token(token, &self.token) => Ok(()),
+            _ => Err((
+                StatusCode::UNAUTHORIZED,
+                "wrong or missing token".to_string(),
+            )),
+        }
+    }
+
+    fn push_line(&self, id: usize, line: String) {
+        if let Some(run) = self.runs.lock().unwrap().iter_mut().find(|r| r.id == id) {
+            if run.output.len() == KEPT_LINES {
+                run.output.remove(0);
+            }
+            run.output.push(line.clone());
+        }
+        // nobody listening isn't an error
+        let _ = self.events.send(RunEvent::Line { run: id, line });
+    }
+
+    fn finish(&self, id: usize, exit_code: Option<i32>) {
+        if let Some(run) = self.runs.lock().unwrap().iter_mut().find(|r| r.id == id) {
+            run.finished = Some(now());
+            run.exit_code = exit_code;
+        }
+        let _ = self.events.send(RunEvent::Done { run: id, exit_code });
+    }
+
+    fn args(&self, request: &RunRequest) -> Vec<String> {
+        let mut args: Vec<String> = match request.command {
+            RunCommand::Fetch => vec![
+                "fetch".into(),
+                "--output".into(),
+                self.input.display().to_string(),
+            ],
+            RunCommand::Download => vec![
+                "download".into(),
+                "--input".into(),
+                self.input.display().to_string(),
+                "--output".into(),
+                self.output_dir.display().to_string(),
+            ],
+        };
+        if let Some(source) = &request.source {
+            args.extend(["--source".into(), source.clone()]);
+        }
+        if let Some(max) = request.max_downloads {
+            args.extend(["--max-downloads".into(), max.to_string()]);
+        }
+        if !request.mirrors.is_empty() {
+            args.extend(["--mirrors".into(), request.mirrors.join(",")]);
+        }
+        for (set, flag) in [
+            (request.verify_after, "--verify-after"),
+            (request.checksums, "--checksums"),
+            (request.index, "--index"),
+        ] {
+            if set {
+                args.push(flag.into());
+            }
+        }
+        args
+    }
+}
+
+async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+async fn maps(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Query(query): Query<MapsQuery>,
+) -> Result<Json<MapsPage>, ApiError> {
+    server.authorize(&headers, None)?;
+    let (input, output_dir) = (server.input.clone(), server.output_dir.clone());
+    let (all, downloaded) = blocking(move || {
+        let (all, _) = fetcher::load_beatmaps_checked(&input)?;
+        Ok((all, downloaded_sets(&output_dir)))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e)))?;
+
+    let text = query
+        .q
+        .as_deref()
+        .map(str::to_lowercase)
+        .filter(|q| !q.is_empty());
+    let matches = |map: &BeatmapInfo| {
+        let fields = [
+            Some(&map.artist),
+            Some(&map.title),
+            Some(&map.version),
+            map.creator.as_ref(),
+        ];
+        text.as_ref().is_none_or(|q| {
+            fields
+                .iter()
+                .flatten()
+                .any(|f| f.to_lowercase().contains(q))
+        }) && query
+            .mode
+            .as_deref()
+            .is_none_or(|m| m.is_empty() || map.native_mode() == Some(m))
+            && query
+                .min_stars
+                .is_none_or(|min| map.stars.is_some_and(|s| s >= min))
+            && query
+                .max_stars
+                .is_none_or(|max| map.stars.is_some_and(|s| s <= max))
+    };
+    let total = all.len();
+    let matched: Vec<BeatmapInfo> = all.into_iter().filter(|m| matches(m)).collect();
+    Ok(Json(MapsPage {
+        matched: matched.len(),
+        total,
+        maps: matched
+            .into_iter()
+            .take(MAX_MAPS)
+            .map(|map| MapRow {
+                downloaded: downloaded.contains(&map.beatmapset_id),
+                map,
+            })
+            .collect(),
+    }))
+}
+
+async fn runs(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<Run>>, ApiError> {
+    server.authorize(&headers, None)?;
+    Ok(Json(server.runs.lock().unwrap().clone()))
+}
+
+async fn mirrors(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<MirrorRow>>, ApiError> {
+    server.authorize(&headers, None)?;
+    let rows = reliability::records(&server.output_dir)
+        .into_iter()
+        .map(|(mirror, record)| MirrorRow {
+            mirror: mirror.name(),
+            successes: record.successes,
+            failures: record.failures,
+            score: record.score(),
+        })
+        .collect();
+    Ok(Json(rows))
+}
+
+async fn start(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Json(request): Json<RunRequest>,
+) -> Result<Json<Run>, ApiError> {
+    server.authorize(&headers, None)?;
+    let args = server.args(&request);
+    let exe =
+        std::env::current_exe().map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+
+    // one start at a time over the check, the start and adding the run, so two requests can't
+    // both start one
+    let _starting = server.starting.lock().await;
+    if server
+        .runs
+        .lock()
+        .unwrap()
+        .last()
+        .is_some_and(|run| run.finished.is_none())
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "a run is already going, wait for it to finish".to_string(),
+        ));
+    }
+    // the same lock the download takes, held here until the run exits so that nothing started
+    // from the command line gets in between
+    let lock = match request.command {
+        RunCommand::Download => {
+            let dir = server.output_dir.clone();
+            blocking(move || {
+                std::fs::create_dir_all(&dir)?;
+                DirLock::acquire(&dir, false)
+            })
+            .await
+            .map_err(|e| (StatusCode::CONFLICT, format!("{:#}", e)))?
+        }
+        RunCommand::Fetch => None,
+    };
+    let child = Command::new(exe)
+        .args(&args)
+        .args(lock.is_some().then_some("--parent-lock"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to start the run: {}", e),
+            )
+        })?;
+
+    let mut runs = server.runs.lock().unwrap();
+    let id = runs.last().map_or(1, |r| r.id + 1);
+    if runs.len() == HISTORY {
+        runs.remove(0);
+    }
+    let run = Run {
+        id,
+        args,
+        started: now(),
+        finished: None,
+        exit_code: None,
+        output: Vec::new(),
+    };
+    runs.push(run.clone());
+    drop(runs);
+
+    tokio::spawn(watch(Arc::clone(&server), run.id, child, lock));
+    Ok(Json(run))
+}
+
+/// pass the lines a run prints on to its output and the event stream until it exits
+async fn watch(server: Arc<Server>, id: usize, mut child: Child, lock: Option<DirLock>) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    if let Some(stdout) = child.stdout.take() {
+        forward(stdout, tx.clone());
+    }
+    if let Some(stderr) = child.stderr.take() {
+        forward(stderr, tx.clone());
+    }
+    drop(tx);
+    while let Some(line) = rx.recv().await {
+        server.push_line(id, line);
+    }
+    let code = child.wait().await.ok().and_then(|status| status.code());
+    // released before the run counts as finished, so the next one can take it
+    drop(lock);
+    server.finish(id, code);
+}
+
+fn forward(pipe: impl AsyncRead + Unpin + Send + 'static, tx: mpsc::UnboundedSender<String>) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(pipe).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+}
+
+async fn events(
+    State(server): State<Arc<Server>>,
+    headers: HeaderMap,
+    Query(query): Query<TokenQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    server.authorize(&headers, query.token.as_deref())?;
+    let stream = stream::unfold(server.events.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => {
+                    let data = serde_json::to_string(&event).unwrap_or_default();
+                    return Some((Ok(Event::default().data(data)), rx));
+                }
+                // a slow client misses lines, the run's output still has them
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+/// serve the web UI for the list at `input` and the archives in `output_dir` until stopped
+pub async fn serve(addr: SocketAddr, input: PathBuf, output_dir: PathBuf) -> Result<()> {
+    let Some(token) = settings::get().serve_token.clone() else {
+        bail!("set SERVE_TOKEN in .env to the token the web UI should ask for");
+    };
+    let server = Arc::new(Server {
+        token,
+        input,
+        output_dir,
+        runs: Mutex::new(Vec::new()),
+        starting: tokio::sync::Mutex::new(()),
+        events: broadcast::channel(1024).0,
+    });
+    let app = Router::new()
+        .route("/", get(page))
+        .route("/api/maps", get(maps))
+        .route("/api/runs", get(runs).post(start))
+        .route("/api/mirrors", get(mirrors))
+        .route("/api/events", get(events))
+        .with_state(server);
+
+    let listener =
+        axum::Server::try_bind(&addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Serving the web UI on http://{}", addr);
+    listener.serve(app.into_make_service()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(same_token("secret", "secret"));
+        assert!(!same_token("secreT", "secret"));
+        assert!(!same_token("secret1", "secret"));
+        assert!(!same_token("", "secret"));
+    }
+
+    #[test]
+    fn authorizes_bearer_or_stream_query_token() {
+        let server = Server {
+            token: "secret".to_string(),
+            input: PathBuf::new(),
+            output_dir: PathBuf::new(),
+            runs: Mutex::new(Vec::new()),
+            starting: tokio::sync::Mutex::new(()),
+            events: broadcast::channel(1).0,
+        };
+        let mut headers = HeaderMap::new();
+        assert!(server.authorize(&headers, None).is_err());
+        assert!(server.authorize(&headers, Some("secret")).is_ok());
+        headers.insert(header::AUTHORIZATION, "Bearer secret".parse().unwrap());
+        assert!(server.authorize(&headers, None).is_ok());
+    }
+}
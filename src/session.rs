@@ -0,0 +1,164 @@
+use indicatif::ProgressBar;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::progress;
+use crate::settings;
+use crate::units;
+
+/// a run split into sessions of --session-size downloads, with a --session-cooldown pause
+/// after each. a session only starts once every download of the one before finished
+pub struct Sessions {
+    size: usize,
+    cooldown: Duration,
+    // sessions allowed to start
+    allowed: AtomicUsize,
+    // held during the cooldown so no new download starts
+    gate: tokio::sync::Mutex<()>,
+    entered: AtomicUsize,
+    finished: AtomicUsize,
+    stats: Mutex<Vec<SessionStats>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct SessionStats {
+    downloaded: usize,
+    failed: usize,
+    bytes: u64,
+    started: Instant,
+    ended: Instant,
+}
+
+impl SessionStats {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            downloaded: 0,
+            failed: 0,
+            bytes: 0,
+            started: now,
+            ended: now,
+        }
+    }
+
+    fn print(&self, label: &str) {
+        println!(
+            "  {:<10} {:>5} downloaded  {:>4} failed  {:>10}  {}",
+            label,
+            units::count(self.downloaded),
+            units::count(self.failed),
+            units::bytes(self.bytes, settings::get().units),
+            units::duration(self.ended - self.started)
+        );
+    }
+}
+
+impl Sessions {
+    pub fn new(size: usize, cooldown: Duration) -> Self {
+        Self {
+            size,
+            cooldown,
+            allowed: AtomicUsize::new(1),
+            gate: tokio::sync::Mutex::new(()),
+            entered: AtomicUsize::new(0),
+            finished: AtomicUsize::new(0),
+            stats: Mutex::new(vec![SessionStats::new()]),
+        }
+    }
+
+    /// wait until the next download may start. the first download of a session waits for the
+    /// previous session to finish, calls `save` so a crash during the cooldown loses nothing,
+    /// then counts the cooldown down on `pb`
+    pub async fn enter(&self, pb: &ProgressBar, save: impl FnOnce()) {
+        let session = self.entered.fetch_add(1, Ordering::SeqCst) / self.size;
+        // the downloads of earlier sessions never wait for the lock, the first one of a new
+        // session holds it until they all finished
+        if session < self.allowed.load(Ordering::SeqCst) {
+            return;
+        }
+        let _gate = self.gate.lock().await;
+        if session < self.allowed.load(Ordering::SeqCst) {
+            return;
+        }
+
+        while self.finished.load(Ordering::SeqCst) < session * self.size {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        let last = *self.stats.lock().unwrap().last().unwrap();
+        progress::println(
+            pb,
+            format!(
+                "Session {} done: {} downloaded, {} failed",
+                session, last.downloaded, last.failed
+            ),
+        );
+        save();
+
+        let end = Instant::now() + self.cooldown;
+        while let Some(left) = end
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+        {
+            progress::status(
+                pb,
+                format!(
+                "Cooling down, session {} starts in {} (Ctrl-C to stop, running again continues)",
+                session + 1,
+                units::duration(left)
+            ),
+            );
+            // without bars the countdown would be a line per second
+            let tick = if pb.is_hidden() {
+                left
+            } else {
+                left.min(Duration::from_secs(1))
+            };
+            tokio::time::sleep(tick).await;
+        }
+        pb.set_message("");
+        self.stats.lock().unwrap().push(SessionStats::new());
+        self.allowed.store(session + 1, Ordering::SeqCst);
+    }
+
+    /// count a finished download towards the current session
+    pub fn record(&self, downloaded: bool, bytes: u64) {
+        let mut stats = self.stats.lock().unwrap();
+        let current = stats.last_mut().unwrap();
+        if downloaded {
+            current.downloaded += 1;
+        } else {
+            current.failed += 1;
+        }
+        current.bytes += bytes;
+        current.ended = Instant::now();
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// count a request the mirror answered with 304 Not Modified, which finishes a download of
+    /// the session without adding to what it downloaded
+    pub fn record_unchanged(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.last_mut().unwrap().ended = Instant::now();
+        self.finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// the results of every session and their total
+    pub fn print_summary(&self) {
+        let stats = self.stats.lock().unwrap();
+        println!("\nSessions:");
+        for (i, session) in stats.iter().enumerate() {
+            session.print(&format!("#{}", i + 1));
+        }
+        let total = stats
+            .iter()
+            .fold(SessionStats::new(), |total, s| SessionStats {
+                downloaded: total.downloaded + s.downloaded,
+                failed: total.failed + s.failed,
+                bytes: total.bytes + s.bytes,
+                started: stats[0].started,
+                ended: s.ended,
+            });
+        total.print("total");
+    }
+}
@@ -0,0 +1,261 @@
+use anyhow::{bail, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, RwLock};
+
+use crate::downloader;
+use crate::units::Units;
+
+/// the settings read from the environment and .env, parsed once at startup. values are
+/// trimmed, empty ones count as unset
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub client_id: Option<u64>,
+    pub client_secret: Option<String>,
+    pub username: Option<String>,
+    pub output_dir: Option<PathBuf>,
+    pub user_agent: Option<String>,
+    pub use_alternative_mirror: bool,
+    /// keep the state files of the output directory and of fetches compressed with zstd
+    pub compress_state: bool,
+    /// the units sizes are shown in, decimal ones (MB) with --si-units
+    pub units: Units,
+    /// the token `serve` asks every API request for
+    #[cfg(feature = "web")]
+    pub serve_token: Option<String>,
+    /// every variable that was set to something invalid, with what's wrong with it. the
+    /// setting itself is left unset
+    pub problems: Vec<Problem>,
+    /// the .env files the variables were read from, the local one first
+    pub env_files: Vec<PathBuf>,
+}
+
+/// a variable set to something invalid
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub key: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
+}
+
+// shared rather than cloned on every read, sizes are formatted and state paths picked with
+// them while downloading. `update` swaps in a changed copy
+static SETTINGS: LazyLock<RwLock<Arc<Settings>>> = LazyLock::new(Default::default);
+
+/// the settings loaded at startup
+pub fn get() -> Arc<Settings> {
+    SETTINGS.read().unwrap().clone()
+}
+
+/// read the .env files and then the settings from the environment, for `get` to return
+pub fn load() {
+    let env_files = load_env_files();
+    let settings = Settings {
+        env_files,
+        ..Settings::from_env(|key| std::env::var(key).ok())
+    };
+    *SETTINGS.write().unwrap() = Arc::new(settings);
+}
+
+/// the .env shared by every project, `~/.config/osu-beatmap-downloader/.env` (or under
+/// `$XDG_CONFIG_HOME`), `%APPDATA%\osu-beatmap-downloader\.env` on Windows
+pub fn global_env_file() -> Option<PathBuf> {
+    let config = if cfg!(target_os = "windows") {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+    };
+    config.map(|c| c.join("osu-beatmap-downloader").join(".env"))
+}
+
+/// put the variables of the local .env (found in the working directory or above) and then the
+/// global one into the environment. neither overrides a variable that's already set, so the
+/// process environment wins over the local file, which wins over the global one
+fn load_env_files() -> Vec<PathBuf> {
+    let mut loaded = Vec::new();
+    if let Ok(path) = dotenvy::dotenv() {
+        loaded.push(path);
+    }
+    if let Some(path) = global_env_file().filter(|p| p.is_file()) {
+        if dotenvy::from_path(&path).is_ok() {
+            loaded.push(path);
+        }
+    }
+    loaded
+}
+
+/// change the loaded settings, e.g. after `init` saved new credentials
+pub fn update(change: impl FnOnce(&mut Settings)) {
+    change(Arc::make_mut(&mut SETTINGS.write().unwrap()));
+}
+
+/// "true", "yes", "on" or "1" and their opposites, in any case
+pub fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.to_lowercase().as_str() {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("'{}' isn't a yes or no, use true or false", s)),
+    }
+}
+
+impl Settings {
+    /// the settings from `var`, which looks a variable up by name
+    pub fn from_env(var: impl Fn(&str) -> Option<String>) -> Self {
+        let value = |key: &str| {
+            var(key)
+                .map(|v| v.trim().to_string())
+                .filter(|v| !v.is_empty())
+        };
+        let mut problems = Vec::new();
+
+        let client_id = parse(
+            value("OSU_CLIENT_ID"),
+            "OSU_CLIENT_ID",
+            &mut problems,
+            |s| {
+                s.parse::<u64>().map_err(|_| {
+                    format!(
+                        "'{}' isn't a number, copy the client ID of your OAuth application",
+                        s
+                    )
+                })
+            },
+        );
+        let user_agent = parse(
+            value("USER_AGENT"),
+            "USER_AGENT",
+            &mut problems,
+            downloader::parse_user_agent,
+        );
+        let use_alternative_mirror = parse(
+            value("USE_ALTERNATIVE_MIRROR"),
+            "USE_ALTERNATIVE_MIRROR",
+            &mut problems,
+            parse_bool,
+        );
+        let compress_state = parse(
+            value("COMPRESS_STATE"),
+            "COMPRESS_STATE",
+            &mut problems,
+            parse_bool,
+        );
+
+        Self {
+            client_id,
+            client_secret: value("OSU_CLIENT_SECRET"),
+            username: value("OSU_USERNAME"),
+            output_dir: value("BEATMAP_OUTPUT_DIR").map(PathBuf::from),
+            user_agent,
+            use_alternative_mirror: use_alternative_mirror.unwrap_or(false),
+            compress_state: compress_state.unwrap_or(false),
+            units: Units::Binary,
+            #[cfg(feature = "web")]
+            serve_token: value("SERVE_TOKEN"),
+            problems,
+            env_files: Vec::new(),
+        }
+    }
+
+    /// fail with every invalid variable at once
+    pub fn check(&self) -> Result<()> {
+        if !self.problems.is_empty() {
+            let problems: Vec<String> = self.problems.iter().map(Problem::to_string).collect();
+            bail!(
+                "invalid settings in the environment or .env:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// `value` parsed with `parser`, an invalid one is added to `problems` under `key`
+fn parse<T>(
+    value: Option<String>,
+    key: &'static str,
+    problems: &mut Vec<Problem>,
+    parser: impl Fn(&str) -> Result<T, String>,
+) -> Option<T> {
+    parser(&value?)
+        .map_err(|message| problems.push(Problem { key, message }))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn from(vars: &[(&str, &str)]) -> Settings {
+        let vars: HashMap<&str, &str> = vars.iter().copied().collect();
+        Settings::from_env(|key| vars.get(key).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn trims_values_and_ignores_empty_ones() {
+        let settings = from(&[
+            ("OSU_CLIENT_ID", " 12345\n"),
+            ("OSU_CLIENT_SECRET", "  secret "),
+            ("OSU_USERNAME", "   "),
+            ("BEATMAP_OUTPUT_DIR", "maps "),
+        ]);
+        assert_eq!(settings.client_id, Some(12345));
+        assert_eq!(settings.client_secret.as_deref(), Some("secret"));
+        assert_eq!(settings.username, None);
+        assert_eq!(settings.output_dir, Some(PathBuf::from("maps")));
+        assert!(settings.problems.is_empty());
+        settings.check().unwrap();
+    }
+
+    #[test]
+    fn parses_booleans_in_any_case() {
+        for (value, expected) in [
+            ("TRUE", true),
+            (" Yes", true),
+            ("on", true),
+            ("1", true),
+            ("No ", false),
+            ("OFF", false),
+        ] {
+            let settings = from(&[("USE_ALTERNATIVE_MIRROR", value), ("COMPRESS_STATE", value)]);
+            assert_eq!(settings.use_alternative_mirror, expected, "{}", value);
+            assert_eq!(settings.compress_state, expected, "{}", value);
+        }
+        assert!(!from(&[]).use_alternative_mirror);
+    }
+
+    #[test]
+    fn lists_every_invalid_variable() {
+        let settings = from(&[
+            ("OSU_CLIENT_ID", "abc"),
+            ("USE_ALTERNATIVE_MIRROR", "maybe"),
+            ("COMPRESS_STATE", "true"),
+            ("USER_AGENT", "bad\u{1}agent"),
+        ]);
+        assert_eq!(settings.client_id, None);
+        assert!(!settings.use_alternative_mirror);
+        assert!(settings.compress_state);
+        let keys: Vec<&str> = settings.problems.iter().map(|p| p.key).collect();
+        assert_eq!(
+            keys,
+            ["OSU_CLIENT_ID", "USER_AGENT", "USE_ALTERNATIVE_MIRROR"]
+        );
+        assert_eq!(
+            settings.problems[2].to_string(),
+            "USE_ALTERNATIVE_MIRROR: 'maybe' isn't a yes or no, use true or false"
+        );
+
+        let error = settings.check().unwrap_err().to_string();
+        assert!(error.starts_with(
+            "invalid settings in the environment or .env:\n  OSU_CLIENT_ID: 'abc' isn't a number"
+        ));
+        assert_eq!(error.lines().count(), 4);
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::{IsTerminal, Write};
+use std::path::Path;
+
+use crate::exit::Failure;
+use crate::persist;
+use crate::settings;
+
+pub const ENV_FILE: &str = ".env";
+const CREDENTIALS: [&str; 3] = ["OSU_CLIENT_ID", "OSU_CLIENT_SECRET", "OSU_USERNAME"];
+const OAUTH_URL: &str = "https://osu.ppy.sh/home/account/edit#oauth";
+const BIN: &str = env!("CARGO_BIN_NAME");
+
+/// the credential variables that aren't set
+pub fn missing_credentials() -> Vec<&'static str> {
+    let settings = settings::get();
+    let set = [
+        settings.client_id.is_some(),
+        settings.client_secret.is_some(),
+        settings.username.is_some(),
+    ];
+    CREDENTIALS
+        .into_iter()
+        .zip(set)
+        .filter(|(_, set)| !set)
+        .map(|(key, _)| key)
+        .collect()
+}
+
+pub fn print_quick_start() {
+    println!("\nQuick start:");
+    println!("  1. {} init", BIN);
+    println!(
+        "     save the client ID and secret of an OAuth application from {} and your username",
+        OAUTH_URL
+    );
+    println!(
+        "  2. {} fetch --source <most-played|favourites|first-places|pinned|recent|all>",
+        BIN
+    );
+    println!("     save the list of maps to download");
+    println!("  3. {} download --output <dir>", BIN);
+    println!("     download the maps of the list that aren't in <dir> yet");
+}
+
+/// with credentials missing, offer to set them up when there's someone to ask and print the
+/// quick start otherwise. returns whether the credentials are set now
+pub fn first_run() -> Result<bool> {
+    let missing = missing_credentials();
+    if missing.is_empty() {
+        return Ok(true);
+    }
+    println!(
+        "The osu! API credentials aren't set up yet ({} missing)",
+        missing.join(", ")
+    );
+    if std::io::stdin().is_terminal() && crate::prompt_confirm("Set them up now?")? {
+        init(Path::new(ENV_FILE))?;
+        return Ok(true);
+    }
+    print_quick_start();
+    Ok(false)
+}
+
+/// ask for the credentials and save them to `path`, replacing ones it already has. they're
+/// also set for the rest of this run
+pub fn init(path: &Path) -> Result<()> {
+    println!(
+        "Create an OAuth application at {} (any name, the callback URL can stay empty)",
+        OAUTH_URL
+    );
+    println!("and copy its client ID and secret\n");
+    let client_id = loop {
+        let id = prompt("Client ID")?;
+        if id.parse::<u64>().is_ok() {
+            break id;
+        }
+        println!("The client ID is a number");
+    };
+    let values = [
+        client_id,
+        prompt("Client secret")?,
+        prompt("osu! username")?,
+    ];
+
+    let existing = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    // the rest of an existing file (output directory, mirror settings) is kept
+    let mut contents: String = existing
+        .lines()
+        .filter(|line| {
+            !CREDENTIALS
+                .iter()
+                .any(|key| line.trim_start().starts_with(&format!("{}=", key)))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    for (key, value) in CREDENTIALS.iter().zip(&values) {
+        contents.push_str(&format!("{}={}\n", key, value));
+    }
+    persist::write_atomic_with(path, |out| {
+        // the secret is only for this user to read
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            out.get_ref()
+                .set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(out.write_all(contents.as_bytes())?)
+    })?;
+    let [client_id, client_secret, username] = values;
+    settings::update(|s| {
+        s.client_id = client_id.parse().ok();
+        s.client_secret = Some(client_secret);
+        s.username = Some(username);
+        s.problems.retain(|p| p.key != "OSU_CLIENT_ID");
+    });
+
+    println!("\nSaved the credentials to {}", path.display());
+    println!(
+        "Run `{} doctor` to check them, or `{} all` to fetch and download your most played maps",
+        BIN, BIN
+    );
+    Ok(())
+}
+
+fn prompt(label: &str) -> Result<String> {
+    loop {
+        print!("{}: ", label);
+        std::io::stdout().flush()?;
+        let Some(input) = crate::keyboard::read_line() else {
+            bail!("no input, cancelled");
+        };
+        let input = input.trim();
+        if !input.is_empty() {
+            return Ok(input.to_string());
+        }
+    }
+}
+
+/// the error for a beatmap list that doesn't exist yet, telling how to make one
+pub fn missing_list(path: &Path) -> anyhow::Error {
+    let next = if missing_credentials().is_empty() {
+        format!("run `{} fetch` first to make it", BIN)
+    } else {
+        format!(
+            "set up your credentials with `{} init`, then run `{} fetch` to make it",
+            BIN, BIN
+        )
+    };
+    anyhow!(
+        "{} doesn't exist, {} (or pass --input)",
+        path.display(),
+        next
+    )
+    .context(Failure::InvalidInput)
+}
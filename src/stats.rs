@@ -0,0 +1,146 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::mirror::Mirror;
+use crate::persist;
+use crate::types::BeatmapInfo;
+use crate::units::{self, Units};
+
+// how many downloads each list of the summary shows
+const SHOWN: usize = 5;
+
+/// a finished download, timed from its first request until the file was written, retries and
+/// mirrors that failed before included
+#[derive(Debug, Clone, Copy)]
+pub struct Downloaded {
+    pub bytes: u64,
+    pub elapsed: Duration,
+    /// requests repeated after rate limits, lost connections and mirrors that failed
+    pub retries: u32,
+    pub mirror: Mirror,
+    /// the mirror answered 304 Not Modified to a refresh, nothing was downloaded
+    pub unchanged: bool,
+}
+
+/// a line of the --stats-file
+#[derive(Debug, Serialize)]
+pub struct DownloadStat {
+    pub beatmapset_id: u32,
+    pub title: String,
+    pub mirror: Mirror,
+    pub bytes: u64,
+    pub seconds: f64,
+    pub bytes_per_second: f64,
+    pub retries: u32,
+}
+
+impl DownloadStat {
+    pub fn new(beatmap: &BeatmapInfo, download: &Downloaded) -> Self {
+        let seconds = download.elapsed.as_secs_f64();
+        Self {
+            beatmapset_id: beatmap.beatmapset_id,
+            title: beatmap.title.clone(),
+            mirror: download.mirror,
+            bytes: download.bytes,
+            seconds,
+            bytes_per_second: if seconds > 0.0 {
+                download.bytes as f64 / seconds
+            } else {
+                0.0
+            },
+            retries: download.retries,
+        }
+    }
+
+    fn line(&self, units: Units) -> String {
+        format!(
+            "  {:>6.1}s  {:>10}  {:>12}  {:>2} retries  {:<9} {} {}\n",
+            self.seconds,
+            units::bytes(self.bytes, units),
+            units::rate(self.bytes_per_second, units),
+            self.retries,
+            self.mirror.name(),
+            self.beatmapset_id,
+            self.title
+        )
+    }
+}
+
+/// the slowest downloads and the ones that needed the most retries, as printed after a run
+pub fn summary(stats: &[DownloadStat], units: Units) -> String {
+    let mut summary = String::new();
+    let mut slowest: Vec<&DownloadStat> = stats.iter().collect();
+    slowest.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+    if !slowest.is_empty() {
+        summary.push_str("\nSlowest downloads:\n");
+        slowest
+            .iter()
+            .take(SHOWN)
+            .for_each(|s| summary.push_str(&s.line(units)));
+    }
+
+    let mut retried: Vec<&DownloadStat> = stats.iter().filter(|s| s.retries > 0).collect();
+    retried.sort_by_key(|s| std::cmp::Reverse(s.retries));
+    if !retried.is_empty() {
+        summary.push_str("\nMost retried downloads:\n");
+        retried
+            .iter()
+            .take(SHOWN)
+            .for_each(|s| summary.push_str(&s.line(units)));
+    }
+    summary
+}
+
+pub fn save(stats: &[DownloadStat], path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(stats)?;
+    persist::write_atomic(path, json.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stat(beatmapset_id: u32, seconds: f64, bytes: u64, retries: u32) -> DownloadStat {
+        DownloadStat {
+            beatmapset_id,
+            title: format!("Set {}", beatmapset_id),
+            mirror: Mirror::Nerinyan,
+            bytes,
+            seconds,
+            bytes_per_second: bytes as f64 / seconds,
+            retries,
+        }
+    }
+
+    #[test]
+    fn summary_snapshot() {
+        let stats = [
+            stat(1, 2.0, 4_194_304, 0),
+            stat(2, 12.5, 10_485_760, 3),
+            stat(3, 0.4, 900, 1),
+        ];
+        assert_eq!(
+            summary(&stats, Units::Binary),
+            "
+Slowest downloads:
+    12.5s    10.0 MiB   819.2 KiB/s   3 retries  Nerinyan  2 Set 2
+     2.0s     4.0 MiB     2.0 MiB/s   0 retries  Nerinyan  1 Set 1
+     0.4s       900 B     2.2 KiB/s   1 retries  Nerinyan  3 Set 3
+
+Most retried downloads:
+    12.5s    10.0 MiB   819.2 KiB/s   3 retries  Nerinyan  2 Set 2
+     0.4s       900 B     2.2 KiB/s   1 retries  Nerinyan  3 Set 3
+"
+        );
+        assert_eq!(
+            summary(&stats[..1], Units::Si),
+            "
+Slowest downloads:
+     2.0s      4.2 MB      2.1 MB/s   0 retries  Nerinyan  1 Set 1
+"
+        );
+        assert_eq!(summary(&[], Units::Binary), "");
+    }
+}
@@ -0,0 +1,65 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::types::BeatmapInfo;
+
+/// the ranked statuses the osu! API knows, for --only-status-changed
+pub const STATUSES: [&str; 7] = [
+    "graveyard",
+    "wip",
+    "pending",
+    "ranked",
+    "approved",
+    "qualified",
+    "loved",
+];
+
+/// a set whose ranked status differs between two lists
+pub struct StatusChange<'a> {
+    pub map: &'a BeatmapInfo,
+    pub from: &'a str,
+    pub to: &'a str,
+}
+
+/// the sets of `current` whose status changed to one of `wanted` since `previous`. sets that
+/// weren't in `previous` or lack a status in either list don't count as changed
+pub fn changed<'a>(
+    previous: &'a [BeatmapInfo],
+    current: &'a [BeatmapInfo],
+    wanted: &[String],
+) -> Vec<StatusChange<'a>> {
+    let before: HashMap<u32, &str> = previous
+        .iter()
+        .filter_map(|m| Some((m.beatmapset_id, m.status.as_deref()?)))
+        .collect();
+    let mut seen = HashSet::new();
+    current
+        .iter()
+        .filter_map(|map| {
+            let to = map.status.as_deref()?;
+            let from = *before.get(&map.beatmapset_id)?;
+            (from != to && wanted.iter().any(|w| w == to) && seen.insert(map.beatmapset_id))
+                .then_some(StatusChange { map, from, to })
+        })
+        .collect()
+}
+
+/// list the changes, grouped by where they went from and to
+pub fn print_changes(changes: &[StatusChange]) {
+    let mut groups: Vec<((&str, &str), Vec<&BeatmapInfo>)> = Vec::new();
+    for change in changes {
+        match groups
+            .iter_mut()
+            .find(|(key, _)| *key == (change.from, change.to))
+        {
+            Some((_, maps)) => maps.push(change.map),
+            None => groups.push(((change.from, change.to), vec![change.map])),
+        }
+    }
+    for ((from, to), maps) in groups {
+        println!("{} → {} ({}):", from, to, maps.len());
+        for map in maps {
+            println!("  {:>8}  {} - {}", map.beatmapset_id, map.artist, map.title);
+        }
+    }
+    println!();
+}
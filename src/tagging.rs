@@ -0,0 +1,30 @@
+use anyhow::{Context, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Seek;
+use std::path::Path;
+
+use crate::types::BeatmapInfo;
+
+/// the `BeatmapInfo` stored in the archive comment, if there is one
+pub fn read_tag(path: &Path) -> Result<Option<BeatmapInfo>> {
+    let archive = zip::ZipArchive::new(File::open(path)?)?;
+    Ok(serde_json::from_slice(archive.comment()).ok())
+}
+
+/// store `beatmap` as JSON in the zip comment of the archive, the entries are left untouched
+/// and only the central directory is rewritten. returns false if the archive was already tagged
+pub fn tag_archive(path: &Path, beatmap: &BeatmapInfo) -> Result<bool> {
+    if read_tag(path)?.is_some_and(|tag| tag.beatmapset_id == beatmap.beatmapset_id) {
+        return Ok(false);
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let mut writer = zip::ZipWriter::new_append(file).context("not a valid zip archive")?;
+    writer.set_comment(serde_json::to_string(beatmap)?);
+    let mut file = writer.finish()?;
+
+    // a previous comment may have been longer than the new one
+    let end = file.stream_position()?;
+    file.set_len(end)?;
+    Ok(true)
+}
@@ -0,0 +1,181 @@
+use clap::ValueEnum;
+use console::{measure_text_width, pad_str, style, truncate_str, Alignment, Term};
+use std::collections::HashMap;
+
+use crate::types::BeatmapInfo;
+use crate::units;
+
+/// what `top` ranks the library by
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum TopBy {
+    /// plays of every difficulty of a set added up
+    Playcount,
+    /// star rating of the hardest difficulty of a set
+    Stars,
+    /// length of a set's longest difficulty
+    Length,
+    /// plays of all sets by an artist
+    Artist,
+    /// plays of all sets by a mapper
+    Creator,
+}
+
+// longest label before it's cut off
+const MAX_LABEL: usize = 40;
+// narrower bars than this aren't worth drawing
+const MIN_BAR: usize = 10;
+// the partial blocks of a bar, by eighths of a character
+const EIGHTHS: [char; 8] = [' ', '▏', '▎', '▍', '▌', '▋', '▊', '▉'];
+
+struct Row {
+    label: String,
+    value: f64,
+}
+
+/// print the `n` highest ranked sets, artists or mappers, as a bar chart when stdout is a
+/// terminal wide enough and `plain` isn't set, as aligned text otherwise
+pub fn print(maps: &[BeatmapInfo], by: TopBy, n: usize, plain: bool) {
+    let (mut rows, skipped) = rows(maps, by);
+    rows.sort_by(|a, b| b.value.total_cmp(&a.value));
+    rows.truncate(n);
+    if rows.is_empty() {
+        println!("Nothing to chart");
+    } else {
+        print_rows(&rows, by, plain);
+    }
+    if skipped > 0 {
+        println!(
+            "\n{} maps don't have the {} yet, `enrich` looks it up",
+            skipped,
+            by_name(by)
+        );
+    }
+}
+
+fn print_rows(rows: &[Row], by: TopBy, plain: bool) {
+    let values: Vec<String> = rows.iter().map(|r| format_value(r.value, by)).collect();
+    let labels: Vec<String> = rows
+        .iter()
+        .map(|r| truncate_str(&r.label, MAX_LABEL, "…").into_owned())
+        .collect();
+    let label_width = labels
+        .iter()
+        .map(|l| measure_text_width(l))
+        .max()
+        .unwrap_or(0);
+    let value_width = values
+        .iter()
+        .map(|v| measure_text_width(v))
+        .max()
+        .unwrap_or(0);
+    let rank_width = rows.len().to_string().len();
+
+    let term = Term::stdout();
+    // rank, ". ", label, a space before and after the bar and the value
+    let used = rank_width + 2 + label_width + 2 + value_width;
+    let bar_width = match term.size_checked() {
+        Some((_, columns)) if !plain && term.is_term() => {
+            (columns as usize).saturating_sub(used + 1)
+        }
+        _ => 0,
+    };
+    let max = rows.first().map_or(0.0, |r| r.value);
+
+    for (i, ((row, label), value)) in rows.iter().zip(&labels).zip(&values).enumerate() {
+        let label = pad_str(label, label_width, Alignment::Left, None);
+        if bar_width < MIN_BAR {
+            println!(
+                "{:>rank_width$}. {} {}",
+                i + 1,
+                label,
+                pad_str(value, value_width, Alignment::Right, None)
+            );
+        } else {
+            let bar = bar(row.value / max, bar_width);
+            println!(
+                "{:>rank_width$}. {} {} {}",
+                i + 1,
+                label,
+                style(bar).cyan(),
+                value
+            );
+        }
+    }
+}
+
+/// the chart's rows and how many sets were left out for lacking the value
+fn rows(maps: &[BeatmapInfo], by: TopBy) -> (Vec<Row>, usize) {
+    let mut totals: HashMap<String, f64> = HashMap::new();
+    let mut sets: HashMap<u32, Row> = HashMap::new();
+    let mut skipped = 0;
+    for map in maps {
+        let label = format!("{} - {}", map.artist, map.title);
+        let value = match by {
+            TopBy::Playcount => Some(map.play_count as f64),
+            TopBy::Stars => map.max_stars().or(map.stars).map(f64::from),
+            TopBy::Length => map.length_seconds.map(f64::from),
+            TopBy::Artist => {
+                *totals.entry(map.artist.clone()).or_default() += map.play_count as f64;
+                continue;
+            }
+            TopBy::Creator => {
+                let Some(creator) = &map.creator else {
+                    skipped += 1;
+                    continue;
+                };
+                *totals.entry(creator.clone()).or_default() += map.play_count as f64;
+                continue;
+            }
+        };
+        let Some(value) = value else {
+            skipped += 1;
+            continue;
+        };
+        // a set can be in the list once per difficulty
+        let row = sets
+            .entry(map.beatmapset_id)
+            .or_insert(Row { label, value: 0.0 });
+        row.value = if by == TopBy::Playcount {
+            row.value + value
+        } else {
+            row.value.max(value)
+        };
+    }
+
+    let rows = if matches!(by, TopBy::Artist | TopBy::Creator) {
+        totals
+            .into_iter()
+            .map(|(label, value)| Row { label, value })
+            .collect()
+    } else {
+        sets.into_values().collect()
+    };
+    (rows, skipped)
+}
+
+/// a bar `fraction` of `width` characters long, in eighths of a character
+fn bar(fraction: f64, width: usize) -> String {
+    let eighths = (fraction.clamp(0.0, 1.0) * (width * 8) as f64).round() as usize;
+    let mut bar = "█".repeat(eighths / 8);
+    if !eighths.is_multiple_of(8) {
+        bar.push(EIGHTHS[eighths % 8]);
+    }
+    format!("{:<width$}", bar)
+}
+
+fn format_value(value: f64, by: TopBy) -> String {
+    match by {
+        TopBy::Stars => format!("{:.2}★", value),
+        TopBy::Length => format!("{}:{:02}", value as u64 / 60, value as u64 % 60),
+        _ => format!("{} plays", units::count(value as usize)),
+    }
+}
+
+fn by_name(by: TopBy) -> &'static str {
+    match by {
+        TopBy::Stars => "star rating",
+        TopBy::Length => "length",
+        TopBy::Creator => "mapper",
+        TopBy::Playcount | TopBy::Artist => "play count",
+    }
+}
@@ -1,6 +1,9 @@
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BeatmapInfo {
     pub beatmap_id: u32,
     pub beatmapset_id: u32,
@@ -9,24 +12,231 @@ pub struct BeatmapInfo {
     pub version: String,
     pub play_count: u32,
     pub download_link: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creator: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub length_seconds: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f32>,
+    /// game mode of the difficulty: osu, taiko, fruits or mania
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<String>,
+    /// mode the difficulty was played in when it's an osu!standard map played as a convert,
+    /// only known for maps fetched from scores
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub played_mode: Option<String>,
+    /// star rating of the difficulty
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stars: Option<f32>,
+    /// ranked status of the difficulty: ranked, loved, graveyard, ...
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// MD5 of the difficulty's .osu file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    /// number of replays found for the map, only set when fetched with --from-replays
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replay_count: Option<u32>,
+    /// when the latest score on the map was set, as a unix timestamp. only known for maps
+    /// fetched from the recent source
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_played: Option<i64>,
+    /// which fetch sources the map came from, unioned when fetches are merged with --merge
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub set_summary: Option<SetSummary>,
+    /// every difficulty of the set, only stored when fetched with --difficulties or enriched
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub difficulties: Vec<DifficultyInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub genre: Option<Genre>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<Language>,
+    /// why the last download of the set failed when no mirror had it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unavailable: Option<Unavailable>,
+}
+
+/// why no mirror had a set
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unavailable {
+    /// the set is still on osu!, the mirrors just don't have it (yet)
+    MirrorMissing,
+    /// the set was deleted from osu!
+    Deleted,
+}
+
+/// overview of all difficulties in the beatmapset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetSummary {
+    pub difficulties: u32,
+    pub min_stars: f32,
+    pub max_stars: f32,
+    /// number of difficulties per game mode
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub mode_counts: BTreeMap<String, u32>,
+}
+
+/// one difficulty of a beatmapset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DifficultyInfo {
+    pub beatmap_id: u32,
+    pub version: String,
+    pub stars: f32,
+    pub mode: String,
 }
 
 impl BeatmapInfo {
-    pub fn filename(&self) -> String {
-        let artist = sanitize_filename(&self.artist);
-        let title = sanitize_filename(&self.title);
-        format!("{} {} - {}.osz", self.beatmapset_id, artist, title)
-    }
-}
-
-fn sanitize_filename(s: &str) -> String {
-    s.chars()
-        .map(|c| match c {
-            '<' | '>' | ':' | '"' | '/' | '\\' | '|' | '?' | '*' => '_',
-            c if c.is_control() => '_',
-            c => c,
-        })
-        .collect::<String>()
-        .trim_matches(|c| c == '.' || c == ' ')
-        .to_string()
-}
\ No newline at end of file
+    /// an entry of a list of bare beatmapset IDs, without any metadata
+    pub fn from_set_id(beatmapset_id: u32) -> Self {
+        Self {
+            beatmapset_id,
+            download_link: format!("https://osu.ppy.sh/beatmapsets/{}", beatmapset_id),
+            ..Default::default()
+        }
+    }
+
+    /// the mode the difficulty was made for, from the set's difficulties when the list has them
+    pub fn native_mode(&self) -> Option<&str> {
+        self.difficulties
+            .iter()
+            .find(|d| d.beatmap_id == self.beatmap_id)
+            .map(|d| d.mode.as_str())
+            .or(self.mode.as_deref())
+    }
+
+    /// star rating of the set's hardest difficulty, if the list has the set's difficulties
+    pub fn max_stars(&self) -> Option<f32> {
+        let hardest = self.difficulties.iter().map(|d| d.stars).reduce(f32::max);
+        hardest.or_else(|| self.set_summary.as_ref().map(|s| s.max_stars))
+    }
+
+    /// short description of the set, e.g. "7 diffs, 1.2★–6.8★, mapped by Sotarks"
+    pub fn set_description(&self) -> Option<String> {
+        let mut parts = Vec::new();
+        if let Some(summary) = &self.set_summary {
+            parts.push(format!(
+                "{} diffs, {:.1}★–{:.1}★",
+                summary.difficulties, summary.min_stars, summary.max_stars
+            ));
+        }
+        if let Some(creator) = &self.creator {
+            parts.push(format!("mapped by {}", creator));
+        }
+        (!parts.is_empty()).then(|| parts.join(", "))
+    }
+}
+
+/// genre of a beatmapset as the osu! website lists it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Genre {
+    Unspecified,
+    #[value(alias = "videogame")]
+    VideoGame,
+    Anime,
+    Rock,
+    Pop,
+    Other,
+    Novelty,
+    #[value(alias = "hiphop")]
+    HipHop,
+    Electronic,
+    Metal,
+    Classical,
+    Folk,
+    Jazz,
+}
+
+/// language of a beatmapset's song as the osu! website lists it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+    Unspecified,
+    English,
+    Japanese,
+    Chinese,
+    Instrumental,
+    Korean,
+    French,
+    German,
+    Swedish,
+    Spanish,
+    Italian,
+    Russian,
+    Polish,
+    Other,
+}
+
+/// extension of the downloaded archives, `.osz` and `.olz` are plain zip files
+#[derive(Debug, Clone, Copy, PartialEq, Default, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ArchiveExtension {
+    #[default]
+    Osz,
+    /// the extension osu!lazer uses for its exports
+    Olz,
+    /// the encrypted package format some mirrors serve for a few sets. it can't be chosen,
+    /// archives get it when the mirror sends one
+    #[value(skip)]
+    Osz2,
+}
+
+// an .osz2 package starts with these bytes, a zip archive with "PK"
+const OSZ2_MAGIC: &[u8] = b"\xecHOC";
+const ZIP_MAGIC: &[u8] = b"PK";
+
+impl ArchiveExtension {
+    const ALL: [Self; 3] = [
+        ArchiveExtension::Osz,
+        ArchiveExtension::Olz,
+        ArchiveExtension::Osz2,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ArchiveExtension::Osz => "osz",
+            ArchiveExtension::Olz => "olz",
+            ArchiveExtension::Osz2 => "osz2",
+        }
+    }
+
+    /// the archive extension of a path, if it has one
+    pub fn of(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?;
+        Self::ALL
+            .into_iter()
+            .find(|v| ext.eq_ignore_ascii_case(v.as_str()))
+    }
+
+    pub fn is_zip(self) -> bool {
+        self != ArchiveExtension::Osz2
+    }
+
+    pub fn is_zip_header(header: &[u8]) -> bool {
+        header.starts_with(ZIP_MAGIC)
+    }
+
+    /// the package type of a download from its first bytes and the filename the server
+    /// gave it. `None` when it's a zip archive, which keeps the configured extension
+    pub fn detect(header: &[u8], server_filename: Option<&str>) -> Option<Self> {
+        if header.starts_with(OSZ2_MAGIC) {
+            return Some(ArchiveExtension::Osz2);
+        }
+        if Self::is_zip_header(header) {
+            return None;
+        }
+        // unknown contents, the server's name is all there is to go on
+        server_filename
+            .and_then(|name| Self::of(Path::new(name)))
+            .filter(|ext| !ext.is_zip())
+    }
+
+    /// whether the path has any archive extension, so switching extensions doesn't
+    /// download everything again
+    pub fn is_archive(path: &Path) -> bool {
+        Self::of(path).is_some()
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+const BINARY: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+const SI: [&str; 5] = ["B", "kB", "MB", "GB", "TB"];
+
+/// the units sizes are shown in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Units {
+    /// KiB, MiB, ...
+    #[default]
+    Binary,
+    /// kB, MB, ..., with --si-units
+    Si,
+}
+
+/// a size in the largest unit it's at least 1 of, e.g. `1.5 MiB`, or `1.6 MB` in SI units
+pub fn bytes(n: u64, units: Units) -> String {
+    let (base, units) = match units {
+        Units::Binary => (1024.0, BINARY),
+        Units::Si => (1000.0, SI),
+    };
+    let mut value = n as f64;
+    let mut unit = 0;
+    while value >= base && unit < units.len() - 1 {
+        value /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", n)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+/// a transfer rate in the units of `bytes`, e.g. `2.4 MiB/s`
+pub fn rate(bytes_per_second: f64, units: Units) -> String {
+    format!("{}/s", bytes(bytes_per_second.max(0.0) as u64, units))
+}
+
+/// a duration as `h:mm:ss`, rounded up to the second so a countdown never shows 0:00:00 early
+pub fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs_f64().ceil() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+/// a count with its thousands separated, e.g. `45,000`
+pub fn count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut grouped = String::with_capacity(digits.len() + digits.len() / 3);
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(bytes(0, Units::Binary), "0 B");
+        assert_eq!(bytes(1023, Units::Binary), "1023 B");
+        assert_eq!(bytes(1536, Units::Binary), "1.5 KiB");
+        assert_eq!(bytes(1_572_864, Units::Binary), "1.5 MiB");
+        assert_eq!(bytes(1_572_864, Units::Si), "1.6 MB");
+        assert_eq!(bytes(5 * 1024_u64.pow(5), Units::Binary), "5120.0 TiB");
+        assert_eq!(rate(2_516_582.4, Units::Binary), "2.4 MiB/s");
+        assert_eq!(rate(-1.0, Units::Si), "0 B/s");
+    }
+
+    #[test]
+    fn formats_durations_and_counts() {
+        assert_eq!(duration(Duration::ZERO), "0:00:00");
+        assert_eq!(duration(Duration::from_millis(59_001)), "0:01:00");
+        assert_eq!(duration(Duration::from_secs(37_230)), "10:20:30");
+        assert_eq!(count(0), "0");
+        assert_eq!(count(999), "999");
+        assert_eq!(count(45_000), "45,000");
+        assert_eq!(count(1_234_567), "1,234,567");
+    }
+}
@@ -0,0 +1,113 @@
+use anyhow::Result;
+use reqwest::header::{HeaderMap, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::jsonl;
+use crate::mirror::Mirror;
+use crate::settings;
+
+/// sidecar file in the output directory with the caching headers of each download, one JSON
+/// object per line. with COMPRESS_STATE it's kept as `http_validators.jsonl.zst`
+pub const VALIDATORS_FILE: &str = "http_validators.jsonl";
+
+/// the `ETag` and `Last-Modified` a mirror sent with a set's archive, sent back when the set
+/// is refreshed so the mirror can answer 304 Not Modified instead of sending it again
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Validator {
+    pub beatmapset_id: u32,
+    pub mirror: Mirror,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validator {
+    /// the validators of a response, `None` for mirrors that don't send either
+    pub fn from_headers(beatmapset_id: u32, mirror: Mirror, headers: &HeaderMap) -> Option<Self> {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        };
+        let (etag, last_modified) = (header(ETAG), header(LAST_MODIFIED));
+        (etag.is_some() || last_modified.is_some()).then_some(Self {
+            beatmapset_id,
+            mirror,
+            etag,
+            last_modified,
+        })
+    }
+}
+
+/// the validators file of `dir`, `http_validators.jsonl` or its compressed `.zst`
+pub fn path(dir: &Path) -> PathBuf {
+    jsonl::state_path(dir.join(VALIDATORS_FILE))
+}
+
+/// recorded validators by set ID and mirror, later lines win over earlier ones
+pub fn load(dir: &Path) -> HashMap<(u32, Mirror), Validator> {
+    jsonl::read(&path(dir))
+        .map(|s| {
+            s.lines()
+                .filter_map(|l| serde_json::from_str::<Validator>(l).ok())
+                .map(|v| ((v.beatmapset_id, v.mirror), v))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// append the validators of a download to the file in `dir`
+pub fn record(dir: &Path, validator: &Validator) -> Result<()> {
+    jsonl::append(&path(dir), &serde_json::to_string(validator)?)
+}
+
+/// what `compact` did
+pub struct Compacted {
+    pub path: PathBuf,
+    pub lines_before: usize,
+    pub lines_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+/// rewrite the validators file of `dir` with only the latest record of each set and mirror, in
+/// the format COMPRESS_STATE asks for. a file in the other format is converted and removed
+pub fn compact(dir: &Path) -> Result<Option<Compacted>> {
+    let from = path(dir);
+    if !from.exists() {
+        return Ok(None);
+    }
+    let content = jsonl::read(&from)?;
+    let lines_before = content.lines().count();
+    let bytes_before = fs::metadata(&from)?.len();
+
+    let mut latest: Vec<Validator> = load(dir).into_values().collect();
+    latest.sort_by_key(|v| (v.beatmapset_id, v.mirror.name()));
+    let lines = latest
+        .iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let plain = dir.join(VALIDATORS_FILE);
+    let to = if settings::get().compress_state {
+        jsonl::compressed_path(&plain)
+    } else {
+        plain
+    };
+    jsonl::write(&to, &lines)?;
+    if to != from {
+        fs::remove_file(&from)?;
+    }
+    Ok(Some(Compacted {
+        bytes_after: fs::metadata(&to)?.len(),
+        path: to,
+        lines_before,
+        lines_after: lines.len(),
+        bytes_before,
+    }))
+}
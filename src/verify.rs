@@ -0,0 +1,100 @@
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use crate::types::ArchiveExtension;
+
+/// directory in the output directory that archives failing the --heal check are moved to. the
+/// scan for existing archives doesn't look into it
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// parse the beatmapset ID from the start of an archive filename
+pub fn parse_mapset_id(filename: &str) -> Option<u32> {
+    filename.split_whitespace().next()?.parse().ok()
+}
+
+/// all `.osz`, `.olz` and `.osz2` archives in a directory and its subdirectories, except the
+/// quarantine of `--heal`
+pub fn list_archives(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut archives = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)?.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            // file_type doesn't follow symlinks, so a link back up the tree can't loop
+            if entry.file_type().is_ok_and(|t| t.is_dir()) {
+                if entry.file_name() != QUARANTINE_DIR {
+                    pending.push(path);
+                }
+            } else if ArchiveExtension::is_archive(&path) {
+                archives.push(path);
+            }
+        }
+    }
+    Ok(archives)
+}
+
+/// check that the file is a readable zip archive with at least one `.osu` difficulty,
+/// every entry is read through so the CRCs get checked as well. `.osz2` packages are
+/// encrypted, so only their header is checked
+pub fn verify_archive(path: &Path) -> Result<()> {
+    if ArchiveExtension::of(path).is_some_and(|ext| !ext.is_zip()) {
+        return verify_osz2(path);
+    }
+    let file = File::open(path)?;
+    let mut archive = zip::ZipArchive::new(file).context("not a valid zip archive")?;
+
+    let mut has_difficulty = false;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).context("corrupt zip entry")?;
+        if entry.name().to_lowercase().ends_with(".osu") {
+            has_difficulty = true;
+        }
+        io::copy(&mut entry, &mut io::sink())
+            .with_context(|| format!("corrupt data in {}", entry.name()))?;
+    }
+
+    if !has_difficulty {
+        bail!("no .osu difficulty in archive");
+    }
+    Ok(())
+}
+
+/// the cheap part of `verify_archive`: the zip's central directory is read and has to list
+/// an `.osu` difficulty, but nothing is decompressed
+pub fn check_header(path: &Path) -> Result<()> {
+    if ArchiveExtension::of(path).is_some_and(|ext| !ext.is_zip()) {
+        return verify_osz2(path);
+    }
+    let archive = zip::ZipArchive::new(File::open(path)?).context("not a valid zip archive")?;
+    if !archive
+        .file_names()
+        .any(|name| name.to_lowercase().ends_with(".osu"))
+    {
+        bail!("no .osu difficulty in archive");
+    }
+    Ok(())
+}
+
+// magic, version, IV and three hashes come before the packed files
+const OSZ2_HEADER_LEN: usize = 4 + 1 + 16 + 3 * 16;
+
+fn verify_osz2(path: &Path) -> Result<()> {
+    let mut header = [0; OSZ2_HEADER_LEN];
+    File::open(path)?
+        .read_exact(&mut header)
+        .context("too short for an osz2 package")?;
+    if ArchiveExtension::detect(&header, None) != Some(ArchiveExtension::Osz2) {
+        bail!("not an osz2 package");
+    }
+    Ok(())
+}
+
+/// verify every path, returning the ones that failed along with the reason
+pub fn verify_archives(paths: &[PathBuf]) -> Vec<(PathBuf, anyhow::Error)> {
+    paths
+        .iter()
+        .filter_map(|p| verify_archive(p).err().map(|e| (p.clone(), e)))
+        .collect()
+}
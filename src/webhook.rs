@@ -0,0 +1,85 @@
+use anyhow::{bail, Result};
+use reqwest::Client;
+use serde_json::json;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::downloader::DownloadReport;
+use crate::settings;
+use crate::units;
+
+// embed colors for a run without failures, with some and with nothing downloaded
+const GREEN: u32 = 0x57f287;
+const YELLOW: u32 = 0xfee75c;
+const RED: u32 = 0xed4245;
+
+// Discord cuts embed field values at 1024 characters
+const MAX_FIELD_LEN: usize = 1024;
+
+/// post a summary of the run to a Discord-compatible --webhook as an embed. a failing webhook is
+/// reported but doesn't change how the run ends
+pub async fn send(client: &Client, url: &str, report: &DownloadReport, output_dir: &Path) {
+    if let Err(e) = post(client, url, report, output_dir).await {
+        println!("Webhook failed: {:#}", e);
+    }
+}
+
+async fn post(
+    client: &Client,
+    url: &str,
+    report: &DownloadReport,
+    output_dir: &Path,
+) -> Result<()> {
+    let color = match (report.downloaded, report.failed) {
+        (_, 0) => GREEN,
+        (0, _) => RED,
+        _ => YELLOW,
+    };
+    let mut fields = vec![
+        json!({ "name": "Downloaded", "value": units::count(report.downloaded), "inline": true }),
+        json!({ "name": "Failed", "value": units::count(report.failed), "inline": true }),
+        json!({ "name": "Duration", "value": units::duration(report.duration), "inline": true }),
+    ];
+    if !report.failed_ids.is_empty() {
+        fields.push(json!({ "name": "Failed beatmapsets", "value": id_list(&report.failed_ids) }));
+    }
+    let body = json!({
+        "embeds": [{
+            "title": "osu! beatmap download finished",
+            "description": format!("{} saved to {}", units::bytes(report.bytes, settings::get().units), output_dir.display()),
+            "color": color,
+            "fields": fields,
+        }]
+    });
+
+    let response = client
+        .post(url)
+        .json(&body)
+        .timeout(Duration::from_secs(15))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        bail!("the webhook answered {}", response.status());
+    }
+    Ok(())
+}
+
+/// the IDs separated by commas, as many as fit into an embed field
+fn id_list(ids: &[u32]) -> String {
+    let mut list = String::new();
+    for (i, id) in ids.iter().enumerate() {
+        let more = format!("...and {} more", ids.len() - i);
+        let next = if i == 0 {
+            id.to_string()
+        } else {
+            format!(", {}", id)
+        };
+        if list.len() + next.len() + more.len() + 1 > MAX_FIELD_LEN {
+            list.push(' ');
+            list.push_str(&more);
+            break;
+        }
+        list.push_str(&next);
+    }
+    list
+}